@@ -2,6 +2,10 @@ use std::net::SocketAddr;
 
 use tokio::net::UdpSocket;
 
+use tox::toxcore::udp::*;
+
+// not every example loads bootstrap nodes from this list
+#[allow(dead_code)]
 pub const BOOTSTRAP_NODES: [(&str, &str); 9] = [
     // Impyy
     ("1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F", "198.98.51.198:33445"),
@@ -23,18 +27,19 @@ pub const BOOTSTRAP_NODES: [(&str, &str); 9] = [
     ("2B2137E094F743AC8BD44652C55F41DFACC502F125E99E4FE24D40537489E32F", "5.189.176.217:5190"),
 ];
 
-/// Bind a UDP listener to the socket address.
+/// Bind a UDP listener to the socket address. If the port is taken the next
+/// ports from the default Tox range are tried.
 pub async fn bind_socket(addr: SocketAddr) -> UdpSocket {
-    let socket = UdpSocket::bind(&addr)
+    let mut binder = UdpBinder::new(addr.ip());
+    if addr.port() != 0 {
+        binder.set_port_range(addr.port() ..= addr.port().max(PORT_RANGE_END));
+    } else {
+        binder.set_port_range(0 ..= 0);
+    }
+
+    let (socket, _addr) = binder.bind()
         .await
         .expect("Failed to bind UDP socket");
 
-    socket.set_broadcast(true).expect("set_broadcast call failed");
-    if addr.is_ipv6() {
-        socket.set_multicast_loop_v6(true)
-            .expect("set_multicast_loop_v6 call failed");
-    }
-
     socket
 }
-
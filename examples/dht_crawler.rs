@@ -0,0 +1,102 @@
+// DHT network crawler that prints all found nodes in JSON or CSV format
+//
+// Usage: cargo run --example dht_crawler [json|csv]
+#[macro_use]
+extern crate log;
+
+use std::io::Error;
+use std::net::SocketAddr;
+
+use futures::{FutureExt, SinkExt, StreamExt};
+use futures::channel::mpsc;
+use failure::Fail;
+
+use tox::toxcore::dht::codec::*;
+use tox::toxcore::dht::crawler::*;
+use tox::toxcore::dht::packed_node::PackedNode;
+use tox::toxcore::crypto_core::*;
+use tox::toxcore::stats::Stats;
+
+mod common;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+
+    if crypto_init().is_err() {
+        panic!("Crypto initialization failed.");
+    }
+
+    let csv = match std::env::args().nth(1).as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(format) => panic!("Unknown output format: {}", format),
+    };
+
+    let (pk, sk) = gen_keypair();
+
+    let (tx, mut rx) = mpsc::channel(256);
+
+    let local_addr: SocketAddr = "0.0.0.0:0".parse()?; // 0.0.0.0 for IPv4
+    // let local_addr: SocketAddr = "[::]:0".parse().unwrap(); // [::] for IPv6
+
+    let crawler = Crawler::new(tx, pk, sk);
+
+    for &(pk, saddr) in &common::BOOTSTRAP_NODES {
+        let pk_bytes: [u8; 32] = hex::FromHex::from_hex(pk).unwrap();
+        let pk = PublicKey::from_slice(&pk_bytes).unwrap();
+        crawler.add_bootstrap_node(PackedNode::new(saddr.parse().unwrap(), &pk));
+    }
+
+    let crawler_c = crawler.clone();
+    let future = async move {
+        let socket = common::bind_socket(local_addr).await;
+        let (mut sink, mut stream) =
+            tokio_util::udp::UdpFramed::new(socket, DhtCodec::new(Stats::new())).split();
+
+        let network_reader = async {
+            while let Some(event) = stream.next().await {
+                match event {
+                    Ok((packet, addr)) => if let Err(e) = crawler_c.handle_packet(packet, addr) {
+                        debug!("Failed to handle packet from {}: {}", addr, e);
+                    },
+                    // ignore packet decode errors
+                    Err(e) => debug!("Packet receive error: {:?}", e),
+                }
+            }
+
+            Ok(())
+        };
+
+        let network_writer = async {
+            while let Some((packet, addr)) = rx.next().await {
+                sink.send((packet, addr)).await
+                    .map_err(|e| Error::other(e.compat()))?;
+            }
+
+            Ok(())
+        };
+
+        futures::select! {
+            res = network_reader.fuse() => res,
+            res = network_writer.fuse() => res,
+            res = crawler_c.clone().run().fuse() =>
+                res.map_err(|e| Error::other(e.compat())),
+        }
+    };
+
+    info!("Crawling DHT network");
+
+    let mut runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(future)?;
+
+    let nodes = crawler.nodes();
+    info!("Found {} nodes, {} of them responded", nodes.len(), nodes.iter().filter(|node| node.responded).count());
+
+    if csv {
+        print!("{}", to_csv(&nodes).map_err(Fail::compat)?);
+    } else {
+        println!("{}", to_json(&nodes).map_err(Fail::compat)?);
+    }
+
+    Ok(())
+}
@@ -6,29 +6,35 @@ extern crate log;
 use std::io::{Error, ErrorKind};
 
 use futures::future::FutureExt;
+#[cfg(feature = "json")]
+use futures::StreamExt;
 use futures::channel::mpsc;
 use failure::Fail;
 
 use std::net::SocketAddr;
+#[cfg(feature = "json")]
+use std::path::PathBuf;
+use std::time::Instant;
 
+#[cfg(not(feature = "json"))]
+use hex::FromHex;
+
+use tox::toxcore::dht::bootstrap_info::BootstrapMotd;
 use tox::toxcore::dht::server::*;
 use tox::toxcore::dht::server_ext::ServerExt;
 use tox::toxcore::dht::lan_discovery::*;
-use tox::toxcore::dht::packed_node::PackedNode;
 use tox::toxcore::crypto_core::*;
+#[cfg(not(feature = "json"))]
+use tox::toxcore::dht::packed_node::PackedNode;
+#[cfg(feature = "json")]
+use tox::toxcore::nodes_json::*;
 use tox::toxcore::stats::Stats;
+#[cfg(feature = "prometheus")]
+use tox::toxcore::prometheus::Exporter;
 
 
 mod common;
 
-fn as_packed_node(pk: &str, saddr: &str) -> PackedNode {
-    let pk_bytes: [u8; 32] = hex::FromHex::from_hex(pk).unwrap();
-    let pk = PublicKey::from_slice(&pk_bytes).unwrap();
-    let saddr: SocketAddr = saddr.parse().unwrap();
-
-    PackedNode::new(saddr, &pk)
-}
-
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
@@ -43,6 +49,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let local_addr: SocketAddr = "0.0.0.0:33445".parse()?; // 0.0.0.0 for IPv4
     // let local_addr: SocketAddr = "[::]:33445".parse().unwrap(); // [::] for IPv6
+    // Use `run_sockets` with separate IPv4 and IPv6 sockets for dual-stack mode
 
     let stats = Stats::new();
 
@@ -50,18 +57,70 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         LanDiscoverySender::new(tx.clone(), server_pk, local_addr.is_ipv6());
 
     let mut server = Server::new(tx, server_pk, server_sk);
-    server.set_bootstrap_info(3_000_000_000, Box::new(|_| b"This is tox-rs".to_vec()));
+    let start_time = Instant::now();
+    server.set_bootstrap_motd(3_000_000_000, Box::new(move |server| {
+        let mut motd = BootstrapMotd::new("This is tox-rs");
+        motd.set_uptime(start_time.elapsed());
+        motd.set_nodes_count(server.close_nodes_count());
+        motd
+    }));
     server.enable_lan_discovery(true);
     server.enable_ipv6_mode(local_addr.is_ipv6());
+    server.set_stats(stats.clone());
+
+    // Bootstrap from nodes listed in nodes.json file. The path can be passed
+    // as the first argument, the file is reloaded periodically
+    #[cfg(feature = "json")]
+    let nodes_path = std::env::args().nth(1).map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/nodes.json")));
+    #[cfg(feature = "json")]
+    let nodes_future = {
+        let nodes = NodesList::load(&nodes_path).map_err(|e| e.compat())?;
+        server.set_initial_bootstrap(nodes.bootstrap_nodes(local_addr.is_ipv6()));
+
+        let (nodes_tx, mut nodes_rx) = mpsc::channel::<NodesList>(1);
+        let server_c = server.clone();
+        async move {
+            tokio::spawn(watch_file(nodes_path, NODES_JSON_RELOAD_INTERVAL, nodes_tx));
+            while let Some(nodes) = nodes_rx.next().await {
+                server_c.set_initial_bootstrap(nodes.bootstrap_nodes(local_addr.is_ipv6()));
+            }
+        }
+    };
 
-    // Bootstrap from nodes
+    // Bootstrap from hardcoded nodes when nodes.json can't be parsed without
+    // `json` feature
+    #[cfg(not(feature = "json"))]
     for &(pk, saddr) in &common::BOOTSTRAP_NODES {
-        let bootstrap_pn = as_packed_node(pk, saddr);
+        // get PK bytes of the bootstrap node
+        let bootstrap_pk_bytes: [u8; 32] = FromHex::from_hex(pk)?;
+        // create PK from bytes
+        let bootstrap_pk = PublicKey::from_slice(&bootstrap_pk_bytes).unwrap();
+
+        let node = PackedNode::new(saddr.parse()?, &bootstrap_pk);
 
-        server.add_initial_bootstrap(bootstrap_pn);
+        server.add_initial_bootstrap(node);
     }
 
+    #[cfg(feature = "prometheus")]
+    let exporter = {
+        let mut exporter = Exporter::new(stats.clone());
+        exporter.set_dht_server(server.clone());
+        exporter
+    };
+
     let future = async move {
+        #[cfg(feature = "prometheus")]
+        {
+            let metrics_addr: SocketAddr = "127.0.0.1:9090".parse().unwrap();
+            let listener = tokio::net::TcpListener::bind(&metrics_addr).await?;
+            info!("Serving metrics on http://{}/metrics", metrics_addr);
+            tokio::spawn(exporter.run(listener));
+        }
+
+        #[cfg(feature = "json")]
+        tokio::spawn(nodes_future);
+
         let socket = common::bind_socket(local_addr).await;
         let server_fut = server.run_socket(socket, rx, stats)
             .boxed();
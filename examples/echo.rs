@@ -69,9 +69,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let mut dht_server = Server::new(tx.clone(), dht_pk, dht_sk.clone());
         dht_server.enable_lan_discovery(true);
         dht_server.enable_ipv6_mode(local_addr.is_ipv6());
+        dht_server.set_stats(stats.clone());
 
-        let tcp_connections = Connections::new(dht_pk, dht_sk.clone(), tcp_incoming_tx);
-        let onion_client = OnionClient::new(dht_server.clone(), tcp_connections.clone(), real_sk.clone(), real_pk);
+        let mut tcp_connections = Connections::new(dht_pk, dht_sk.clone(), tcp_incoming_tx);
+        tcp_connections.set_stats(stats.clone());
+        let mut onion_client = OnionClient::new(dht_server.clone(), tcp_connections.clone(), real_sk.clone(), real_pk);
+        onion_client.set_stats(stats.clone());
 
         let (lossless_tx, mut lossless_rx) = mpsc::unbounded();
         let (lossy_tx, mut lossy_rx) = mpsc::unbounded();
@@ -79,7 +82,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let (friend_request_tx, mut friend_request_sink_rx) = mpsc::unbounded();
         onion_client.set_friend_request_sink(friend_request_tx);
 
-        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+        let mut net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx: tx,
             lossless_tx,
             lossy_tx,
@@ -92,6 +95,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
         let (net_crypto_tcp_tx, mut net_crypto_tcp_rx) = mpsc::channel(32);
         net_crypto.set_tcp_sink(net_crypto_tcp_tx);
+        net_crypto.set_stats(stats.clone());
 
         dht_server.set_net_crypto(net_crypto.clone());
         dht_server.set_onion_client(onion_client.clone());
@@ -129,14 +133,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let onion_client_c = onion_client.clone();
         let net_crypto_c = net_crypto.clone();
         let tcp_incoming_future = async {
-            while let Some((_relay_pk, packet)) = tcp_incoming_rx.next().await { // TODO: do we need relay_pk at all?
+            while let Some((relay_pk, packet)) = tcp_incoming_rx.next().await {
                 let future = async {
                     match packet {
                         IncomingPacket::Data(sender_pk, packet) => match packet {
                             DataPayload::CookieRequest(packet) => net_crypto_c.handle_tcp_cookie_request(&packet, sender_pk).map_err(Error::from).await,
                             DataPayload::CookieResponse(packet) => net_crypto_c.handle_tcp_cookie_response(&packet, sender_pk).map_err(Error::from).await,
-                            DataPayload::CryptoHandshake(packet) => net_crypto_c.handle_tcp_crypto_handshake(&packet, sender_pk).map_err(Error::from).await,
-                            DataPayload::CryptoData(packet) => net_crypto_c.handle_tcp_crypto_data(&packet, sender_pk).map_err(Error::from).await,
+                            DataPayload::CryptoHandshake(packet) => net_crypto_c.handle_tcp_crypto_handshake(&packet, sender_pk, relay_pk).map_err(Error::from).await,
+                            DataPayload::CryptoData(packet) => net_crypto_c.handle_tcp_crypto_data(packet, sender_pk, relay_pk).map_err(Error::from).await,
                         },
                         IncomingPacket::Oob(_sender_pk, _packet) => Ok(()),
                         IncomingPacket::Onion(packet) => match packet {
@@ -178,7 +182,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             Ok(())
                         },
                         0x40 => { // PACKET_ID_CHAT_MESSAGE
-                            net_crypto_c.send_lossless(pk, packet).map_err(Error::from).await?;
+                            net_crypto_c.send_lossless(pk, packet.to_vec()).map_err(Error::from).await?;
                             Ok(())
                         },
                         _ => Ok(()),
@@ -4,6 +4,8 @@ extern crate log;
 use tox::toxcore::crypto_core::*;
 use tox::toxcore::tcp::server::{Server, ServerExt};
 use tox::toxcore::stats::Stats;
+#[cfg(feature = "prometheus")]
+use tox::toxcore::prometheus::Exporter;
 
 use tokio::net::TcpListener;
 
@@ -33,7 +35,23 @@ fn main() {
     let server = Server::new();
 
     let stats = Stats::new();
+
+    #[cfg(feature = "prometheus")]
+    let exporter = {
+        let mut exporter = Exporter::new(stats.clone());
+        exporter.set_tcp_server(server.clone());
+        exporter
+    };
+
     let future = async move {
+        #[cfg(feature = "prometheus")]
+        {
+            let metrics_addr: std::net::SocketAddr = "127.0.0.1:9090".parse().unwrap();
+            let listener = TcpListener::bind(&metrics_addr).await.unwrap();
+            info!("Serving metrics on http://{}/metrics", metrics_addr);
+            tokio::spawn(exporter.run(listener));
+        }
+
         let listener = TcpListener::bind(&addr).await.unwrap();
         drop(server.run(listener, server_sk, stats, TCP_CONNECTIONS_LIMIT).await);
     };
@@ -27,6 +27,11 @@ pub mod toxcore {
     #[macro_use]
     pub mod binary_io;
     pub mod io_tokio;
+    pub mod udp;
+    pub mod transport;
+    pub mod capture;
+    pub mod shutdown;
+    pub mod port_mapping;
     pub mod ip_port;
     pub mod packed_node;
     pub mod crypto_core;
@@ -41,6 +46,12 @@ pub mod toxcore {
     pub mod friend_connection;
     pub mod messenger;
     pub mod stats;
+    #[cfg(feature = "json")]
+    pub mod nodes_json;
+    #[cfg(any(test, feature = "simulation"))]
+    pub mod simulation;
+    #[cfg(feature = "prometheus")]
+    pub mod prometheus;
 }
 
 /// Tox Encrypt Save (a.k.a. **TES**) module. Can be used to ecrypt / decrypt
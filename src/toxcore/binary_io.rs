@@ -4,6 +4,7 @@
 pub use nom::IResult;
 pub use cookie_factory::GenError;
 
+use bytes::BytesMut;
 use nom::number::streaming::{le_u8, le_u16};
 use std::net::{
     IpAddr,
@@ -87,6 +88,41 @@ pub fn gen_error(_buf: (&mut [u8], usize), error: u32) -> Result<(&mut [u8], usi
     Err(GenError::CustomError(error))
 }
 
+/// Size of the region that `to_bytes_mut` initially reserves for a value.
+/// Most packets fit in it so that the rest of `max_size` isn't zero-filled.
+const TO_BYTES_MUT_INITIAL_SIZE: usize = 256;
+
+/** Serialize value directly to the end of `BytesMut` without an intermediate
+buffer. At most `max_size` bytes are reserved for the serialized value. The
+reserved region starts small and grows only when serialization reports that
+it needs more space.
+
+Returns the size of serialized value. `buf` is left unchanged in case of
+error.
+*/
+pub fn to_bytes_mut<T: ToBytes>(value: &T, buf: &mut BytesMut, max_size: usize) -> Result<usize, GenError> {
+    let start = buf.len();
+    let mut size = max_size.min(TO_BYTES_MUT_INITIAL_SIZE);
+    loop {
+        buf.resize(start + size, 0);
+        match value.to_bytes((&mut buf[start..], 0)) {
+            Ok((_, written)) => {
+                buf.truncate(start + written);
+                return Ok(written)
+            },
+            // serializers report either missing bytes or required position
+            // so the hint is used only as a lower bound for the growth
+            Err(GenError::BufferTooSmall(needed)) if size < max_size => {
+                size = max_size.min((size + needed).max(size * 2));
+            },
+            Err(error) => {
+                buf.truncate(start);
+                return Err(error)
+            },
+        }
+    }
+}
+
 /** Create test that encodes/decodes specified value and checks that result
 equals original value. Type of this value should implement `ToBytes`,
 `FromBytes`, `Clone`, `Eq` traits.
@@ -137,3 +173,49 @@ macro_rules! unpack {
         }
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::toxcore::dht::packet::CryptoData;
+
+    #[test]
+    fn to_bytes_mut_appends() {
+        let mut buf = BytesMut::from(&[42][..]);
+        let size = to_bytes_mut(&Ipv4Addr::new(1, 2, 3, 4), &mut buf, 16).unwrap();
+        assert_eq!(size, 4);
+        assert_eq!(&buf[..], &[42, 1, 2, 3, 4][..]);
+    }
+
+    #[test]
+    fn to_bytes_mut_grows() {
+        let packet = CryptoData {
+            nonce_last_bytes: 42,
+            payload: vec![42; 1000],
+        };
+        let mut buf = BytesMut::from(&[42][..]);
+        let size = to_bytes_mut(&packet, &mut buf, 2048).unwrap();
+        assert_eq!(size, 1003);
+        assert_eq!(buf.len(), 1004);
+        assert_eq!(buf[1], 0x1b);
+    }
+
+    #[test]
+    fn to_bytes_mut_too_big() {
+        let packet = CryptoData {
+            nonce_last_bytes: 42,
+            payload: vec![42; 1000],
+        };
+        let mut buf = BytesMut::from(&[42][..]);
+        assert!(to_bytes_mut(&packet, &mut buf, 1000).is_err());
+        assert_eq!(&buf[..], &[42][..]);
+    }
+
+    #[test]
+    fn to_bytes_mut_too_small() {
+        let mut buf = BytesMut::from(&[42][..]);
+        assert!(to_bytes_mut(&Ipv4Addr::new(1, 2, 3, 4), &mut buf, 3).is_err());
+        assert_eq!(&buf[..], &[42][..]);
+    }
+}
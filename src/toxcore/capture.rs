@@ -0,0 +1,645 @@
+/*! Capture and replay of DHT, TCP and net_crypto packets.
+
+[`Capture`] records packets with timestamp, direction, peer address and
+whether the packet was parsed successfully to a length-prefixed file:
+
+```text
+file   = magic record*
+magic  = "TOXCAP" 0x00 0x01
+record = length:u32 timestamp:u64 direction:u8 layer:u8 parsed:u8 addr data
+addr   = 0x00 | 0x04 ip:[u8; 4] port:u16 | 0x06 ip:[u8; 16] port:u16
+```
+
+All numbers are big endian, `length` is the length of the rest of the record
+and `timestamp` is the number of microseconds since the unix epoch.
+
+DHT packets are captured by wrapping the socket with [`CaptureTransport`], TCP
+packets are captured by TCP `Codec` after decryption and net_crypto packets
+are captured by `NetCrypto` after decryption. Records are written by
+[`CaptureWriter`] on a dedicated thread so that capturing blocks neither packet
+processing nor the executor. [`CaptureReader`] reads records back and [`replay_dht`] feeds captured incoming DHT packets to a DHT server to
+reproduce packet-level issues.
+
+[`Capture`]: ./struct.Capture.html
+[`CaptureTransport`]: ./struct.CaptureTransport.html
+[`CaptureWriter`]: ./struct.CaptureWriter.html
+[`CaptureReader`]: ./struct.CaptureReader.html
+[`replay_dht`]: ./fn.replay_dht.html
+*/
+
+use std::fs::File;
+use std::io::{BufWriter, Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::mpsc as std_mpsc;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::{Fail, ResultExt};
+use futures::{ready, FutureExt, StreamExt};
+use futures::channel::{mpsc, oneshot};
+
+use crate::toxcore::binary_io::FromBytes;
+use crate::toxcore::dht::packet::Packet;
+use crate::toxcore::dht::server::Server;
+use crate::toxcore::transport::DatagramTransport;
+
+/// Magic bytes at the beginning of a capture file.
+pub const CAPTURE_MAGIC: &[u8; 8] = b"TOXCAP\x00\x01";
+
+/// Maximum length of a record without its length prefix. It's much bigger
+/// than any captured packet and protects the reader from allocating huge
+/// buffers for corrupted captures.
+pub const MAX_RECORD_SIZE: usize = 65_536;
+
+error_kind! {
+    #[doc = "Error that can happen when reading a capture."]
+    #[derive(Debug)]
+    CaptureError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, Fail)]
+    CaptureErrorKind {
+        #[doc = "IO error."]
+        #[fail(display = "IO error")]
+        Io,
+        #[doc = "Error indicates that the file doesn't start with capture magic bytes."]
+        #[fail(display = "Invalid capture header")]
+        InvalidHeader,
+        #[doc = "Error indicates that a record is malformed."]
+        #[fail(display = "Invalid capture record")]
+        InvalidRecord,
+        #[doc = "Error indicates that a record is longer than `MAX_RECORD_SIZE`."]
+        #[fail(display = "Capture record should not be longer than 65536 bytes: {} bytes", len)]
+        TooBigRecord {
+            #[doc = "Length of the record."]
+            len: usize
+        },
+    }
+}
+
+/// Direction of a captured packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// Packet was received.
+    Incoming,
+    /// Packet was sent.
+    Outgoing,
+}
+
+/// Layer a packet was captured at.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Layer {
+    /// Raw UDP datagram handled by `DhtCodec`.
+    Udp,
+    /// Decrypted TCP packet handled by TCP `Codec`.
+    Tcp,
+    /// Decrypted data of `CryptoData` packet handled by `NetCrypto`.
+    NetCrypto,
+}
+
+/// Single captured packet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    /// Time since the unix epoch when the packet was captured.
+    pub timestamp: Duration,
+    /// Direction of the packet.
+    pub direction: Direction,
+    /// Layer the packet was captured at.
+    pub layer: Layer,
+    /// Address of the peer if it's known.
+    pub addr: Option<SocketAddr>,
+    /// Whether the packet was parsed successfully.
+    pub parsed: bool,
+    /// Packet bytes.
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// Serialize record with its length prefix.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.data.len() + 36);
+        buf.extend_from_slice(&[0; 4]);
+        let micros = self.timestamp.as_secs() * 1_000_000 + u64::from(self.timestamp.subsec_micros());
+        buf.extend_from_slice(&micros.to_be_bytes());
+        buf.push(match self.direction {
+            Direction::Incoming => 0,
+            Direction::Outgoing => 1,
+        });
+        buf.push(match self.layer {
+            Layer::Udp => 0,
+            Layer::Tcp => 1,
+            Layer::NetCrypto => 2,
+        });
+        buf.push(self.parsed as u8);
+        match self.addr {
+            None => buf.push(0),
+            Some(SocketAddr::V4(addr)) => {
+                buf.push(4);
+                buf.extend_from_slice(&addr.ip().octets());
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+            },
+            Some(SocketAddr::V6(addr)) => {
+                buf.push(6);
+                buf.extend_from_slice(&addr.ip().octets());
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+            },
+        }
+        buf.extend_from_slice(&self.data);
+        let len = (buf.len() - 4) as u32;
+        buf[.. 4].copy_from_slice(&len.to_be_bytes());
+        buf
+    }
+
+    /// Deserialize record without its length prefix.
+    fn from_bytes(buf: &[u8]) -> Option<Record> {
+        if buf.len() < 12 {
+            return None;
+        }
+        let mut micros = [0; 8];
+        micros.copy_from_slice(&buf[.. 8]);
+        let timestamp = Duration::from_micros(u64::from_be_bytes(micros));
+        let direction = match buf[8] {
+            0 => Direction::Incoming,
+            1 => Direction::Outgoing,
+            _ => return None,
+        };
+        let layer = match buf[9] {
+            0 => Layer::Udp,
+            1 => Layer::Tcp,
+            2 => Layer::NetCrypto,
+            _ => return None,
+        };
+        let parsed = match buf[10] {
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        let (addr, rest) = match buf[11] {
+            0 => (None, &buf[12 ..]),
+            4 if buf.len() >= 18 => {
+                let mut ip = [0; 4];
+                ip.copy_from_slice(&buf[12 .. 16]);
+                let port = u16::from_be_bytes([buf[16], buf[17]]);
+                (Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port)), &buf[18 ..])
+            },
+            6 if buf.len() >= 30 => {
+                let mut ip = [0; 16];
+                ip.copy_from_slice(&buf[12 .. 28]);
+                let port = u16::from_be_bytes([buf[28], buf[29]]);
+                (Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)), &buf[30 ..])
+            },
+            _ => return None,
+        };
+        Some(Record {
+            timestamp,
+            direction,
+            layer,
+            addr,
+            parsed,
+            data: rest.to_vec(),
+        })
+    }
+}
+
+/// Shared handle for recording captured packets. It can be cloned and given to
+/// several modules to record their packets to the same capture. Records are
+/// sent over a channel to [`CaptureWriter`] so that recording never blocks
+/// packet processing.
+///
+/// [`CaptureWriter`]: ./struct.CaptureWriter.html
+#[derive(Clone)]
+pub struct Capture {
+    tx: mpsc::UnboundedSender<Record>,
+}
+
+impl Capture {
+    /// Create new `Capture` that writes records to `writer`. Capture header
+    /// is written immediately. Records are written only while the returned
+    /// `CaptureWriter` is running.
+    pub fn new<W: Write>(mut writer: W) -> Result<(Capture, CaptureWriter<W>), IoError> {
+        writer.write_all(CAPTURE_MAGIC)?;
+        let (tx, rx) = mpsc::unbounded();
+        Ok((Capture { tx }, CaptureWriter { writer, rx }))
+    }
+
+    /// Create new `Capture` that writes records to a file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<(Capture, CaptureWriter<BufWriter<File>>), IoError> {
+        Capture::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Record a packet with the current timestamp.
+    pub fn record(&self, direction: Direction, layer: Layer, addr: Option<SocketAddr>, parsed: bool, data: &[u8]) {
+        self.write(Record {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+            direction,
+            layer,
+            addr,
+            parsed,
+            data: data.to_vec(),
+        });
+    }
+
+    /// Send a record to the writer.
+    pub fn write(&self, record: Record) {
+        if self.tx.unbounded_send(record).is_err() {
+            trace!("Capture writer is stopped, dropping record");
+        }
+    }
+}
+
+/// Writer of records sent by `Capture` handles.
+pub struct CaptureWriter<W> {
+    writer: W,
+    rx: mpsc::UnboundedReceiver<Record>,
+}
+
+impl<W: Write + Send + 'static> CaptureWriter<W> {
+    /// Write records until all `Capture` handles are dropped. Records are
+    /// written on a dedicated thread so that slow disks don't block the
+    /// executor. The underlying writer is flushed every time there are no more
+    /// pending records. Write errors are logged and don't stop the writer.
+    pub async fn run(self) {
+        let CaptureWriter { mut writer, mut rx } = self;
+        let (batch_tx, batch_rx) = std_mpsc::channel::<Vec<u8>>();
+        let (done_tx, done_rx) = oneshot::channel();
+
+        let spawned = thread::Builder::new()
+            .name("capture-writer".to_owned())
+            .spawn(move || {
+                for batch in batch_rx {
+                    if let Err(e) = writer.write_all(&batch) {
+                        warn!("Failed to write capture records: {}", e);
+                    }
+                    if let Err(e) = writer.flush() {
+                        warn!("Failed to flush capture: {}", e);
+                    }
+                }
+                done_tx.send(()).ok();
+            });
+        if let Err(e) = spawned {
+            error!("Failed to spawn capture writer thread: {}", e);
+            return;
+        }
+
+        while let Some(record) = rx.next().await {
+            let mut batch = Vec::new();
+            Self::encode(&mut batch, &record);
+            while let Some(Some(record)) = rx.next().now_or_never() {
+                Self::encode(&mut batch, &record);
+            }
+            if batch_tx.send(batch).is_err() {
+                break;
+            }
+        }
+
+        drop(batch_tx);
+        done_rx.await.ok();
+    }
+
+    /// Append serialized record to `batch` skipping records that are too big
+    /// to be read back.
+    fn encode(batch: &mut Vec<u8>, record: &Record) {
+        let bytes = record.to_bytes();
+        if bytes.len() - 4 > MAX_RECORD_SIZE {
+            warn!("Capture record is too big: {} bytes", bytes.len() - 4);
+            return;
+        }
+        batch.extend_from_slice(&bytes);
+    }
+}
+
+/// Writer that can be read back after capturing.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuf(std::sync::Arc<parking_lot::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl SharedBuf {
+    /// Read all records written to the buffer.
+    pub(crate) fn records(&self) -> Vec<Record> {
+        let data = self.0.lock().clone();
+        CaptureReader::new(std::io::Cursor::new(data)).unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.0.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+
+/// Reader of records written by `Capture`.
+pub struct CaptureReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Create new `CaptureReader` checking capture header.
+    pub fn new(mut reader: R) -> Result<CaptureReader<R>, CaptureError> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)
+            .context(CaptureErrorKind::InvalidHeader)?;
+        if &magic != CAPTURE_MAGIC {
+            return Err(CaptureErrorKind::InvalidHeader.into());
+        }
+        Ok(CaptureReader { reader })
+    }
+
+    /// Read the next record. Returns `None` at the end of the capture.
+    pub fn read_record(&mut self) -> Result<Option<Record>, CaptureError> {
+        let mut len = [0; 4];
+        let mut read = 0;
+        while read < len.len() {
+            match self.reader.read(&mut len[read ..]) {
+                // EOF between records is the end of the capture while EOF
+                // inside the length prefix means that the capture is truncated
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(CaptureErrorKind::InvalidRecord.into()),
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == IoErrorKind::Interrupted => {},
+                Err(e) => return Err(e.context(CaptureErrorKind::Io).into()),
+            }
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_RECORD_SIZE {
+            return Err(CaptureErrorKind::TooBigRecord { len }.into());
+        }
+        let mut buf = vec![0; len];
+        self.reader.read_exact(&mut buf)
+            .context(CaptureErrorKind::InvalidRecord)?;
+        Record::from_bytes(&buf)
+            .map(Some)
+            .ok_or_else(|| CaptureErrorKind::InvalidRecord.into())
+    }
+}
+
+impl CaptureReader<File> {
+    /// Open capture file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<CaptureReader<File>, CaptureError> {
+        let file = File::open(path)
+            .context(CaptureErrorKind::Io)?;
+        CaptureReader::new(file)
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<Record, CaptureError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// `DatagramTransport` wrapper that records all sent and received datagrams
+/// as `Layer::Udp` packets.
+pub struct CaptureTransport<T> {
+    transport: T,
+    capture: Capture,
+}
+
+impl<T: DatagramTransport> CaptureTransport<T> {
+    /// Wrap `transport` recording its datagrams to `capture`.
+    pub fn new(transport: T, capture: Capture) -> CaptureTransport<T> {
+        CaptureTransport {
+            transport,
+            capture,
+        }
+    }
+}
+
+impl<T: DatagramTransport> DatagramTransport for CaptureTransport<T> {
+    fn local_addr(&self) -> Result<SocketAddr, IoError> {
+        self.transport.local_addr()
+    }
+
+    fn poll_recv_from(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<Result<(usize, SocketAddr), IoError>> {
+        let (n, addr) = ready!(self.transport.poll_recv_from(cx, buf))?;
+        let parsed = Packet::from_bytes(&buf[.. n]).is_ok();
+        self.capture.record(Direction::Incoming, Layer::Udp, Some(addr), parsed, &buf[.. n]);
+        Poll::Ready(Ok((n, addr)))
+    }
+
+    fn poll_send_to(&mut self, cx: &mut Context, buf: &[u8], target: &SocketAddr) -> Poll<Result<usize, IoError>> {
+        let n = ready!(self.transport.poll_send_to(cx, buf, target))?;
+        self.capture.record(Direction::Outgoing, Layer::Udp, Some(*target), true, &buf[.. n]);
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Result of replaying a capture.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ReplayStats {
+    /// Number of packets handled by the server successfully.
+    pub handled: usize,
+    /// Number of packets the server failed to handle.
+    pub failed: usize,
+    /// Number of records that were skipped because they are outgoing, don't
+    /// belong to DHT or can't be parsed.
+    pub skipped: usize,
+}
+
+/// Feed incoming `Layer::Udp` packets from `records` to DHT server in the
+/// order they were captured. Handling errors are logged and counted.
+pub async fn replay_dht<I: IntoIterator<Item = Record>>(server: &Server, records: I) -> ReplayStats {
+    let mut stats = ReplayStats::default();
+
+    for record in records {
+        let addr = match record.addr {
+            Some(addr) if record.direction == Direction::Incoming && record.layer == Layer::Udp => addr,
+            _ => {
+                stats.skipped += 1;
+                continue;
+            },
+        };
+        let packet = match Packet::from_bytes(&record.data) {
+            Ok((_, packet)) => packet,
+            Err(_) => {
+                stats.skipped += 1;
+                continue;
+            },
+        };
+
+        match server.handle_packet(packet, addr).await {
+            Ok(()) => stats.handled += 1,
+            Err(e) => {
+                debug!("Failed to handle replayed packet from {}: {:?}", addr, e);
+                stats.failed += 1;
+            },
+        }
+    }
+
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use crate::toxcore::binary_io::ToBytes;
+    use crate::toxcore::crypto_core::*;
+    use crate::toxcore::dht::packet::*;
+
+    #[tokio::test]
+    async fn record_encode_decode() {
+        let records = vec![
+            Record {
+                timestamp: Duration::from_micros(123_456_789),
+                direction: Direction::Incoming,
+                layer: Layer::Udp,
+                addr: Some("1.2.3.4:33445".parse().unwrap()),
+                parsed: true,
+                data: vec![1, 2, 3],
+            },
+            Record {
+                timestamp: Duration::from_micros(1),
+                direction: Direction::Outgoing,
+                layer: Layer::Tcp,
+                addr: Some("[::1]:33445".parse().unwrap()),
+                parsed: false,
+                data: vec![],
+            },
+            Record {
+                timestamp: Duration::from_micros(2),
+                direction: Direction::Incoming,
+                layer: Layer::NetCrypto,
+                addr: None,
+                parsed: true,
+                data: vec![42; 100],
+            },
+        ];
+
+        let buf = SharedBuf::default();
+        let (capture, writer) = Capture::new(buf.clone()).unwrap();
+        for record in &records {
+            capture.write(record.clone());
+        }
+        drop(capture);
+        writer.run().await;
+
+        assert_eq!(buf.records(), records);
+    }
+
+    #[test]
+    fn reader_invalid_header() {
+        let error = CaptureReader::new(Cursor::new(b"TOXCAP\x00\x02".to_vec())).err().unwrap();
+        assert_eq!(*error.kind(), CaptureErrorKind::InvalidHeader);
+    }
+
+    #[test]
+    fn reader_truncated_record() {
+        let mut data = CAPTURE_MAGIC.to_vec();
+        data.extend_from_slice(&[0, 0, 0, 20, 1, 2, 3]);
+        let mut reader = CaptureReader::new(Cursor::new(data)).unwrap();
+        let error = reader.read_record().err().unwrap();
+        assert_eq!(*error.kind(), CaptureErrorKind::InvalidRecord);
+    }
+
+    #[test]
+    fn reader_truncated_length() {
+        let mut data = CAPTURE_MAGIC.to_vec();
+        data.extend_from_slice(&[0, 0]);
+        let mut reader = CaptureReader::new(Cursor::new(data)).unwrap();
+        let error = reader.read_record().err().unwrap();
+        assert_eq!(*error.kind(), CaptureErrorKind::InvalidRecord);
+    }
+
+    #[test]
+    fn reader_too_big_record() {
+        let mut data = CAPTURE_MAGIC.to_vec();
+        data.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+        let mut reader = CaptureReader::new(Cursor::new(data)).unwrap();
+        let error = reader.read_record().err().unwrap();
+        assert_eq!(*error.kind(), CaptureErrorKind::TooBigRecord { len: 0xffff_ffff });
+    }
+
+    #[tokio::test]
+    async fn writer_skips_too_big_record() {
+        let record = Record {
+            timestamp: Duration::from_micros(1),
+            direction: Direction::Incoming,
+            layer: Layer::Tcp,
+            addr: None,
+            parsed: true,
+            data: vec![1, 2, 3],
+        };
+        let too_big_record = Record {
+            data: vec![42; MAX_RECORD_SIZE],
+            .. record.clone()
+        };
+
+        let buf = SharedBuf::default();
+        let (capture, writer) = Capture::new(buf.clone()).unwrap();
+        capture.write(too_big_record);
+        capture.write(record.clone());
+        drop(capture);
+        writer.run().await;
+
+        assert_eq!(buf.records(), vec![record]);
+    }
+
+    #[tokio::test]
+    async fn capture_transport_and_replay() {
+        crypto_init().unwrap();
+
+        let (pk, sk) = gen_keypair();
+        let (client_pk, client_sk) = gen_keypair();
+        let shared_secret = precompute(&pk, &client_sk);
+
+        let socket_1 = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket_2 = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr_1 = socket_1.local_addr().unwrap();
+        let addr_2 = socket_2.local_addr().unwrap();
+
+        let buf = SharedBuf::default();
+        let (capture, writer) = Capture::new(buf.clone()).unwrap();
+        let mut transport = CaptureTransport::new(socket_2, capture);
+
+        let ping_request = Packet::PingRequest(PingRequest::new(&shared_secret, &client_pk, &PingRequestPayload { id: 42 }));
+        let mut packet_buf = [0; 512];
+        let (_, size) = ping_request.to_bytes((&mut packet_buf, 0)).unwrap();
+
+        let mut socket_1 = socket_1;
+        socket_1.send_to(&packet_buf[.. size], &addr_2).await.unwrap();
+        socket_1.send_to(&[42; 5], &addr_2).await.unwrap();
+
+        let mut recv_buf = [0; 512];
+        for _ in 0 .. 2 {
+            futures::future::poll_fn(|cx| transport.poll_recv_from(cx, &mut recv_buf)).await.unwrap();
+        }
+        futures::future::poll_fn(|cx| transport.poll_send_to(cx, &[1, 2, 3], &addr_1)).await.unwrap();
+
+        drop(transport);
+        writer.run().await;
+
+        let records = buf.records();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].direction, Direction::Incoming);
+        assert_eq!(records[0].addr, Some(addr_1));
+        assert!(records[0].parsed);
+        assert_eq!(&records[0].data[..], &packet_buf[.. size]);
+        assert!(!records[1].parsed);
+        assert_eq!(records[2].direction, Direction::Outgoing);
+        assert_eq!(records[2].data, vec![1, 2, 3]);
+
+        let (tx, mut rx) = mpsc::channel(32);
+        let server = Server::new(tx, pk, sk);
+        let stats = replay_dht(&server, records).await;
+        assert_eq!(stats, ReplayStats { handled: 1, failed: 0, skipped: 2 });
+
+        let (packet, addr) = rx.next().await.unwrap();
+        assert_eq!(addr, addr_1);
+        let ping_response = unpack!(packet, Packet::PingResponse);
+        assert_eq!(ping_response.get_payload(&shared_secret).unwrap().id, 42);
+    }
+}
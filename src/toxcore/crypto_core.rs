@@ -152,6 +152,51 @@ pub fn decrypt_data_symmetric(precomputed_key: &PrecomputedKey,
 }
 
 
+/** Encrypt data in place.
+
+    `buf` should contain `MACBYTES` of free space followed by plain data. Plain
+    data is encrypted without allocation and the authentication tag is written
+    to the first `MACBYTES` of `buf`, so the result has the same layout as
+    `encrypt_data_symmetric` returns.
+
+    A wrapper for the
+    [`seal_detached_precomputed()`](../../../sodiumoxide/crypto/box_/curve25519xsalsa20poly1305/fn.seal_detached_precomputed.html)
+    function from `sodiumoxide`.
+*/
+pub fn encrypt_data_symmetric_in_place(precomputed_key: &PrecomputedKey,
+                                       nonce: &Nonce,
+                                       buf: &mut [u8]) {
+    assert!(buf.len() >= MACBYTES, "Buffer should have space for MAC");
+    let (tag_buf, plain) = buf.split_at_mut(MACBYTES);
+    let Tag(tag) = seal_detached_precomputed(plain, nonce, precomputed_key);
+    tag_buf.copy_from_slice(&tag);
+}
+
+
+/** Decrypt data in place, or return `()` if data couldn't be decrypted.
+
+    `buf` should contain data encrypted by `encrypt_data_symmetric`. On success
+    the returned slice of `buf` contains plain data with length of
+    `encrypted - 16`. No allocation is made, so this function is suitable for
+    buffers that are already owned like received `BytesMut`.
+
+    A wrapper for the
+    [`open_detached_precomputed()`](../../../sodiumoxide/crypto/box_/curve25519xsalsa20poly1305/fn.open_detached_precomputed.html)
+    function from `sodiumoxide`.
+*/
+pub fn decrypt_data_symmetric_in_place<'a>(precomputed_key: &PrecomputedKey,
+                                           nonce: &Nonce,
+                                           buf: &'a mut [u8]) -> Result<&'a mut [u8], ()> {
+    if buf.len() < MACBYTES {
+        return Err(());
+    }
+    let (tag, encrypted) = buf.split_at_mut(MACBYTES);
+    let tag = Tag::from_slice(tag).ok_or(())?;
+    open_detached_precomputed(encrypted, &tag, nonce, precomputed_key)?;
+    Ok(encrypted)
+}
+
+
 /** Inrement given nonce by 1.
 
     Treats `Nonce` as BE number.
@@ -390,6 +435,56 @@ pub mod tests {
         assert_eq!(alice_plain, &bob_plain[..]);
     }
 
+    #[test]
+    fn encrypt_data_symmetric_in_place_test() {
+        crypto_init().unwrap();
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, bob_sk) = gen_keypair();
+
+        let alice_plain = b"Hi, Bob.";
+
+        let precomputed_key = precompute(&bob_pk, &alice_sk);
+        let nonce = gen_nonce();
+
+        let mut buf = vec![0; MACBYTES];
+        buf.extend_from_slice(alice_plain);
+        encrypt_data_symmetric_in_place(&precomputed_key, &nonce, &mut buf);
+
+        assert_eq!(buf, encrypt_data_symmetric(&precomputed_key, &nonce, alice_plain));
+
+        let bob_plain = open(&buf, &nonce, &alice_pk, &bob_sk).unwrap();
+
+        assert_eq!(alice_plain, &bob_plain[..]);
+    }
+
+    #[test]
+    fn decrypt_data_symmetric_in_place_test() {
+        crypto_init().unwrap();
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, bob_sk) = gen_keypair();
+
+        let alice_plain = b"Hi, Bob.";
+
+        let precomputed_key = precompute(&alice_pk, &bob_sk);
+        let nonce = gen_nonce();
+
+        let mut ciphertext = seal(alice_plain, &nonce, &bob_pk, &alice_sk);
+
+        let bob_plain = decrypt_data_symmetric_in_place(&precomputed_key, &nonce, &mut ciphertext).unwrap();
+
+        assert_eq!(alice_plain, bob_plain);
+    }
+
+    #[test]
+    fn decrypt_data_symmetric_in_place_invalid_test() {
+        crypto_init().unwrap();
+        let precomputed_key = precompute(&gen_keypair().0, &gen_keypair().1);
+        let nonce = gen_nonce();
+
+        assert!(decrypt_data_symmetric_in_place(&precomputed_key, &nonce, &mut [42; MACBYTES - 1]).is_err());
+        assert!(decrypt_data_symmetric_in_place(&precomputed_key, &nonce, &mut [42; MACBYTES + 8]).is_err());
+    }
+
 
     // increment_nonce()
 
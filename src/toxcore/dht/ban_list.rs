@@ -0,0 +1,251 @@
+/*! List of banned DHT nodes.
+
+Nodes can be banned by their `PublicKey` or by their IP address. Packets from
+banned nodes are ignored and banned nodes are never added to close lists.
+
+Manual bans are permanent and can be serialized to be restored after restart.
+Nodes that misbehave (see [`Reputation`]) are banned temporarily, such bans
+are not serialized.
+
+Serialized form:
+
+Length      | Content
+----------- | ------
+`4`         | Number of banned keys (N)
+`32 * N`    | Banned keys
+`4`         | Number of banned IP addresses (M)
+variable    | M banned IP addresses
+
+Serialized IP address:
+
+Length      | Content
+----------- | ------
+`1`         | IP type: `2` for IPv4, `10` for IPv6
+`4` or `16` | IPv4 or IPv6 address
+
+[`Reputation`]: ../reputation/struct.Reputation.html
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use nom::number::complete::{le_u8, le_u32};
+
+use crate::toxcore::binary_io::*;
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::dht::packed_node::*;
+use crate::toxcore::time::*;
+
+/// How long misbehaving nodes are banned for.
+pub const MISBEHAVING_BAN_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// List of banned DHT nodes.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BanList {
+    /// Permanently banned `PublicKey`s.
+    keys: HashSet<PublicKey>,
+    /// Permanently banned IP addresses.
+    ips: HashSet<IpAddr>,
+    /// Temporarily banned `PublicKey`s with time when the ban was set and its
+    /// duration.
+    temporary_keys: HashMap<PublicKey, (Instant, Duration)>,
+}
+
+impl BanList {
+    /// Create new empty `BanList`.
+    pub fn new() -> Self {
+        BanList::default()
+    }
+
+    /// Permanently ban node by its `PublicKey`.
+    pub fn ban_key(&mut self, pk: PublicKey) {
+        self.keys.insert(pk);
+    }
+
+    /// Remove both permanent and temporary bans of `PublicKey`. Returns `true`
+    /// if the key was banned.
+    pub fn unban_key(&mut self, pk: &PublicKey) -> bool {
+        let permanent = self.keys.remove(pk);
+        let temporary = self.temporary_keys.remove(pk).is_some();
+        permanent || temporary
+    }
+
+    /// Permanently ban all nodes with the IP address.
+    pub fn ban_ip(&mut self, ip: IpAddr) {
+        self.ips.insert(normalize_ip(ip));
+    }
+
+    /// Remove ban of the IP address. Returns `true` if the address was banned.
+    pub fn unban_ip(&mut self, ip: &IpAddr) -> bool {
+        self.ips.remove(&normalize_ip(*ip))
+    }
+
+    /// Ban node by its `PublicKey` for specified duration.
+    pub fn ban_key_temporarily(&mut self, pk: PublicKey, duration: Duration) {
+        self.temporary_keys.insert(pk, (clock_now(), duration));
+    }
+
+    /// Check if `PublicKey` is banned either permanently or temporarily.
+    pub fn is_key_banned(&self, pk: &PublicKey) -> bool {
+        if self.keys.contains(pk) {
+            return true;
+        }
+
+        match self.temporary_keys.get(pk) {
+            Some(&(time, duration)) => clock_elapsed(time) < duration,
+            None => false,
+        }
+    }
+
+    /// Check if IP address is banned.
+    pub fn is_ip_banned(&self, ip: &IpAddr) -> bool {
+        !self.ips.is_empty() && self.ips.contains(&normalize_ip(*ip))
+    }
+
+    /// Check if either `PublicKey` or IP address of the node is banned.
+    pub fn is_banned(&self, node: &PackedNode) -> bool {
+        self.is_key_banned(&node.pk) || self.is_ip_banned(&node.saddr.ip())
+    }
+
+    /// Remove expired temporary bans.
+    pub fn clear_expired(&mut self) {
+        self.temporary_keys.retain(|_, &mut (time, duration)| clock_elapsed(time) < duration);
+    }
+
+    /// Iterate over permanently banned `PublicKey`s.
+    pub fn keys(&self) -> impl Iterator<Item = &PublicKey> {
+        self.keys.iter()
+    }
+
+    /// Iterate over permanently banned IP addresses.
+    pub fn ips(&self) -> impl Iterator<Item = &IpAddr> {
+        self.ips.iter()
+    }
+
+    /// Check if there are neither permanent nor temporary bans.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.ips.is_empty() && self.temporary_keys.is_empty()
+    }
+}
+
+/// Convert IPv4-mapped IPv6 address to IPv4 so that it matches bans set for
+/// IPv4 address.
+fn normalize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4().map_or(IpAddr::V6(v6), IpAddr::V4),
+        ip => ip,
+    }
+}
+
+named!(ip_addr_from_bytes<IpAddr>, switch!(le_u8,
+    2  => map!(Ipv4Addr::from_bytes, IpAddr::V4) |
+    10 => map!(Ipv6Addr::from_bytes, IpAddr::V6)
+));
+
+impl FromBytes for BanList {
+    named!(from_bytes<BanList>, do_parse!(
+        keys: length_count!(le_u32, PublicKey::from_bytes) >>
+        ips: length_count!(le_u32, ip_addr_from_bytes) >>
+        (BanList {
+            keys: keys.into_iter().collect(),
+            ips: ips.into_iter().collect(),
+            temporary_keys: HashMap::new(),
+        })
+    ));
+}
+
+impl ToBytes for BanList {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_le_u32!(self.keys.len() as u32) >>
+            gen_many_ref!(&self.keys, |buf, pk: &PublicKey| gen_slice!(buf, pk.as_ref())) >>
+            gen_le_u32!(self.ips.len() as u32) >>
+            gen_many_ref!(&self.ips, |buf, ip| ip_addr_to_bytes(ip, buf))
+        )
+    }
+}
+
+fn ip_addr_to_bytes<'a>(ip: &IpAddr, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(buf,
+        gen_if_else!(ip.is_ipv4(), gen_be_u8!(2), gen_be_u8!(10)) >>
+        gen_call!(|buf, ip| IpAddr::to_bytes(ip, buf), ip)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        ban_list_encode_decode,
+        {
+            let mut ban_list = BanList::new();
+            ban_list.ban_key(gen_keypair().0);
+            ban_list.ban_key(gen_keypair().0);
+            ban_list.ban_ip("1.2.3.4".parse().unwrap());
+            ban_list.ban_ip("2001:db8::1".parse().unwrap());
+            ban_list
+        }
+    );
+
+    #[test]
+    fn temporary_bans_are_not_serialized() {
+        crypto_init().unwrap();
+        let mut ban_list = BanList::new();
+        ban_list.ban_key_temporarily(gen_keypair().0, MISBEHAVING_BAN_TIMEOUT);
+
+        let mut buf = [0; 16];
+        let (_, size) = ban_list.to_bytes((&mut buf, 0)).unwrap();
+        let (_, decoded) = BanList::from_bytes(&buf[..size]).unwrap();
+
+        assert!(decoded.is_empty());
+    }
+
+    #[test]
+    fn ban_unban_key() {
+        crypto_init().unwrap();
+        let mut ban_list = BanList::new();
+        let pk = gen_keypair().0;
+        let node = PackedNode::new("1.2.3.4:33445".parse().unwrap(), &pk);
+
+        assert!(!ban_list.is_banned(&node));
+        ban_list.ban_key(pk);
+        assert!(ban_list.is_key_banned(&pk));
+        assert!(ban_list.is_banned(&node));
+        assert!(ban_list.unban_key(&pk));
+        assert!(!ban_list.is_banned(&node));
+        assert!(!ban_list.unban_key(&pk));
+    }
+
+    #[test]
+    fn ban_unban_ip() {
+        crypto_init().unwrap();
+        let mut ban_list = BanList::new();
+        let node = PackedNode::new("1.2.3.4:33445".parse().unwrap(), &gen_keypair().0);
+
+        ban_list.ban_ip("1.2.3.4".parse().unwrap());
+        assert!(ban_list.is_banned(&node));
+        // IPv4-mapped address matches IPv4 ban
+        assert!(ban_list.is_ip_banned(&"::ffff:1.2.3.4".parse().unwrap()));
+        assert!(ban_list.unban_ip(&"1.2.3.4".parse().unwrap()));
+        assert!(!ban_list.is_banned(&node));
+    }
+
+    #[tokio::test]
+    async fn temporary_ban_expires() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+
+        let mut ban_list = BanList::new();
+        let pk = gen_keypair().0;
+        ban_list.ban_key_temporarily(pk, MISBEHAVING_BAN_TIMEOUT);
+        assert!(ban_list.is_key_banned(&pk));
+
+        tokio::time::advance(MISBEHAVING_BAN_TIMEOUT).await;
+
+        assert!(!ban_list.is_key_banned(&pk));
+        ban_list.clear_expired();
+        assert!(ban_list.is_empty());
+    }
+}
@@ -0,0 +1,446 @@
+/*! Client for `BootstrapInfo` requests and structured MOTD.
+
+Bootstrap nodes respond to `BootstrapInfo` requests with their version and
+message of the day. [`request_bootstrap_info`] sends such request to a node
+and waits for the response, so bootstrap nodes can be monitored without
+running the whole DHT server.
+
+MOTD is free text by default. [`BootstrapMotd`] allows to put structured
+`key=value` lines after the text and the `[fields]` marker line so that
+monitoring tools can get uptime, number of known nodes, TCP ports or external
+addresses of the node:
+
+```text
+Welcome to the tox node
+[fields]
+uptime=3600
+nodes=42
+tcp_ports=443,3389,33445
+external_addr=1.2.3.4:33445
+```
+
+[`request_bootstrap_info`]: ./fn.request_bootstrap_info.html
+[`BootstrapMotd`]: ./struct.BootstrapMotd.html
+*/
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use failure::Fail;
+use futures::{SinkExt, StreamExt};
+use tokio::net::UdpSocket;
+
+use crate::toxcore::dht::codec::DhtCodec;
+use crate::toxcore::dht::packet::*;
+use crate::toxcore::stats::Stats;
+use crate::toxcore::transport::{DatagramTransport, DatagramFramed};
+
+error_kind! {
+    #[doc = "Error that can happen when requesting `BootstrapInfo` from a node."]
+    #[derive(Debug)]
+    BootstrapInfoError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, Fail)]
+    BootstrapInfoErrorKind {
+        #[doc = "Failed to bind UDP socket."]
+        #[fail(display = "Failed to bind UDP socket")]
+        Bind,
+        #[doc = "Failed to send request."]
+        #[fail(display = "Failed to send BootstrapInfo request")]
+        SendTo,
+        #[doc = "Failed to receive response."]
+        #[fail(display = "Failed to receive BootstrapInfo response")]
+        Receive,
+        #[doc = "The node didn't respond in time."]
+        #[fail(display = "BootstrapInfo request timed out")]
+        Timeout,
+    }
+}
+
+/// Default timeout for `BootstrapInfo` requests.
+pub const BOOTSTRAP_INFO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Line that separates free text of MOTD from structured fields.
+pub const MOTD_FIELDS_MARKER: &str = "[fields]";
+
+/// MOTD key for the uptime of the node in seconds.
+pub const MOTD_KEY_UPTIME: &str = "uptime";
+/// MOTD key for the number of nodes the node knows about.
+pub const MOTD_KEY_NODES: &str = "nodes";
+/// MOTD key for the comma separated list of TCP relay ports.
+pub const MOTD_KEY_TCP_PORTS: &str = "tcp_ports";
+/// MOTD key for the external address of the DHT UDP socket.
+pub const MOTD_KEY_EXTERNAL_ADDR: &str = "external_addr";
+/// MOTD key for the external address of the TCP relay.
+pub const MOTD_KEY_EXTERNAL_TCP_ADDR: &str = "external_tcp_addr";
+
+/// Version and MOTD received from a bootstrap node.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NodeInfo {
+    /// Version of the node.
+    pub version: u32,
+    /// Message of the day.
+    pub motd: BootstrapMotd,
+}
+
+/// MOTD consisting of free text followed by the `[fields]` marker line and
+/// `key=value` lines.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BootstrapMotd {
+    /// Free text.
+    pub text: String,
+    /// Structured fields in the order they appear in MOTD.
+    pub fields: Vec<(String, String)>,
+}
+
+/// Check if the string can be used as a key of MOTD field.
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+/// Split `key=value` line of MOTD to key and value.
+fn parse_field(line: &str) -> Option<(&str, &str)> {
+    let pos = line.find('=')?;
+    if is_valid_key(&line[.. pos]) {
+        Some((&line[.. pos], &line[pos + 1 ..]))
+    } else {
+        None
+    }
+}
+
+impl BootstrapMotd {
+    /// Create new `BootstrapMotd` with free text and without fields.
+    pub fn new<S: Into<String>>(text: S) -> BootstrapMotd {
+        BootstrapMotd {
+            text: text.into(),
+            fields: Vec::new(),
+        }
+    }
+
+    /// Parse MOTD received in `BootstrapInfo` packet. Trailing zero bytes are
+    /// ignored. Lines after the last `[fields]` marker line are treated as
+    /// fields if all of them look like `key=value`. Otherwise, as well as for
+    /// nodes that don't use structured format, the whole MOTD is parsed as
+    /// text.
+    pub fn parse(motd: &[u8]) -> BootstrapMotd {
+        let len = motd.iter().rposition(|&b| b != 0).map_or(0, |pos| pos + 1);
+        let motd = String::from_utf8_lossy(&motd[.. len]);
+
+        let lines = motd.split('\n').collect::<Vec<_>>();
+        if let Some(pos) = lines.iter().rposition(|&line| line == MOTD_FIELDS_MARKER) {
+            let fields = lines[pos + 1 ..].iter()
+                .map(|line| parse_field(line).map(|(key, value)| (key.to_owned(), value.to_owned())))
+                .collect::<Option<Vec<_>>>();
+            if let Some(fields) = fields {
+                return BootstrapMotd {
+                    text: lines[.. pos].join("\n"),
+                    fields,
+                };
+            }
+        }
+
+        BootstrapMotd::new(motd)
+    }
+
+    /// Encode MOTD to be sent in `BootstrapInfo` packet. Text is truncated
+    /// and fields that don't fit are dropped so that the result is not longer
+    /// than `BOOSTRAP_SERVER_MAX_MOTD_LENGTH`. The `[fields]` marker line is
+    /// added when there are fields or when the text contains it so that the
+    /// text is not mistaken for fields.
+    pub fn encode(&self) -> Vec<u8> {
+        let marker = format!("\n{}", MOTD_FIELDS_MARKER);
+        let with_marker = !self.fields.is_empty() || self.text.split('\n').any(|line| line == MOTD_FIELDS_MARKER);
+        let max_text_len = if with_marker {
+            BOOSTRAP_SERVER_MAX_MOTD_LENGTH - marker.len()
+        } else {
+            BOOSTRAP_SERVER_MAX_MOTD_LENGTH
+        };
+
+        let mut text_len = self.text.len().min(max_text_len);
+        while !self.text.is_char_boundary(text_len) {
+            text_len -= 1;
+        }
+
+        let mut motd = self.text.as_bytes()[.. text_len].to_vec();
+        if with_marker {
+            motd.extend_from_slice(marker.as_bytes());
+        }
+        for (key, value) in &self.fields {
+            let line = format!("\n{}={}", key, value);
+            if motd.len() + line.len() > BOOSTRAP_SERVER_MAX_MOTD_LENGTH {
+                warn!("MOTD field {} doesn't fit into BootstrapInfo packet", key);
+                continue;
+            }
+            motd.extend_from_slice(line.as_bytes());
+        }
+        motd
+    }
+
+    /// Get value of the field with the given key.
+    pub fn field(&self, key: &str) -> Option<&str> {
+        self.fields.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Set value of the field replacing the previous value. Key should
+    /// consist of ASCII alphanumeric characters, `_`, `-` or `.` and value
+    /// should not contain new lines, otherwise the field is ignored.
+    pub fn set_field<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        let key = key.into();
+        let value = value.into();
+        if !is_valid_key(&key) || value.contains('\n') {
+            warn!("Invalid MOTD field {:?}={:?}", key, value);
+            return;
+        }
+
+        if let Some(field) = self.fields.iter_mut().find(|(k, _)| *k == key) {
+            field.1 = value;
+        } else {
+            self.fields.push((key, value));
+        }
+    }
+
+    /// Get uptime of the node.
+    pub fn uptime(&self) -> Option<Duration> {
+        self.field(MOTD_KEY_UPTIME)
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_secs)
+    }
+
+    /// Set uptime of the node. It's rounded down to seconds.
+    pub fn set_uptime(&mut self, uptime: Duration) {
+        self.set_field(MOTD_KEY_UPTIME, uptime.as_secs().to_string());
+    }
+
+    /// Get number of nodes the node knows about.
+    pub fn nodes_count(&self) -> Option<usize> {
+        self.field(MOTD_KEY_NODES)
+            .and_then(|value| value.parse().ok())
+    }
+
+    /// Set number of nodes the node knows about.
+    pub fn set_nodes_count(&mut self, count: usize) {
+        self.set_field(MOTD_KEY_NODES, count.to_string());
+    }
+
+    /// Get TCP relay ports of the node.
+    pub fn tcp_ports(&self) -> Option<Vec<u16>> {
+        self.field(MOTD_KEY_TCP_PORTS).and_then(|value|
+            value.split(',')
+                .filter(|port| !port.is_empty())
+                .map(|port| port.trim().parse().ok())
+                .collect()
+        )
+    }
+
+    /// Set TCP relay ports of the node.
+    pub fn set_tcp_ports(&mut self, ports: &[u16]) {
+        let ports = ports.iter()
+            .map(u16::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        self.set_field(MOTD_KEY_TCP_PORTS, ports);
+    }
+
+    /// Get external address of the DHT UDP socket of the node.
+    pub fn external_addr(&self) -> Option<SocketAddr> {
+        self.field(MOTD_KEY_EXTERNAL_ADDR)
+            .and_then(|value| value.parse().ok())
+    }
+
+    /// Set external address of the DHT UDP socket of the node.
+    pub fn set_external_addr(&mut self, addr: SocketAddr) {
+        self.set_field(MOTD_KEY_EXTERNAL_ADDR, addr.to_string());
+    }
+
+    /// Get external address of the TCP relay of the node.
+    pub fn external_tcp_addr(&self) -> Option<SocketAddr> {
+        self.field(MOTD_KEY_EXTERNAL_TCP_ADDR)
+            .and_then(|value| value.parse().ok())
+    }
+
+    /// Set external address of the TCP relay of the node.
+    pub fn set_external_tcp_addr(&mut self, addr: SocketAddr) {
+        self.set_field(MOTD_KEY_EXTERNAL_TCP_ADDR, addr.to_string());
+    }
+}
+
+/// Send `BootstrapInfo` request to the node with address `addr` and wait for
+/// its response. A new UDP socket is bound to a random port for the request.
+pub async fn request_bootstrap_info(addr: SocketAddr, timeout: Duration) -> Result<NodeInfo, BootstrapInfoError> {
+    let local_addr: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(&local_addr).await
+        .map_err(|e| e.context(BootstrapInfoErrorKind::Bind))?;
+
+    request_bootstrap_info_via(socket, addr, timeout).await
+}
+
+/// Send `BootstrapInfo` request to the node with address `addr` via the
+/// given transport and wait for its response. Packets received from other
+/// addresses are ignored.
+pub async fn request_bootstrap_info_via<T: DatagramTransport>(transport: T, addr: SocketAddr, timeout: Duration) -> Result<NodeInfo, BootstrapInfoError> {
+    let mut framed = DatagramFramed::new(transport, DhtCodec::new(Stats::new()));
+
+    let request = Packet::BootstrapInfo(BootstrapInfo {
+        version: 0,
+        motd: vec![0; BOOSTRAP_CLIENT_MAX_MOTD_LENGTH],
+    });
+    framed.send((request, addr)).await
+        .map_err(|e| e.context(BootstrapInfoErrorKind::SendTo))?;
+
+    let response = async {
+        while let Some(res) = framed.next().await {
+            match res {
+                Ok((Packet::BootstrapInfo(packet), from)) if from == addr =>
+                    return Ok(packet),
+                Ok((packet, from)) =>
+                    trace!("Ignoring unexpected packet {:?} from {}", packet, from),
+                Err(e) =>
+                    debug!("Failed to decode packet: {}", e),
+            }
+        }
+
+        Err(BootstrapInfoError::from(BootstrapInfoErrorKind::Receive))
+    };
+
+    let packet = tokio::time::timeout(timeout, response).await
+        .map_err(|e| e.context(BootstrapInfoErrorKind::Timeout))??;
+
+    Ok(NodeInfo {
+        version: packet.version,
+        motd: BootstrapMotd::parse(&packet.motd),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::channel::mpsc;
+
+    use crate::toxcore::crypto_core::*;
+    use crate::toxcore::dht::server::Server;
+    use crate::toxcore::dht::server_ext::ServerExt;
+
+    #[test]
+    fn motd_encode_parse() {
+        let mut motd = BootstrapMotd::new("Welcome\nto the node");
+        motd.set_uptime(Duration::from_secs(3600));
+        motd.set_nodes_count(42);
+        motd.set_tcp_ports(&[443, 33445]);
+        motd.set_external_addr("1.2.3.4:33445".parse().unwrap());
+        motd.set_external_tcp_addr("[2001:db8::1]:443".parse().unwrap());
+
+        let encoded = motd.encode();
+        assert_eq!(encoded, b"Welcome\nto the node\n[fields]\nuptime=3600\nnodes=42\ntcp_ports=443,33445\nexternal_addr=1.2.3.4:33445\nexternal_tcp_addr=[2001:db8::1]:443".to_vec());
+
+        let parsed = BootstrapMotd::parse(&encoded);
+        assert_eq!(parsed, motd);
+        assert_eq!(parsed.uptime(), Some(Duration::from_secs(3600)));
+        assert_eq!(parsed.nodes_count(), Some(42));
+        assert_eq!(parsed.tcp_ports(), Some(vec![443, 33445]));
+        assert_eq!(parsed.external_addr(), Some("1.2.3.4:33445".parse().unwrap()));
+        assert_eq!(parsed.external_tcp_addr(), Some("[2001:db8::1]:443".parse().unwrap()));
+    }
+
+    #[test]
+    fn motd_parse_plain_text() {
+        let motd = BootstrapMotd::parse(b"Just text, 1 + 1 = 2\0\0\0");
+        assert_eq!(motd.text, "Just text, 1 + 1 = 2");
+        assert!(motd.fields.is_empty());
+        assert_eq!(motd.uptime(), None);
+
+        let motd = BootstrapMotd::parse(b"Welcome\nuptime=3600");
+        assert_eq!(motd.text, "Welcome\nuptime=3600");
+        assert!(motd.fields.is_empty());
+        assert_eq!(motd.uptime(), None);
+
+        let motd = BootstrapMotd::parse(b"[fields]\nnot a field");
+        assert_eq!(motd.text, "[fields]\nnot a field");
+        assert!(motd.fields.is_empty());
+    }
+
+    #[test]
+    fn motd_encode_parse_text_with_marker() {
+        let motd = BootstrapMotd::new("Text\n[fields]\nuptime=3600");
+        let encoded = motd.encode();
+        assert_eq!(encoded, b"Text\n[fields]\nuptime=3600\n[fields]".to_vec());
+        assert_eq!(BootstrapMotd::parse(&encoded), motd);
+
+        let mut motd = BootstrapMotd::new("Text\n[fields]\nnodes=1");
+        motd.set_nodes_count(42);
+        let parsed = BootstrapMotd::parse(&motd.encode());
+        assert_eq!(parsed, motd);
+        assert_eq!(parsed.nodes_count(), Some(42));
+    }
+
+    #[test]
+    fn motd_set_field() {
+        let mut motd = BootstrapMotd::default();
+        motd.set_field("key", "1");
+        motd.set_field("key", "2");
+        motd.set_field("bad key", "3");
+        motd.set_field("other", "multi\nline");
+        assert_eq!(motd.fields, vec![("key".to_owned(), "2".to_owned())]);
+        assert_eq!(motd.field("key"), Some("2"));
+    }
+
+    #[test]
+    fn motd_encode_too_long() {
+        let mut motd = BootstrapMotd::new("a".repeat(BOOSTRAP_SERVER_MAX_MOTD_LENGTH - 20));
+        motd.set_nodes_count(1);
+        motd.set_uptime(Duration::from_secs(123_456_789));
+
+        let encoded = motd.encode();
+        assert!(encoded.len() <= BOOSTRAP_SERVER_MAX_MOTD_LENGTH);
+        let parsed = BootstrapMotd::parse(&encoded);
+        assert_eq!(parsed.nodes_count(), Some(1));
+        assert_eq!(parsed.uptime(), None);
+
+        let motd = BootstrapMotd::new("é".repeat(BOOSTRAP_SERVER_MAX_MOTD_LENGTH));
+        let encoded = motd.encode();
+        assert_eq!(encoded.len(), BOOSTRAP_SERVER_MAX_MOTD_LENGTH);
+        assert!(String::from_utf8(encoded).is_ok());
+    }
+
+    #[tokio::test]
+    async fn request_from_server() {
+        crypto_init().unwrap();
+        let (pk, sk) = gen_keypair();
+        let (tx, rx) = mpsc::channel(32);
+        let mut server = Server::new(tx, pk, sk);
+        server.set_external_addr(Some("1.2.3.4:33445".parse().unwrap()));
+        server.set_bootstrap_motd(42, Box::new(|server| {
+            let mut motd = BootstrapMotd::new("hello");
+            motd.set_nodes_count(server.close_nodes_count());
+            motd
+        }));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(server.run_socket(socket, rx, Stats::new()));
+
+        let info = request_bootstrap_info(addr, BOOTSTRAP_INFO_TIMEOUT).await.unwrap();
+        assert_eq!(info.version, 42);
+        assert_eq!(info.motd.text, "hello");
+        assert_eq!(info.motd.nodes_count(), Some(0));
+        // external address is added automatically
+        assert_eq!(info.motd.external_addr(), Some("1.2.3.4:33445".parse().unwrap()));
+        assert_eq!(info.motd.external_tcp_addr(), None);
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        // socket that never responds
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let error = request_bootstrap_info(addr, Duration::from_millis(100)).await.err().unwrap();
+        assert_eq!(*error.kind(), BootstrapInfoErrorKind::Timeout);
+    }
+}
@@ -121,11 +121,13 @@ impl Decoder for DhtCodec {
 
         match Packet::from_bytes(buf) {
             Err(error) => {
+                self.stats.counters.increase_parse_failures();
+
                 Err(DecodeError::deserialize(error, buf.to_vec()))
             },
             Ok((_, packet)) => {
-                // Add 1 to incoming counter
-                self.stats.counters.increase_incoming();
+                // Account incoming packet by its kind
+                self.stats.counters.add_incoming_packet(Transport::Udp, buf[0], len);
 
                 Ok(Some(packet))
             }
@@ -138,13 +140,11 @@ impl Encoder for DhtCodec {
     type Error = EncodeError;
 
     fn encode(&mut self, packet: Self::Item, buf: &mut BytesMut) -> Result<(), Self::Error> {
-        let mut packet_buf = [0; MAX_DHT_PACKET_SIZE];
-        packet.to_bytes((&mut packet_buf, 0))
-            .map(|(packet_buf, size)| {
-                // Add 1 to outgoing counter
-                self.stats.counters.increase_outgoing();
-
-                buf.extend(&packet_buf[..size]);
+        let start = buf.len();
+        to_bytes_mut(&packet, buf, MAX_DHT_PACKET_SIZE)
+            .map(|size| {
+                // Account outgoing packet by its kind
+                self.stats.counters.add_outgoing_packet(Transport::Udp, buf[start], size);
             })
             .map_err(|error|
                 EncodeError::serialize(error)
@@ -0,0 +1,616 @@
+/*! DHT network crawler.
+
+Crawler starts from bootstrap nodes and sends `NodesRequest` packets to every
+node it learns about. Requests are sent both for a random key and for keys that
+fall into the first k-buckets of the requested node, so that the node returns
+nodes from different parts of its close list. Besides that every node is asked
+for its version and MOTD with `BootstrapInfo` packet.
+
+Crawling finishes when there are no queued requests left and all sent requests
+are either answered or timed out. Number of discovered nodes and queued
+requests is limited so that a malicious node can't exhaust our memory by
+returning random nodes. Discovered nodes can be printed in JSON or CSV format
+with [`to_json`] and [`to_csv`] functions when `json` feature is enabled.
+
+[`to_json`]: ./fn.to_json.html
+[`to_csv`]: ./fn.to_csv.html
+*/
+
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "json")]
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use failure::Fail;
+use futures::{stream, SinkExt, StreamExt};
+use futures::channel::mpsc;
+use parking_lot::RwLock;
+#[cfg(feature = "json")]
+use serde::Serialize;
+
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::dht::bootstrap_info::BootstrapMotd;
+use crate::toxcore::dht::kbucket::kbucket_index;
+use crate::toxcore::dht::packed_node::*;
+use crate::toxcore::dht::packet::*;
+use crate::toxcore::dht::precomputed_cache::*;
+use crate::toxcore::dht::request_queue::*;
+use crate::toxcore::time::*;
+
+error_kind! {
+    #[doc = "Error that can happen during crawling."]
+    #[derive(Debug)]
+    CrawlerError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, Fail)]
+    CrawlerErrorKind {
+        #[doc = "Error indicates that getting payload of received packet error."]
+        #[fail(display = "Get payload of received packet error")]
+        GetPayload,
+        #[doc = "Error indicates that received packet's ping_id does not match."]
+        #[fail(display = "Ping id mismatch error")]
+        PingIdMismatch,
+        #[doc = "Error indicates that we didn't ask the node for BootstrapInfo."]
+        #[fail(display = "Unexpected BootstrapInfo error")]
+        UnexpectedBootstrapInfo,
+        #[doc = "Send packet(s) error."]
+        #[fail(display = "Send packet(s) error")]
+        SendTo,
+        #[doc = "Error indicates that discovered nodes can't be serialized."]
+        #[fail(display = "Serialize nodes error")]
+        Serialize,
+    }
+}
+
+/// Timeout for `NodesRequest` and `BootstrapInfo` requests.
+pub const CRAWLER_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval of time between sending batches of queued requests.
+pub const CRAWLER_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Maximum number of requests sent every `CRAWLER_INTERVAL`.
+pub const MAX_REQUESTS_PER_INTERVAL: usize = 128;
+
+/// Number of k-buckets of a node we send targeted `NodesRequest` for. Farther
+/// buckets are unlikely to be filled.
+pub const TARGETED_BUCKETS: u8 = 12;
+
+/// Maximum number of discovered nodes. Nodes returned after the limit is
+/// reached are ignored.
+pub const MAX_CRAWLED_NODES: usize = 1 << 16;
+
+/// Maximum number of requests waiting to be sent. Nodes that don't fit into
+/// the queue are not considered discovered so they will be queued when some
+/// other node returns them.
+pub const MAX_QUEUED_REQUESTS: usize = 1 << 14;
+
+/// Number of requests queued for every discovered node: `BootstrapInfo`,
+/// `NodesRequest` for a random key and targeted `NodesRequest` packets.
+const REQUESTS_PER_NODE: usize = TARGETED_BUCKETS as usize + 2;
+
+/// Size of MOTD in `BootstrapInfo` request. Together with packet kind and
+/// version it makes 78 bytes that the server expects.
+const BOOTSTRAP_INFO_REQUEST_MOTD_SIZE: usize = 73;
+
+/// Shorthand for the transmit half of the message channel.
+type Tx = mpsc::Sender<(Packet, SocketAddr)>;
+
+/// Node discovered during crawling.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CrawledNode {
+    /// DHT `PublicKey` of the node.
+    pub pk: PublicKey,
+    /// Address of the node.
+    pub saddr: SocketAddr,
+    /// Whether the node responded to our `NodesRequest`.
+    pub responded: bool,
+    /// Version from `BootstrapInfo` response.
+    pub version: Option<u32>,
+    /// Message of the day from `BootstrapInfo` response.
+    pub motd: Option<Vec<u8>>,
+}
+
+impl CrawledNode {
+    /// Create new `CrawledNode` we don't know anything about except its
+    /// address.
+    fn new(node: PackedNode) -> Self {
+        CrawledNode {
+            pk: node.pk,
+            saddr: node.saddr,
+            responded: false,
+            version: None,
+            motd: None,
+        }
+    }
+
+    /// MOTD as a string with trailing zero bytes removed.
+    pub fn motd_string(&self) -> Option<String> {
+        self.motd.as_ref().map(|motd| {
+            let len = motd.iter().rposition(|&b| b != 0).map_or(0, |pos| pos + 1);
+            String::from_utf8_lossy(&motd[..len]).into_owned()
+        })
+    }
+
+    /// MOTD parsed as free text with structured `key=value` fields.
+    pub fn motd_info(&self) -> Option<BootstrapMotd> {
+        self.motd.as_ref().map(|motd| BootstrapMotd::parse(motd))
+    }
+}
+
+/// Request queued to be sent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum CrawlRequest {
+    /// `NodesRequest` to the node searching for the key.
+    Nodes(PackedNode, PublicKey),
+    /// `BootstrapInfo` request to the address.
+    Info(SocketAddr),
+}
+
+/// Mutable state of the crawler.
+struct CrawlerState {
+    /// Discovered nodes by their DHT `PublicKey`.
+    nodes: HashMap<PublicKey, CrawledNode>,
+    /// Requests that are waiting to be sent.
+    queue: VecDeque<CrawlRequest>,
+    /// Sent `NodesRequest` packets with `PublicKey` of the requested node.
+    requests: RequestQueue<PublicKey>,
+    /// Addresses we sent `BootstrapInfo` request to with time of sending.
+    info_requests: HashMap<SocketAddr, Instant>,
+}
+
+/// DHT network crawler.
+#[derive(Clone)]
+pub struct Crawler {
+    /// Sink to send packets to UDP socket.
+    tx: Tx,
+    /// Our DHT `PublicKey`.
+    pk: PublicKey,
+    /// Lru cache for precomputed keys.
+    precomputed_keys: PrecomputedCache,
+    /// Crawler state.
+    state: Arc<RwLock<CrawlerState>>,
+}
+
+impl Crawler {
+    /// Create new `Crawler`.
+    pub fn new(tx: Tx, pk: PublicKey, sk: SecretKey) -> Self {
+        Crawler {
+            tx,
+            pk,
+            precomputed_keys: PrecomputedCache::new(sk, 1024),
+            state: Arc::new(RwLock::new(CrawlerState {
+                nodes: HashMap::new(),
+                queue: VecDeque::new(),
+                requests: RequestQueue::new(CRAWLER_REQUEST_TIMEOUT),
+                info_requests: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Add node to start crawling from.
+    pub fn add_bootstrap_node(&self, node: PackedNode) {
+        Crawler::discover(&mut self.state.write(), node);
+    }
+
+    /// Add node to discovered nodes and queue requests to it if it wasn't
+    /// discovered before and there is room for it.
+    fn discover(state: &mut CrawlerState, node: PackedNode) {
+        if state.nodes.contains_key(&node.pk) {
+            return;
+        }
+
+        if state.nodes.len() >= MAX_CRAWLED_NODES || state.queue.len() + REQUESTS_PER_NODE > MAX_QUEUED_REQUESTS {
+            trace!("Crawler limits are reached, ignoring node {:?}", node.pk);
+            return;
+        }
+
+        state.nodes.insert(node.pk, CrawledNode::new(node));
+        state.queue.push_back(CrawlRequest::Info(node.saddr));
+        state.queue.push_back(CrawlRequest::Nodes(node, gen_keypair().0));
+        for index in 0 .. TARGETED_BUCKETS {
+            state.queue.push_back(CrawlRequest::Nodes(node, key_in_bucket(&node.pk, index)));
+        }
+    }
+
+    /// Handle packet received from the network. Packets other than
+    /// `NodesResponse` and `BootstrapInfo` are ignored.
+    pub fn handle_packet(&self, packet: Packet, addr: SocketAddr) -> Result<(), CrawlerError> {
+        match packet {
+            Packet::NodesResponse(packet) => self.handle_nodes_resp(packet),
+            Packet::BootstrapInfo(packet) => self.handle_bootstrap_info(packet, addr),
+            _ => Ok(()),
+        }
+    }
+
+    /// Handle `NodesResponse` packet: mark the sender as responded and queue
+    /// requests to nodes from the response.
+    fn handle_nodes_resp(&self, packet: NodesResponse) -> Result<(), CrawlerError> {
+        let precomputed_key = self.precomputed_keys.get(packet.pk);
+        let payload = packet.get_payload(&precomputed_key)
+            .map_err(|e| e.context(CrawlerErrorKind::GetPayload))?;
+
+        let mut state = self.state.write();
+
+        if state.requests.check_ping_id(payload.id, |&node_pk| node_pk == packet.pk).is_none() {
+            return Err(CrawlerErrorKind::PingIdMismatch.into());
+        }
+
+        if let Some(node) = state.nodes.get_mut(&packet.pk) {
+            node.responded = true;
+        }
+
+        for node in payload.nodes {
+            if node.pk != self.pk {
+                Crawler::discover(&mut state, node);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle `BootstrapInfo` packet: store version and MOTD of the node.
+    fn handle_bootstrap_info(&self, packet: BootstrapInfo, addr: SocketAddr) -> Result<(), CrawlerError> {
+        let mut state = self.state.write();
+
+        match state.info_requests.remove(&addr) {
+            Some(time) if clock_elapsed(time) <= CRAWLER_REQUEST_TIMEOUT => { },
+            _ => return Err(CrawlerErrorKind::UnexpectedBootstrapInfo.into()),
+        }
+
+        for node in state.nodes.values_mut().filter(|node| node.saddr == addr) {
+            node.version = Some(packet.version);
+            node.motd = Some(packet.motd.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Send up to `MAX_REQUESTS_PER_INTERVAL` queued requests.
+    async fn send_requests(&self) -> Result<(), mpsc::SendError> {
+        let packets = {
+            let mut state = self.state.write();
+            let count = state.queue.len().min(MAX_REQUESTS_PER_INTERVAL);
+            let requests = state.queue.drain(.. count).collect::<Vec<_>>();
+
+            requests.into_iter().map(|request| match request {
+                CrawlRequest::Nodes(node, search_pk) => {
+                    let payload = NodesRequestPayload {
+                        pk: search_pk,
+                        id: state.requests.new_ping_id(node.pk),
+                    };
+                    let precomputed_key = self.precomputed_keys.get(node.pk);
+                    let packet = Packet::NodesRequest(NodesRequest::new(&precomputed_key, &self.pk, &payload));
+                    (packet, node.saddr)
+                },
+                CrawlRequest::Info(saddr) => {
+                    state.info_requests.insert(saddr, clock_now());
+                    let packet = Packet::BootstrapInfo(BootstrapInfo {
+                        version: 0,
+                        motd: vec![0; BOOTSTRAP_INFO_REQUEST_MOTD_SIZE],
+                    });
+                    (packet, saddr)
+                },
+            }).collect::<Vec<_>>()
+        };
+
+        let mut tx = self.tx.clone();
+        let mut stream = stream::iter(packets.into_iter().map(Ok));
+        tx.send_all(&mut stream).await
+    }
+
+    /// Remove timed out requests.
+    fn clear_timed_out(&self) {
+        let mut state = self.state.write();
+        state.requests.clear_timed_out();
+        state.info_requests.retain(|_, &mut time| clock_elapsed(time) <= CRAWLER_REQUEST_TIMEOUT);
+    }
+
+    /// Check if there are neither queued nor pending requests.
+    pub fn is_finished(&self) -> bool {
+        let state = self.state.read();
+        state.queue.is_empty() &&
+            state.requests.get_values().next().is_none() &&
+            state.info_requests.values().all(|&time| clock_elapsed(time) > CRAWLER_REQUEST_TIMEOUT)
+    }
+
+    /// Get all discovered nodes sorted by their addresses.
+    pub fn nodes(&self) -> Vec<CrawledNode> {
+        let mut nodes = self.state.read().nodes.values().cloned().collect::<Vec<_>>();
+        nodes.sort_by_key(|node| node.saddr);
+        nodes
+    }
+
+    /// Run crawler. Result future is completed when crawling is finished.
+    pub async fn run(self) -> Result<(), CrawlerError> {
+        let mut wakeups = tokio::time::interval(CRAWLER_INTERVAL);
+
+        while wakeups.next().await.is_some() {
+            self.clear_timed_out();
+
+            if self.is_finished() {
+                break;
+            }
+
+            if let Err(e) = self.send_requests().await {
+                warn!("Failed to send crawler requests: {}", e);
+
+                return Err(e.context(CrawlerErrorKind::SendTo).into())
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Generate random `PublicKey` that belongs to the k-bucket with the given
+/// index relative to `base_pk`.
+fn key_in_bucket(base_pk: &PublicKey, index: u8) -> PublicKey {
+    let PublicKey(mut key) = gen_keypair().0;
+    let byte = index as usize / 8;
+    let bit = 0x80 >> (index % 8);
+    // the key has the same prefix as base key before the bucket's bit
+    key[.. byte].copy_from_slice(&base_pk.0[.. byte]);
+    let prefix_mask = !(0xffu8 >> (index % 8));
+    key[byte] = (base_pk.0[byte] & prefix_mask) | (!base_pk.0[byte] & bit) | (key[byte] & !prefix_mask & !bit);
+    let key = PublicKey(key);
+    debug_assert_eq!(kbucket_index(base_pk, &key), Some(index));
+    key
+}
+
+/// Hex representation of `PublicKey`.
+#[cfg(feature = "json")]
+fn pk_to_hex(pk: &PublicKey) -> String {
+    pk.0.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Discovered node as it's written to JSON and CSV output.
+#[cfg(feature = "json")]
+#[derive(Serialize)]
+struct NodeRecord {
+    public_key: String,
+    ip: IpAddr,
+    port: u16,
+    responded: bool,
+    version: Option<u32>,
+    motd: Option<String>,
+}
+
+#[cfg(feature = "json")]
+impl NodeRecord {
+    fn new(node: &CrawledNode) -> Self {
+        NodeRecord {
+            public_key: pk_to_hex(&node.pk),
+            ip: node.saddr.ip(),
+            port: node.saddr.port(),
+            responded: node.responded,
+            version: node.version,
+            motd: node.motd_string(),
+        }
+    }
+}
+
+/// Format discovered nodes as JSON array.
+#[cfg(feature = "json")]
+pub fn to_json(nodes: &[CrawledNode]) -> Result<String, CrawlerError> {
+    let records = nodes.iter().map(NodeRecord::new).collect::<Vec<_>>();
+    serde_json::to_string(&records)
+        .map_err(|e| e.context(CrawlerErrorKind::Serialize).into())
+}
+
+/// Format discovered nodes as CSV with header.
+#[cfg(feature = "json")]
+pub fn to_csv(nodes: &[CrawledNode]) -> Result<String, CrawlerError> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for node in nodes {
+        writer.serialize(NodeRecord::new(node))
+            .map_err(|e| e.context(CrawlerErrorKind::Serialize))?;
+    }
+    let csv = writer.into_inner()
+        .map_err(|e| e.into_error().context(CrawlerErrorKind::Serialize))?;
+    String::from_utf8(csv)
+        .map_err(|e| e.context(CrawlerErrorKind::Serialize).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_crawler() -> (Crawler, PublicKey, mpsc::Receiver<(Packet, SocketAddr)>) {
+        crypto_init().unwrap();
+        let (pk, sk) = gen_keypair();
+        let (tx, rx) = mpsc::channel(256);
+        (Crawler::new(tx, pk, sk), pk, rx)
+    }
+
+    #[test]
+    fn key_in_bucket_test() {
+        crypto_init().unwrap();
+        let base_pk = gen_keypair().0;
+        for index in 0 .. 255 {
+            assert_eq!(kbucket_index(&base_pk, &key_in_bucket(&base_pk, index)), Some(index));
+        }
+    }
+
+    #[tokio::test]
+    async fn crawl() {
+        let (crawler, pk, mut rx) = create_crawler();
+        let (node_pk, node_sk) = gen_keypair();
+        let node_addr = "127.0.0.1:33445".parse().unwrap();
+        crawler.add_bootstrap_node(PackedNode::new(node_addr, &node_pk));
+
+        crawler.send_requests().await.unwrap();
+
+        // BootstrapInfo request goes first
+        let (packet, addr) = rx.next().await.unwrap();
+        assert_eq!(addr, node_addr);
+        let bootstrap_info = unpack!(packet, Packet::BootstrapInfo);
+        assert_eq!(bootstrap_info.motd.len(), BOOTSTRAP_INFO_REQUEST_MOTD_SIZE);
+
+        let precomputed_key = precompute(&pk, &node_sk);
+        let mut ids = Vec::new();
+        for _ in 0 .. TARGETED_BUCKETS + 1 {
+            let (packet, addr) = rx.next().await.unwrap();
+            assert_eq!(addr, node_addr);
+            let nodes_req = unpack!(packet, Packet::NodesRequest);
+            ids.push(nodes_req.get_payload(&precomputed_key).unwrap().id);
+        }
+
+        crawler.handle_packet(Packet::BootstrapInfo(BootstrapInfo {
+            version: 1717,
+            motd: b"hello\0\0".to_vec(),
+        }), node_addr).unwrap();
+
+        let new_node = PackedNode::new("127.0.0.2:33445".parse().unwrap(), &gen_keypair().0);
+        let nodes_resp = NodesResponse::new(&precomputed_key, &node_pk, &NodesResponsePayload {
+            nodes: vec![new_node, PackedNode::new(node_addr, &node_pk)],
+            id: ids[0],
+        });
+        crawler.handle_packet(Packet::NodesResponse(nodes_resp.clone()), node_addr).unwrap();
+
+        // the same response can't be accepted twice
+        let res = crawler.handle_packet(Packet::NodesResponse(nodes_resp), node_addr);
+        assert_eq!(*res.err().unwrap().kind(), CrawlerErrorKind::PingIdMismatch);
+
+        let nodes = crawler.nodes();
+        assert_eq!(nodes, vec![
+            CrawledNode {
+                pk: node_pk,
+                saddr: node_addr,
+                responded: true,
+                version: Some(1717),
+                motd: Some(b"hello\0\0".to_vec()),
+            },
+            CrawledNode::new(new_node),
+        ]);
+        assert_eq!(nodes[0].motd_string(), Some("hello".to_owned()));
+        assert_eq!(nodes[0].motd_info(), Some(BootstrapMotd::new("hello")));
+
+        // requests to the new node are queued
+        assert!(!crawler.is_finished());
+        crawler.send_requests().await.unwrap();
+        let (_packet, addr) = rx.next().await.unwrap();
+        assert_eq!(addr, new_node.saddr);
+    }
+
+    #[test]
+    fn handle_unexpected_bootstrap_info() {
+        let (crawler, _pk, _rx) = create_crawler();
+
+        let res = crawler.handle_packet(Packet::BootstrapInfo(BootstrapInfo {
+            version: 1717,
+            motd: Vec::new(),
+        }), "127.0.0.1:33445".parse().unwrap());
+        assert_eq!(*res.err().unwrap().kind(), CrawlerErrorKind::UnexpectedBootstrapInfo);
+    }
+
+    #[test]
+    fn handle_nodes_resp_invalid_payload() {
+        let (crawler, _pk, _rx) = create_crawler();
+        let (node_pk, node_sk) = gen_keypair();
+
+        let nodes_resp = NodesResponse::new(&precompute(&node_pk, &node_sk), &node_pk, &NodesResponsePayload {
+            nodes: Vec::new(),
+            id: 42,
+        });
+        let res = crawler.handle_packet(Packet::NodesResponse(nodes_resp), "127.0.0.1:33445".parse().unwrap());
+        assert_eq!(*res.err().unwrap().kind(), CrawlerErrorKind::GetPayload);
+    }
+
+    #[tokio::test]
+    async fn finished_after_timeout() {
+        let (crawler, _pk, _rx) = create_crawler();
+        assert!(crawler.is_finished());
+
+        tokio::time::pause();
+        crawler.add_bootstrap_node(PackedNode::new("127.0.0.1:33445".parse().unwrap(), &gen_keypair().0));
+        assert!(!crawler.is_finished());
+
+        crawler.send_requests().await.unwrap();
+        assert!(!crawler.is_finished());
+
+        tokio::time::advance(CRAWLER_REQUEST_TIMEOUT + Duration::from_secs(1)).await;
+        crawler.clear_timed_out();
+        assert!(crawler.is_finished());
+    }
+
+    /// Create unique `PublicKey` from the index without generating keypair.
+    fn index_pk(index: usize) -> PublicKey {
+        let mut key = [0; PUBLICKEYBYTES];
+        key[.. 8].copy_from_slice(&(index as u64).to_be_bytes());
+        PublicKey(key)
+    }
+
+    #[test]
+    fn discover_queue_limit() {
+        let (crawler, _pk, _rx) = create_crawler();
+        let saddr = "127.0.0.1:33445".parse().unwrap();
+
+        let count = MAX_QUEUED_REQUESTS / REQUESTS_PER_NODE;
+        for index in 0 ..= count {
+            crawler.add_bootstrap_node(PackedNode::new(saddr, &index_pk(index)));
+        }
+
+        let state = crawler.state.read();
+        assert_eq!(state.nodes.len(), count);
+        assert!(!state.nodes.contains_key(&index_pk(count)));
+        assert_eq!(state.queue.len(), count * REQUESTS_PER_NODE);
+    }
+
+    #[test]
+    fn discover_nodes_limit() {
+        let (crawler, _pk, _rx) = create_crawler();
+        let saddr = "127.0.0.1:33445".parse().unwrap();
+
+        {
+            let mut state = crawler.state.write();
+            for index in 0 .. MAX_CRAWLED_NODES {
+                let pk = index_pk(index);
+                state.nodes.insert(pk, CrawledNode::new(PackedNode::new(saddr, &pk)));
+            }
+        }
+
+        crawler.add_bootstrap_node(PackedNode::new(saddr, &index_pk(MAX_CRAWLED_NODES)));
+
+        let state = crawler.state.read();
+        assert_eq!(state.nodes.len(), MAX_CRAWLED_NODES);
+        assert!(state.queue.is_empty());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn output_formats() {
+        crypto_init().unwrap();
+        let pk = PublicKey([0xab; PUBLICKEYBYTES]);
+        let nodes = vec![
+            CrawledNode {
+                pk,
+                saddr: "1.2.3.4:33445".parse().unwrap(),
+                responded: true,
+                version: Some(3),
+                motd: Some(b"say \"hi\", bye".to_vec()),
+            },
+            CrawledNode {
+                pk,
+                saddr: "[::1]:33445".parse().unwrap(),
+                responded: false,
+                version: None,
+                motd: None,
+            },
+        ];
+        let hex = "AB".repeat(PUBLICKEYBYTES);
+
+        assert_eq!(to_json(&nodes).unwrap(), format!(
+            "[{{\"public_key\":\"{0}\",\"ip\":\"1.2.3.4\",\"port\":33445,\"responded\":true,\"version\":3,\"motd\":\"say \\\"hi\\\", bye\"}},\
+            {{\"public_key\":\"{0}\",\"ip\":\"::1\",\"port\":33445,\"responded\":false,\"version\":null,\"motd\":null}}]",
+            hex
+        ));
+        assert_eq!(to_csv(&nodes).unwrap(), format!(
+            "public_key,ip,port,responded,version,motd\n\
+            {0},1.2.3.4,33445,true,3,\"say \"\"hi\"\", bye\"\n\
+            {0},::1,33445,false,,\n",
+            hex
+        ));
+    }
+}
@@ -12,6 +12,9 @@ use crate::toxcore::state_format::old::*;
 use crate::toxcore::binary_io::*;
 use crate::toxcore::dht::kbucket::*;
 use crate::toxcore::dht::ktree::*;
+use crate::toxcore::dht::ban_list::*;
+use crate::toxcore::onion::onion_announce::*;
+use crate::toxcore::crypto_core::PUBLICKEYBYTES;
 
 use failure::Fail;
 use nom::{Err, error::ErrorKind as NomErrorKind};
@@ -90,12 +93,78 @@ impl DaemonState {
     }
 }
 
+impl DaemonState {
+    /// Serialize permanent bans of DHT nodes so that they can be restored
+    /// after restart.
+    pub fn serialize_ban_list(server: &Server) -> Vec<u8> {
+        let ban_list = server.ban_list();
+
+        let size = 8 + ban_list.keys().count() * PUBLICKEYBYTES + ban_list.ips().count() * 17;
+        let mut buf = vec![0u8; size];
+        let (_, buf_len) = ban_list.to_bytes((&mut buf, 0)).expect("BanList.to_bytes has failed");
+
+        buf.truncate(buf_len);
+        buf
+    }
+
+    /// Deserialize permanent bans of DHT nodes and add them to the server's
+    /// ban list.
+    pub fn deserialize_ban_list(server: &Server, serialized_data: &[u8]) -> Result<(), DeserializeError> {
+        let ban_list = match BanList::from_bytes(serialized_data) {
+            Err(error) => {
+                return Err(DeserializeError::deserialize(error, serialized_data.to_vec()))
+            },
+            Ok((_, ban_list)) => ban_list,
+        };
+
+        for &pk in ban_list.keys() {
+            server.ban_key(pk);
+        }
+        for &ip in ban_list.ips() {
+            server.ban_ip(ip);
+        }
+
+        Ok(())
+    }
+
+    /// Serialize onion announce entries that are not timed out together with
+    /// the secret used to calculate onion ping ids so that announced nodes
+    /// don't have to reannounce themselves after restart.
+    pub fn serialize_onion_announce(server: &Server) -> Vec<u8> {
+        let state = server.onion_announce.read().state();
+
+        let mut buf = vec![0u8; state.size()];
+        let (_, buf_len) = state.to_bytes((&mut buf, 0)).expect("OnionAnnounceState.to_bytes has failed");
+
+        buf.truncate(buf_len);
+        buf
+    }
+
+    /// Deserialize onion announce entries and add them to the server's onion
+    /// announce list. Entries that timed out while the server was down are
+    /// skipped.
+    pub fn deserialize_onion_announce(server: &Server, serialized_data: &[u8]) -> Result<(), DeserializeError> {
+        let state = match OnionAnnounceState::from_bytes(serialized_data) {
+            Err(error) => {
+                return Err(DeserializeError::deserialize(error, serialized_data.to_vec()))
+            },
+            Ok((_, state)) => state,
+        };
+
+        let restored = server.onion_announce.write().restore(state);
+        debug!("Restored {} onion announce entries", restored);
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use crate::toxcore::crypto_core::*;
     use crate::toxcore::dht::packet::*;
+    use crate::toxcore::onion::packet::OnionReturn;
 
     use futures::channel::mpsc;
     use futures::StreamExt;
@@ -155,4 +224,66 @@ mod tests {
         let serialized_vec = DaemonState::serialize_old(&alice);
         assert!(DaemonState::deserialize_old(&alice, &serialized_vec).await.is_ok());
     }
+
+    #[test]
+    fn ban_list_serialize_deserialize() {
+        crypto_init().unwrap();
+        let (pk, sk) = gen_keypair();
+        let (tx, _rx) = mpsc::channel(1);
+        let alice = Server::new(tx, pk, sk);
+
+        let banned_pk = gen_keypair().0;
+        let banned_ip = "1.2.3.4".parse().unwrap();
+        let node = PackedNode::new("1.2.3.4:33445".parse().unwrap(), &gen_keypair().0);
+        alice.close_nodes.write().try_add(node);
+        alice.ban_key(banned_pk);
+        alice.ban_ip(banned_ip);
+        // banning IP removes nodes with this IP from close nodes list
+        assert!(alice.close_nodes.read().get_node(&node.pk).is_none());
+
+        let serialized_vec = DaemonState::serialize_ban_list(&alice);
+
+        let (pk, sk) = gen_keypair();
+        let (tx, _rx) = mpsc::channel(1);
+        let bob = Server::new(tx, pk, sk);
+        DaemonState::deserialize_ban_list(&bob, &serialized_vec).unwrap();
+
+        assert_eq!(bob.ban_list(), alice.ban_list());
+        assert!(bob.is_banned(&node));
+
+        // test with corrupted serialized data
+        assert!(DaemonState::deserialize_ban_list(&bob, &[42; 3]).is_err());
+    }
+
+    #[tokio::test]
+    async fn onion_announce_serialize_deserialize() {
+        crypto_init().unwrap();
+        let (pk, sk) = gen_keypair();
+        let (tx, _rx) = mpsc::channel(1);
+        let alice = Server::new(tx, pk, sk.clone());
+
+        let entry = OnionAnnounceEntry::new(
+            gen_keypair().0,
+            "1.2.3.4".parse().unwrap(),
+            12345,
+            OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; 42]
+            },
+            gen_keypair().0
+        );
+        alice.onion_announce.write().add_to_entries(entry);
+
+        let serialized_vec = DaemonState::serialize_onion_announce(&alice);
+
+        let (tx, _rx) = mpsc::channel(1);
+        let bob = Server::new(tx, pk, sk);
+        DaemonState::deserialize_onion_announce(&bob, &serialized_vec).unwrap();
+
+        assert_eq!(bob.onion_announce_entries_count(), 1);
+        assert_eq!(bob.onion_announce.read().state().len(), 1);
+
+        // test with corrupted serialized data
+        assert!(DaemonState::deserialize_onion_announce(&bob, &[42; 3]).is_err());
+    }
 }
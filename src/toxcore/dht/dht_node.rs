@@ -12,6 +12,7 @@ use std::time::{Duration, Instant};
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::dht::kbucket::*;
 use crate::toxcore::dht::packed_node::*;
+use crate::toxcore::dht::reputation::*;
 use crate::toxcore::time::*;
 
 /// Ping interval for each node in our lists.
@@ -92,6 +93,7 @@ we should make decision to add new node to close node list, or not.
 the PK's distance and status of node help making decision.
 Bad node have higher priority than Good node.
 If both node is Good node, then we compare PK's distance.
+Good node with poor reputation can be replaced as well.
 */
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct DhtNode {
@@ -101,6 +103,8 @@ pub struct DhtNode {
     pub assoc6: SockAndTime<SocketAddrV6>,
     /// Public Key of the node.
     pub pk: PublicKey,
+    /// Reputation of the node built from its responses.
+    pub reputation: Reputation,
 }
 
 impl DhtNode {
@@ -115,6 +119,7 @@ impl DhtNode {
             pk: pn.pk,
             assoc4: SockAndTime::new(saddr_v4),
             assoc6: SockAndTime::new(saddr_v6),
+            reputation: Reputation::new(),
         }
     }
 
@@ -222,11 +227,19 @@ impl KbucketNode for DhtNode {
         }
     }
     fn is_evictable(&self) -> bool {
-        self.is_bad()
+        self.is_bad() || self.reputation.is_poor()
     }
     fn eviction_index(nodes: &[Self]) -> Option<usize> {
         nodes.iter().rposition(|n| n.is_discarded()).or_else(||
             nodes.iter().rposition(|n| n.is_bad())
+        ).or_else(||
+            nodes.iter()
+                .enumerate()
+                // the last node with the lowest score
+                .rev()
+                .filter(|(_, n)| n.reputation.is_poor())
+                .min_by_key(|(_, n)| n.reputation.score())
+                .map(|(i, _)| i)
         )
     }
 }
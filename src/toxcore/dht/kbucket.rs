@@ -229,9 +229,12 @@ where
                         Some(index) => {
                             debug!(target: "Kbucket",
                                 "No free space left in the kbucket, the last bad node removed.");
-                            // replace the farthest bad node
+                            // replace the farthest bad node keeping nodes
+                            // sorted by distance
                             self.nodes.remove(index);
-                            self.nodes.push(new_node.into());
+                            let index = self.nodes.binary_search_by(|n| base_pk.distance(&n.pk(), &new_node.pk()))
+                                .unwrap_or_else(|index| index);
+                            self.nodes.insert(index, new_node.into());
                             true
                         },
                         None => {
@@ -468,6 +471,40 @@ mod tests {
         assert!(kbucket.try_add(&pk, node_1, /* evict */ false));
     }
 
+    #[test]
+    fn kbucket_try_add_should_replace_nodes_with_poor_reputation() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::<DhtNode>::new(2);
+
+        let node_1 = PackedNode::new(
+            "1.2.3.4:12345".parse().unwrap(),
+            &PublicKey([1; PUBLICKEYBYTES])
+        );
+        let node_2 = PackedNode::new(
+            "1.2.3.4:12346".parse().unwrap(),
+            &PublicKey([2; PUBLICKEYBYTES])
+        );
+        let node_3 = PackedNode::new(
+            "1.2.3.4:12347".parse().unwrap(),
+            &PublicKey([3; PUBLICKEYBYTES])
+        );
+
+        assert!(kbucket.try_add(&pk, node_2, /* evict */ false));
+        assert!(kbucket.try_add(&pk, node_3, /* evict */ false));
+        assert!(!kbucket.can_add(&pk, &node_1, /* evict */ false));
+
+        // node_2 doesn't answer our requests
+        let reputation = &mut kbucket.get_node_mut(&pk, &node_2.pk).unwrap().reputation;
+        for _ in 0 .. 8 {
+            reputation.on_request();
+        }
+
+        // replacing node with poor reputation
+        assert!(kbucket.try_add(&pk, node_1, /* evict */ false));
+        assert!(!kbucket.contains(&pk, &node_2.pk));
+        assert!(kbucket.contains(&pk, &node_3.pk));
+    }
+
     #[tokio::test]
     async fn kbucket_try_add_evict_should_replace_bad_nodes() {
         let pk = PublicKey([0; PUBLICKEYBYTES]);
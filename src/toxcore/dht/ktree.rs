@@ -7,13 +7,16 @@ use crate::toxcore::dht::ip_port::IsGlobal;
 use crate::toxcore::dht::kbucket::*;
 
 /** K-buckets structure to hold up to
-[`KBUCKET_MAX_ENTRIES`](./constant.KBUCKET_MAX_ENTRIES.html) *
-[`KBUCKET_DEFAULT_SIZE`](./constant.KBUCKET_DEFAULT_SIZE.html) nodes close to
-own PK.
+[`KBUCKET_MAX_ENTRIES`](./constant.KBUCKET_MAX_ENTRIES.html) * `k` nodes close
+to own PK, where `k` is [`KBUCKET_DEFAULT_SIZE`] by default.
 
 Buckets in ktree are sorted by closeness to the PK; closest bucket is the last
 one, while furthest is the first one.
 
+Every bucket has a replacement cache with nodes that didn't fit into the
+bucket. These nodes are not verified, they should be pinged when the bucket
+gets a free slot or a bad node so that they can replace it after responding.
+
 Further reading: [Tox spec](https://zetok.github.io/tox-spec#k-buckets).
 
 The name references to the kademlia binary tree from
@@ -25,6 +28,8 @@ pub struct Ktree {
     pk: PublicKey,
     /// List of [`Kbucket`](./struct.Kbucket.html)s.
     pub kbuckets: Vec<Kbucket<DhtNode>>,
+    /// Replacement caches for every kbucket with the same indices.
+    pub replacements: Vec<Kbucket<PackedNode>>,
 }
 
 /** Maximum number of [`Kbucket`](./struct.Kbucket.html)s that [`Ktree`]
@@ -37,13 +42,46 @@ Realistically, not even half of that will be ever used, given how
 */
 pub const KBUCKET_MAX_ENTRIES: u8 = ::std::u8::MAX;
 
+/// Default number of nodes that replacement cache of every kbucket can hold.
+pub const REPLACEMENT_CACHE_DEFAULT_SIZE: u8 = 8;
+
 impl Ktree {
-    /// Create a new `Ktree`.
+    /// Create a new `Ktree` with default kbucket and replacement cache sizes.
     pub fn new(pk: &PublicKey) -> Self {
+        Ktree::with_capacity(pk, KBUCKET_DEFAULT_SIZE, REPLACEMENT_CACHE_DEFAULT_SIZE)
+    }
+
+    /// Create a new `Ktree` with kbuckets that can hold up to `capacity` nodes
+    /// and replacement caches that can hold up to `replacement_capacity`
+    /// nodes.
+    pub fn with_capacity(pk: &PublicKey, capacity: u8, replacement_capacity: u8) -> Self {
         trace!(target: "Ktree", "Creating new Ktree with PK: {:?}", pk);
         Ktree {
             pk: *pk,
-            kbuckets: vec![Kbucket::new(KBUCKET_DEFAULT_SIZE); KBUCKET_MAX_ENTRIES as usize]
+            kbuckets: vec![Kbucket::new(capacity); KBUCKET_MAX_ENTRIES as usize],
+            replacements: vec![Kbucket::new(replacement_capacity); KBUCKET_MAX_ENTRIES as usize],
+        }
+    }
+
+    /// Number of nodes every kbucket can hold.
+    pub fn capacity(&self) -> u8 {
+        self.kbuckets[0].capacity
+    }
+
+    /** Change number of nodes every kbucket can hold.
+
+    If kbuckets are shrunk the farthest nodes are moved to replacement caches.
+    */
+    pub fn set_capacity(&mut self, capacity: u8) {
+        let pk = self.pk;
+        for (kbucket, replacements) in self.kbuckets.iter_mut().zip(self.replacements.iter_mut()) {
+            kbucket.capacity = capacity;
+            while kbucket.len() > kbucket.capacity() {
+                let node = kbucket.nodes.pop().expect("Kbucket is not empty");
+                if let Some(node) = node.to_packed_node() {
+                    replacements.try_add(&pk, node, /* evict */ true);
+                }
+            }
         }
     }
 
@@ -82,7 +120,9 @@ impl Ktree {
     * its [`kbucket index`](./fn.kbucket_index.html) is lower than the
       number of kbuckets.
     * [`Kbucket`](./struct.Kbucket.html) to which it is added has free space
-      or added node is closer to the PK than other node in the kbucket.
+      or a node that can be evicted.
+
+    Otherwise node is added to the replacement cache of the kbucket.
 
     Returns `true` if node was added successfully, `false` otherwise.
     */
@@ -91,7 +131,13 @@ impl Ktree {
         trace!(target: "Ktree", "With PN: {:?}; and self: {:?}", node, self);
 
         match self.kbucket_index(&node.pk) {
-            Some(index) => self.kbuckets[index].try_add(&self.pk, node, /* evict */ false),
+            Some(index) => if self.kbuckets[index].try_add(&self.pk, node, /* evict */ false) {
+                self.replacements[index].remove(&self.pk, &node.pk);
+                true
+            } else {
+                self.replacements[index].try_add(&self.pk, node, /* evict */ true);
+                false
+            },
             None => {
                 trace!("Failed to add node: {:?}", node);
                 false
@@ -114,6 +160,52 @@ impl Ktree {
         }
     }
 
+    /** Add [`PackedNode`](./struct.PackedNode.html) to the replacement cache
+    of the kbucket where it should be placed.
+
+    Node is not added if it's already in the kbucket. If the replacement cache
+    is full the farthest node is evicted in favor of a closer one.
+
+    Returns `true` if node was added successfully, `false` otherwise.
+    */
+    pub fn add_replacement(&mut self, node: PackedNode) -> bool {
+        match self.kbucket_index(&node.pk) {
+            Some(index) if !self.kbuckets[index].contains(&self.pk, &node.pk) =>
+                self.replacements[index].try_add(&self.pk, node, /* evict */ true),
+            _ => false,
+        }
+    }
+
+    /// Check if [`PackedNode`](./struct.PackedNode.html) would change the
+    /// replacement cache if it was added by `add_replacement`.
+    pub fn can_add_replacement(&self, node: &PackedNode) -> bool {
+        match self.kbucket_index(&node.pk) {
+            Some(index) if !self.kbuckets[index].contains(&self.pk, &node.pk) =>
+                self.replacements[index].can_add(&self.pk, node, /* evict */ true),
+            _ => false,
+        }
+    }
+
+    /** Take nodes from replacement caches that should be pinged.
+
+    The closest replacement node is taken from every kbucket that has a free
+    slot or a node that can be evicted. If the taken node responds to the ping
+    it will be added to the kbucket by `try_add`.
+    */
+    pub fn take_replacements_to_ping(&mut self) -> Vec<PackedNode> {
+        self.kbuckets.iter()
+            .zip(self.replacements.iter_mut())
+            .filter(|(kbucket, replacements)| !replacements.is_empty() &&
+                (!kbucket.is_full() || kbucket.iter().any(|node| node.is_evictable())))
+            .map(|(_, replacements)| replacements.nodes.remove(0))
+            .collect()
+    }
+
+    /// Total number of nodes in replacement caches.
+    pub fn replacements_count(&self) -> usize {
+        self.replacements.iter().map(|replacements| replacements.len()).sum()
+    }
+
     /** Get (up to) 4 closest nodes to given PK.
 
     Functionality for [`SendNodes`](./struct.SendNodes.html).
@@ -435,4 +527,101 @@ mod tests {
 
         assert!(ktree.is_all_discarded());
     }
+
+    // Ktree replacement caches
+
+    /// Create node that gets into the first kbucket of a `Ktree` with zero
+    /// `PublicKey`.
+    fn first_kbucket_node(i: u8) -> PackedNode {
+        let mut pk = [i; PUBLICKEYBYTES];
+        pk[0] = 255;
+        let addr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + u16::from(i));
+        PackedNode::new(addr, &PublicKey(pk))
+    }
+
+    #[test]
+    fn ktree_with_capacity() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut ktree = Ktree::with_capacity(&pk, 16, 4);
+        assert_eq!(ktree.capacity(), 16);
+
+        for i in 0 .. 16 {
+            assert!(ktree.try_add(first_kbucket_node(i + 2)));
+        }
+        assert!(!ktree.try_add(first_kbucket_node(18)));
+        assert_eq!(ktree.kbuckets[0].len(), 16);
+        assert_eq!(ktree.replacements_count(), 1);
+    }
+
+    #[test]
+    fn ktree_set_capacity() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut ktree = Ktree::new(&pk);
+
+        for i in 0 .. 8 {
+            assert!(ktree.try_add(first_kbucket_node(i + 2)));
+        }
+
+        ktree.set_capacity(4);
+        assert_eq!(ktree.capacity(), 4);
+        assert_eq!(ktree.kbuckets[0].len(), 4);
+        // the farthest nodes are moved to the replacement cache
+        assert_eq!(ktree.replacements_count(), 4);
+        assert!(ktree.contains(&first_kbucket_node(2).pk));
+        assert!(!ktree.contains(&first_kbucket_node(9).pk));
+
+        ktree.set_capacity(8);
+        assert!(ktree.try_add(first_kbucket_node(20)));
+        // added node is removed from the replacement cache
+        assert!(ktree.try_add(first_kbucket_node(9)));
+        assert_eq!(ktree.replacements_count(), 3);
+    }
+
+    #[test]
+    fn ktree_add_replacement() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut ktree = Ktree::new(&pk);
+
+        let node = first_kbucket_node(2);
+        assert!(ktree.try_add(node));
+        // node that is already in the kbucket can't be a replacement
+        assert!(!ktree.can_add_replacement(&node));
+        assert!(!ktree.add_replacement(node));
+        // own key can't be a replacement
+        let own_node = PackedNode::new("1.2.3.4:12345".parse().unwrap(), &pk);
+        assert!(!ktree.can_add_replacement(&own_node));
+        assert!(!ktree.add_replacement(own_node));
+        assert!(ktree.can_add_replacement(&first_kbucket_node(3)));
+        assert!(ktree.add_replacement(first_kbucket_node(3)));
+        assert_eq!(ktree.replacements_count(), 1);
+        // the same node doesn't change the replacement cache
+        assert!(!ktree.can_add_replacement(&first_kbucket_node(3)));
+    }
+
+    #[tokio::test]
+    async fn ktree_take_replacements_to_ping() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut ktree = Ktree::new(&pk);
+
+        for i in 0 .. 8 {
+            assert!(ktree.try_add(first_kbucket_node(i + 2)));
+        }
+        assert!(ktree.add_replacement(first_kbucket_node(11)));
+        assert!(ktree.add_replacement(first_kbucket_node(10)));
+
+        // kbucket is full of good nodes so replacements are not needed
+        assert!(ktree.take_replacements_to_ping().is_empty());
+
+        tokio::time::pause();
+        tokio::time::advance(BAD_NODE_TIMEOUT + Duration::from_secs(1)).await;
+
+        // the closest replacement is taken to be pinged
+        assert_eq!(ktree.take_replacements_to_ping(), vec![first_kbucket_node(10)]);
+        assert_eq!(ktree.replacements_count(), 1);
+
+        // after response it evicts a bad node
+        assert!(ktree.try_add(first_kbucket_node(10)));
+        assert!(ktree.contains(&first_kbucket_node(10).pk));
+        assert_eq!(ktree.kbuckets[0].len(), 8);
+    }
 }
@@ -9,9 +9,13 @@ pub mod codec;
 pub mod server;
 pub mod dht_friend;
 pub mod dht_node;
+pub mod reputation;
+pub mod ban_list;
 pub mod daemon_state;
 pub mod lan_discovery;
 pub mod ip_port;
 pub mod request_queue;
 pub mod precomputed_cache;
 pub mod server_ext;
+pub mod crawler;
+pub mod bootstrap_info;
@@ -1,6 +1,7 @@
 /*! CryptoData packet
 */
 
+use bytes::Bytes;
 use nom::{
     bytes::complete::take_while,
     number::complete::{be_u16, be_u32},
@@ -89,18 +90,39 @@ impl CryptoData {
     - fails to parse `CryptoDataPayload`
     */
     pub fn get_payload(&self, shared_secret: &PrecomputedKey, nonce: &Nonce) -> Result<CryptoDataPayload, GetPayloadError> {
-        let decrypted = open_precomputed(&self.payload, nonce, shared_secret)
+        self.clone().into_payload(shared_secret, nonce)
+    }
+
+    /** Decrypt payload in place with precomputed key and nonce and try to
+    parse it as `CryptoDataPayload`.
+
+    Unlike `get_payload` it doesn't copy the payload: data of the returned
+    `CryptoDataPayload` points to the buffer of this packet.
+
+    Returns `Error` in case of failure:
+
+    - fails to decrypt
+    - fails to parse `CryptoDataPayload`
+    */
+    pub fn into_payload(mut self, shared_secret: &PrecomputedKey, nonce: &Nonce) -> Result<CryptoDataPayload, GetPayloadError> {
+        let payload_len = self.payload.len();
+        let decrypted = decrypt_data_symmetric_in_place(shared_secret, nonce, &mut self.payload)
             .map_err(|()| {
                 debug!("Decrypting CryptoData failed!");
                 GetPayloadError::decrypt()
             })?;
-        match CryptoDataPayload::from_bytes(&decrypted) {
+        match CryptoDataPayload::header_from_bytes(decrypted) {
             Err(error) => {
                 debug!(target: "Dht", "CryptoDataPayload return deserialize error: {:?}", error);
-                Err(GetPayloadError::deserialize(error, decrypted.clone()))
+                Err(GetPayloadError::deserialize(error, decrypted.to_vec()))
             },
-            Ok((_, payload)) => {
-                Ok(payload)
+            Ok((data, (buffer_start, packet_number))) => {
+                let data_start = payload_len - data.len();
+                Ok(CryptoDataPayload {
+                    buffer_start,
+                    packet_number,
+                    data: Bytes::from(self.payload).slice(data_start ..),
+                })
             }
         }
     }
@@ -124,16 +146,27 @@ pub struct CryptoDataPayload {
     /// Packet number used by the receiver to know if any packets have been lost
     pub packet_number: u32,
     /// Data of `CryptoData` packet
-    pub data: Vec<u8>
+    pub data: Bytes
+}
+
+impl CryptoDataPayload {
+    /// Parse buffer start and packet number skipping the padding so that the
+    /// rest of the input is data.
+    fn header_from_bytes(input: &[u8]) -> IResult<&[u8], (u32, u32)> {
+        do_parse!(input,
+            buffer_start: be_u32 >>
+            packet_number: be_u32 >>
+            call!(take_while(|b| b == 0)) >>
+            ((buffer_start, packet_number))
+        )
+    }
 }
 
 impl FromBytes for CryptoDataPayload {
     named!(from_bytes<CryptoDataPayload>, do_parse!(
-        buffer_start: be_u32 >>
-        packet_number: be_u32 >>
-        call!(take_while(|b| b == 0)) >>
+        header: call!(CryptoDataPayload::header_from_bytes) >>
         data: rest >>
-        (CryptoDataPayload { buffer_start, packet_number, data: data.to_vec() })
+        (CryptoDataPayload { buffer_start: header.0, packet_number: header.1, data: Bytes::copy_from_slice(data) })
     ));
 }
 
@@ -143,7 +176,7 @@ impl ToBytes for CryptoDataPayload {
             gen_be_u32!(self.buffer_start) >>
             gen_be_u32!(self.packet_number) >>
             gen_slice!(vec![0; (MAX_CRYPTO_DATA_SIZE - self.data.len()) % CRYPTO_MAX_PADDING]) >>
-            gen_slice!(self.data.as_slice())
+            gen_slice!(self.data.as_ref())
         )
     }
 }
@@ -166,7 +199,7 @@ mod tests {
         CryptoDataPayload {
             buffer_start: 12345,
             packet_number: 54321,
-            data: vec![42; 123],
+            data: Bytes::from(vec![42; 123]),
         }
     );
 
@@ -175,7 +208,7 @@ mod tests {
         CryptoDataPayload {
             buffer_start: 0,
             packet_number: 0,
-            data: vec![],
+            data: Bytes::new(),
         }
     );
 
@@ -189,7 +222,7 @@ mod tests {
         let payload = CryptoDataPayload {
             buffer_start: 12345,
             packet_number: 54321,
-            data: vec![42; 123],
+            data: Bytes::from(vec![42; 123]),
         };
         // encode payload with shared secret
         let crypto_data = CryptoData::new(&shared_secret, nonce, &payload);
@@ -202,6 +235,9 @@ mod tests {
         let decoded_payload = crypto_data.get_payload(&shared_secret, &nonce).unwrap();
         // payloads should be equal
         assert_eq!(decoded_payload, payload);
+        // decode payload in place
+        let decoded_payload = crypto_data.into_payload(&shared_secret, &nonce).unwrap();
+        assert_eq!(decoded_payload, payload);
     }
 
     #[test]
@@ -216,7 +252,7 @@ mod tests {
         let payload = CryptoDataPayload {
             buffer_start: 12345,
             packet_number: 54321,
-            data: vec![42; 123],
+            data: Bytes::from(vec![42; 123]),
         };
         // encode payload with shared secret
         let crypto_data = CryptoData::new(&shared_secret, nonce, &payload);
@@ -0,0 +1,208 @@
+/*! Reputation of DHT nodes.
+
+Every [`DhtNode`] carries a [`Reputation`] that is built from the share of our
+requests it answered, its round trip time and the number of invalid responses
+it sent us (`NodesResponse` packets with bogus entries). Undecryptable packets
+don't affect the score since anyone can send them on behalf of the node.
+
+The resulting score is used to choose which nodes to evict from a full
+[`Kbucket`] and which nodes to avoid when building onion paths.
+
+[`DhtNode`]: ../dht_node/struct.DhtNode.html
+[`Kbucket`]: ../kbucket/struct.Kbucket.html
+[`Reputation`]: ./struct.Reputation.html
+*/
+
+use std::time::Duration;
+
+/// Maximum score a node can have.
+pub const MAX_REPUTATION_SCORE: u8 = 100;
+
+/// Nodes with score lower than this value are considered poor and can be
+/// evicted from a full `Kbucket` and are not used for onion paths.
+pub const MIN_REPUTATION_SCORE: u8 = 40;
+
+/// Number of invalid responses after which a node is considered misbehaving.
+/// Misbehaving nodes are removed from close lists and temporarily banned.
+pub const MAX_INVALID_RESPONSES: u32 = 3;
+
+/// Number of requests that should be sent to a node before its response rate
+/// affects the score.
+const MIN_REQUESTS_TO_RATE: u32 = 4;
+
+/// When requests count reaches this value both requests and responses counters
+/// are halved so that recent behaviour of a node matters more than old one.
+const REQUESTS_WINDOW: u32 = 64;
+
+/// Score penalty for each invalid response.
+const INVALID_RESPONSE_PENALTY: u8 = 25;
+
+/// RTT after which a node gets a score penalty.
+const SLOW_RTT: Duration = Duration::from_millis(500);
+
+/// RTT after which a node gets a doubled score penalty.
+const VERY_SLOW_RTT: Duration = Duration::from_millis(1500);
+
+/// Score penalty for slow nodes.
+const SLOW_RTT_PENALTY: u8 = 10;
+
+/// Reputation of a DHT node.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Reputation {
+    /// Number of requests sent to the node.
+    pub requests: u32,
+    /// Number of valid responses received from the node.
+    pub responses: u32,
+    /// Number of invalid responses received from the node.
+    pub invalid_responses: u32,
+    /// Smoothed round trip time of the node.
+    pub rtt: Option<Duration>,
+}
+
+impl Reputation {
+    /// Create new `Reputation` for a node we don't know anything about.
+    pub fn new() -> Self {
+        Reputation::default()
+    }
+
+    /// Account a request sent to the node.
+    pub fn on_request(&mut self) {
+        self.requests = self.requests.saturating_add(1);
+        if self.requests >= REQUESTS_WINDOW {
+            self.requests /= 2;
+            self.responses /= 2;
+        }
+    }
+
+    /// Account a valid response received from the node. `rtt` is the time
+    /// passed since the corresponding request was sent.
+    pub fn on_response(&mut self, rtt: Duration) {
+        self.responses = self.responses.saturating_add(1).min(self.requests.max(1));
+        // exponentially weighted moving average with 1/8 weight of the new
+        // sample as it's done for TCP RTT estimation
+        self.rtt = Some(match self.rtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+
+    /// Account an invalid response received from the node.
+    pub fn on_invalid_response(&mut self) {
+        self.invalid_responses = self.invalid_responses.saturating_add(1);
+    }
+
+    /// Calculate the score of the node in range from 0 to
+    /// `MAX_REPUTATION_SCORE`. The higher score is the better the node is.
+    pub fn score(&self) -> u8 {
+        let mut score = if self.requests < MIN_REQUESTS_TO_RATE {
+            MAX_REPUTATION_SCORE
+        } else {
+            (u64::from(self.responses) * u64::from(MAX_REPUTATION_SCORE) / u64::from(self.requests))
+                .min(u64::from(MAX_REPUTATION_SCORE)) as u8
+        };
+
+        match self.rtt {
+            Some(rtt) if rtt >= VERY_SLOW_RTT => score = score.saturating_sub(SLOW_RTT_PENALTY * 2),
+            Some(rtt) if rtt >= SLOW_RTT => score = score.saturating_sub(SLOW_RTT_PENALTY),
+            _ => {},
+        }
+
+        let invalid_penalty = self.invalid_responses
+            .saturating_mul(u32::from(INVALID_RESPONSE_PENALTY))
+            .min(u32::from(MAX_REPUTATION_SCORE)) as u8;
+
+        score.saturating_sub(invalid_penalty)
+    }
+
+    /// Check if the node has poor reputation i.e. its score is lower than
+    /// `MIN_REPUTATION_SCORE`.
+    pub fn is_poor(&self) -> bool {
+        self.score() < MIN_REPUTATION_SCORE
+    }
+
+    /// Check if the node sent us at least `MAX_INVALID_RESPONSES` invalid
+    /// responses.
+    pub fn is_misbehaving(&self) -> bool {
+        self.invalid_responses >= MAX_INVALID_RESPONSES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_node_has_max_score() {
+        let reputation = Reputation::new();
+        assert_eq!(reputation.score(), MAX_REPUTATION_SCORE);
+        assert!(!reputation.is_poor());
+        assert!(!reputation.is_misbehaving());
+    }
+
+    #[test]
+    fn score_by_response_rate() {
+        let mut reputation = Reputation::new();
+        for _ in 0 .. 10 {
+            reputation.on_request();
+        }
+        for _ in 0 .. 5 {
+            reputation.on_response(Duration::from_millis(50));
+        }
+        assert_eq!(reputation.score(), 50);
+        assert!(!reputation.is_poor());
+
+        for _ in 0 .. 10 {
+            reputation.on_request();
+        }
+        assert_eq!(reputation.score(), 25);
+        assert!(reputation.is_poor());
+    }
+
+    #[test]
+    fn responses_dont_exceed_requests() {
+        let mut reputation = Reputation::new();
+        for _ in 0 .. 4 {
+            reputation.on_request();
+        }
+        for _ in 0 .. 10 {
+            reputation.on_response(Duration::from_millis(50));
+        }
+        assert_eq!(reputation.responses, 4);
+        assert_eq!(reputation.score(), MAX_REPUTATION_SCORE);
+    }
+
+    #[test]
+    fn counters_are_halved() {
+        let mut reputation = Reputation::new();
+        for _ in 0 .. REQUESTS_WINDOW - 1 {
+            reputation.on_request();
+            reputation.on_response(Duration::from_millis(50));
+        }
+        reputation.on_request();
+        assert_eq!(reputation.requests, REQUESTS_WINDOW / 2);
+        assert_eq!(reputation.responses, (REQUESTS_WINDOW - 1) / 2);
+    }
+
+    #[test]
+    fn rtt_smoothing() {
+        let mut reputation = Reputation::new();
+        reputation.on_request();
+        reputation.on_response(Duration::from_millis(800));
+        assert_eq!(reputation.rtt, Some(Duration::from_millis(800)));
+        reputation.on_request();
+        reputation.on_response(Duration::from_millis(0));
+        assert_eq!(reputation.rtt, Some(Duration::from_millis(700)));
+        assert_eq!(reputation.score(), MAX_REPUTATION_SCORE - SLOW_RTT_PENALTY);
+    }
+
+    #[test]
+    fn invalid_responses() {
+        let mut reputation = Reputation::new();
+        reputation.on_invalid_response();
+        assert_eq!(reputation.score(), MAX_REPUTATION_SCORE - INVALID_RESPONSE_PENALTY);
+        reputation.on_invalid_response();
+        reputation.on_invalid_response();
+        assert!(reputation.is_misbehaving());
+        assert!(reputation.is_poor());
+    }
+}
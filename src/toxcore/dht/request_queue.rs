@@ -49,15 +49,23 @@ impl<T> RequestQueue<T> {
     /// satisfies passed condition this function removes received request ID and
     /// returns stored data. So a request ID can be verified only once.
     pub fn check_ping_id<F: FnOnce(&T) -> bool>(&mut self, ping_id: u64, cond: F) -> Option<T> {
+        self.check_ping_id_elapsed(ping_id, cond).map(|(_elapsed, data)| data)
+    }
+
+    /// The same as `check_ping_id` but additionally returns time passed since
+    /// the request ID was generated. It can be used to calculate round trip
+    /// time.
+    pub fn check_ping_id_elapsed<F: FnOnce(&T) -> bool>(&mut self, ping_id: u64, cond: F) -> Option<(Duration, T)> {
         if ping_id == 0 {
             return None;
         }
 
         if let Entry::Occupied(entry) = self.ping_map.entry(ping_id) {
             let (time, data) = entry.get();
-            if clock_elapsed(*time) <= self.timeout && cond(data) {
+            let elapsed = clock_elapsed(*time);
+            if elapsed <= self.timeout && cond(data) {
                 let (_ping_id, (_time, data)) = entry.remove_entry();
-                Some(data)
+                Some((elapsed, data))
             } else {
                 None
             }
@@ -112,6 +120,18 @@ mod tests {
         assert_eq!(queue.check_ping_id(ping_id, |&data| data == 7), None);
     }
 
+    #[tokio::test]
+    async fn check_ping_id_elapsed() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+
+        let mut queue = RequestQueue::new(Duration::from_secs(42));
+
+        let ping_id = queue.new_ping_id(7);
+        tokio::time::advance(Duration::from_secs(3)).await;
+        assert_eq!(queue.check_ping_id_elapsed(ping_id, |&data| data == 7), Some((Duration::from_secs(3), 7)));
+    }
+
     #[test]
     fn check_ping_id_zero() {
         let mut queue = RequestQueue::<()>::new(Duration::from_secs(42));
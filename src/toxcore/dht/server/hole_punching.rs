@@ -6,8 +6,9 @@ https://zetok.github.io/tox-spec/#hole-punching
 
 use std::net::{IpAddr, SocketAddr};
 use std::time::{Duration, Instant};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
+use crate::toxcore::crypto_core::random_limit_usize;
 use crate::toxcore::dht::dht_friend::*;
 use crate::toxcore::dht::server::*;
 use crate::toxcore::utils::*;
@@ -24,6 +25,76 @@ const MAX_PORTS_TO_PUNCH: u32 = 48;
 /// After this number of hole punching attempts we will use advanced port
 /// guessing algorithm besides simple algorithm.
 const MAX_NORMAL_PUNCHING_TRIES: u32 = 5;
+/// Maximum distance between two ports allocated by NAT (in strides) for which
+/// we still consider the allocation predictable. Other hosts behind the same
+/// NAT can take ports in between so they are not always consecutive.
+const MAX_PREDICTABLE_GAP: u32 = 8;
+/// Maximum stride between ports allocated by NAT for which we still consider
+/// the allocation predictable.
+const MAX_PREDICTABLE_STRIDE: u16 = 64;
+/// Number of random ports to punch every round when NAT allocates ports
+/// randomly. Since both sides are punching simultaneously the probability of
+/// a collision grows quadratically with this number (birthday paradox).
+const BIRTHDAY_BURST_SIZE: usize = 256;
+/// Ports below this one are not allocated by NATs.
+const MIN_NAT_PORT: u16 = 1024;
+
+/// Pattern of port allocation by friend's NAT inferred from ports returned by
+/// friend's close nodes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PortAllocation {
+    /// NAT preserves the same port for all destinations.
+    Preserving,
+    /// NAT allocates ports sequentially.
+    Sequential,
+    /// NAT allocates ports with a fixed stride.
+    Stride(u16),
+    /// NAT allocates ports randomly.
+    Random,
+}
+
+impl PortAllocation {
+    /// Infer port allocation pattern from the list of ports that friend's close
+    /// nodes see. Returns `None` if the list is empty.
+    pub fn infer(ports: &[u16]) -> Option<PortAllocation> {
+        let mut ports = ports.to_vec();
+        ports.sort_unstable();
+        ports.dedup();
+
+        if ports.is_empty() {
+            return None;
+        }
+        if ports.len() == 1 {
+            return Some(PortAllocation::Preserving);
+        }
+
+        let deltas = ports.windows(2).map(|pair| pair[1] - pair[0]).collect::<Vec<_>>();
+        let stride = deltas.iter().fold(0, |stride, &delta| gcd(stride, delta));
+        let max_delta = deltas.iter().cloned().max().unwrap_or(0);
+
+        if stride > MAX_PREDICTABLE_STRIDE || u32::from(max_delta) > u32::from(stride) * MAX_PREDICTABLE_GAP {
+            Some(PortAllocation::Random)
+        } else if stride == 1 {
+            Some(PortAllocation::Sequential)
+        } else {
+            Some(PortAllocation::Stride(stride))
+        }
+    }
+
+    /// Stride between allocated ports if the allocation is predictable.
+    fn stride(self) -> Option<u16> {
+        match self {
+            PortAllocation::Sequential => Some(1),
+            PortAllocation::Stride(stride) => Some(stride),
+            PortAllocation::Preserving | PortAllocation::Random => None,
+        }
+    }
+}
+
+/// Greatest common divisor.
+fn gcd(a: u16, b: u16) -> u16 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
 
 /// Struct for hole punching.
 #[derive(Clone, Debug)]
@@ -53,6 +124,12 @@ pub struct HolePunching {
     /// Ping id that is used to send `NatPingRequest` packets. It's refreshed
     /// every time we receive valid `NatPingResponse` packet.
     pub ping_id: u64,
+    /// Whether NAT port prediction should be used instead of simple port
+    /// guessing algorithm.
+    pub port_prediction: bool,
+    /// Offset of predicted ports from the highest known port. It's increased
+    /// every round so that the next round tries next ports.
+    pub prediction_index: u32,
 }
 
 impl Default for HolePunching {
@@ -67,12 +144,14 @@ impl HolePunching {
         HolePunching {
             is_punching_done: true,
             num_punch_tries: 0,
-            last_recv_ping_time: Instant::now(),
+            last_recv_ping_time: clock_now(),
             last_send_ping_time: None,
             last_punching_time: None,
             first_punching_index: 0,
             last_punching_index: 0,
             ping_id: gen_ping_id(),
+            port_prediction: false,
+            prediction_index: 0,
         }
     }
 
@@ -86,8 +165,8 @@ impl HolePunching {
     ///`PingRequest` packet.
     pub fn next_punch_addrs(&mut self, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
         if !self.is_punching_done &&
-            self.last_punching_time.map_or(true, |time| time.elapsed() >= PUNCH_INTERVAL) &&
-            self.last_recv_ping_time.elapsed() <= PUNCH_INTERVAL * 2 {
+            self.last_punching_time.map_or(true, |time| clock_elapsed(time) >= PUNCH_INTERVAL) &&
+            clock_elapsed(self.last_recv_ping_time) <= PUNCH_INTERVAL * 2 {
                 let ip = match HolePunching::get_common_ip(addrs, u32::from(FRIEND_CLOSE_NODES_COUNT) / 2) {
                     // A friend can have maximum 8 close node. If 4 or more close nodes returned
                     // the same friend's IP address but with different port we consider that friend
@@ -96,10 +175,11 @@ impl HolePunching {
                     Some(ip) => ip,
                 };
 
-                if self.last_punching_time.map_or(true, |time| time.elapsed() > RESET_PUNCH_INTERVAL) {
+                if self.last_punching_time.map_or(true, |time| clock_elapsed(time) > RESET_PUNCH_INTERVAL) {
                     self.num_punch_tries = 0;
                     self.first_punching_index = 0;
                     self.last_punching_index = 0;
+                    self.prediction_index = 0;
                 }
 
                 let ports_to_try = HolePunching::get_nat_ports(&addrs, ip);
@@ -178,6 +258,30 @@ impl HolePunching {
         }).collect()
     }
 
+    /// Port prediction algorithm for NATs that allocate ports sequentially or
+    /// with a fixed stride. It uses ports following the highest known port.
+    fn predicted_hole_punching(&self, ports: &[u16], stride: u16, ip: IpAddr) -> Vec<SocketAddr> {
+        let max_port = u32::from(ports.iter().cloned().max().unwrap_or(0));
+
+        (1 ..= MAX_PORTS_TO_PUNCH)
+            .map(|i| max_port + u32::from(stride) * (i + self.prediction_index))
+            .take_while(|&port| port <= u32::from(u16::MAX))
+            .map(|port| SocketAddr::new(ip, port as u16))
+            .collect()
+    }
+
+    /// Port prediction algorithm for NATs that allocate ports randomly. It
+    /// uses a burst of random ports relying on birthday paradox.
+    fn birthday_hole_punching(ip: IpAddr) -> Vec<SocketAddr> {
+        let ports_count = usize::from(u16::MAX - MIN_NAT_PORT) + 1;
+
+        (0 .. BIRTHDAY_BURST_SIZE).map(|_| {
+            let port = MIN_NAT_PORT + random_limit_usize(ports_count) as u16;
+
+            SocketAddr::new(ip, port)
+        }).collect()
+    }
+
     /// Get addresses for hole punching using different port guessing
     /// algorithms.
     ///
@@ -192,20 +296,36 @@ impl HolePunching {
         let num_ports = ports.len();
         let num_same_port = ports.iter().filter(|port| **port == first_port).count();
 
+        let allocation = if self.port_prediction {
+            PortAllocation::infer(ports)
+        } else {
+            None
+        };
+
         let mut addrs = if num_same_port == num_ports {
             vec![SocketAddr::new(ip, first_port)]
+        } else if let Some(stride) = allocation.and_then(PortAllocation::stride) {
+            let addrs = self.predicted_hole_punching(ports, stride, ip);
+            self.prediction_index += MAX_PORTS_TO_PUNCH;
+            addrs
+        } else if allocation == Some(PortAllocation::Random) {
+            HolePunching::birthday_hole_punching(ip)
         } else {
             let addrs = self.first_hole_punching(ports, ip);
             self.first_punching_index += MAX_PORTS_TO_PUNCH;
             addrs
         };
 
-        if self.num_punch_tries > MAX_NORMAL_PUNCHING_TRIES {
+        // sequential sweep is useless when NAT allocates ports randomly
+        if self.num_punch_tries > MAX_NORMAL_PUNCHING_TRIES && allocation != Some(PortAllocation::Random) {
             addrs.append(&mut self.last_hole_punching(ip));
             self.last_punching_index += MAX_PORTS_TO_PUNCH - (MAX_PORTS_TO_PUNCH / 2);
         };
 
-        addrs.dedup();
+        // addresses of different algorithms can overlap so duplicates are not
+        // necessarily adjacent
+        let mut unique_addrs = HashSet::new();
+        addrs.retain(|addr| unique_addrs.insert(*addr));
 
         self.num_punch_tries = self.num_punch_tries.saturating_add(1);
 
@@ -303,4 +423,94 @@ mod tests {
 
         assert!(!hole_punch.next_punch_addrs(&addrs).is_empty());
     }
+
+    #[test]
+    fn port_allocation_infer() {
+        assert_eq!(PortAllocation::infer(&[]), None);
+        assert_eq!(PortAllocation::infer(&[33445, 33445]), Some(PortAllocation::Preserving));
+        assert_eq!(PortAllocation::infer(&[1003, 1001, 1002, 1005]), Some(PortAllocation::Sequential));
+        assert_eq!(PortAllocation::infer(&[1004, 1000, 1012, 1008]), Some(PortAllocation::Stride(4)));
+        assert_eq!(PortAllocation::infer(&[11111, 22222, 44444, 55555]), Some(PortAllocation::Random));
+        assert_eq!(PortAllocation::infer(&[1000, 1100]), Some(PortAllocation::Random));
+    }
+
+    #[test]
+    fn hole_punch_predict_sequential() {
+        let addrs = vec![
+            "127.0.0.1:10001".parse().unwrap(),
+            "127.0.0.1:10002".parse().unwrap(),
+            "127.0.0.1:10004".parse().unwrap(),
+            "127.0.0.1:10005".parse().unwrap(),
+            "127.0.0.1:10007".parse().unwrap(),
+        ];
+
+        let mut hole_punch = HolePunching::new();
+        hole_punch.is_punching_done = false;
+        hole_punch.port_prediction = true;
+
+        let punch_addrs = hole_punch.next_punch_addrs(&addrs);
+        assert_eq!(punch_addrs.len(), MAX_PORTS_TO_PUNCH as usize);
+        assert_eq!(punch_addrs[0], "127.0.0.1:10008".parse().unwrap());
+        assert_eq!(punch_addrs[1], "127.0.0.1:10009".parse().unwrap());
+        assert_eq!(hole_punch.prediction_index, MAX_PORTS_TO_PUNCH);
+
+        // next round continues with the following ports
+        hole_punch.is_punching_done = false;
+        hole_punch.last_punching_time = Some(clock_now() - PUNCH_INTERVAL);
+        let punch_addrs = hole_punch.next_punch_addrs(&addrs);
+        assert_eq!(punch_addrs[0], SocketAddr::new("127.0.0.1".parse().unwrap(), 10008 + MAX_PORTS_TO_PUNCH as u16));
+    }
+
+    #[test]
+    fn hole_punch_predict_stride() {
+        let mut hole_punch = HolePunching::new();
+        hole_punch.port_prediction = true;
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let punch_addrs = hole_punch.punch_addrs(&[65500, 65510, 65520], ip);
+        assert_eq!(punch_addrs, vec![
+            SocketAddr::new(ip, 65530),
+        ]);
+    }
+
+    #[test]
+    fn hole_punch_predict_random() {
+        let mut hole_punch = HolePunching::new();
+        hole_punch.port_prediction = true;
+        hole_punch.num_punch_tries = MAX_NORMAL_PUNCHING_TRIES + 1;
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let punch_addrs = hole_punch.punch_addrs(&[11111, 22222, 44444, 55555], ip);
+        assert!(punch_addrs.len() <= BIRTHDAY_BURST_SIZE);
+        assert!(punch_addrs.len() > BIRTHDAY_BURST_SIZE / 2);
+        assert!(punch_addrs.iter().all(|addr| addr.ip() == ip && addr.port() >= MIN_NAT_PORT));
+        assert_eq!(hole_punch.last_punching_index, 0);
+    }
+
+    #[test]
+    fn hole_punch_without_prediction() {
+        let mut hole_punch = HolePunching::new();
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let punch_addrs = hole_punch.punch_addrs(&[11111, 22222, 44444, 55555], ip);
+        assert!(punch_addrs.len() <= MAX_PORTS_TO_PUNCH as usize);
+        assert_eq!(punch_addrs[0], SocketAddr::new(ip, 11111));
+        assert_eq!(hole_punch.first_punching_index, MAX_PORTS_TO_PUNCH);
+    }
+
+    #[test]
+    fn hole_punch_removes_duplicates() {
+        let mut hole_punch = HolePunching::new();
+        hole_punch.num_punch_tries = MAX_NORMAL_PUNCHING_TRIES + 1;
+
+        let ip = "127.0.0.1".parse().unwrap();
+        // first algorithm punches ports around 1024 that are punched by the
+        // sequential sweep as well
+        let punch_addrs = hole_punch.punch_addrs(&[1024, 2000], ip);
+        let unique_addrs = punch_addrs.iter().collect::<HashSet<_>>();
+        assert_eq!(unique_addrs.len(), punch_addrs.len());
+        assert_eq!(punch_addrs[0], SocketAddr::new(ip, 1024));
+        assert_eq!(punch_addrs[1], SocketAddr::new(ip, 2000));
+        assert!(punch_addrs.contains(&SocketAddr::new(ip, 1024 + MAX_PORTS_TO_PUNCH as u16 - 1)));
+    }
 }
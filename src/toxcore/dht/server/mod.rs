@@ -8,12 +8,12 @@ mod errors;
 
 use failure::Fail;
 use futures::{Future, FutureExt, TryFutureExt, StreamExt, SinkExt, future};
-use futures::future::{Either};
+use futures::future::{BoxFuture, Either};
 use futures::channel::mpsc;
-use parking_lot::RwLock;
+use parking_lot::{RwLock, RwLockUpgradableReadGuard};
 
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use std::{iter, mem};
@@ -32,6 +32,9 @@ use crate::toxcore::dht::request_queue::*;
 use crate::toxcore::ip_port::*;
 use crate::toxcore::dht::dht_friend::*;
 use crate::toxcore::dht::dht_node::*;
+use crate::toxcore::dht::reputation::*;
+use crate::toxcore::dht::ban_list::*;
+use crate::toxcore::dht::bootstrap_info::BootstrapMotd;
 use crate::toxcore::dht::server::hole_punching::*;
 use crate::toxcore::tcp::packet::OnionRequest;
 use crate::toxcore::net_crypto::*;
@@ -39,6 +42,8 @@ use crate::toxcore::dht::ip_port::IsGlobal;
 use crate::toxcore::utils::*;
 use crate::toxcore::dht::server::errors::*;
 use crate::toxcore::io_tokio::*;
+use crate::toxcore::stats::Stats;
+use crate::toxcore::shutdown::ShutdownSignal;
 
 /// Shorthand for the transmit half of the message channel.
 type Tx = mpsc::Sender<(Packet, SocketAddr)>;
@@ -124,7 +129,7 @@ pub struct Server {
     /// Symmetric key used for onion return encryption.
     onion_symmetric_key: Arc<RwLock<secretbox::Key>>,
     /// Onion announce struct to handle `OnionAnnounce` and `OnionData` packets.
-    onion_announce: Arc<RwLock<OnionAnnounce>>,
+    pub(crate) onion_announce: Arc<RwLock<OnionAnnounce>>,
 
     fake_friends_keys: Vec<PublicKey>,
     /// Friends list used to store friends related data like close nodes per
@@ -169,16 +174,29 @@ pub struct Server {
     /// If LAN discovery is enabled `Server` will handle `LanDiscovery` packets
     /// and send `NodesRequest` packets in reply.
     lan_discovery_enabled: bool,
+    /// Whether NAT port prediction should be used for hole punching.
+    is_port_prediction_enabled: bool,
+    /// Our external address as reported by the gateway, e.g. when UDP port
+    /// is mapped with NAT-PMP or PCP.
+    external_addr: Arc<RwLock<Option<SocketAddr>>>,
+    /// External address of our TCP relay as reported by the gateway.
+    external_tcp_addr: Arc<RwLock<Option<SocketAddr>>>,
     /// If IPv6 mode is enabled `Server` will send packets to IPv6 addresses. If
     /// it's disabled such packets will be dropped.
     is_ipv6_enabled: bool,
     /// Initial bootstrap nodes list. We send `NodesRequest` packet to each node
     /// from this list if Ktree doesn't have good (or bad but not discarded)
-    /// nodes.
-    initial_bootstrap: Vec<PackedNode>,
+    /// nodes. It can be replaced at runtime, e.g. when `nodes.json` list is
+    /// reloaded.
+    initial_bootstrap: Arc<RwLock<Vec<PackedNode>>>,
     /// Lru cache for precomputed keys. It stores precomputed keys to avoid
     /// redundant calculations.
     precomputed_keys: PrecomputedCache,
+    /// List of banned nodes. Packets from these nodes are ignored and they are
+    /// never added to close nodes lists.
+    ban_list: Arc<RwLock<BanList>>,
+    /// Statistics where occupancy of Ktree is reported.
+    stats: Stats,
 }
 
 impl Server {
@@ -225,9 +243,14 @@ impl Server {
             net_crypto: None,
             onion_client: None,
             lan_discovery_enabled: true,
+            is_port_prediction_enabled: false,
+            external_addr: Arc::new(RwLock::new(None)),
+            external_tcp_addr: Arc::new(RwLock::new(None)),
             is_ipv6_enabled: false,
-            initial_bootstrap: Vec::new(),
+            initial_bootstrap: Arc::new(RwLock::new(Vec::new())),
             precomputed_keys,
+            ban_list: Arc::new(RwLock::new(BanList::new())),
+            stats: Stats::new(),
         }
     }
 
@@ -246,6 +269,36 @@ impl Server {
         self.lan_discovery_enabled = enable;
     }
 
+    /// Enable/disable NAT port prediction for hole punching. When enabled we
+    /// try to infer how friend's NAT allocates ports and punch predicted
+    /// ports instead of guessing them.
+    pub fn enable_port_prediction(&mut self, enable: bool) {
+        self.is_port_prediction_enabled = enable;
+    }
+
+    /// Set our external address, e.g. when UDP port is mapped by the gateway.
+    /// It's advertised in structured `BootstrapInfo` MOTD and disables hole
+    /// punching since friends can reach us directly.
+    pub fn set_external_addr(&self, addr: Option<SocketAddr>) {
+        *self.external_addr.write() = addr;
+    }
+
+    /// Get our external address if it's known.
+    pub fn external_addr(&self) -> Option<SocketAddr> {
+        *self.external_addr.read()
+    }
+
+    /// Set external address of our TCP relay, e.g. when its port is mapped by
+    /// the gateway. It's advertised in structured `BootstrapInfo` MOTD.
+    pub fn set_external_tcp_addr(&self, addr: Option<SocketAddr>) {
+        *self.external_tcp_addr.write() = addr;
+    }
+
+    /// Get external address of our TCP relay if it's known.
+    pub fn external_tcp_addr(&self) -> Option<SocketAddr> {
+        *self.external_tcp_addr.read()
+    }
+
     /// Check if we have at least one node in good state.
     pub fn is_connected(&self) -> bool {
         self.close_nodes.read()
@@ -253,6 +306,39 @@ impl Server {
             .any(|node| !node.is_bad())
     }
 
+    /// Get number of nodes in close nodes list.
+    pub fn close_nodes_count(&self) -> usize {
+        self.close_nodes.read().iter().count()
+    }
+
+    /// Get number of nodes in replacement caches of close nodes list.
+    pub fn replacement_nodes_count(&self) -> usize {
+        self.close_nodes.read().replacements_count()
+    }
+
+    /// Set number of nodes every kbucket of close nodes list can hold.
+    /// Bootstrap nodes can increase it to have a larger view of the network.
+    pub fn set_kbucket_size(&self, size: u8) {
+        self.close_nodes.write().set_capacity(size);
+    }
+
+    /// Get number of nodes announced to us via onion.
+    pub fn onion_announce_entries_count(&self) -> usize {
+        self.onion_announce.read().entries_count()
+    }
+
+    /// Get maximum number of nodes that can be announced to us via onion.
+    pub fn onion_announce_capacity(&self) -> usize {
+        self.onion_announce.read().capacity()
+    }
+
+    /// Set maximum number of nodes that can be announced to us via onion.
+    /// When it's decreased nodes farthest from our DHT `PublicKey` are
+    /// removed.
+    pub fn set_onion_announce_capacity(&self, capacity: usize) {
+        self.onion_announce.write().set_capacity(capacity);
+    }
+
     /// Get closest nodes from both close_nodes and friend's close_nodes
     fn get_closest_inner(
         close_nodes: &Ktree,
@@ -333,6 +419,12 @@ impl Server {
 
         request_queue.clear_timed_out();
 
+        self.remove_misbehaving_nodes(&mut close_nodes, &mut friends);
+
+        self.stats.gauges.set_ktree_occupancy(
+            close_nodes.kbuckets.iter().map(|kbucket| kbucket.len() as u8).collect()
+        );
+
         // Send NodesRequest packets to nodes from the Server
         let ping_nodes_to_bootstrap = self.ping_nodes_to_bootstrap(&mut request_queue, &mut nodes_to_bootstrap, self.pk);
         let ping_close_nodes = self.ping_close_nodes(&mut request_queue, close_nodes.iter_mut(), self.pk);
@@ -377,26 +469,169 @@ impl Server {
         }
     }
 
+    /// Remove nodes that sent us too many invalid responses from close nodes
+    /// lists and ban them for `MISBEHAVING_BAN_TIMEOUT` so that they won't be
+    /// added back immediately.
+    fn remove_misbehaving_nodes(&self, close_nodes: &mut Ktree, friends: &mut HashMap<PublicKey, DhtFriend>) {
+        let mut misbehaving = close_nodes.iter()
+            .chain(friends.values().flat_map(|friend| friend.close_nodes.iter()))
+            .filter(|node| node.reputation.is_misbehaving())
+            .map(|node| node.pk)
+            .collect::<Vec<_>>();
+        misbehaving.sort();
+        misbehaving.dedup();
+
+        let mut ban_list = self.ban_list.write();
+        ban_list.clear_expired();
+
+        for pk in misbehaving {
+            debug!("Banning misbehaving node {:?}", pk);
+            close_nodes.remove(&pk);
+            for friend in friends.values_mut() {
+                friend.close_nodes.remove(&friend.pk, &pk);
+            }
+            ban_list.ban_key_temporarily(pk, MISBEHAVING_BAN_TIMEOUT);
+        }
+    }
+
+    /// Ban node by its `PublicKey` and remove it from close nodes lists.
+    pub fn ban_key(&self, pk: PublicKey) {
+        let mut close_nodes = self.close_nodes.write();
+        let mut friends = self.friends.write();
+
+        close_nodes.remove(&pk);
+        for friend in friends.values_mut() {
+            friend.close_nodes.remove(&friend.pk, &pk);
+        }
+
+        self.ban_list.write().ban_key(pk);
+    }
+
+    /// Ban all nodes with the IP address and remove them from close nodes
+    /// lists.
+    pub fn ban_ip(&self, ip: IpAddr) {
+        let mut close_nodes = self.close_nodes.write();
+        let mut friends = self.friends.write();
+
+        let mut ban_list = self.ban_list.write();
+        ban_list.ban_ip(ip);
+
+        let banned = close_nodes.iter()
+            .chain(friends.values().flat_map(|friend| friend.close_nodes.iter()))
+            .filter(|node| node.get_all_addrs().iter().any(|addr| ban_list.is_ip_banned(&addr.ip())))
+            .map(|node| node.pk)
+            .collect::<Vec<_>>();
+
+        for pk in banned {
+            close_nodes.remove(&pk);
+            for friend in friends.values_mut() {
+                friend.close_nodes.remove(&friend.pk, &pk);
+            }
+        }
+    }
+
+    /// Remove the ban of the node by its `PublicKey`. Returns `true` if the key
+    /// was banned.
+    pub fn unban_key(&self, pk: &PublicKey) -> bool {
+        self.ban_list.write().unban_key(pk)
+    }
+
+    /// Remove the ban of the IP address. Returns `true` if the address was
+    /// banned.
+    pub fn unban_ip(&self, ip: &IpAddr) -> bool {
+        self.ban_list.write().unban_ip(ip)
+    }
+
+    /// Get a copy of the current ban list.
+    pub fn ban_list(&self) -> BanList {
+        self.ban_list.read().clone()
+    }
+
+    /// Check if the node is banned either by its `PublicKey` or by its IP
+    /// address.
+    pub fn is_banned(&self, node: &PackedNode) -> bool {
+        self.ban_list.read().is_banned(node)
+    }
+
+    /// Check if the node is not banned and doesn't have poor reputation in our
+    /// close nodes lists. Nodes we don't know anything about are considered
+    /// good.
+    pub fn has_good_reputation(&self, node: &PackedNode) -> bool {
+        if self.is_banned(node) {
+            return false;
+        }
+
+        let close_nodes = self.close_nodes.read();
+        let friends = self.friends.read();
+
+        let is_poor = close_nodes.get_node(&node.pk)
+            .into_iter()
+            .chain(friends.values().flat_map(|friend| friend.close_nodes.get_node(&friend.pk, &node.pk)))
+            .any(|node| node.reputation.is_poor());
+
+        !is_poor
+    }
+
+    /// Update reputation of the node in all close nodes lists it's stored in.
+    fn update_reputation<F>(close_nodes: &mut Ktree, friends: &mut HashMap<PublicKey, DhtFriend>, pk: &PublicKey, f: F)
+        where F: Fn(&mut Reputation)
+    {
+        if let Some(node) = close_nodes.get_node_mut(pk) {
+            f(&mut node.reputation);
+        }
+        for friend in friends.values_mut() {
+            if let Some(node) = friend.close_nodes.get_node_mut(&friend.pk, pk) {
+                f(&mut node.reputation);
+            }
+        }
+    }
+
     /// Run DHT periodical tasks. Result future will never be completed
     /// successfully.
     pub fn run(self) -> impl Future<Output = Result<(), RunError>> + Send {
         async {
-            let (r1, r2, r3, r4) = futures::join!(
+            let (r1, r2, r3, r4, r5) = futures::join!(
                 self.clone().run_pings_sending(),
                 self.clone().run_onion_key_refreshing(),
+                self.clone().run_onion_announce_expiring(),
                 self.clone().run_main_loop(),
                 self.run_bootstrap_requests_sending(),
             );
 
-            r1?; r2?; r3?; r4?;
+            r1?; r2?; r3?; r4?; r5?;
 
             Ok(())
         }
     }
 
+    /// Run DHT periodical tasks until shutdown is triggered. Returns close
+    /// nodes that are not discarded so that they can be saved and used for
+    /// bootstrapping after restart.
+    pub async fn run_until(self, signal: ShutdownSignal) -> Result<Vec<PackedNode>, RunError> {
+        signal.run(self.clone().run()).await?;
+
+        let close_nodes = self.close_nodes.read();
+        let nodes = close_nodes.iter()
+            .filter(|node| !node.is_discarded())
+            .flat_map(|node| node.to_packed_node())
+            .collect();
+        Ok(nodes)
+    }
+
     /// Store bootstap nodes
     pub fn add_initial_bootstrap(&mut self, pn: PackedNode) {
-        self.initial_bootstrap.push(pn);
+        self.initial_bootstrap.write().push(pn);
+    }
+
+    /// Replace bootstrap nodes list. Can be used to update the list while the
+    /// server is running.
+    pub fn set_initial_bootstrap(&self, nodes: Vec<PackedNode>) {
+        *self.initial_bootstrap.write() = nodes;
+    }
+
+    /// Get bootstrap nodes list.
+    pub fn initial_bootstrap(&self) -> Vec<PackedNode> {
+        self.initial_bootstrap.read().clone()
     }
 
     /// Run initial bootstrapping. It sends `NodesRequest` packet to bootstrap
@@ -446,10 +681,12 @@ impl Server {
             return Either::Left(future::ok(()));
         }
 
+        let initial_bootstrap = self.initial_bootstrap.read();
+
         let futures = close_nodes
             .iter()
             .flat_map(|node| node.to_all_packed_nodes())
-            .chain(self.initial_bootstrap.iter().cloned())
+            .chain(initial_bootstrap.iter().cloned())
             .map(|node| self.send_nodes_req(&node, &mut request_queue, self.pk))
             .collect::<Vec<_>>();
 
@@ -503,6 +740,24 @@ impl Server {
         }
     }
 
+    /// Remove timed out onion announce entries periodically. Result future
+    /// will never be completed successfully.
+    fn run_onion_announce_expiring(self) -> impl Future<Output = Result<(), RunError>> + Send {
+        let interval = ONION_ANNOUNCE_EXPIRY_INTERVAL;
+        let mut wakeups = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+
+        async move {
+            while let Some(_) = wakeups.next().await {
+                let removed = self.onion_announce.write().remove_timed_out();
+                if removed > 0 {
+                    trace!("Removed {} timed out onion announce entries", removed);
+                }
+            }
+
+            Ok(())
+        }
+    }
+
     /// Run ping sending periodically. Result future will never be completed
     /// successfully.
     fn run_pings_sending(self) -> impl Future<Output = Result<(), RunError>> + Send {
@@ -519,20 +774,22 @@ impl Server {
         }
     }
 
-    /// Send `PingRequest` packets to nodes from `nodes_to_ping` list.
+    /// Send `PingRequest` packets to nodes from `nodes_to_ping` list and to
+    /// nodes from replacement caches of kbuckets that have free or bad slots.
     fn send_pings(&self) -> impl Future<Output = Result<(), mpsc::SendError>> + Send {
         let nodes_to_ping = mem::replace(
             &mut *self.nodes_to_ping.write(),
             Kbucket::<PackedNode>::new(MAX_TO_PING)
         );
+        let replacements = self.close_nodes.write().take_replacements_to_ping();
 
-        if nodes_to_ping.is_empty() {
+        if nodes_to_ping.is_empty() && replacements.is_empty() {
             return Either::Left(future::ok(()))
         }
 
         let mut request_queue = self.request_queue.write();
 
-        let futures = nodes_to_ping.iter().map(|node|
+        let futures = nodes_to_ping.iter().chain(replacements.iter()).map(|node|
             self.send_ping_req(node, &mut request_queue)
         ).collect::<Vec<_>>();
 
@@ -544,12 +801,22 @@ impl Server {
     /// `PingRequest` immediately instead of adding to a `nodes_to_ping`
     /// list.
     fn ping_add(&self, node: &PackedNode) -> impl Future<Output = Result<(), mpsc::SendError>> + Send {
-        let close_nodes = self.close_nodes.read();
-
-        if !close_nodes.can_add(&node) {
+        if self.is_banned(node) {
             return Either::Left(future::ok(()))
         }
 
+        {
+            let close_nodes = self.close_nodes.upgradable_read();
+
+            if !close_nodes.can_add(node) {
+                // keep the node to replace a bad one when it appears
+                if close_nodes.can_add_replacement(node) {
+                    RwLockUpgradableReadGuard::upgrade(close_nodes).add_replacement(*node);
+                }
+                return Either::Left(future::ok(()))
+            }
+        }
+
         let friends = self.friends.read();
 
         // If node is friend and we don't know friend's IP address yet then send
@@ -592,6 +859,9 @@ impl Server {
                 let ping_addr_v6 = node.assoc6
                     .ping_addr()
                     .map(|addr| PackedNode::new(addr.into(), &node.pk));
+                if ping_addr_v4.is_some() || ping_addr_v6.is_some() {
+                    node.reputation.on_request();
+                }
                 ping_addr_v4.into_iter().chain(ping_addr_v6.into_iter())
             })
             .map(|node| self.send_nodes_req(&node, request_queue, pk))
@@ -671,8 +941,11 @@ impl Server {
     }
 
     /// Send `NatPingRequest` packet to all friends and try to punch holes.
+    /// Holes are not punched when our port is mapped by the gateway since
+    /// friends can reach us directly in this case.
     fn send_nat_ping_req(&self, request_queue: &mut RequestQueue<PublicKey>, friends: &mut HashMap<PublicKey, DhtFriend>)
         -> impl Future<Output = Result<(), mpsc::SendError>> + Send {
+        let is_port_mapped = self.external_addr().is_some();
         let futures = friends.values_mut()
             .filter(|friend| !friend.is_addr_known())
             .map(|friend| {
@@ -683,7 +956,11 @@ impl Server {
             // close nodes connected to a friend
             .filter(|(_, addrs)| addrs.len() >= FRIEND_CLOSE_NODES_COUNT as usize / 2)
             .map(|(friend, addrs)| {
-                let punch_future = self.punch_holes(request_queue, friend, &addrs);
+                let punch_future = if is_port_mapped {
+                    Either::Left(future::ok(()))
+                } else {
+                    Either::Right(self.punch_holes(request_queue, friend, &addrs))
+                };
 
                 if friend.hole_punch.last_send_ping_time.map_or(true, |time| clock_elapsed(time) >= PUNCH_INTERVAL) {
                     friend.hole_punch.last_send_ping_time = Some(clock_now());
@@ -711,6 +988,7 @@ impl Server {
     /// Try to punch holes to specified friend.
     fn punch_holes(&self, request_queue: &mut RequestQueue<PublicKey>, friend: &mut DhtFriend, returned_addrs: &[SocketAddr])
         -> impl Future<Output = Result<(), mpsc::SendError>> + Send + 'static {
+        friend.hole_punch.port_prediction = self.is_port_prediction_enabled;
         let punch_addrs = friend.hole_punch.next_punch_addrs(returned_addrs);
 
         let packets = punch_addrs.into_iter().map(|addr| {
@@ -748,7 +1026,23 @@ impl Server {
     }
 
     /// Function to handle incoming packets and send responses if necessary.
+    /// Packets that can't be decrypted are counted in stats.
     pub fn handle_packet(&self, packet: Packet, addr: SocketAddr) -> impl Future<Output = Result<(), HandlePacketError>> + Send {
+        let stats = self.stats.clone();
+        self.handle_packet_inner(packet, addr).inspect_err(move |e|
+            if *e.kind() == HandlePacketErrorKind::GetPayload {
+                stats.counters.increase_decrypt_failures();
+            }
+        )
+    }
+
+    /// Handle incoming packet dispatching it by its kind.
+    fn handle_packet_inner(&self, packet: Packet, addr: SocketAddr) -> BoxFuture<'static, Result<(), HandlePacketError>> {
+        if self.ban_list.read().is_ip_banned(&addr.ip()) {
+            trace!("Dropping packet from banned address {}", addr);
+            return future::ok(()).boxed();
+        }
+
         match packet {
             Packet::PingRequest(packet) =>
                 self.handle_ping_req(&packet, addr).boxed(),
@@ -787,7 +1081,7 @@ impl Server {
             Packet::BootstrapInfo(packet) =>
                 self.handle_bootstrap_info(&packet, addr).boxed(),
             Packet::CryptoData(packet) =>
-                self.handle_crypto_data(&packet, addr).boxed(),
+                self.handle_crypto_data(packet, addr).boxed(),
             Packet::OnionDataResponse(packet) =>
                 self.handle_onion_data_response(&packet).boxed(),
             Packet::OnionAnnounceResponse(packet) =>
@@ -811,6 +1105,10 @@ impl Server {
     /// and can be added there then it will be added to ping list.
     fn handle_ping_req(&self, packet: &PingRequest, addr: SocketAddr)
         -> impl Future<Output = Result<(), HandlePacketError>> + Send {
+        if self.ban_list.read().is_key_banned(&packet.pk) {
+            return Either::Left(future::ok(()));
+        }
+
         let precomputed_key = self.precomputed_keys.get(packet.pk);
         let payload = match packet.get_payload(&precomputed_key) {
             Err(e) => return Either::Left(
@@ -841,6 +1139,11 @@ impl Server {
     /// Add node to close list after we received a response from it. If it's a
     /// friend then send it's IP address to appropriate sink.
     fn try_add_to_close(&self, close_nodes: &mut Ktree, friends: &mut HashMap<PublicKey, DhtFriend>, node: PackedNode) -> impl Future<Output = Result<(), HandlePacketError>> {
+        if self.is_banned(&node) {
+            trace!("Ignoring banned node {:?}", node);
+            return Either::Right(future::ok(()));
+        }
+
         close_nodes.try_add(node);
         for friend in friends.values_mut() {
             friend.try_add_to_close(node);
@@ -860,6 +1163,8 @@ impl Server {
     /// that sent this packet to close nodes lists.
     fn handle_ping_resp(&self, packet: &PingResponse, addr: SocketAddr) -> impl Future<Output = Result<(), HandlePacketError>> + Send {
         let precomputed_key = self.precomputed_keys.get(packet.pk);
+        // sender's `PublicKey` is not authenticated until the payload is
+        // decrypted so it's not penalized for undecryptable packets
         let payload = match packet.get_payload(&precomputed_key) {
             Err(e) => return Either::Left(future::err(e.context(HandlePacketErrorKind::GetPayload).into())),
             Ok(payload) => payload,
@@ -873,11 +1178,14 @@ impl Server {
 
         let mut request_queue = self.request_queue.write();
 
-        if request_queue.check_ping_id(payload.id, |&pk| pk == packet.pk).is_some() {
+        if let Some((rtt, _)) = request_queue.check_ping_id_elapsed(payload.id, |&pk| pk == packet.pk) {
             let mut close_nodes = self.close_nodes.write();
             let mut friends = self.friends.write();
 
-            Either::Right(self.try_add_to_close(&mut close_nodes, &mut friends, PackedNode::new(addr, &packet.pk)))
+            let future = self.try_add_to_close(&mut close_nodes, &mut friends, PackedNode::new(addr, &packet.pk));
+            Server::update_reputation(&mut close_nodes, &mut friends, &packet.pk, |reputation| reputation.on_response(rtt));
+
+            Either::Right(future)
         } else {
             Either::Left(future::err(
                 HandlePacketError::from(HandlePacketErrorKind::PingIdMismatch)
@@ -890,6 +1198,10 @@ impl Server {
     /// and can be added there then it will be added to ping list.
     fn handle_nodes_req(&self, packet: &NodesRequest, addr: SocketAddr)
         -> impl Future<Output = Result<(), HandlePacketError>> + Send {
+        if self.ban_list.read().is_key_banned(&packet.pk) {
+            return Either::Left(future::ok(()));
+        }
+
         let precomputed_key = self.precomputed_keys.get(packet.pk);
         let payload = match packet.get_payload(&precomputed_key) {
             Err(e) => return Either::Left(future::err(e.context(HandlePacketErrorKind::GetPayload).into())),
@@ -925,6 +1237,8 @@ impl Server {
     fn handle_nodes_resp(&self, packet: &NodesResponse, addr: SocketAddr)
         -> impl Future<Output = Result<(), HandlePacketError>> + Send {
         let precomputed_key = self.precomputed_keys.get(packet.pk);
+        // sender's `PublicKey` is not authenticated until the payload is
+        // decrypted so it's not penalized for undecryptable packets
         let payload = match packet.get_payload(&precomputed_key) {
             Err(e) => return Either::Left(future::err(e.context(HandlePacketErrorKind::GetPayload).into())),
             Ok(payload) => payload,
@@ -932,7 +1246,7 @@ impl Server {
 
         let mut request_queue = self.request_queue.write();
 
-        if request_queue.check_ping_id(payload.id, |&pk| pk == packet.pk).is_some() {
+        if let Some((rtt, _)) = request_queue.check_ping_id_elapsed(payload.id, |&pk| pk == packet.pk) {
             trace!("Received nodes with NodesResponse from {}: {:?}", addr, payload.nodes);
 
             let mut close_nodes = self.close_nodes.write();
@@ -941,9 +1255,16 @@ impl Server {
 
             let future = self.try_add_to_close(&mut close_nodes, &mut friends, PackedNode::new(addr, &packet.pk));
 
+            let mut has_bogus_nodes = false;
+
             // Process nodes from NodesResponse
             for &node in &payload.nodes {
-                if !self.is_ipv6_enabled && node.saddr.is_ipv6() {
+                if is_bogus_node(&node) {
+                    has_bogus_nodes = true;
+                    continue;
+                }
+
+                if !self.is_ipv6_enabled && node.saddr.is_ipv6() || self.is_banned(&node) {
                     continue;
                 }
 
@@ -960,6 +1281,12 @@ impl Server {
                 self.update_returned_addr(&node, &packet.pk, &mut close_nodes, &mut friends);
             }
 
+            Server::update_reputation(&mut close_nodes, &mut friends, &packet.pk, |reputation| if has_bogus_nodes {
+                reputation.on_invalid_response()
+            } else {
+                reputation.on_response(rtt)
+            });
+
             Either::Right(future)
         } else {
             // Some old version toxcore responds with wrong ping_id.
@@ -1027,7 +1354,7 @@ impl Server {
     }
 
     /// Handle received `CryptoData` packet and pass it to `net_crypto` module.
-    fn handle_crypto_data(&self, packet: &CryptoData, addr: SocketAddr)
+    fn handle_crypto_data(&self, packet: CryptoData, addr: SocketAddr)
         -> impl Future<Output = Result<(), HandlePacketError>> + Send {
         if let Some(ref net_crypto) = self.net_crypto {
             Either::Left(net_crypto.handle_udp_crypto_data(packet, addr)
@@ -1540,6 +1867,23 @@ impl Server {
         });
     }
 
+    /// Set toxcore version and structured message of the day callback. It
+    /// allows to advertise uptime, nodes count, TCP ports or any other
+    /// `key=value` data along with free text. Our external addresses are
+    /// added to MOTD when they are known unless the callback sets them.
+    pub fn set_bootstrap_motd(&mut self, version: u32, motd_cb: Box<dyn Fn(&Server) -> BootstrapMotd + Send + Sync>) {
+        self.set_bootstrap_info(version, Box::new(move |server| {
+            let mut motd = motd_cb(server);
+            if let (None, Some(addr)) = (motd.external_addr(), server.external_addr()) {
+                motd.set_external_addr(addr);
+            }
+            if let (None, Some(addr)) = (motd.external_tcp_addr(), server.external_tcp_addr()) {
+                motd.set_external_tcp_addr(addr);
+            }
+            motd.encode()
+        }));
+    }
+
     /// Set TCP sink for onion packets.
     pub fn set_tcp_onion_sink(&mut self, tcp_onion_sink: TcpOnionTx) {
         self.tcp_onion_sink = Some(tcp_onion_sink)
@@ -1550,6 +1894,11 @@ impl Server {
         self.net_crypto = Some(net_crypto);
     }
 
+    /// Set statistics object to report occupancy of close nodes list to.
+    pub fn set_stats(&mut self, stats: Stats) {
+        self.stats = stats;
+    }
+
     /// Set `onion_client` module.
     pub fn set_onion_client(&mut self, onion_client: OnionClient) {
         self.onion_client = Some(Box::new(onion_client));
@@ -1566,12 +1915,24 @@ impl Server {
     }
 }
 
+/// Check if the node from `NodesResponse` has an address that can't belong to
+/// a real DHT node.
+fn is_bogus_node(node: &PackedNode) -> bool {
+    let ip = node.saddr.ip();
+    node.saddr.port() == 0 || ip.is_unspecified() || ip.is_multicast() ||
+        ip == IpAddr::V4(Ipv4Addr::BROADCAST)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use std::net::SocketAddr;
 
+    use bytes::Bytes;
+
+    use crate::toxcore::shutdown::Shutdown;
+
     const ONION_RETURN_1_PAYLOAD_SIZE: usize = ONION_RETURN_1_SIZE - secretbox::NONCEBYTES;
     const ONION_RETURN_2_PAYLOAD_SIZE: usize = ONION_RETURN_2_SIZE - secretbox::NONCEBYTES;
     const ONION_RETURN_3_PAYLOAD_SIZE: usize = ONION_RETURN_3_SIZE - secretbox::NONCEBYTES;
@@ -1756,7 +2117,9 @@ mod tests {
 
     #[tokio::test]
     async fn handle_ping_req_invalid_payload() {
-        let (alice, precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+        let (mut alice, precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+        let stats = Stats::new();
+        alice.set_stats(stats.clone());
 
         // can't be decrypted payload since packet contains wrong key
         let req_payload = PingRequestPayload { id: 42 };
@@ -1765,6 +2128,7 @@ mod tests {
         let res = alice.handle_packet(ping_req, addr).await;
         assert!(res.is_err());
         assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::GetPayload);
+        assert_eq!(stats.counters.decrypt_failures(), 1);
     }
 
     // handle_ping_resp
@@ -2158,6 +2522,143 @@ mod tests {
         assert!(rx.collect::<Vec<_>>().await.is_empty());
     }
 
+    #[tokio::test]
+    async fn handle_nodes_resp_updates_reputation() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        assert!(alice.close_nodes.write().try_add(PackedNode::new(addr, &bob_pk)));
+
+        tokio::time::pause();
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+        alice.close_nodes.write().get_node_mut(&bob_pk).unwrap().reputation.on_request();
+
+        let resp_payload = NodesResponsePayload { nodes: Vec::new(), id: ping_id };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        alice.handle_packet(nodes_resp, addr).await.unwrap();
+
+        let close_nodes = alice.close_nodes.read();
+        let reputation = close_nodes.get_node(&bob_pk).unwrap().reputation;
+        assert_eq!(reputation.responses, 1);
+        assert_eq!(reputation.rtt, Some(Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn handle_nodes_resp_bogus_nodes() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let bogus_node = PackedNode::new("0.0.0.0:33445".parse().unwrap(), &gen_keypair().0);
+        let node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        let resp_payload = NodesResponsePayload { nodes: vec![bogus_node, node], id: ping_id };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(nodes_resp, addr).await.unwrap();
+
+        // bogus node should be ignored while valid node should be processed
+        assert!(!alice.nodes_to_bootstrap.read().contains(&alice.pk, &bogus_node.pk));
+        assert!(alice.nodes_to_bootstrap.read().contains(&alice.pk, &node.pk));
+
+        let close_nodes = alice.close_nodes.read();
+        let reputation = close_nodes.get_node(&bob_pk).unwrap().reputation;
+        assert_eq!(reputation.invalid_responses, 1);
+    }
+
+    #[tokio::test]
+    async fn handle_nodes_resp_invalid_payload_doesnt_update_reputation() {
+        let (alice, _precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        assert!(alice.close_nodes.write().try_add(PackedNode::new(addr, &bob_pk)));
+
+        // can't be decrypted payload since packet is encrypted with wrong key
+        let precomp = precompute(&alice.pk, &gen_keypair().1);
+        let resp_payload = NodesResponsePayload { nodes: Vec::new(), id: 42 };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        assert!(alice.handle_packet(nodes_resp, addr).await.is_err());
+
+        let close_nodes = alice.close_nodes.read();
+        let reputation = close_nodes.get_node(&bob_pk).unwrap().reputation;
+        assert_eq!(reputation.invalid_responses, 0);
+    }
+
+    #[tokio::test]
+    async fn handle_packet_from_banned_ip() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        alice.ban_ip(addr.ip());
+
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(ping_req, addr).await.unwrap();
+
+        assert!(!alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
+
+        // Necessary to drop tx so that rx.collect::<Vec<_>>() can be finished
+        drop(alice);
+
+        assert!(rx.collect::<Vec<_>>().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unban() {
+        let (alice, _precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+        let node = PackedNode::new(addr, &bob_pk);
+
+        alice.ban_key(bob_pk);
+        alice.ban_ip(addr.ip());
+        assert!(alice.is_banned(&node));
+
+        assert!(alice.unban_key(&bob_pk));
+        assert!(!alice.unban_key(&bob_pk));
+        assert!(alice.is_banned(&node));
+
+        assert!(alice.unban_ip(&addr.ip()));
+        assert!(!alice.is_banned(&node));
+        assert!(alice.ban_list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn handle_ping_resp_invalid_payload_doesnt_update_reputation() {
+        let (alice, _precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        assert!(alice.close_nodes.write().try_add(PackedNode::new(addr, &bob_pk)));
+
+        // anyone can send garbage on behalf of the node
+        let precomp = precompute(&alice.pk, &gen_keypair().1);
+        let resp_payload = PingResponsePayload { id: 42 };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        assert!(alice.handle_packet(ping_resp, addr).await.is_err());
+
+        let close_nodes = alice.close_nodes.read();
+        let reputation = close_nodes.get_node(&bob_pk).unwrap().reputation;
+        assert_eq!(reputation.invalid_responses, 0);
+    }
+
+    #[tokio::test]
+    async fn handle_ping_req_from_banned_key() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        alice.ban_key(bob_pk);
+
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(ping_req, addr).await.unwrap();
+
+        // Necessary to drop tx so that rx.collect::<Vec<_>>() can be finished
+        drop(alice);
+
+        assert!(rx.collect::<Vec<_>>().await.is_empty());
+    }
+
     // handle_cookie_request
     #[tokio::test]
     async fn handle_cookie_request() {
@@ -3189,6 +3690,39 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn send_nat_ping_req_port_mapped() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, _addr) = create_node();
+        alice.set_external_addr(Some("1.2.3.4:33445".parse().unwrap()));
+
+        let (friend_pk, _friend_sk) = gen_keypair();
+        alice.add_friend(friend_pk);
+
+        // friend is behind NAT and responded to NatPingRequest so we would
+        // punch holes if our port wasn't mapped
+        let send_future = {
+            let mut friends = alice.friends.write();
+            let friend = friends.get_mut(&friend_pk).unwrap();
+            for i in 0 .. 5 {
+                let node = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &gen_keypair().0);
+                friend.try_add_to_close(node);
+                let dht_node = friend.close_nodes.get_node_mut(&friend_pk, &node.pk).unwrap();
+                dht_node.update_returned_addr(SocketAddr::new("1.2.3.4".parse().unwrap(), 33445 + i));
+            }
+            friend.hole_punch.is_punching_done = false;
+            friend.hole_punch.last_recv_ping_time = clock_now();
+
+            alice.send_nat_ping_req(&mut alice.request_queue.write(), &mut friends)
+        };
+        // punching would block on the full channel
+        tokio::time::timeout(Duration::from_secs(1), send_future).await.unwrap().unwrap();
+        drop(alice);
+
+        let packets = rx.map(|(packet, _addr)| packet).collect::<Vec<_>>().await;
+        assert!(!packets.is_empty());
+        assert!(packets.iter().all(|packet| matches!(packet, Packet::DhtRequest(_))));
+    }
+
     // handle_lan_discovery
     #[tokio::test]
     async fn handle_lan_discovery() {
@@ -3350,6 +3884,52 @@ mod tests {
         }).collect::<Vec<_>>().await;
     }
 
+    #[tokio::test]
+    async fn ping_replacement_nodes() {
+        let (alice, _precomp, bob_pk, bob_sk, mut rx, _addr) = create_node();
+        alice.set_kbucket_size(1);
+
+        // find two nodes that get into the same kbucket
+        let (node_pk, index) = loop {
+            let (node_pk, _node_sk) = gen_keypair();
+            if let Some(index) = kbucket_index(&alice.pk, &node_pk) {
+                if Some(index) == kbucket_index(&alice.pk, &bob_pk) {
+                    break (node_pk, index);
+                }
+            }
+        };
+        let node = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &node_pk);
+        let bob_node = PackedNode::new("127.0.0.1:33445".parse().unwrap(), &bob_pk);
+        let (closer, farther) = if alice.pk.distance(&node_pk, &bob_pk) == std::cmp::Ordering::Less {
+            (node, bob_node)
+        } else {
+            (bob_node, node)
+        };
+
+        assert!(alice.close_nodes.write().try_add(farther));
+        // node that doesn't fit into the kbucket goes to the replacement cache
+        alice.ping_add(&closer).await.unwrap();
+        assert_eq!(alice.replacement_nodes_count(), 1);
+        assert!(alice.nodes_to_ping.read().is_empty());
+
+        // replacements are not pinged while the kbucket has only good nodes
+        alice.send_pings().await.unwrap();
+        assert!(rx.try_recv().is_err());
+
+        alice.close_nodes.write().remove(&farther.pk);
+        alice.send_pings().await.unwrap();
+        assert_eq!(alice.replacement_nodes_count(), 0);
+
+        let (packet, addr) = rx.next().await.unwrap();
+        assert_eq!(addr, closer.saddr);
+        let ping_req = unpack!(packet, Packet::PingRequest);
+        if closer.pk == bob_pk {
+            let precomputed_key = precompute(&ping_req.pk, &bob_sk);
+            assert!(ping_req.get_payload(&precomputed_key).is_ok());
+        }
+        assert_eq!(kbucket_index(&alice.pk, &closer.pk), Some(index));
+    }
+
     #[tokio::test]
     async fn ping_nodes_when_nodes_to_ping_list_is_empty() {
         let (alice, _precomp, _bob_pk, _bob_sk, rx, _addr) = create_node();
@@ -3362,6 +3942,44 @@ mod tests {
         assert!(rx.collect::<Vec<_>>().await.is_empty());
     }
 
+    #[tokio::test]
+    async fn dht_main_loop_removes_misbehaving_nodes() {
+        let (alice, _precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let node = PackedNode::new(addr, &bob_pk);
+        assert!(alice.close_nodes.write().try_add(node));
+        {
+            let mut close_nodes = alice.close_nodes.write();
+            let reputation = &mut close_nodes.get_node_mut(&bob_pk).unwrap().reputation;
+            for _ in 0 .. MAX_INVALID_RESPONSES {
+                reputation.on_invalid_response();
+            }
+        }
+
+        alice.dht_main_loop().await.unwrap();
+
+        assert!(alice.close_nodes.read().get_node(&bob_pk).is_none());
+        assert!(alice.is_banned(&node));
+        // banned node can't be added back
+        assert!(!alice.has_good_reputation(&node));
+    }
+
+    #[tokio::test]
+    async fn dht_main_loop_reports_ktree_occupancy() {
+        let (mut alice, _precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+        let stats = Stats::new();
+        alice.set_stats(stats.clone());
+
+        let node = PackedNode::new(addr, &bob_pk);
+        assert!(alice.close_nodes.write().try_add(node));
+
+        alice.dht_main_loop().await.unwrap();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.ktree_occupancy.len(), KBUCKET_MAX_ENTRIES as usize);
+        assert_eq!(snapshot.ktree_size(), 1);
+    }
+
     #[tokio::test]
     async fn ping_close_nodes() {
         let (alice, _precomp, bob_pk, bob_sk, rx, _addr) = create_node();
@@ -3629,6 +4247,34 @@ mod tests {
         }).collect::<Vec<_>>().await;
     }
 
+    #[tokio::test]
+    async fn set_initial_bootstrap() {
+        let (mut alice, _precomp, bob_pk, _bob_sk, rx, _addr) = create_node();
+        let (node_pk, node_sk) = gen_keypair();
+
+        let pn = PackedNode::new("127.0.0.1:33445".parse().unwrap(), &bob_pk);
+        alice.add_initial_bootstrap(pn);
+
+        let pn = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &node_pk);
+        alice.set_initial_bootstrap(vec![pn]);
+
+        alice.send_bootstrap_requests().await.unwrap();
+
+        // Necessary to drop tx so that rx.collect::<Vec<_>>() can be finished
+        let request_queue = alice.request_queue.clone();
+        drop(alice);
+
+        let packets = rx.collect::<Vec<_>>().await;
+        assert_eq!(packets.len(), 1);
+
+        let (packet, addr) = packets.into_iter().next().unwrap();
+        assert_eq!(addr, "127.1.1.1:12345".parse().unwrap());
+        let nodes_req = unpack!(packet, Packet::NodesRequest);
+        let precomputed_key = precompute(&nodes_req.pk, &node_sk);
+        let nodes_req_payload = nodes_req.get_payload(&precomputed_key).unwrap();
+        assert!(request_queue.write().check_ping_id(nodes_req_payload.id, |&pk| pk == node_pk).is_some());
+    }
+
     #[tokio::test]
     async fn send_bootstrap_requests_when_ktree_has_good_node() {
         let (mut alice, _precomp, bob_pk, _bob_sk, rx, _addr) = create_node();
@@ -3695,7 +4341,7 @@ mod tests {
         let data_payload = CryptoDataPayload {
             buffer_start: 1,
             packet_number: 0,
-            data: vec![1, 2, 3, 4]
+            data: Bytes::from(vec![1, 2, 3, 4])
         };
 
         let data = Packet::CryptoData(CryptoData::new(&precomp, gen_nonce(), &data_payload));
@@ -3795,4 +4441,67 @@ mod tests {
         alice.add_node(PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0));
         assert!(alice.is_connected());
     }
+
+    fn create_onion_announce_entry() -> OnionAnnounceEntry {
+        OnionAnnounceEntry::new(
+            gen_keypair().0,
+            "1.2.3.4".parse().unwrap(),
+            12345,
+            OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; 42]
+            },
+            gen_keypair().0
+        )
+    }
+
+    #[test]
+    fn set_onion_announce_capacity() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        assert_eq!(alice.onion_announce_capacity(), ONION_ANNOUNCE_MAX_ENTRIES);
+
+        for _ in 0..3 {
+            alice.onion_announce.write().add_to_entries(create_onion_announce_entry());
+        }
+        alice.set_onion_announce_capacity(2);
+
+        assert_eq!(alice.onion_announce_capacity(), 2);
+        assert_eq!(alice.onion_announce_entries_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn run_onion_announce_expiring() {
+        tokio::time::pause();
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        alice.onion_announce.write().add_to_entries(create_onion_announce_entry());
+        assert_eq!(alice.onion_announce_entries_count(), 1);
+
+        tokio::spawn(alice.clone().run_onion_announce_expiring());
+
+        // the entry times out and then the timer fires
+        tokio::time::advance(ONION_ANNOUNCE_TIMEOUT).await;
+        tokio::time::advance(ONION_ANNOUNCE_EXPIRY_INTERVAL).await;
+
+        // timed out entry was already removed by the timer
+        assert_eq!(alice.onion_announce.write().remove_timed_out(), 0);
+        assert_eq!(alice.onion_announce_entries_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn run_until_returns_close_nodes() {
+        tokio::time::pause();
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        let node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+        alice.add_node(node);
+
+        let shutdown = Shutdown::new();
+        let handle = tokio::spawn(alice.run_until(shutdown.signal()));
+        shutdown.shutdown();
+
+        let nodes = handle.await.unwrap().unwrap();
+        assert_eq!(nodes, vec![node]);
+    }
 }
@@ -1,45 +1,163 @@
-//! Extension trait for running DHT server on `UdpSocket`
+//! Extension trait for running DHT server on `UdpSocket` or any other
+//! `DatagramTransport`
 
 use std::io::{Error, ErrorKind};
 use std::net::{SocketAddr, IpAddr};
 use std::pin::Pin;
+use std::sync::Arc;
 
-use futures::{Future, FutureExt, SinkExt, StreamExt};
+use futures::{future, stream, Future, FutureExt, SinkExt, StreamExt};
 use futures::channel::mpsc::Receiver;
-use tokio::net::{UdpSocket};
 use failure::Fail;
+use lru::LruCache;
+use parking_lot::Mutex;
 
 use crate::toxcore::dht::codec::*;
 use crate::toxcore::dht::packet::Packet;
 use crate::toxcore::dht::server::Server;
 use crate::toxcore::stats::Stats;
+use crate::toxcore::transport::{DatagramTransport, DatagramFramed};
+
+/// How many addresses we remember the receiving socket for to send replies
+/// through the same socket.
+const MAX_ROUTES: usize = 4096;
+
+/// `DatagramTransport` to run DHT server on with settings specific to this
+/// transport.
+pub struct DhtSocket<T> {
+    /// Transport to send and receive packets.
+    socket: T,
+    /// Whether IPv6 socket can be used to communicate with IPv6 nodes.
+    ipv6_mode: bool,
+}
+
+impl<T: DatagramTransport> DhtSocket<T> {
+    /// Create new `DhtSocket` with enabled IPv6 mode.
+    pub fn new(socket: T) -> Self {
+        DhtSocket {
+            socket,
+            ipv6_mode: true,
+        }
+    }
+
+    /// Enable or disable IPv6 mode of the socket. When it's disabled IPv6
+    /// socket is used only to communicate with IPv4 nodes via IPv4-mapped
+    /// addresses and packets from other IPv6 addresses are dropped. It has
+    /// no effect for IPv4 sockets.
+    pub fn enable_ipv6_mode(&mut self, enable: bool) {
+        self.ipv6_mode = enable;
+    }
+}
 
-/// Extension trait for running DHT server on `UdpSocket`.
+impl<T: DatagramTransport> From<T> for DhtSocket<T> {
+    fn from(socket: T) -> Self {
+        DhtSocket::new(socket)
+    }
+}
+
+/// Extension trait for running DHT server on `UdpSocket` or any other
+/// `DatagramTransport`.
 pub trait ServerExt {
-    /// Run DHT server on `UdpSocket`.
-    fn run_socket(self, socket: UdpSocket, rx: Receiver<(Packet, SocketAddr)>, stats: Stats) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+    /// Run DHT server on `UdpSocket` or any other `DatagramTransport`.
+    fn run_socket<T: DatagramTransport>(self, socket: T, rx: Receiver<(Packet, SocketAddr)>, stats: Stats) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+    /// Run DHT server on several `UdpSocket`s, e.g. separate IPv4 and IPv6
+    /// sockets or sockets bound to specific interfaces. Incoming packets from
+    /// all sockets are merged. Replies are sent through the socket the last
+    /// packet from the destination was received from. Other outgoing packets
+    /// are sent through the first socket of the same address family as the
+    /// destination. IPv4 packets are sent through IPv6 socket as IPv4-mapped
+    /// addresses only when there is no IPv4 socket. IPv6 packets are dropped
+    /// when there is no IPv6 socket with enabled IPv6 mode.
+    fn run_sockets<T: DatagramTransport>(self, sockets: Vec<DhtSocket<T>>, rx: Receiver<(Packet, SocketAddr)>, stats: Stats) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+}
+
+/// Get the address that should be used to send a packet to `addr` through
+/// the socket bound to `local_addr` if it's possible.
+fn socket_addr_for(local_addr: SocketAddr, ipv6_mode: bool, addr: SocketAddr) -> Option<SocketAddr> {
+    match (local_addr.ip(), addr.ip()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => Some(addr),
+        (IpAddr::V4(_), IpAddr::V6(_)) => None,
+        (IpAddr::V6(_), IpAddr::V4(ip)) => Some(SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port())),
+        (IpAddr::V6(_), IpAddr::V6(ip)) if ipv6_mode || ip.to_ipv4_mapped().is_some() => Some(addr),
+        (IpAddr::V6(_), IpAddr::V6(_)) => None,
+    }
+}
+
+/// Find index of the socket that should be used to send a packet to `addr`
+/// and the address that should be used for sending. Sockets are described by
+/// their local addresses and IPv6 modes. The socket the packet from `addr` was
+/// received from is preferred.
+fn route(sockets: &[(SocketAddr, bool)], addr: SocketAddr, received_from: Option<usize>) -> Option<(usize, SocketAddr)> {
+    let route_for = |index: usize| {
+        let (local_addr, ipv6_mode) = sockets[index];
+        socket_addr_for(local_addr, ipv6_mode, addr).map(|addr| (index, addr))
+    };
+
+    received_from
+        .filter(|&index| index < sockets.len())
+        .and_then(route_for)
+        .or_else(|| sockets.iter()
+            .position(|(local_addr, _)| local_addr.is_ipv4() == addr.is_ipv4())
+            .and_then(route_for)
+        )
+        .or_else(|| (0 .. sockets.len()).find_map(route_for))
 }
 
 impl ServerExt for Server {
-    fn run_socket(
+    fn run_socket<T: DatagramTransport>(
+        self,
+        socket: T,
+        rx: Receiver<(Packet, SocketAddr)>,
+        stats: Stats
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> {
+        self.run_sockets(vec![DhtSocket::new(socket)], rx, stats)
+    }
+
+    fn run_sockets<T: DatagramTransport>(
         self,
-        socket: UdpSocket,
+        sockets: Vec<DhtSocket<T>>,
         mut rx: Receiver<(Packet, SocketAddr)>,
         stats: Stats
     ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> {
-        let udp_addr = socket.local_addr()
-            .expect("Failed to get socket address");
+        if sockets.is_empty() {
+            return Box::pin(future::err(Error::new(ErrorKind::InvalidInput, "No sockets to run DHT server on")));
+        }
+
+        let local_addrs = sockets.iter()
+            .map(|socket| socket.socket.local_addr().map(|addr| (addr, socket.ipv6_mode)))
+            .collect::<Result<Vec<_>, _>>();
+        let local_addrs = match local_addrs {
+            Ok(local_addrs) => local_addrs,
+            Err(e) => return Box::pin(future::err(e)),
+        };
 
-        let codec = DhtCodec::new(stats);
-        let (mut sink, mut stream) =
-            tokio_util::udp::UdpFramed::new(socket, codec).split();
+        let (mut sinks, streams): (Vec<_>, Vec<_>) = sockets.into_iter()
+            .enumerate()
+            .map(|(index, socket)| {
+                let (sink, stream) = DatagramFramed::new(socket.socket, DhtCodec::new(stats.clone())).split();
+                (sink, stream.map(move |event| (index, event)))
+            })
+            .unzip();
+        let mut stream = stream::select_all(streams);
+
+        // sockets the last packets from remote addresses were received from
+        let routes = Arc::new(Mutex::new(LruCache::new(MAX_ROUTES)));
+        let routes_c = routes.clone();
+        let local_addrs_c = local_addrs.clone();
 
         let self_c = self.clone();
 
         let network_reader = async move {
-            while let Some(event) = stream.next().await {
+            while let Some((index, event)) = stream.next().await {
                 match event {
                     Ok((packet, addr)) => {
+                        let (local_addr, ipv6_mode) = local_addrs_c[index];
+                        if socket_addr_for(local_addr, ipv6_mode, addr).is_none() {
+                            trace!("Dropped packet from {:?} since IPv6 mode is disabled", addr);
+                            continue;
+                        }
+                        routes_c.lock().put((addr.ip(), addr.port()), index);
+
                         trace!("Received packet {:?}", packet);
                         let res = self_c.handle_packet(packet, addr).await;
 
@@ -62,18 +180,17 @@ impl ServerExt for Server {
         };
 
         let network_writer = async move {
-            while let Some((packet, mut addr)) = rx.next().await {
-                // filter out IPv6 packets if node is running in IPv4 mode
-                if udp_addr.is_ipv4() && addr.is_ipv6() { continue }
-
-                if udp_addr.is_ipv6() {
-                    if let IpAddr::V4(ip) = addr.ip() {
-                        addr = SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port());
-                    }
-                }
+            while let Some((packet, addr)) = rx.next().await {
+                // filter out packets that can't be sent through any socket,
+                // e.g. IPv6 packets if node is running only on IPv4 sockets
+                let received_from = routes.lock().get(&(addr.ip(), addr.port())).cloned();
+                let (index, addr) = match route(&local_addrs, addr, received_from) {
+                    Some(route) => route,
+                    None => continue,
+                };
 
                 trace!("Sending packet {:?} to {:?}", packet, addr);
-                sink.send((packet, addr)).await
+                sinks[index].send((packet, addr)).await
                     .map_err(|e| Error::new(ErrorKind::Other, e.compat()))?
             }
 
@@ -99,8 +216,11 @@ impl ServerExt for Server {
 mod tests {
     use super::*;
 
+    use std::task::{Context, Poll};
+
     use futures::channel::mpsc;
     use futures::TryStreamExt;
+    use tokio::net::UdpSocket;
 
     use crate::toxcore::crypto_core::*;
     use crate::toxcore::dht::packet::*;
@@ -136,7 +256,7 @@ mod tests {
                 .map_err(|e| Error::new(ErrorKind::Other, e.compat()))?;
 
             let stats = Stats::new();
-            let codec = DhtCodec::new(stats);
+            let codec = DhtCodec::new(stats.clone());
             let (mut sink, stream) = tokio_util::udp::UdpFramed::new(client_socket, codec).split();
 
             // Send ping request
@@ -176,4 +296,249 @@ mod tests {
             res = server_future.fuse() => ()
         };
     }
+
+    #[test]
+    fn route_by_address_family() {
+        let v4: SocketAddr = "0.0.0.0:33445".parse().unwrap();
+        let v6: SocketAddr = "[::]:33445".parse().unwrap();
+        let to_v4: SocketAddr = "1.2.3.4:12345".parse().unwrap();
+        let to_v6: SocketAddr = "[FF::01]:12345".parse().unwrap();
+        let to_v4_mapped: SocketAddr = "[::ffff:1.2.3.4]:12345".parse().unwrap();
+
+        let v4 = (v4, true);
+        let v6 = (v6, true);
+
+        assert_eq!(route(&[v4, v6], to_v4, None), Some((0, to_v4)));
+        assert_eq!(route(&[v4, v6], to_v6, None), Some((1, to_v6)));
+        assert_eq!(route(&[v6, v4], to_v4, None), Some((1, to_v4)));
+        assert_eq!(route(&[v4], to_v4, None), Some((0, to_v4)));
+        assert_eq!(route(&[v4], to_v6, None), None);
+        assert_eq!(route(&[v6], to_v4, None), Some((0, to_v4_mapped)));
+        assert_eq!(route(&[v6], to_v6, None), Some((0, to_v6)));
+    }
+
+    #[test]
+    fn route_to_receiving_socket() {
+        let v4_1: SocketAddr = "1.1.1.1:33445".parse().unwrap();
+        let v4_2: SocketAddr = "2.2.2.2:33445".parse().unwrap();
+        let v6: SocketAddr = "[::]:33445".parse().unwrap();
+        let to_v4: SocketAddr = "1.2.3.4:12345".parse().unwrap();
+        let to_v4_mapped: SocketAddr = "[::ffff:1.2.3.4]:12345".parse().unwrap();
+        let to_v6: SocketAddr = "[FF::01]:12345".parse().unwrap();
+        let sockets = [(v4_1, true), (v4_2, true), (v6, true)];
+
+        assert_eq!(route(&sockets, to_v4, Some(1)), Some((1, to_v4)));
+        assert_eq!(route(&sockets, to_v4, Some(2)), Some((2, to_v4_mapped)));
+        // the receiving socket can't be used
+        assert_eq!(route(&sockets, to_v6, Some(1)), Some((2, to_v6)));
+        assert_eq!(route(&sockets, to_v4, Some(3)), Some((0, to_v4)));
+    }
+
+    #[test]
+    fn route_ipv6_mode_disabled() {
+        let v4: SocketAddr = "0.0.0.0:33445".parse().unwrap();
+        let v6_1: SocketAddr = "[::1]:33445".parse().unwrap();
+        let v6_2: SocketAddr = "[::2]:33445".parse().unwrap();
+        let to_v4: SocketAddr = "1.2.3.4:12345".parse().unwrap();
+        let to_v4_mapped: SocketAddr = "[::ffff:1.2.3.4]:12345".parse().unwrap();
+        let to_v6: SocketAddr = "[FF::01]:12345".parse().unwrap();
+
+        assert_eq!(route(&[(v6_1, false)], to_v6, None), None);
+        assert_eq!(route(&[(v6_1, false)], to_v4, None), Some((0, to_v4_mapped)));
+        assert_eq!(route(&[(v6_1, false)], to_v4_mapped, None), Some((0, to_v4_mapped)));
+        assert_eq!(route(&[(v4, true), (v6_1, false), (v6_2, true)], to_v6, None), Some((2, to_v6)));
+        assert_eq!(route(&[(v4, true), (v6_1, false), (v6_2, true)], to_v6, Some(1)), Some((2, to_v6)));
+    }
+
+    #[tokio::test]
+    async fn run_sockets_without_sockets() {
+        let (tx, rx) = mpsc::channel(32);
+        let (pk, sk) = gen_keypair();
+        let server = Server::new(tx, pk, sk);
+
+        let res = server.run_sockets(Vec::<DhtSocket<UdpSocket>>::new(), rx, Stats::new()).await;
+        assert_eq!(res.err().unwrap().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn run_sockets() {
+        crypto_init().unwrap();
+        let (client_pk, client_sk) = gen_keypair();
+        let (server_pk, server_sk) = gen_keypair();
+        let shared_secret = precompute(&server_pk, &client_sk);
+
+        let (tx, rx) = mpsc::channel(32);
+
+        let server = Server::new(tx, server_pk, server_sk);
+
+        // Bind two server sockets
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server_socket_1 = UdpSocket::bind(&addr).await.unwrap();
+        let server_socket_2 = UdpSocket::bind(&addr).await.unwrap();
+        let server_addr_2 = server_socket_2.local_addr().unwrap();
+
+        let stats = Stats::new();
+        let server_future = server.run_sockets(vec![server_socket_1.into(), server_socket_2.into()], rx, stats);
+
+        let client_socket = UdpSocket::bind(&addr).await.unwrap();
+
+        let client_future = async move {
+            let codec = DhtCodec::new(Stats::new());
+            let (mut sink, stream) = tokio_util::udp::UdpFramed::new(client_socket, codec).split();
+
+            // Send ping request to the second socket
+            let ping_id = 42;
+            let ping_request_payload = PingRequestPayload {
+                id: ping_id,
+            };
+            let ping_request = PingRequest::new(&shared_secret, &client_pk, &ping_request_payload);
+
+            sink.send((Packet::PingRequest(ping_request), server_addr_2)).await
+                .map_err(|e| Error::new(ErrorKind::Other, e.compat()))?;
+
+            // And wait for ping response from the same socket
+            let ping_response = stream
+                .try_filter_map(|(packet, addr)| futures::future::ok(
+                    match packet {
+                        Packet::PingResponse(ping_response) => Some((ping_response, addr)),
+                        _ => None,
+                    }
+                ))
+                .next()
+                .await
+                .unwrap();
+
+            let (ping_response, addr) = ping_response
+                .map_err(|e| Error::new(ErrorKind::Other, e.compat()))?;
+            let ping_response_payload = ping_response.get_payload(&shared_secret).unwrap();
+
+            assert_eq!(ping_response_payload.id, ping_id);
+            assert_eq!(addr, server_addr_2);
+
+            let res: Result<_, Error> = Ok(());
+            res
+        };
+
+        futures::select! {
+            res = client_future.fuse() => res.unwrap(),
+            _ = server_future.fuse() => ()
+        };
+    }
+
+    /// In-process datagram pipe.
+    struct Pipe {
+        addr: SocketAddr,
+        tx: mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>,
+        rx: mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>,
+    }
+
+    impl DatagramTransport for Pipe {
+        fn local_addr(&self) -> Result<SocketAddr, Error> {
+            Ok(self.addr)
+        }
+
+        fn poll_recv_from(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<Result<(usize, SocketAddr), Error>> {
+            match futures::ready!(self.rx.poll_next_unpin(cx)) {
+                Some((data, addr)) => {
+                    buf[.. data.len()].copy_from_slice(&data);
+                    Poll::Ready(Ok((data.len(), addr)))
+                },
+                None => Poll::Ready(Err(Error::new(ErrorKind::BrokenPipe, "Pipe is closed"))),
+            }
+        }
+
+        fn poll_send_to(&mut self, _cx: &mut Context, buf: &[u8], _target: &SocketAddr) -> Poll<Result<usize, Error>> {
+            self.tx.unbounded_send((buf.to_vec(), self.addr))
+                .map_err(|e| Error::new(ErrorKind::BrokenPipe, e))?;
+            Poll::Ready(Ok(buf.len()))
+        }
+    }
+
+    #[tokio::test]
+    async fn run_custom_transport() {
+        crypto_init().unwrap();
+        let (client_pk, client_sk) = gen_keypair();
+        let (server_pk, server_sk) = gen_keypair();
+        let shared_secret = precompute(&server_pk, &client_sk);
+
+        let (tx, rx) = mpsc::channel(32);
+
+        let server = Server::new(tx, server_pk, server_sk);
+
+        let server_addr: SocketAddr = "127.0.0.1:33445".parse().unwrap();
+        let client_addr: SocketAddr = "127.0.0.1:33446".parse().unwrap();
+        let (to_server_tx, to_server_rx) = mpsc::unbounded();
+        let (to_client_tx, to_client_rx) = mpsc::unbounded();
+        let server_pipe = Pipe { addr: server_addr, tx: to_client_tx, rx: to_server_rx };
+        let client_pipe = Pipe { addr: client_addr, tx: to_server_tx, rx: to_client_rx };
+
+        let server_future = server.run_socket(server_pipe, rx, Stats::new());
+
+        let client_future = async move {
+            let codec = DhtCodec::new(Stats::new());
+            let (mut sink, stream) = DatagramFramed::new(client_pipe, codec).split();
+
+            let ping_id = 42;
+            let ping_request_payload = PingRequestPayload {
+                id: ping_id,
+            };
+            let ping_request = PingRequest::new(&shared_secret, &client_pk, &ping_request_payload);
+
+            sink.send((Packet::PingRequest(ping_request), server_addr)).await
+                .map_err(|e| Error::new(ErrorKind::Other, e.compat()))?;
+
+            let ping_response = stream
+                .try_filter_map(|(packet, _)| futures::future::ok(
+                    match packet {
+                        Packet::PingResponse(ping_response) => Some(ping_response),
+                        _ => None,
+                    }
+                ))
+                .next()
+                .await
+                .unwrap();
+
+            let ping_response = ping_response
+                .map_err(|e| Error::new(ErrorKind::Other, e.compat()))?;
+            let ping_response_payload = ping_response.get_payload(&shared_secret).unwrap();
+
+            assert_eq!(ping_response_payload.id, ping_id);
+
+            let res: Result<_, Error> = Ok(());
+            res
+        };
+
+        futures::select! {
+            res = client_future.fuse() => res.unwrap(),
+            _ = server_future.fuse() => ()
+        };
+    }
+
+    /// Transport that is not bound to any address.
+    struct Unbound;
+
+    impl DatagramTransport for Unbound {
+        fn local_addr(&self) -> Result<SocketAddr, Error> {
+            Err(Error::new(ErrorKind::NotConnected, "Transport is not bound"))
+        }
+
+        fn poll_recv_from(&mut self, _cx: &mut Context, _buf: &mut [u8]) -> Poll<Result<(usize, SocketAddr), Error>> {
+            Poll::Pending
+        }
+
+        fn poll_send_to(&mut self, _cx: &mut Context, _buf: &[u8], _target: &SocketAddr) -> Poll<Result<usize, Error>> {
+            Poll::Pending
+        }
+    }
+
+    #[tokio::test]
+    async fn run_sockets_local_addr_error() {
+        crypto_init().unwrap();
+        let (server_pk, server_sk) = gen_keypair();
+        let (tx, rx) = mpsc::channel(32);
+        let server = Server::new(tx, server_pk, server_sk);
+
+        let error = server.run_sockets(vec![DhtSocket::new(Unbound)], rx, Stats::new()).await.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotConnected);
+    }
 }
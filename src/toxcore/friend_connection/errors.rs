@@ -42,7 +42,10 @@ error_kind! {
         AddTcpConnection,
         #[doc = "Failed to send connection status."]
         #[fail(display = "Failed to send connection status")]
-        SendToConnectionStatus
+        SendToConnectionStatus,
+        #[doc = "Failed to send connection transport."]
+        #[fail(display = "Failed to send connection transport")]
+        SendToTransport
     }
 }
 
@@ -71,3 +74,16 @@ error_kind! {
         GetConnectionStatus
     }
 }
+
+error_kind! {
+    #[doc = "Error that can happen while setting friend's transport policy."]
+    #[derive(Debug)]
+    SetTransportPolicyError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Debug, Eq, PartialEq, Fail)]
+    SetTransportPolicyErrorKind {
+        #[doc = "There is no such friend."]
+        #[fail(display = "There is no such friend")]
+        NoFriend,
+    }
+}
@@ -23,9 +23,10 @@ use crate::toxcore::dht::packed_node::PackedNode;
 use crate::toxcore::dht::server::{Server as DhtServer};
 use crate::toxcore::friend_connection::errors::*;
 use crate::toxcore::friend_connection::packet::*;
-use crate::toxcore::net_crypto::NetCrypto;
+use crate::toxcore::net_crypto::{ConnectionTransport, NetCrypto, TransportPolicy};
 use crate::toxcore::net_crypto::errors::KillConnectionErrorKind;
 use crate::toxcore::onion::client::OnionClient;
+use crate::toxcore::shutdown::ShutdownSignal;
 use crate::toxcore::tcp::client::{Connections as TcpConnections};
 use crate::toxcore::time::*;
 
@@ -34,6 +35,11 @@ use crate::toxcore::time::*;
 /// long term key of the connection.
 type ConnectionStatusTx = mpsc::UnboundedSender<(PublicKey, bool)>;
 
+/// Shorthand for the transmit half of the message channel for sending a
+/// transport used to communicate with a friend when it changes. The key is a
+/// long term key of the friend.
+type TransportTx = mpsc::UnboundedSender<(PublicKey, Option<ConnectionTransport>)>;
+
 /// How often we should send ping packets to a friend.
 const FRIEND_PING_INTERVAL: Duration = Duration::from_secs(8);
 
@@ -72,6 +78,10 @@ struct Friend {
     ping_received_time: Option<Instant>,
     /// Time when we sent the last `ShareRelays` packet.
     share_relays_time: Option<Instant>,
+    /// Transports that can be used to connect to this friend.
+    transport_policy: TransportPolicy,
+    /// Transport that is currently used to communicate with this friend.
+    transport: Option<ConnectionTransport>,
 }
 
 impl Friend {
@@ -86,6 +96,8 @@ impl Friend {
             ping_sent_time: None,
             ping_received_time: None,
             share_relays_time: None,
+            transport_policy: TransportPolicy::default(),
+            transport: None,
         }
     }
 }
@@ -102,6 +114,9 @@ pub struct FriendConnections {
     /// Sink to send a connection status when it becomes connected or
     /// disconnected. The key is a long term key of the connection.
     connection_status_tx: Arc<RwLock<Option<ConnectionStatusTx>>>,
+    /// Sink to send a transport used to communicate with a friend when it
+    /// changes. The key is a long term key of the friend.
+    transport_tx: Arc<RwLock<Option<TransportTx>>>,
     /// DHT server.
     dht: DhtServer,
     /// TCP connections.
@@ -127,6 +142,7 @@ impl FriendConnections {
             real_pk,
             friends: Arc::new(RwLock::new(HashMap::new())),
             connection_status_tx: Arc::new(RwLock::new(None)),
+            transport_tx: Arc::new(RwLock::new(None)),
             dht,
             tcp_connections,
             onion_client,
@@ -155,6 +171,31 @@ impl FriendConnections {
         }
     }
 
+    /// Set transports that can be used to connect to a friend. With
+    /// `TransportPolicy::RelayOnly` the friend is not searched in DHT and no
+    /// hole punching is done. Note that it doesn't hide our IP address from
+    /// the friend: our DHT `PublicKey` is still announced to him and he can
+    /// find our IP address in DHT with it.
+    pub fn set_friend_transport_policy(&self, friend_pk: PublicKey, policy: TransportPolicy) -> Result<(), SetTransportPolicyError> {
+        let mut friends = self.friends.write();
+        let friend = if let Some(friend) = friends.get_mut(&friend_pk) {
+            friend
+        } else {
+            return Err(SetTransportPolicyErrorKind::NoFriend.into())
+        };
+
+        if let Some(dht_pk) = friend.dht_pk {
+            if policy == TransportPolicy::RelayOnly {
+                self.dht.remove_friend(dht_pk);
+            } else {
+                self.dht.add_friend(dht_pk);
+            }
+        }
+        friend.transport_policy = policy;
+        self.net_crypto.set_friend_transport_policy(friend_pk, policy);
+        Ok(())
+    }
+
     /// Remove a friend and drop all connections with him.
     pub fn remove_friend(&self, friend_pk: PublicKey) -> impl Future<Output = Result<(), RemoveFriendError>> + Send {
         let mut friends = self.friends.write();
@@ -261,7 +302,11 @@ impl FriendConnections {
 
                         friend.dht_pk = Some(dht_pk);
 
-                        dht.add_friend(dht_pk);
+                        // DHT friend search is needed only to find UDP
+                        // addresses of the friend and to punch holes to them
+                        if friend.transport_policy != TransportPolicy::RelayOnly {
+                            dht.add_friend(dht_pk);
+                        }
                         net_crypto.add_connection(real_pk, dht_pk);
                         onion_client.set_friend_dht_pk(real_pk, dht_pk);
 
@@ -294,7 +339,9 @@ impl FriendConnections {
                         friend.saddr = Some(node.saddr);
 
                         net_crypto.add_connection(friend.real_pk, node.pk);
-                        net_crypto.set_friend_udp_addr(friend.real_pk, node.saddr);
+                        if friend.transport_policy != TransportPolicy::RelayOnly {
+                            net_crypto.set_friend_udp_addr(friend.real_pk, node.saddr);
+                        }
                     }
                 }
 
@@ -342,6 +389,31 @@ impl FriendConnections {
             })
     }
 
+    /// Handle the stream of connection transports.
+    fn handle_transport(&self, transport_rx: mpsc::UnboundedReceiver<(PublicKey, Option<ConnectionTransport>)>) -> impl Future<Output = Result<(), RunError>> + Send {
+        let friends = self.friends.clone();
+        let transport_tx = self.transport_tx.clone();
+        transport_rx
+            .map(Ok)
+            .try_for_each(move |(real_pk, transport)| {
+                if let Some(friend) = friends.write().get_mut(&real_pk) {
+                    if friend.transport != transport {
+                        friend.transport = transport;
+                        if let Some(mut transport_tx) = transport_tx.read().clone() {
+                            let res = async move {
+                                transport_tx.send((real_pk, transport)).await
+                                    .map_err(|e| e.context(RunErrorKind::SendToTransport).into())
+                            };
+
+                            return Either::Left(res);
+                        }
+                    }
+                }
+
+                Either::Right(future::ok(()))
+            })
+    }
+
     /// Send some of our relays to a friend and start using these relays to
     /// connect to this friend.
     fn share_relays(&self, friend_pk: PublicKey) -> impl Future<Output = Result<(), RunError>> + Send {
@@ -420,7 +492,7 @@ impl FriendConnections {
 
                 if let Some(dht_pk) = friend.dht_pk {
                     self.net_crypto.add_connection(friend.real_pk, dht_pk);
-                    if let Some(saddr) = friend.saddr {
+                    if let Some(saddr) = friend.saddr.filter(|_| friend.transport_policy != TransportPolicy::RelayOnly) {
                         self.net_crypto.set_friend_udp_addr(friend.real_pk, saddr);
                     }
                 }
@@ -466,9 +538,13 @@ impl FriendConnections {
         let (connection_status_tx, connection_status_rx) = mpsc::unbounded();
         self.net_crypto.set_connection_status_sink(connection_status_tx);
 
+        let (transport_tx, transport_rx) = mpsc::unbounded();
+        self.net_crypto.set_transport_sink(transport_tx);
+
         let dht_pk_future = self.handle_dht_pk(dht_pk_rx);
         let friend_saddr_future = self.handle_friend_saddr(friend_saddr_rx);
         let connection_status_future = self.handle_connection_status(connection_status_rx);
+        let transport_future = self.handle_transport(transport_rx);
         let main_loop_future = self.run_main_loop();
 
         async {
@@ -476,6 +552,7 @@ impl FriendConnections {
                 res = dht_pk_future.fuse() => res,
                 res = friend_saddr_future.fuse() => res,
                 res = connection_status_future.fuse() => res,
+                res = transport_future.fuse() => res,
                 res = main_loop_future.fuse() => res,
             };
 
@@ -483,23 +560,58 @@ impl FriendConnections {
         }
     }
 
+    /// Run friends connection module until shutdown is triggered. Then report
+    /// all connected friends as disconnected since their connections are
+    /// going to be killed by `NetCrypto`.
+    pub async fn run_until(self, signal: ShutdownSignal) -> Result<(), RunError> {
+        signal.run(self.clone().run()).await?;
+
+        let mut disconnected = Vec::new();
+        for (&real_pk, friend) in self.friends.write().iter_mut() {
+            if friend.connected {
+                friend.connected = false;
+                self.onion_client.set_friend_connected(real_pk, false);
+                disconnected.push(real_pk);
+            }
+        }
+
+        let connection_status_tx = self.connection_status_tx.read().clone();
+        if let Some(mut connection_status_tx) = connection_status_tx {
+            for real_pk in disconnected {
+                connection_status_tx.send((real_pk, false)).await
+                    .map_err(|e| e.context(RunErrorKind::SendToConnectionStatus))?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Set sink to send a connection status when it becomes connected or
     /// disconnected.
     pub fn set_connection_status_sink(&self, connection_status_tx: mpsc::UnboundedSender<(PublicKey, bool)>) {
         *self.connection_status_tx.write() = Some(connection_status_tx);
     }
+
+    /// Set sink to send a transport used to communicate with a friend when it
+    /// changes. `None` means that there is no transport to reach the friend.
+    pub fn set_transport_sink(&self, transport_tx: TransportTx) {
+        *self.transport_tx.write() = Some(transport_tx);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use bytes::Bytes;
+
     use crate::toxcore::dht::packet::{Packet as DhtPacket, *};
     use crate::toxcore::dht::precomputed_cache::*;
     use crate::toxcore::net_crypto::*;
+    use crate::toxcore::shutdown::Shutdown;
 
     type DhtRx = mpsc::Receiver<(DhtPacket, SocketAddr)>;
-    type LosslessRx = mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>;
+    type LosslessRx = mpsc::UnboundedReceiver<(PublicKey, Bytes)>;
 
     fn create_friend_connections() -> (FriendConnections, DhtRx, LosslessRx) {
         let (dht_pk, dht_sk) = gen_keypair();
@@ -687,6 +799,52 @@ mod tests {
         assert_eq!(friend.saddr_time, Some(now + delay));
     }
 
+    #[tokio::test]
+    async fn handle_friend_saddr_relay_only() {
+        let (friend_connections, _udp_rx, _lossless_rx) = create_friend_connections();
+
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+        let mut friend = Friend::new(friend_pk);
+        friend.dht_pk = Some(friend_dht_pk);
+        friend.transport_policy = TransportPolicy::RelayOnly;
+        friend_connections.friends.write().insert(friend_pk, friend);
+
+        let saddr = "127.0.0.1:12345".parse().unwrap();
+        let (mut friend_saddr_tx, friend_saddr_rx) = mpsc::unbounded();
+        friend_saddr_tx.send(PackedNode::new(saddr, &friend_dht_pk)).await.unwrap();
+        drop(friend_saddr_tx);
+
+        friend_connections.handle_friend_saddr(friend_saddr_rx).await.unwrap();
+        assert_eq!(friend_connections.net_crypto.connection_dht_pk(&friend_pk), Some(friend_dht_pk));
+        assert_eq!(friend_connections.net_crypto.connection_saddr(&friend_pk), None);
+    }
+
+    #[test]
+    fn set_friend_transport_policy() {
+        crypto_init().unwrap();
+        let (friend_connections, _udp_rx, _lossless_rx) = create_friend_connections();
+
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+
+        let error = friend_connections.set_friend_transport_policy(friend_pk, TransportPolicy::RelayOnly).err().unwrap();
+        assert_eq!(*error.kind(), SetTransportPolicyErrorKind::NoFriend);
+
+        let mut friend = Friend::new(friend_pk);
+        friend.dht_pk = Some(friend_dht_pk);
+        friend_connections.friends.write().insert(friend_pk, friend);
+        friend_connections.dht.add_friend(friend_dht_pk);
+
+        // friend is not searched in DHT to avoid hole punching
+        friend_connections.set_friend_transport_policy(friend_pk, TransportPolicy::RelayOnly).unwrap();
+        assert!(!friend_connections.dht.has_friend(&friend_dht_pk));
+        assert_eq!(friend_connections.friends.read()[&friend_pk].transport_policy, TransportPolicy::RelayOnly);
+
+        friend_connections.set_friend_transport_policy(friend_pk, TransportPolicy::Auto).unwrap();
+        assert!(friend_connections.dht.has_friend(&friend_dht_pk));
+    }
+
     #[tokio::test]
     async fn handle_connection_status_connected() {
         tokio::time::pause();
@@ -747,6 +905,42 @@ mod tests {
         assert!(!friend_connections.onion_client.is_friend_connected(&friend_pk));
     }
 
+    #[tokio::test]
+    async fn handle_transport() {
+        let (friend_connections, _udp_rx, _lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+        friend_connections.friends.write().insert(friend_pk, Friend::new(friend_pk));
+        let (unknown_pk, _unknown_sk) = gen_keypair();
+
+        let (transport_tx, transport_rx) = mpsc::unbounded();
+        friend_connections.set_transport_sink(transport_tx);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        let (relay_pk, _relay_sk) = gen_keypair();
+        let (mut net_crypto_transport_tx, net_crypto_transport_rx) = mpsc::unbounded();
+        net_crypto_transport_tx.send((friend_pk, Some(ConnectionTransport::TcpRelay(relay_pk)))).await.unwrap();
+        // the same transport should not be reported twice
+        net_crypto_transport_tx.send((friend_pk, Some(ConnectionTransport::TcpRelay(relay_pk)))).await.unwrap();
+        // transports of unknown connections should not be reported
+        net_crypto_transport_tx.send((unknown_pk, Some(ConnectionTransport::TcpRelay(relay_pk)))).await.unwrap();
+        net_crypto_transport_tx.send((friend_pk, Some(ConnectionTransport::UdpV4(addr)))).await.unwrap();
+        net_crypto_transport_tx.send((friend_pk, None)).await.unwrap();
+        drop(net_crypto_transport_tx);
+
+        friend_connections.handle_transport(net_crypto_transport_rx).await.unwrap();
+
+        assert!(friend_connections.friends.read()[&friend_pk].transport.is_none());
+
+        drop(friend_connections);
+
+        let transports = transport_rx.collect::<Vec<_>>().await;
+        assert_eq!(transports, vec![
+            (friend_pk, Some(ConnectionTransport::TcpRelay(relay_pk))),
+            (friend_pk, Some(ConnectionTransport::UdpV4(addr))),
+            (friend_pk, None),
+        ]);
+    }
+
     #[tokio::test]
     async fn main_loop_remove_timed_out() {
         let (friend_connections, udp_rx, _lossless_rx) = create_friend_connections();
@@ -1046,7 +1240,7 @@ mod tests {
             let crypto_data_payload = CryptoDataPayload {
                 buffer_start: 0,
                 packet_number: 0,
-                data: vec![PACKET_ID_ALIVE],
+                data: Bytes::from(vec![PACKET_ID_ALIVE]),
             };
             let crypto_data = CryptoData::new(&session_precomputed_key, sent_nonce, &crypto_data_payload);
 
@@ -1076,4 +1270,40 @@ mod tests {
 
         future.await;
     }
+
+    #[tokio::test]
+    async fn run_until() {
+        tokio::time::pause();
+        let (friend_connections, _udp_rx, _lossless_rx) = create_friend_connections();
+        friend_connections.add_friend(gen_keypair().0);
+
+        let shutdown = Shutdown::new();
+        let handle = tokio::spawn(friend_connections.run_until(shutdown.signal()));
+        shutdown.shutdown();
+
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn run_until_reports_disconnected() {
+        tokio::time::pause();
+        let (friend_connections, _udp_rx, _lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+        friend_connections.add_friend(friend_pk);
+        friend_connections.friends.write().get_mut(&friend_pk).unwrap().connected = true;
+
+        let (connection_status_tx, connection_status_rx) = mpsc::unbounded();
+        friend_connections.set_connection_status_sink(connection_status_tx);
+
+        let shutdown = Shutdown::new();
+        let handle = tokio::spawn(friend_connections.clone().run_until(shutdown.signal()));
+        shutdown.shutdown();
+        handle.await.unwrap().unwrap();
+
+        assert!(!friend_connections.friends.read()[&friend_pk].connected);
+
+        drop(friend_connections);
+        let statuses = connection_status_rx.collect::<Vec<_>>().await;
+        assert_eq!(statuses, vec![(friend_pk, false)]);
+    }
 }
@@ -0,0 +1,522 @@
+/*! Congestion control algorithms for crypto connections.
+
+Congestion controller estimates how many lossless packets per second can be
+sent via a crypto connection. It's updated every
+`PACKET_COUNTER_AVERAGE_INTERVAL` with counters collected by the connection
+and can also receive delay samples when sent packets are acknowledged.
+
+Two controllers are provided:
+- `ToxCongestionController` is the default one. It's compatible with
+  c-toxcore and estimates rate by the number of delivered packets and the
+  size of the send queue.
+- `DelayCongestionController` is a LEDBAT-like controller that keeps queuing
+  delay close to `DELAY_TARGET`. It ramps up faster on links with high
+  latency.
+
+*/
+
+use std::fmt::Debug;
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
+
+use super::crypto_connection::{CRYPTO_PACKET_MIN_RATE, PACKET_COUNTER_AVERAGE_INTERVAL, PACKET_COUNTER_AVERAGE_INTERVAL_MS};
+
+/// How many last sizes of `send_array` should be recorded for congestion
+/// control.
+pub const CONGESTION_QUEUE_ARRAY_SIZE: usize = 12;
+
+/// How many numbers of sent lossless packets should be recorded for congestion
+/// control. It should be bigger than `CONGESTION_QUEUE_ARRAY_SIZE` due to rtt.
+pub const CONGESTION_LAST_SENT_ARRAY_SIZE: usize = CONGESTION_QUEUE_ARRAY_SIZE * 2;
+
+/// Timeout for increasing speed after congestion event.
+pub const CONGESTION_EVENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// If the send queue grows so that it will take more than 2 seconds to send all
+/// its packet we will reduce send rate.
+pub const SEND_QUEUE_CLEARANCE_TIME: f64 = 2.0;
+
+/// Minimum packets in the send queue to reduce send rate.
+pub const CRYPTO_MIN_QUEUE_LENGTH: u32 = 64;
+
+/// Queuing delay that `DelayCongestionController` tries to keep.
+pub const DELAY_TARGET: Duration = Duration::from_millis(100);
+
+/// How fast `DelayCongestionController` changes send rate. Rate is changed by
+/// `DELAY_GAIN` times per second when queuing delay is zero or twice as big as
+/// `DELAY_TARGET`.
+pub const DELAY_GAIN: f64 = 2.0;
+
+/// Interval after which the lowest delay is forgotten by
+/// `DelayCongestionController` so that it can adapt to route changes.
+pub const BASE_DELAY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Counters of a crypto connection collected since the last update of the
+/// congestion controller.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CongestionSample {
+    /// Time of the update.
+    pub now: Instant,
+    /// Round trip time of the connection.
+    pub rtt: Duration,
+    /// Number of packets in `send_array`.
+    pub send_array_len: u32,
+    /// Number of sent lossless packets. It does not include resent packets.
+    pub packets_sent: u32,
+    /// Number of resent lossless packets.
+    pub packets_resent: u32,
+}
+
+/// Helper trait that allows to clone boxed congestion controllers. It's
+/// implemented automatically for all controllers that implement `Clone`.
+pub trait CongestionControllerClone {
+    /// Clone the controller into a new box.
+    fn box_clone(&self) -> Box<dyn CongestionController>;
+}
+
+impl<T: CongestionController + Clone + 'static> CongestionControllerClone for T {
+    fn box_clone(&self) -> Box<dyn CongestionController> {
+        Box::new(self.clone())
+    }
+}
+
+/** Algorithm that estimates send rate of a crypto connection.
+
+Every crypto connection has its own controller so it can keep state between
+updates. A new controller is created by cloning the one set to `NetCrypto`.
+
+*/
+pub trait CongestionController: CongestionControllerClone + Debug + Send + Sync {
+    /// Name of the algorithm. Controllers with the same name are considered
+    /// equal regardless of their state.
+    fn name(&self) -> &'static str;
+
+    /// Update the estimated rates with counters of the connection. Called
+    /// every `PACKET_COUNTER_AVERAGE_INTERVAL` for established connections.
+    fn update(&mut self, sample: &CongestionSample);
+
+    /// Handle the time elapsed between sending a packet and receiving its
+    /// acknowledgement.
+    fn on_delay_sample(&mut self, _delay: Duration) { }
+
+    /// Estimated packets send rate per second.
+    fn send_rate(&self) -> f64;
+
+    /// Estimated send rate per second for requested packets.
+    fn send_rate_requested(&self) -> f64;
+}
+
+impl Clone for Box<dyn CongestionController> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+impl Default for Box<dyn CongestionController> {
+    fn default() -> Self {
+        Box::new(ToxCongestionController::default())
+    }
+}
+
+/// Congestion controller of a crypto connection. It wraps a boxed controller
+/// so that connections can be compared: controllers are considered equal
+/// when they have the same name.
+#[derive(Clone, Debug, Default)]
+pub struct CongestionControl(Box<dyn CongestionController>);
+
+impl CongestionControl {
+    /// Create new `CongestionControl` with the given controller.
+    pub fn new(controller: Box<dyn CongestionController>) -> Self {
+        CongestionControl(controller)
+    }
+}
+
+impl From<Box<dyn CongestionController>> for CongestionControl {
+    fn from(controller: Box<dyn CongestionController>) -> Self {
+        CongestionControl(controller)
+    }
+}
+
+impl Deref for CongestionControl {
+    type Target = dyn CongestionController;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+impl DerefMut for CongestionControl {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_mut()
+    }
+}
+
+impl PartialEq for CongestionControl {
+    fn eq(&self, other: &Self) -> bool {
+        self.name() == other.name()
+    }
+}
+
+/// Congestion controller compatible with c-toxcore. It estimates the number
+/// of delivered packets by the number of sent packets and changes of the send
+/// queue size.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ToxCongestionController {
+    /// Current position in `last_send_array_sizes` and `last_num_packets` arrays.
+    pub last_sendqueue_counter: u32,
+    /// Last sizes of `send_array`.
+    pub last_send_array_sizes: [u32; CONGESTION_QUEUE_ARRAY_SIZE],
+    /// Last sent packets counts.
+    pub last_num_packets_sent: [u32; CONGESTION_LAST_SENT_ARRAY_SIZE],
+    /// Last resent packets counts.
+    pub last_num_packets_resent: [u32; CONGESTION_LAST_SENT_ARRAY_SIZE],
+    /// Congestion event is a time when we couldn't send all packets due to
+    /// slow connection.
+    pub last_congestion_event: Option<Instant>,
+    /// Estimated packets send rate.
+    pub packet_send_rate: f64,
+    /// Estimated requested packets send rate.
+    pub packet_send_rate_requested: f64,
+}
+
+impl Default for ToxCongestionController {
+    fn default() -> Self {
+        ToxCongestionController {
+            last_sendqueue_counter: 0,
+            last_send_array_sizes: [0; CONGESTION_QUEUE_ARRAY_SIZE],
+            last_num_packets_sent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
+            last_num_packets_resent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
+            last_congestion_event: None,
+            packet_send_rate: CRYPTO_PACKET_MIN_RATE,
+            packet_send_rate_requested: CRYPTO_PACKET_MIN_RATE,
+        }
+    }
+}
+
+impl CongestionController for ToxCongestionController {
+    fn name(&self) -> &'static str {
+        "tox"
+    }
+
+    fn update(&mut self, sample: &CongestionSample) {
+        let pos = self.last_sendqueue_counter as usize % CONGESTION_QUEUE_ARRAY_SIZE;
+        let n_p_pos = self.last_sendqueue_counter as usize % CONGESTION_LAST_SENT_ARRAY_SIZE;
+        self.last_sendqueue_counter = (self.last_sendqueue_counter + 1) %
+            // divide by the common multiple to prevent overflow
+            (CONGESTION_QUEUE_ARRAY_SIZE * CONGESTION_LAST_SENT_ARRAY_SIZE) as u32;
+
+        let send_array_len = sample.send_array_len;
+
+        self.last_send_array_sizes[pos] = send_array_len;
+        self.last_num_packets_sent[n_p_pos] = sample.packets_sent;
+        self.last_num_packets_resent[n_p_pos] = sample.packets_resent;
+
+        // How changed the size of send_array per CONGESTION_QUEUE_ARRAY_SIZE * PACKET_COUNTER_AVERAGE_INTERVAL interval
+        let sum = send_array_len as i32 - self.last_send_array_sizes[(pos + 1) % CONGESTION_QUEUE_ARRAY_SIZE] as i32;
+
+        // The maximum allowed delay
+        const CONGESTION_MAX_DELAY: usize = CONGESTION_LAST_SENT_ARRAY_SIZE - CONGESTION_QUEUE_ARRAY_SIZE;
+
+        // Based on rtt offset in number of positions for last_num_packets arrays (one position equals 50 ms)
+        let delay = ((
+            sample.rtt.as_secs() * 1000 +
+                u64::from(sample.rtt.subsec_millis()) +
+                PACKET_COUNTER_AVERAGE_INTERVAL_MS / 2 // add half of the interval to make delay rounded
+        ) / PACKET_COUNTER_AVERAGE_INTERVAL_MS) as usize;
+        let delay = delay.min(CONGESTION_MAX_DELAY);
+
+        // Total number of sent packets per CONGESTION_QUEUE_ARRAY_SIZE * PACKET_COUNTER_AVERAGE_INTERVAL interval
+        // For instance if the delay is 3 elements marked with '+' will be taken ('x' is the current pos):
+        // ...++++++++++++..x......
+        let mut total_sent = 0;
+        let mut total_resent = 0;
+        for i in 0 .. CONGESTION_QUEUE_ARRAY_SIZE {
+            let i = (n_p_pos + (CONGESTION_MAX_DELAY - delay) + i) % CONGESTION_LAST_SENT_ARRAY_SIZE;
+            total_sent += self.last_num_packets_sent[i] as i32;
+            total_resent += self.last_num_packets_resent[i] as i32;
+        }
+
+        if sum > 0 {
+            // send_array increased i.e. we sent more packets that was delivered
+            // decrease total_sent packets by this number so that it includes only delivered packets
+            total_sent -= sum;
+        } else if total_resent > -sum {
+            // send_array decreased and not all resent packets were delivered
+            // use this number to count only delivered packets
+            total_resent = -sum;
+        }
+
+        // Average number of successfully delivered packets per second
+        let coeff = 1000.0 / (CONGESTION_QUEUE_ARRAY_SIZE as f64 * PACKET_COUNTER_AVERAGE_INTERVAL_MS as f64);
+        let min_speed = (f64::from(total_sent) * coeff).max(CRYPTO_PACKET_MIN_RATE);
+        let min_speed_request = f64::from(total_sent + total_resent) * coeff;
+
+        // Time necessary to send all packets from send queue
+        let send_array_time = f64::from(send_array_len) / min_speed;
+
+        // And, finally, estimated packets send rate
+        let packet_send_rate = if send_array_time > SEND_QUEUE_CLEARANCE_TIME && send_array_len > CRYPTO_MIN_QUEUE_LENGTH {
+            // It will take more than SEND_QUEUE_CLEARANCE_TIME seconds to send
+            // all packets from send queue. Reduce packets send rate in this case
+            min_speed / (send_array_time / SEND_QUEUE_CLEARANCE_TIME)
+        } else if self.last_congestion_event.map_or(true, |time| (sample.now - time) > CONGESTION_EVENT_TIMEOUT) {
+            // Congestion event happened long ago so increase packets send rate
+            min_speed * 1.2
+        } else {
+            // Congestion event happened recently so decrease packets send rate
+            min_speed * 0.9
+        };
+        let packet_send_rate = packet_send_rate.max(CRYPTO_PACKET_MIN_RATE);
+        let packet_send_rate_requested = min_speed_request * 1.2;
+        let packet_send_rate_requested = packet_send_rate_requested.max(packet_send_rate);
+
+        self.packet_send_rate = packet_send_rate;
+        self.packet_send_rate_requested = packet_send_rate_requested;
+    }
+
+    fn send_rate(&self) -> f64 {
+        self.packet_send_rate
+    }
+
+    fn send_rate_requested(&self) -> f64 {
+        self.packet_send_rate_requested
+    }
+}
+
+/** LEDBAT-like congestion controller driven by queuing delay.
+
+The lowest delay between sending a packet and receiving its acknowledgement
+is considered as the delay of the path without queues. The difference between
+the current delay and the lowest one is a queuing delay. Send rate grows while
+the queuing delay is lower than `DELAY_TARGET` and decreases when it's higher.
+Unlike `ToxCongestionController` it doesn't depend on the number of packets
+delivered during the last RTT so it doesn't underuse links with high latency.
+
+Send rate grows only when the connection actually sends at least half of the
+allowed rate to avoid unlimited growth while the connection is idle.
+
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct DelayCongestionController {
+    /// Estimated packets send rate.
+    pub packet_send_rate: f64,
+    /// The lowest delay since `base_delay_time`.
+    pub base_delay: Option<Duration>,
+    /// The lowest delay since the last `BASE_DELAY_INTERVAL` started. It
+    /// replaces `base_delay` when the interval ends.
+    pub next_base_delay: Option<Duration>,
+    /// Time when the current `BASE_DELAY_INTERVAL` started.
+    pub base_delay_time: Option<Instant>,
+    /// The lowest delay received since the last update.
+    pub current_delay: Option<Duration>,
+    /// Queuing delay calculated during the last update.
+    pub queuing_delay: Duration,
+    /// Time of the last update.
+    pub last_update: Option<Instant>,
+}
+
+impl Default for DelayCongestionController {
+    fn default() -> Self {
+        DelayCongestionController {
+            packet_send_rate: CRYPTO_PACKET_MIN_RATE,
+            base_delay: None,
+            next_base_delay: None,
+            base_delay_time: None,
+            current_delay: None,
+            queuing_delay: Duration::from_secs(0),
+            last_update: None,
+        }
+    }
+}
+
+impl DelayCongestionController {
+    /// Forget the lowest delay if `BASE_DELAY_INTERVAL` is elapsed.
+    fn rotate_base_delay(&mut self, now: Instant) {
+        let base_delay_time = *self.base_delay_time.get_or_insert(now);
+        if now - base_delay_time >= BASE_DELAY_INTERVAL {
+            self.base_delay = self.next_base_delay.take().or(self.base_delay);
+            self.base_delay_time = Some(now);
+        }
+    }
+}
+
+impl CongestionController for DelayCongestionController {
+    fn name(&self) -> &'static str {
+        "delay"
+    }
+
+    fn update(&mut self, sample: &CongestionSample) {
+        let dt = self.last_update.map_or(PACKET_COUNTER_AVERAGE_INTERVAL, |time| sample.now - time);
+        let dt = dt.as_secs() as f64 + f64::from(dt.subsec_millis()) / 1000.0;
+        self.last_update = Some(sample.now);
+        self.rotate_base_delay(sample.now);
+
+        let current_delay = match self.current_delay.take() {
+            Some(current_delay) => current_delay,
+            // no packets were acknowledged so we don't know anything new about the path
+            None => return,
+        };
+        let base_delay = self.base_delay.unwrap_or(current_delay);
+        self.queuing_delay = current_delay - base_delay.min(current_delay);
+
+        let target = DELAY_TARGET.as_secs() as f64 + f64::from(DELAY_TARGET.subsec_millis()) / 1000.0;
+        let queuing_delay = self.queuing_delay.as_secs() as f64 + f64::from(self.queuing_delay.subsec_millis()) / 1000.0;
+        let off_target = ((target - queuing_delay) / target).max(-1.0);
+
+        let used_rate = f64::from(sample.packets_sent + sample.packets_resent) / dt.max(f64::EPSILON);
+        if off_target > 0.0 && used_rate * 2.0 < self.packet_send_rate {
+            // the connection doesn't use the allowed rate so we can't check
+            // whether the path can handle a higher one
+            return;
+        }
+
+        let packet_send_rate = self.packet_send_rate * (1.0 + DELAY_GAIN * off_target * dt.min(1.0));
+        self.packet_send_rate = packet_send_rate.max(CRYPTO_PACKET_MIN_RATE);
+    }
+
+    fn on_delay_sample(&mut self, delay: Duration) {
+        self.current_delay = Some(self.current_delay.map_or(delay, |current| current.min(delay)));
+        self.base_delay = Some(self.base_delay.map_or(delay, |base| base.min(delay)));
+        self.next_base_delay = Some(self.next_base_delay.map_or(delay, |base| base.min(delay)));
+    }
+
+    fn send_rate(&self) -> f64 {
+        self.packet_send_rate
+    }
+
+    fn send_rate_requested(&self) -> f64 {
+        self.packet_send_rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::toxcore::time::*;
+
+    fn sample(now: Instant, send_array_len: u32, packets_sent: u32) -> CongestionSample {
+        CongestionSample {
+            now,
+            rtt: Duration::from_millis(50),
+            send_array_len,
+            packets_sent,
+            packets_resent: 0,
+        }
+    }
+
+    #[test]
+    fn congestion_control_clone_and_eq() {
+        let control = CongestionControl::default();
+        let control_c = control.clone();
+        assert_eq!(control_c.name(), "tox");
+        assert_eq!(control_c, control);
+
+        // controllers of the same kind are equal regardless of their state
+        let updated = CongestionControl::new(Box::new(ToxCongestionController {
+            packet_send_rate: 1000.0,
+            .. ToxCongestionController::default()
+        }));
+        assert_eq!(updated, control);
+
+        let other = CongestionControl::new(Box::new(DelayCongestionController::default()));
+        assert_ne!(other, control);
+    }
+
+    #[test]
+    fn tox_increases_rate_when_packets_are_delivered() {
+        let mut controller = ToxCongestionController::default();
+        let now = clock_now();
+
+        for i in 0 .. CONGESTION_LAST_SENT_ARRAY_SIZE as u32 {
+            controller.update(&sample(now + PACKET_COUNTER_AVERAGE_INTERVAL * i, 0, 10));
+        }
+
+        // 10 packets per 50 ms are delivered
+        assert!((controller.send_rate() - 240.0).abs() < 0.001);
+        assert!(controller.send_rate_requested() >= controller.send_rate());
+    }
+
+    #[test]
+    fn tox_decreases_rate_after_congestion_event() {
+        let mut controller = ToxCongestionController::default();
+        let now = clock_now();
+
+        for i in 0 .. CONGESTION_LAST_SENT_ARRAY_SIZE as u32 {
+            controller.update(&sample(now + PACKET_COUNTER_AVERAGE_INTERVAL * i, 0, 10));
+        }
+        let now = now + PACKET_COUNTER_AVERAGE_INTERVAL * CONGESTION_LAST_SENT_ARRAY_SIZE as u32;
+        controller.last_congestion_event = Some(now);
+        controller.update(&sample(now, 0, 10));
+
+        assert!((controller.send_rate() - 180.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn delay_increases_rate_without_queuing_delay() {
+        let mut controller = DelayCongestionController::default();
+        let now = clock_now();
+
+        for i in 0 .. 20 {
+            controller.on_delay_sample(Duration::from_millis(300));
+            let rate = controller.send_rate() as u32;
+            controller.update(&sample(now + PACKET_COUNTER_AVERAGE_INTERVAL * i, 0, rate));
+        }
+
+        assert_eq!(controller.queuing_delay, Duration::from_secs(0));
+        assert!(controller.send_rate() > CRYPTO_PACKET_MIN_RATE * 5.0);
+    }
+
+    #[test]
+    fn delay_decreases_rate_with_queuing_delay() {
+        let mut controller = DelayCongestionController {
+            packet_send_rate: 1000.0,
+            .. DelayCongestionController::default()
+        };
+        let now = clock_now();
+
+        controller.on_delay_sample(Duration::from_millis(300));
+        controller.update(&sample(now, 0, 50));
+        assert!(controller.send_rate() > 1000.0);
+
+        let rate = controller.send_rate();
+        controller.on_delay_sample(Duration::from_millis(600));
+        controller.update(&sample(now + PACKET_COUNTER_AVERAGE_INTERVAL, 0, 50));
+
+        assert_eq!(controller.queuing_delay, Duration::from_millis(300));
+        assert!(controller.send_rate() < rate);
+    }
+
+    #[test]
+    fn delay_does_not_increase_rate_when_idle() {
+        let mut controller = DelayCongestionController {
+            packet_send_rate: 1000.0,
+            .. DelayCongestionController::default()
+        };
+        let now = clock_now();
+
+        controller.on_delay_sample(Duration::from_millis(300));
+        controller.update(&sample(now, 0, 1));
+
+        assert!((controller.send_rate() - 1000.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn delay_forgets_base_delay() {
+        let mut controller = DelayCongestionController::default();
+        let now = clock_now();
+
+        controller.on_delay_sample(Duration::from_millis(100));
+        controller.update(&sample(now, 0, 4));
+        controller.update(&sample(now + BASE_DELAY_INTERVAL, 0, 4));
+        assert_eq!(controller.base_delay, Some(Duration::from_millis(100)));
+
+        // the route has changed and the delay has become bigger
+        controller.on_delay_sample(Duration::from_millis(300));
+        controller.update(&sample(now + BASE_DELAY_INTERVAL + PACKET_COUNTER_AVERAGE_INTERVAL, 0, 4));
+        assert_eq!(controller.base_delay, Some(Duration::from_millis(100)));
+        controller.update(&sample(now + BASE_DELAY_INTERVAL * 2, 0, 4));
+
+        assert_eq!(controller.base_delay, Some(Duration::from_millis(300)));
+    }
+}
@@ -4,6 +4,9 @@ use std::convert::Into;
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::time::{Duration, Instant};
 
+use bytes::Bytes;
+
+use super::congestion::*;
 use super::packets_array::*;
 
 use crate::toxcore::dht::ip_port::IsGlobal;
@@ -25,6 +28,10 @@ pub const MAX_NUM_SENDPACKET_TRIES: u8 = 8;
 /// connection is considered dead
 pub const UDP_DIRECT_TIMEOUT: Duration = Duration::from_secs(8);
 
+/// If we don't receive packets via a TCP relay for this amount of time the
+/// relay is not considered used by the connection anymore
+pub const TCP_RELAY_TIMEOUT: Duration = Duration::from_secs(8);
+
 /// Default RTT (round trip time)
 pub const DEFAULT_RTT: Duration = Duration::from_millis(1000);
 
@@ -37,27 +44,9 @@ pub const PACKET_COUNTER_AVERAGE_INTERVAL_MS: u64 = 50;
 /// The dT for the average packet receiving rate calculations.
 pub const PACKET_COUNTER_AVERAGE_INTERVAL: Duration = Duration::from_millis(PACKET_COUNTER_AVERAGE_INTERVAL_MS);
 
-/// How many last sizes of `send_array` should be recorded for congestion
-/// control.
-pub const CONGESTION_QUEUE_ARRAY_SIZE: usize = 12;
-
-/// How many numbers of sent lossless packets should be recorded for congestion
-/// control. It should be bigger than `CONGESTION_QUEUE_ARRAY_SIZE` due to rtt.
-pub const CONGESTION_LAST_SENT_ARRAY_SIZE: usize = CONGESTION_QUEUE_ARRAY_SIZE * 2;
-
 /// Minimum packets rate per second.
 pub const CRYPTO_PACKET_MIN_RATE: f64 = 4.0;
 
-/// Timeout for increasing speed after congestion event.
-pub const CONGESTION_EVENT_TIMEOUT: Duration = Duration::from_secs(1);
-
-/// If the send queue grows so that it will take more than 2 seconds to send all
-/// its packet we will reduce send rate.
-pub const SEND_QUEUE_CLEARANCE_TIME: f64 = 2.0;
-
-/// Minimum packets in the send queue to reduce send rate.
-pub const CRYPTO_MIN_QUEUE_LENGTH: u32 = 64;
-
 /// Ratio of recv queue size / recv packet rate (in seconds) times
 /// the number of ms between request packets to send at that ratio.
 pub const REQUEST_PACKETS_COMPARE_CONSTANT: f64 = 0.125 * 100.0;
@@ -212,12 +201,12 @@ impl SentPacket {
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct RecvPacket {
     /// Packet data
-    pub data: Vec<u8>
+    pub data: Bytes
 }
 
 impl RecvPacket {
     /// Create new `RecvPacket`
-    pub fn new(data: Vec<u8>) -> RecvPacket {
+    pub fn new(data: Bytes) -> RecvPacket {
         RecvPacket {
             data
         }
@@ -253,6 +242,73 @@ impl<T: Into<SocketAddr> + Copy> ConnectionAddr<T> {
     }
 }
 
+/// Transport that is currently used to send packets to the peer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionTransport {
+    /// Direct UDP connection via IPv4 address.
+    UdpV4(SocketAddrV4),
+    /// Direct UDP connection via IPv6 address.
+    UdpV6(SocketAddrV6),
+    /// Connection via TCP relay with the given `PublicKey`. It's the relay
+    /// the last packet from the peer was received through.
+    TcpRelay(PublicKey),
+}
+
+/// Policy that defines which transports can be used to send packets to the
+/// peer.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TransportPolicy {
+    /// Send packets via alive UDP address when it's known and via TCP relays
+    /// otherwise. Small packets are occasionally sent via UDP to check if it
+    /// became alive.
+    #[default]
+    Auto,
+    /// Like `Auto` but every packet is also sent via UDP when UDP address is
+    /// known but not alive, so direct connection is established as soon as
+    /// possible.
+    PreferDirect,
+    /// Send and receive packets via TCP relays only. Packets received from
+    /// the peer via UDP are dropped.
+    ///
+    /// This policy gives no IP address privacy. The peer learns our DHT
+    /// `PublicKey` from `DhtPkAnnounce` packets, which it needs to connect to
+    /// us via TCP relays, and can find our IP address in DHT with it. The
+    /// policy only defines which transports are used.
+    RelayOnly,
+    /// Send packets via UDP only. TCP relays are never used.
+    UdpOnly,
+}
+
+/// Transport statistics of a crypto connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectionInfo {
+    /// Whether the connection is established.
+    pub is_established: bool,
+    /// Transport that is currently used to send packets. `None` if no
+    /// transport is known to work yet or all working transports are forbidden
+    /// by the transport policy.
+    pub transport: Option<ConnectionTransport>,
+    /// Round trip time - the lowest difference between time when a packet
+    /// was sent and time when we received the confirmation.
+    pub rtt: Duration,
+    /// Estimated lossless packets send rate per second.
+    pub send_rate: f64,
+    /// Rate of receiving lossless packets per second.
+    pub recv_rate: f64,
+    /// Total number of sent lossless packets not including resent packets.
+    pub packets_sent: u64,
+    /// Total number of resent lossless packets.
+    pub packets_resent: u64,
+    /// Ratio of resent lossless packets to sent ones.
+    pub retransmission_ratio: f64,
+    /// Number of sent packets that are not confirmed by the peer yet.
+    pub send_queue: u32,
+    /// Number of packets in the receive buffer including missing ones.
+    pub recv_queue: u32,
+    /// Time since the connection became established.
+    pub since_handshake: Option<Duration>,
+}
+
 /** Secure connection to send data between two friends that provides encryption,
 ordered delivery, and perfect forward secrecy.
 
@@ -298,23 +354,27 @@ pub struct CryptoConnection {
     pub packets_sent: u32,
     /// Number of resent lossless packets.
     pub packets_resent: u32,
-    /// Current position in `last_send_array_sizes` and `last_num_packets` arrays.
-    pub last_sendqueue_counter: u32,
-    /// Last sizes of `send_array`.
-    pub last_send_array_sizes: [u32; CONGESTION_QUEUE_ARRAY_SIZE],
-    /// Last sent packets counts.
-    pub last_num_packets_sent: [u32; CONGESTION_LAST_SENT_ARRAY_SIZE],
-    /// Last resent packets counts.
-    pub last_num_packets_resent: [u32; CONGESTION_LAST_SENT_ARRAY_SIZE],
-    /// Congestion event is a time when we couldn't send all packets due to
-    /// slow connection.
-    pub last_congestion_event: Option<Instant>,
     /// Rate of receiving lossless packets.
     pub packet_recv_rate: f64,
-    /// Estimated packets send rate.
-    pub packet_send_rate: f64,
-    /// Estimated requested packets send rate.
-    pub packet_send_rate_requested: f64,
+    /// Algorithm that estimates send rate of this connection.
+    pub congestion: CongestionControl,
+    /// Transports that can be used to send packets to the peer.
+    pub transport_policy: TransportPolicy,
+    /// The last transport reported to the transport sink.
+    pub reported_transport: Option<ConnectionTransport>,
+    /// `PublicKey` of the TCP relay the last packet from the peer was
+    /// received through with the time when it was received.
+    pub tcp_relay: Option<(PublicKey, Instant)>,
+
+    // Total stats of the connection
+
+    /// Total number of sent lossless packets. It does not include resent
+    /// packets.
+    pub total_packets_sent: u64,
+    /// Total number of resent lossless packets.
+    pub total_packets_resent: u64,
+    /// Time when the connection became established.
+    pub established_time: Option<Instant>,
 }
 
 impl CryptoConnection {
@@ -351,14 +411,14 @@ impl CryptoConnection {
             packets_received: 0,
             packets_sent: 0,
             packets_resent: 0,
-            last_sendqueue_counter: 0,
-            last_send_array_sizes: [0; CONGESTION_QUEUE_ARRAY_SIZE],
-            last_num_packets_sent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
-            last_num_packets_resent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
-            last_congestion_event: None,
             packet_recv_rate: 0.0,
-            packet_send_rate: CRYPTO_PACKET_MIN_RATE,
-            packet_send_rate_requested: CRYPTO_PACKET_MIN_RATE,
+            congestion: CongestionControl::default(),
+            transport_policy: TransportPolicy::default(),
+            reported_transport: None,
+            tcp_relay: None,
+            total_packets_sent: 0,
+            total_packets_resent: 0,
+            established_time: None,
         }
     }
 
@@ -410,14 +470,14 @@ impl CryptoConnection {
             packets_received: 0,
             packets_sent: 0,
             packets_resent: 0,
-            last_sendqueue_counter: 0,
-            last_send_array_sizes: [0; CONGESTION_QUEUE_ARRAY_SIZE],
-            last_num_packets_sent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
-            last_num_packets_resent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
-            last_congestion_event: None,
             packet_recv_rate: 0.0,
-            packet_send_rate: CRYPTO_PACKET_MIN_RATE,
-            packet_send_rate_requested: CRYPTO_PACKET_MIN_RATE,
+            congestion: CongestionControl::default(),
+            transport_policy: TransportPolicy::default(),
+            reported_transport: None,
+            tcp_relay: None,
+            total_packets_sent: 0,
+            total_packets_resent: 0,
+            established_time: None,
         }
     }
 
@@ -506,6 +566,19 @@ impl CryptoConnection {
         None
     }
 
+    /// Set TCP relay we received a packet from the peer through.
+    pub fn set_tcp_relay(&mut self, relay_pk: PublicKey) {
+        self.tcp_relay = Some((relay_pk, clock_now()));
+    }
+
+    /// Get TCP relay we received the last packet from the peer through if it
+    /// happened not later than 8 seconds ago.
+    pub fn get_tcp_relay(&self) -> Option<PublicKey> {
+        self.tcp_relay
+            .filter(|&(_, time)| clock_elapsed(time) < TCP_RELAY_TIMEOUT)
+            .map(|(relay_pk, _)| relay_pk)
+    }
+
     /// Set time when we made an attempt to send UDP packet
     pub fn update_udp_send_attempt_time(&mut self) {
         self.udp_send_attempt_time = Some(clock_now())
@@ -531,81 +604,16 @@ impl CryptoConnection {
         self.packet_recv_rate = f64::from(self.packets_received) / (dt.as_secs() as f64 + f64::from(dt.subsec_millis()) / 1000.0);
     }
 
-    /// Calculate packets send rate.
+    /// Update send rate estimated by the congestion controller.
     fn calculate_send_rate(&mut self, now: Instant) {
-        let pos = self.last_sendqueue_counter as usize % CONGESTION_QUEUE_ARRAY_SIZE;
-        let n_p_pos = self.last_sendqueue_counter as usize % CONGESTION_LAST_SENT_ARRAY_SIZE;
-        self.last_sendqueue_counter = (self.last_sendqueue_counter + 1) %
-            // divide by the common multiple to prevent overflow
-            (CONGESTION_QUEUE_ARRAY_SIZE * CONGESTION_LAST_SENT_ARRAY_SIZE) as u32;
-
-        let send_array_len = self.send_array.len();
-
-        self.last_send_array_sizes[pos] = send_array_len;
-        self.last_num_packets_sent[n_p_pos] = self.packets_sent;
-        self.last_num_packets_resent[n_p_pos] = self.packets_resent;
-
-        // How changed the size of send_array per CONGESTION_QUEUE_ARRAY_SIZE * PACKET_COUNTER_AVERAGE_INTERVAL interval
-        let sum = send_array_len as i32 - self.last_send_array_sizes[(pos + 1) % CONGESTION_QUEUE_ARRAY_SIZE] as i32;
-
-        // The maximum allowed delay
-        const CONGESTION_MAX_DELAY: usize = CONGESTION_LAST_SENT_ARRAY_SIZE - CONGESTION_QUEUE_ARRAY_SIZE;
-
-        // Based on rtt offset in number of positions for last_num_packets arrays (one position equals 50 ms)
-        let delay = ((
-            self.rtt.as_secs() * 1000 +
-                u64::from(self.rtt.subsec_millis()) +
-                PACKET_COUNTER_AVERAGE_INTERVAL_MS / 2 // add half of the interval to make delay rounded
-        ) / PACKET_COUNTER_AVERAGE_INTERVAL_MS) as usize;
-        let delay = delay.min(CONGESTION_MAX_DELAY);
-
-        // Total number of sent packets per CONGESTION_QUEUE_ARRAY_SIZE * PACKET_COUNTER_AVERAGE_INTERVAL interval
-        // For instance if the delay is 3 elements marked with '+' will be taken ('x' is the current pos):
-        // ...++++++++++++..x......
-        let mut total_sent = 0;
-        let mut total_resent = 0;
-        for i in 0 .. CONGESTION_QUEUE_ARRAY_SIZE {
-            let i = (n_p_pos + (CONGESTION_MAX_DELAY - delay) + i) % CONGESTION_LAST_SENT_ARRAY_SIZE;
-            total_sent += self.last_num_packets_sent[i] as i32;
-            total_resent += self.last_num_packets_resent[i] as i32;
-        }
-
-        if sum > 0 {
-            // send_array increased i.e. we sent more packets that was delivered
-            // decrease total_sent packets by this number so that it includes only delivered packets
-            total_sent -= sum;
-        } else if total_resent > -sum {
-            // send_array decreased and not all resent packets were delivered
-            // use this number to count only delivered packets
-            total_resent = -sum;
-        }
-
-        // Average number of successfully delivered packets per second
-        let coeff = 1000.0 / (CONGESTION_QUEUE_ARRAY_SIZE as f64 * PACKET_COUNTER_AVERAGE_INTERVAL_MS as f64);
-        let min_speed = (f64::from(total_sent) * coeff).max(CRYPTO_PACKET_MIN_RATE);
-        let min_speed_request = f64::from(total_sent + total_resent) * coeff;
-
-        // Time necessary to send all packets from send queue
-        let send_array_time = f64::from(send_array_len) / min_speed;
-
-        // And, finally, estimated packets send rate
-        let packet_send_rate = if send_array_time > SEND_QUEUE_CLEARANCE_TIME && send_array_len > CRYPTO_MIN_QUEUE_LENGTH {
-            // It will take more than SEND_QUEUE_CLEARANCE_TIME seconds to send
-            // all packets from send queue. Reduce packets send rate in this case
-            min_speed / (send_array_time / SEND_QUEUE_CLEARANCE_TIME)
-        } else if self.last_congestion_event.map_or(true, |time| (now - time) > CONGESTION_EVENT_TIMEOUT) {
-            // Congestion event happened long ago so increase packets send rate
-            min_speed * 1.2
-        } else {
-            // Congestion event happened recently so decrease packets send rate
-            min_speed * 0.9
+        let sample = CongestionSample {
+            now,
+            rtt: self.rtt,
+            send_array_len: self.send_array.len(),
+            packets_sent: self.packets_sent,
+            packets_resent: self.packets_resent,
         };
-        let packet_send_rate = packet_send_rate.max(CRYPTO_PACKET_MIN_RATE);
-        let packet_send_rate_requested = min_speed_request * 1.2;
-        let packet_send_rate_requested = packet_send_rate_requested.max(packet_send_rate);
-
-        self.packet_send_rate = packet_send_rate;
-        self.packet_send_rate_requested = packet_send_rate_requested;
+        self.congestion.update(&sample);
     }
 
     /// Reset congestion counters after they were used for stats calculation.
@@ -624,6 +632,80 @@ impl CryptoConnection {
         self.reset_congestion_counters(now);
     }
 
+    /// Estimated packets send rate.
+    pub fn packet_send_rate(&self) -> f64 {
+        self.congestion.send_rate()
+    }
+
+    /// Estimated requested packets send rate.
+    pub fn packet_send_rate_requested(&self) -> f64 {
+        self.congestion.send_rate_requested()
+    }
+
+    /// Transport that is currently used to send packets to the peer. Alive
+    /// UDP address is preferred, TCP relay we recently received packets
+    /// through is used otherwise. Returns `None` if no transport is known to
+    /// work or the transport policy forbids all working transports.
+    pub fn transport(&self) -> Option<ConnectionTransport> {
+        let udp_transport = match self.get_udp_addr() {
+            Some(SocketAddr::V4(addr)) => Some(ConnectionTransport::UdpV4(addr)),
+            Some(SocketAddr::V6(addr)) => Some(ConnectionTransport::UdpV6(addr)),
+            None => None,
+        };
+        let tcp_transport = self.get_tcp_relay().map(ConnectionTransport::TcpRelay);
+        match self.transport_policy {
+            TransportPolicy::Auto | TransportPolicy::PreferDirect if self.is_udp_alive() => udp_transport,
+            TransportPolicy::Auto | TransportPolicy::PreferDirect | TransportPolicy::RelayOnly => tcp_transport,
+            TransportPolicy::UdpOnly => udp_transport,
+        }
+    }
+
+    /// Check if packets can be sent to the peer via UDP.
+    pub fn udp_allowed(&self) -> bool {
+        self.transport_policy != TransportPolicy::RelayOnly
+    }
+
+    /// Check if packets can be sent to the peer via TCP relays.
+    pub fn tcp_allowed(&self) -> bool {
+        self.transport_policy != TransportPolicy::UdpOnly
+    }
+
+    /// Collect transport statistics of this connection.
+    pub fn info(&self) -> ConnectionInfo {
+        let retransmission_ratio = if self.total_packets_sent == 0 {
+            0.0
+        } else {
+            self.total_packets_resent as f64 / self.total_packets_sent as f64
+        };
+
+        ConnectionInfo {
+            is_established: self.is_established(),
+            transport: self.transport(),
+            rtt: self.rtt,
+            send_rate: self.packet_send_rate(),
+            recv_rate: self.packet_recv_rate,
+            packets_sent: self.total_packets_sent,
+            packets_resent: self.total_packets_resent,
+            retransmission_ratio,
+            send_queue: self.send_array.len(),
+            recv_queue: self.recv_array.len(),
+            since_handshake: self.established_time.map(clock_elapsed),
+        }
+    }
+
+    /// Check if a new lossless packet can be sent without exceeding the send
+    /// rate estimated by the congestion controller. At least one packet per
+    /// `PACKET_COUNTER_AVERAGE_INTERVAL` is always allowed.
+    pub fn can_send_lossless(&self) -> bool {
+        if self.send_array.len() >= CRYPTO_PACKET_BUFFER_SIZE {
+            return false;
+        }
+
+        let interval = PACKET_COUNTER_AVERAGE_INTERVAL_MS as f64 / 1000.0;
+        let max_packets = (self.packet_send_rate() * interval).ceil().max(1.0);
+        f64::from(self.packets_sent) < max_packets
+    }
+
     /// Calculate the interval in ms for request packet.
     pub fn request_packet_interval(&self) -> Duration {
         let request_packet_interval = REQUEST_PACKETS_COMPARE_CONSTANT / ((f64::from(self.recv_array.len()) + 1.0) / (self.packet_recv_rate + 1.0));
@@ -721,7 +803,7 @@ mod tests {
     #[test]
     fn recv_packet_clone() {
         crypto_init().unwrap();
-        let recv_packet = RecvPacket::new(vec![42; 123]);
+        let recv_packet = RecvPacket::new(Bytes::from(vec![42; 123]));
         let recv_packet_c = recv_packet.clone();
         assert_eq!(recv_packet_c, recv_packet);
     }
@@ -836,6 +918,152 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn info() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        tokio::time::pause();
+
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            session_precomputed_key: precompute(&gen_keypair().0, &gen_keypair().1),
+        };
+        connection.established_time = Some(clock_now());
+        let addr = "[::1]:12345".parse().unwrap();
+        connection.set_udp_addr(SocketAddr::V6(addr));
+        connection.total_packets_sent = 100;
+        connection.total_packets_resent = 25;
+        connection.recv_array.buffer_end = 3;
+        connection.packet_recv_rate = 10.0;
+
+        tokio::time::advance(UDP_DIRECT_TIMEOUT).await;
+
+        let info = connection.info();
+        assert!(info.is_established);
+        // UDP address is not alive anymore and no packets were received via
+        // TCP relays
+        assert_eq!(info.transport, None);
+        assert_eq!(info.rtt, DEFAULT_RTT);
+        assert_eq!(info.send_rate, CRYPTO_PACKET_MIN_RATE);
+        assert_eq!(info.recv_rate, 10.0);
+        assert_eq!(info.packets_sent, 100);
+        assert_eq!(info.packets_resent, 25);
+        assert_eq!(info.retransmission_ratio, 0.25);
+        assert_eq!(info.send_queue, 0);
+        assert_eq!(info.recv_queue, 3);
+        assert_eq!(info.since_handshake, Some(UDP_DIRECT_TIMEOUT));
+
+        let (relay_pk, _relay_sk) = gen_keypair();
+        connection.set_tcp_relay(relay_pk);
+        assert_eq!(connection.info().transport, Some(ConnectionTransport::TcpRelay(relay_pk)));
+
+        connection.set_udp_addr(SocketAddr::V6(addr));
+        assert_eq!(connection.info().transport, Some(ConnectionTransport::UdpV6(addr)));
+    }
+
+    #[tokio::test]
+    async fn tcp_relay_timeout() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        assert_eq!(connection.get_tcp_relay(), None);
+
+        tokio::time::pause();
+
+        let (relay_pk, _relay_sk) = gen_keypair();
+        connection.set_tcp_relay(relay_pk);
+        assert_eq!(connection.get_tcp_relay(), Some(relay_pk));
+
+        tokio::time::advance(TCP_RELAY_TIMEOUT).await;
+
+        assert_eq!(connection.get_tcp_relay(), None);
+        assert_eq!(connection.transport(), None);
+    }
+
+    #[tokio::test]
+    async fn transport_policy() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        connection.transport_policy = TransportPolicy::UdpOnly;
+        assert_eq!(connection.transport(), None);
+        assert!(connection.udp_allowed());
+        assert!(!connection.tcp_allowed());
+
+        tokio::time::pause();
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(SocketAddr::V4(addr));
+
+        tokio::time::advance(UDP_DIRECT_TIMEOUT).await;
+
+        // dead UDP address is still the only transport
+        assert_eq!(connection.transport(), Some(ConnectionTransport::UdpV4(addr)));
+
+        connection.transport_policy = TransportPolicy::PreferDirect;
+        assert_eq!(connection.transport(), None);
+
+        let (relay_pk, _relay_sk) = gen_keypair();
+        connection.set_tcp_relay(relay_pk);
+        assert_eq!(connection.transport(), Some(ConnectionTransport::TcpRelay(relay_pk)));
+
+        connection.set_udp_addr(SocketAddr::V4(addr));
+        assert_eq!(connection.transport(), Some(ConnectionTransport::UdpV4(addr)));
+
+        connection.transport_policy = TransportPolicy::RelayOnly;
+        assert_eq!(connection.transport(), Some(ConnectionTransport::TcpRelay(relay_pk)));
+        assert!(!connection.udp_allowed());
+        assert!(connection.tcp_allowed());
+    }
+
+    #[test]
+    fn can_send_lossless() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        // at least one packet per interval is allowed
+        assert!(connection.can_send_lossless());
+        connection.packets_sent = 1;
+        assert!(!connection.can_send_lossless());
+
+        // 10 packets per interval are allowed
+        connection.congestion = CongestionControl::new(Box::new(ToxCongestionController {
+            packet_send_rate: 200.0,
+            .. ToxCongestionController::default()
+        }));
+        connection.packets_sent = 9;
+        assert!(connection.can_send_lossless());
+        connection.packets_sent = 10;
+        assert!(!connection.can_send_lossless());
+
+        // send array is full
+        connection.packets_sent = 0;
+        connection.send_array.buffer_end = CRYPTO_PACKET_BUFFER_SIZE;
+        assert!(!connection.can_send_lossless());
+    }
+
     #[test]
     fn is_established() {
         crypto_init().unwrap();
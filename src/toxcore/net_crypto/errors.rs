@@ -2,7 +2,7 @@
 Module for errors of NetCrypto.
 */
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
 use failure::Fail;
 
@@ -141,6 +141,27 @@ error_kind! {
             #[doc = "The packet id that is invalid."]
             id: u8,
         },
+        #[doc = "Error indicates that the maximum number of connections is reached."]
+        #[fail(display = "Too many crypto connections")]
+        TooManyConnections,
+        #[doc = "Error indicates that UDP packet was received for the connection with relay only transport policy."]
+        #[fail(display = "UDP is not allowed for the connection")]
+        UdpNotAllowed,
+        #[doc = "Error indicates that too many handshakes were received for the friend recently."]
+        #[fail(display = "Handshakes rate limit is exceeded")]
+        HandshakeRateLimited,
+        #[doc = "Error indicates that too many cookie requests were received from the address recently."]
+        #[fail(display = "Cookie requests rate limit is exceeded for address: {:?}", ip)]
+        CookieRequestRateLimited {
+            #[doc = "The address that sent too many cookie requests."]
+            ip: IpAddr,
+        },
+        #[doc = "Error indicates that not established connection received too many packets."]
+        #[fail(display = "Too many packets for not established connection: {:?}", packet_number)]
+        TooManyUnconfirmedPackets {
+            #[doc = "The number of the packet that can't be buffered."]
+            packet_number: u32,
+        },
     }
 }
 
@@ -169,6 +190,18 @@ impl HandlePacketError {
             id,
         })
     }
+
+    pub(crate) fn cookie_request_rate_limited(ip: IpAddr) -> HandlePacketError {
+        HandlePacketError::from(HandlePacketErrorKind::CookieRequestRateLimited {
+            ip,
+        })
+    }
+
+    pub(crate) fn too_many_unconfirmed_packets(packet_number: u32) -> HandlePacketError {
+        HandlePacketError::from(HandlePacketErrorKind::TooManyUnconfirmedPackets {
+            packet_number,
+        })
+    }
 }
 
 error_kind! {
@@ -187,6 +220,9 @@ error_kind! {
         #[doc = "Error indicates that sending connection status error."]
         #[fail(display = "Sending connection status error")]
         SendToConnectionStatus,
+        #[doc = "Error indicates that sending connection transport error."]
+        #[fail(display = "Sending connection transport error")]
+        SendToTransport,
     }
 }
 
@@ -0,0 +1,275 @@
+/*! Limits that protect `NetCrypto` from resource exhaustion by malicious
+peers.
+*/
+
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+
+use crate::toxcore::crypto_core::PublicKey;
+
+/// Default maximum number of crypto connections that can be created by
+/// incoming handshakes.
+pub const MAX_CRYPTO_CONNECTIONS: usize = 1024;
+
+/// Default maximum number of incoming handshakes for new connections that are
+/// handled per second for each friend.
+pub const MAX_HANDSHAKES_PER_SECOND: u32 = 8;
+
+/// Default maximum number of cookie requests per second from one IP address.
+/// IPv6 addresses are counted by their /64 prefix.
+pub const MAX_COOKIE_REQUESTS_PER_IP: u32 = 8;
+
+/// Default maximum number of lossless packets that can be buffered in
+/// `recv_array` of a connection that is not established yet.
+pub const MAX_UNCONFIRMED_RECV_PACKETS: u32 = 64;
+
+/// Number of IP addresses for which cookie requests are counted. When more
+/// addresses send requests they share a single counter until counters of the
+/// least recently seen addresses expire.
+pub const COOKIE_REQUEST_ADDRS_CAPACITY: usize = 4096;
+
+/// Number of friends for which handshakes are counted.
+const HANDSHAKE_KEYS_CAPACITY: usize = MAX_CRYPTO_CONNECTIONS;
+
+/// Interval during which rate limited events are counted.
+const RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Limits for incoming crypto connections and packets.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NetCryptoLimits {
+    /// Maximum number of crypto connections. New connections are not created
+    /// by incoming handshakes when it's reached. Connections added with
+    /// `add_connection` are not limited.
+    pub max_connections: usize,
+    /// Maximum number of incoming handshakes for new connections handled per
+    /// second for each friend. Handshakes are counted after their cookie is
+    /// checked so that junk handshakes don't affect friends, and exceeding
+    /// handshakes are dropped before decryption of their payload.
+    pub max_handshakes_per_second: u32,
+    /// Maximum number of cookie requests per second from one IP address.
+    /// IPv6 addresses are counted by their /64 prefix. Cookie requests
+    /// received via TCP relays are not limited since the relay hides the
+    /// address of the sender.
+    pub max_cookie_requests_per_ip: u32,
+    /// Maximum number of lossless packets that can be buffered in
+    /// `recv_array` of a connection that is not established yet.
+    pub max_unconfirmed_recv_packets: u32,
+}
+
+impl Default for NetCryptoLimits {
+    fn default() -> Self {
+        NetCryptoLimits {
+            max_connections: MAX_CRYPTO_CONNECTIONS,
+            max_handshakes_per_second: MAX_HANDSHAKES_PER_SECOND,
+            max_cookie_requests_per_ip: MAX_COOKIE_REQUESTS_PER_IP,
+            max_unconfirmed_recv_packets: MAX_UNCONFIRMED_RECV_PACKETS,
+        }
+    }
+}
+
+/// Counter of events that happened during the current `RATE_LIMIT_INTERVAL`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct RateCounter {
+    /// Time when the current interval started.
+    interval_start: Instant,
+    /// Number of events during the current interval.
+    count: u32,
+}
+
+impl RateCounter {
+    fn new(now: Instant) -> RateCounter {
+        RateCounter {
+            interval_start: now,
+            count: 0,
+        }
+    }
+
+    /// Check if the current interval is over so that the counter would be
+    /// reset by the next event.
+    fn is_expired(&self, now: Instant) -> bool {
+        now - self.interval_start >= RATE_LIMIT_INTERVAL
+    }
+
+    /// Count an event if the number of events during the current interval
+    /// doesn't exceed `limit`.
+    fn try_acquire(&mut self, now: Instant, limit: u32) -> bool {
+        if self.is_expired(now) {
+            self.interval_start = now;
+            self.count = 0;
+        }
+
+        if self.count < limit {
+            self.count += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Counters of events by key. When all slots are occupied by counters that
+/// are not expired yet new keys share a single overflow counter. So events
+/// from many keys can't evict a counter and reset it.
+struct KeyedRateCounters<K: Hash + Eq> {
+    /// Counters by key.
+    counters: LruCache<K, RateCounter>,
+    /// Counter shared by keys that don't fit into `counters`.
+    overflow: Option<RateCounter>,
+}
+
+impl<K: Hash + Eq> KeyedRateCounters<K> {
+    fn new(capacity: usize) -> KeyedRateCounters<K> {
+        KeyedRateCounters {
+            counters: LruCache::new(capacity),
+            overflow: None,
+        }
+    }
+
+    /// Count an event for the key if the number of its events during the
+    /// current interval doesn't exceed `limit`.
+    fn try_acquire(&mut self, key: K, now: Instant, limit: u32) -> bool {
+        if let Some(counter) = self.counters.get_mut(&key) {
+            return counter.try_acquire(now, limit);
+        }
+
+        let can_evict = self.counters.peek_lru()
+            .map_or(true, |(_, counter)| counter.is_expired(now));
+        if self.counters.len() < self.counters.cap() || can_evict {
+            let mut counter = RateCounter::new(now);
+            let allowed = counter.try_acquire(now, limit);
+            self.counters.put(key, counter);
+            allowed
+        } else {
+            self.overflow
+                .get_or_insert_with(|| RateCounter::new(now))
+                .try_acquire(now, limit)
+        }
+    }
+}
+
+/// Key by which requests from the IP address are counted. IPv6 addresses are
+/// counted by their /64 prefix since a single host usually owns the whole
+/// prefix.
+fn ip_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3], 0, 0, 0, 0))
+        },
+    }
+}
+
+/// Rate limiter of handshakes and cookie requests.
+pub(crate) struct RateLimiter {
+    /// Counters of handshakes for new connections by `PublicKey` of a friend.
+    handshakes: KeyedRateCounters<PublicKey>,
+    /// Counters of cookie requests by IP address.
+    cookie_requests: KeyedRateCounters<IpAddr>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter {
+            handshakes: KeyedRateCounters::new(HANDSHAKE_KEYS_CAPACITY),
+            cookie_requests: KeyedRateCounters::new(COOKIE_REQUEST_ADDRS_CAPACITY),
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Check if one more handshake for a new connection to the friend can be
+    /// handled.
+    pub(crate) fn allow_handshake(&mut self, real_pk: PublicKey, now: Instant, limit: u32) -> bool {
+        self.handshakes.try_acquire(real_pk, now, limit)
+    }
+
+    /// Check if one more cookie request from the IP address can be handled.
+    pub(crate) fn allow_cookie_request(&mut self, ip: IpAddr, now: Instant, limit: u32) -> bool {
+        self.cookie_requests.try_acquire(ip_key(ip), now, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::toxcore::crypto_core::*;
+    use crate::toxcore::time::*;
+
+    #[test]
+    fn allow_handshake() {
+        crypto_init().unwrap();
+        let mut limiter = RateLimiter::default();
+        let now = clock_now();
+        let (pk_1, _sk_1) = gen_keypair();
+        let (pk_2, _sk_2) = gen_keypair();
+
+        assert!(limiter.allow_handshake(pk_1, now, 2));
+        assert!(limiter.allow_handshake(pk_1, now, 2));
+        assert!(!limiter.allow_handshake(pk_1, now, 2));
+        // other friends are counted separately
+        assert!(limiter.allow_handshake(pk_2, now, 2));
+        assert!(!limiter.allow_handshake(pk_1, now + RATE_LIMIT_INTERVAL / 2, 2));
+        assert!(limiter.allow_handshake(pk_1, now + RATE_LIMIT_INTERVAL, 2));
+    }
+
+    #[test]
+    fn allow_cookie_request() {
+        let mut limiter = RateLimiter::default();
+        let now = clock_now();
+        let ip_1 = "1.2.3.4".parse().unwrap();
+        let ip_2 = "1.2.3.5".parse().unwrap();
+
+        assert!(limiter.allow_cookie_request(ip_1, now, 1));
+        assert!(!limiter.allow_cookie_request(ip_1, now, 1));
+        // other addresses are counted separately
+        assert!(limiter.allow_cookie_request(ip_2, now, 1));
+        assert!(limiter.allow_cookie_request(ip_1, now + RATE_LIMIT_INTERVAL, 1));
+    }
+
+    #[test]
+    fn allow_cookie_request_zero_limit() {
+        let mut limiter = RateLimiter::default();
+        let ip = "1.2.3.4".parse().unwrap();
+
+        assert!(!limiter.allow_cookie_request(ip, clock_now(), 0));
+    }
+
+    #[test]
+    fn allow_cookie_request_ipv6_prefix() {
+        let mut limiter = RateLimiter::default();
+        let now = clock_now();
+        let ip_1 = "2001:db8::1".parse().unwrap();
+        let ip_2 = "2001:db8::2".parse().unwrap();
+        let ip_3 = "2001:db8:0:1::1".parse().unwrap();
+
+        assert!(limiter.allow_cookie_request(ip_1, now, 1));
+        // addresses from the same /64 network share the counter
+        assert!(!limiter.allow_cookie_request(ip_2, now, 1));
+        assert!(limiter.allow_cookie_request(ip_3, now, 1));
+    }
+
+    #[test]
+    fn keyed_rate_counters_overflow() {
+        let mut counters = KeyedRateCounters::new(2);
+        let now = clock_now();
+
+        assert!(counters.try_acquire(1, now, 1));
+        assert!(!counters.try_acquire(1, now, 1));
+        // new keys don't evict counters that are not expired
+        assert!(counters.try_acquire(2, now, 1));
+        assert!(counters.try_acquire(3, now, 1));
+        assert!(!counters.try_acquire(4, now, 1));
+        assert!(!counters.try_acquire(1, now, 1));
+        // expired counters can be evicted, the least recently used is 2
+        let now = now + RATE_LIMIT_INTERVAL;
+        assert!(counters.try_acquire(3, now, 1));
+        assert!(counters.counters.contains(&3));
+        assert!(counters.counters.contains(&1));
+        assert!(!counters.counters.contains(&2));
+    }
+}
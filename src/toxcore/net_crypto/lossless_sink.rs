@@ -0,0 +1,77 @@
+/*! Sink of lossless packets with flow control.
+*/
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::{ready, Future, FutureExt, Sink};
+
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::net_crypto::NetCrypto;
+use crate::toxcore::net_crypto::errors::*;
+
+/// Future that sends already queued lossless packet.
+type SendFuture = Pin<Box<dyn Future<Output = Result<(), SendLosslessPacketError>> + Send>>;
+
+/** Sink that sends lossless packets to a friend via `NetCrypto`.
+
+Unlike `NetCrypto::send_lossless` it doesn't fail when the send array is full.
+`poll_ready` returns `Pending` until the connection is established, the send
+array has room and the congestion controller allows sending, so file transfers
+and other streams can be forwarded into this sink with `SinkExt::send_all`.
+
+*/
+pub struct LosslessSink {
+    /// `NetCrypto` that manages the connection to the friend.
+    net_crypto: NetCrypto,
+    /// Long term `PublicKey` of the friend.
+    real_pk: PublicKey,
+    /// Sending of the last packet passed to `start_send`.
+    pending: Option<SendFuture>,
+}
+
+impl LosslessSink {
+    /// Create new `LosslessSink`.
+    pub fn new(net_crypto: NetCrypto, real_pk: PublicKey) -> LosslessSink {
+        LosslessSink {
+            net_crypto,
+            real_pk,
+            pending: None,
+        }
+    }
+
+    /// Long term `PublicKey` of the friend packets are sent to.
+    pub fn real_pk(&self) -> PublicKey {
+        self.real_pk
+    }
+}
+
+impl Sink<Vec<u8>> for LosslessSink {
+    type Error = SendLosslessPacketError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        self.net_crypto.poll_send_lossless_ready(cx, self.real_pk)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, packet: Vec<u8>) -> Result<(), Self::Error> {
+        // the packet is put to the send array synchronously so its order is
+        // preserved even if it's not sent to the socket yet
+        let future = self.net_crypto.send_lossless(self.real_pk, packet);
+        self.pending = Some(future.boxed());
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        if let Some(ref mut pending) = self.pending {
+            let result = ready!(pending.as_mut().poll(cx));
+            self.pending = None;
+            result?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
@@ -10,26 +10,42 @@ TCP relays.
 
 */
 
+mod congestion;
 mod crypto_connection;
+mod limits;
+mod lossless_sink;
 mod packets_array;
 pub mod errors;
 
+pub use self::congestion::*;
 pub use self::crypto_connection::*;
+pub use self::limits::NetCryptoLimits;
+pub use self::limits::{
+    MAX_CRYPTO_CONNECTIONS,
+    MAX_HANDSHAKES_PER_SECOND,
+    MAX_COOKIE_REQUESTS_PER_IP,
+    MAX_UNCONFIRMED_RECV_PACKETS,
+    COOKIE_REQUEST_ADDRS_CAPACITY,
+};
+use self::limits::RateLimiter;
+pub use self::lossless_sink::*;
 use self::packets_array::*;
 use self::errors::*;
 
 use std::collections::{HashMap, HashSet};
 use std::net::{SocketAddr, IpAddr};
 use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
 use std::time::{Duration, Instant};
 use std::u16;
 
+use bytes::Bytes;
 use failure::Fail;
 use futures::{Future, TryFutureExt, StreamExt, SinkExt};
 use futures::future;
 use futures::future::Either;
 use futures::channel::mpsc;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use futures::future::FutureExt;
 
 use crate::toxcore::binary_io::*;
@@ -39,6 +55,9 @@ use crate::toxcore::dht::precomputed_cache::*;
 use crate::toxcore::io_tokio::*;
 use crate::toxcore::tcp::packet::{DataPayload as TcpDataPayload};
 use crate::toxcore::time::*;
+use crate::toxcore::stats::Stats;
+use crate::toxcore::capture::{Capture, Direction, Layer};
+use crate::toxcore::shutdown::ShutdownSignal;
 
 /// Maximum size of `Packet` when we try to send it to UDP address even if
 /// it's considered dead.
@@ -56,6 +75,10 @@ const PACKET_ID_REQUEST: u8 = 1;
 /// Packet with this ID means that this crypto connection should be killed.
 const PACKET_ID_KILL: u8 = 2;
 
+/// Maximum time to wait on shutdown until queued lossless packets are
+/// delivered before connections are killed.
+pub const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Packets with ID from 0 to `PACKET_ID_CRYPTO_RANGE_END` are reserved for
 /// `net_crypto`.
 const PACKET_ID_CRYPTO_RANGE_END: u8 = 15;
@@ -86,15 +109,20 @@ type DhtPkTx = mpsc::UnboundedSender<(PublicKey, PublicKey)>;
 /// long term key of the connection.
 type ConnectionStatusTx = mpsc::UnboundedSender<(PublicKey, bool)>;
 
+/// Shorthand for the transmit half of the message channel for sending a
+/// transport of an established connection when it changes. The key is a long
+/// term key of the connection.
+type TransportTx = mpsc::UnboundedSender<(PublicKey, Option<ConnectionTransport>)>;
+
 /// Shorthand for the transmit half of the message channel for sending lossless
 /// packets. The key is a long term public key of the peer that sent this
 /// packet.
-type LosslessTx = mpsc::UnboundedSender<(PublicKey, Vec<u8>)>;
+type LosslessTx = mpsc::UnboundedSender<(PublicKey, Bytes)>;
 
 /// Shorthand for the transmit half of the message channel for sending lossy
 /// packets. The key is a long term public key of the peer that sent this
 /// packet.
-type LossyTx = mpsc::UnboundedSender<(PublicKey, Vec<u8>)>;
+type LossyTx = mpsc::UnboundedSender<(PublicKey, Bytes)>;
 
 /// Packet that can be sent as UDP packet or as TCP payload via TCP relay. The
 /// way it should be sent is determined automatically. It doesn't contain
@@ -181,6 +209,9 @@ pub struct NetCrypto {
     /// Sink to send a connection status when it becomes connected or
     /// disconnected. The key is a long term key of the connection.
     connection_status_tx: Arc<RwLock<Option<ConnectionStatusTx>>>,
+    /// Sink to send a transport of an established connection when it changes.
+    /// The key is a long term key of the connection.
+    transport_tx: Arc<RwLock<Option<TransportTx>>>,
     /// Sink to send lossless packets. The key is a long term public key of the
     /// peer that sent this packet.
     lossless_tx: LosslessTx,
@@ -208,6 +239,25 @@ pub struct NetCrypto {
     /// Lru cache for precomputed keys. It stores precomputed keys to avoid
     /// redundant calculations.
     precomputed_keys: PrecomputedCache,
+    /// Statistics where number of connections is reported.
+    stats: Stats,
+    /// Capture of decrypted data packets.
+    capture: Arc<RwLock<Option<Capture>>>,
+    /// Congestion controller that is cloned for new connections.
+    congestion_controller: Arc<RwLock<Box<dyn CongestionController>>>,
+    /// Congestion controllers chosen for connections to particular friends.
+    /// The key is a long term public key of the friend.
+    friend_congestion_controllers: Arc<RwLock<HashMap<PublicKey, Box<dyn CongestionController>>>>,
+    /// Transport policies chosen for connections to particular friends. The
+    /// key is a long term public key of the friend.
+    transport_policies: Arc<RwLock<HashMap<PublicKey, TransportPolicy>>>,
+    /// Tasks waiting until lossless packets can be sent to a friend. The key
+    /// is a long term public key of the friend.
+    send_wakers: Arc<Mutex<HashMap<PublicKey, Vec<Waker>>>>,
+    /// Limits for incoming connections and packets.
+    limits: Arc<RwLock<NetCryptoLimits>>,
+    /// Rate limiter of incoming handshakes and cookie requests.
+    rate_limiter: Arc<Mutex<RateLimiter>>,
 }
 
 impl NetCrypto {
@@ -218,6 +268,7 @@ impl NetCrypto {
             tcp_tx: Default::default(),
             dht_pk_tx: Default::default(),
             connection_status_tx: Default::default(),
+            transport_tx: Default::default(),
             lossless_tx: args.lossless_tx,
             lossy_tx: args.lossy_tx,
             dht_pk: args.dht_pk,
@@ -229,6 +280,14 @@ impl NetCrypto {
             connections: Arc::new(RwLock::new(HashMap::new())),
             keys_by_addr: Arc::new(RwLock::new(HashMap::new())),
             precomputed_keys: args.precomputed_keys,
+            stats: Stats::new(),
+            capture: Default::default(),
+            congestion_controller: Default::default(),
+            friend_congestion_controllers: Default::default(),
+            transport_policies: Default::default(),
+            send_wakers: Default::default(),
+            limits: Default::default(),
+            rate_limiter: Default::default(),
         }
     }
 
@@ -240,6 +299,23 @@ impl NetCrypto {
     /// Remove a friend to stop accepting incoming connections from him.
     pub fn remove_friend(&self, real_pk: PublicKey) {
         self.friends.write().remove(&real_pk);
+        self.friend_congestion_controllers.write().remove(&real_pk);
+        self.transport_policies.write().remove(&real_pk);
+    }
+
+    /// Create congestion controller for a new connection to a friend.
+    fn new_congestion_controller(&self, real_pk: &PublicKey) -> CongestionControl {
+        self.friend_congestion_controllers.read().get(real_pk)
+            .unwrap_or(&*self.congestion_controller.read())
+            .clone()
+            .into()
+    }
+
+    /// Get transport policy for a connection to a friend.
+    fn transport_policy(&self, real_pk: &PublicKey) -> TransportPolicy {
+        self.transport_policies.read().get(real_pk)
+            .cloned()
+            .unwrap_or_default()
     }
 
     /// Add connection to a friend when its DHT `PublicKey` is known.
@@ -251,13 +327,15 @@ impl NetCrypto {
         }
 
         let dht_precomputed_key = precompute(&peer_dht_pk, &self.dht_sk);
-        let connection = CryptoConnection::new(
+        let mut connection = CryptoConnection::new(
             &dht_precomputed_key,
             self.dht_pk,
             self.real_pk,
             peer_real_pk,
             peer_dht_pk
         );
+        connection.congestion = self.new_congestion_controller(&peer_real_pk);
+        connection.transport_policy = self.transport_policy(&peer_real_pk);
         let connection = Arc::new(RwLock::new(connection));
         connections.insert(peer_real_pk, connection);
     }
@@ -305,15 +383,17 @@ impl NetCrypto {
         if let Some(connection) = self.connections.write().remove(&real_pk) {
             let mut connection = connection.write();
             self.clear_keys_by_addr(&connection);
+            self.wake_senders(&real_pk);
 
             let status_future = self.send_connection_status(&connection, false)
                 .map_err(|e| e.context(KillConnectionErrorKind::SendToConnectionStatus).into());
             let kill_future = self.send_kill_packet(&mut connection)
                 .map_err(|e| e.context(KillConnectionErrorKind::SendTo).into());
 
+            // kill packet should be sent even if the status can't be
             Either::Left(
-                future::try_join(kill_future, status_future)
-                    .map_ok(drop)
+                future::join(kill_future, status_future)
+                    .map(|(kill_res, status_res)| kill_res.and(status_res))
             )
         } else {
             Either::Right(
@@ -331,7 +411,8 @@ impl NetCrypto {
             return
         };
 
-        if connection.get_udp_addr_v4() == Some(saddr) || connection.get_udp_addr_v6() == Some(saddr) {
+        if !connection.udp_allowed() ||
+            connection.get_udp_addr_v4() == Some(saddr) || connection.get_udp_addr_v6() == Some(saddr) {
             return
         }
 
@@ -348,10 +429,19 @@ impl NetCrypto {
         keys_by_addr.insert((saddr.ip(), saddr.port()), real_pk);
     }
 
+    /// Check if lossless packet has ID from the lossless packets range.
+    fn validate_lossless_packet(packet: &[u8]) -> Result<(), SendLosslessPacketError> {
+        if packet.first().map_or(true, |&packet_id| packet_id <= PACKET_ID_CRYPTO_RANGE_END || packet_id >= PACKET_ID_LOSSY_RANGE_START) {
+            Err(SendLosslessPacketErrorKind::InvalidPacketId.into())
+        } else {
+            Ok(())
+        }
+    }
+
     /// Send lossless packet to a friend via established connection.
     pub fn send_lossless(&self, real_pk: PublicKey, packet: Vec<u8>) -> impl Future<Output = Result<(), SendLosslessPacketError>> {
-        if packet.first().map_or(true, |&packet_id| packet_id <= PACKET_ID_CRYPTO_RANGE_END || packet_id >= PACKET_ID_LOSSY_RANGE_START) {
-            return Either::Right(future::err(SendLosslessPacketErrorKind::InvalidPacketId.into()));
+        if let Err(e) = NetCrypto::validate_lossless_packet(&packet) {
+            return Either::Right(future::err(e));
         }
 
         if let Some(connection) = self.connections.read().get(&real_pk) {
@@ -361,6 +451,7 @@ impl NetCrypto {
                 Either::Right(future::err(e.context(SendLosslessPacketErrorKind::FullSendArray).into()))
             } else {
                 connection.packets_sent += 1;
+                connection.total_packets_sent += 1;
                 Either::Left(self.send_data_packet(&mut connection, packet, packet_number)
                     .map_err(|e| e.context(SendLosslessPacketErrorKind::SendTo).into()))
             }
@@ -369,6 +460,65 @@ impl NetCrypto {
         }
     }
 
+    /// Get transport statistics of the connection to a friend.
+    pub fn connection_info(&self, real_pk: PublicKey) -> Option<ConnectionInfo> {
+        self.connection_by_key(real_pk).map(|connection| connection.read().info())
+    }
+
+    /// Check if a lossless packet can be sent to a friend right now, i.e. the
+    /// connection is established, its send array has room and the congestion
+    /// controller allows sending. If it can't the current task will be woken
+    /// up when the state of the connection changes.
+    pub fn poll_send_lossless_ready(&self, cx: &mut Context, real_pk: PublicKey) -> Poll<Result<(), SendLosslessPacketError>> {
+        let connection = match self.connection_by_key(real_pk) {
+            Some(connection) => connection,
+            None => return Poll::Ready(Err(SendLosslessPacketErrorKind::NoConnection.into())),
+        };
+        let connection = connection.read();
+
+        if connection.is_established() && connection.can_send_lossless() {
+            return Poll::Ready(Ok(()));
+        }
+
+        let mut send_wakers = self.send_wakers.lock();
+        let wakers = send_wakers.entry(real_pk).or_default();
+        if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    /// Send lossless packet to a friend waiting until the connection is
+    /// established and the packet can be sent without exceeding the send rate
+    /// estimated by the congestion controller.
+    pub async fn send_lossless_ready(&self, real_pk: PublicKey, packet: Vec<u8>) -> Result<(), SendLosslessPacketError> {
+        NetCrypto::validate_lossless_packet(&packet)?;
+        loop {
+            future::poll_fn(|cx| self.poll_send_lossless_ready(cx, real_pk)).await?;
+            // another task might fill the send array after the check so we
+            // should wait again in this case
+            match self.send_lossless(real_pk, packet.clone()).await {
+                Err(ref e) if *e.kind() == SendLosslessPacketErrorKind::FullSendArray => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Create a sink that sends lossless packets to a friend respecting the
+    /// send rate of the connection.
+    pub fn lossless_sink(&self, real_pk: PublicKey) -> LosslessSink {
+        LosslessSink::new(self.clone(), real_pk)
+    }
+
+    /// Wake up tasks waiting until lossless packets can be sent to a friend.
+    fn wake_senders(&self, real_pk: &PublicKey) {
+        if let Some(wakers) = self.send_wakers.lock().remove(real_pk) {
+            for waker in wakers {
+                waker.wake();
+            }
+        }
+    }
+
     /// Send `Packet` packet to UDP socket
     fn send_to_udp(&self, addr: SocketAddr, packet: DhtPacket) -> impl Future<Output = Result<(), mpsc::SendError>> + Send {
         let mut tx = self.udp_tx.clone();
@@ -413,6 +563,11 @@ impl NetCrypto {
 
     /// Handle `CookieRequest` packet received from UDP socket
     pub fn handle_udp_cookie_request(&self, packet: &CookieRequest, addr: SocketAddr) -> impl Future<Output = Result<(), HandlePacketError>> + Send {
+        let limit = self.limits.read().max_cookie_requests_per_ip;
+        if !self.rate_limiter.lock().allow_cookie_request(addr.ip(), clock_now(), limit) {
+            return Either::Right(future::err(HandlePacketError::cookie_request_rate_limited(addr.ip())));
+        }
+
         match self.handle_cookie_request(packet) {
             Ok(response) => Either::Left(self.send_to_udp(addr, DhtPacket::CookieResponse(response))
                 .map_err(|e| e.context(HandlePacketErrorKind::SendTo).into())),
@@ -480,6 +635,9 @@ impl NetCrypto {
         let connection = self.key_by_addr(addr).and_then(|pk| self.connection_by_key(pk));
         if let Some(connection) = connection {
             let mut connection = connection.write();
+            if !connection.udp_allowed() {
+                return Either::Right(future::err(HandlePacketErrorKind::UdpNotAllowed.into()));
+            }
             connection.set_udp_addr(addr);
             Either::Left(self.handle_cookie_response(&mut connection, packet))
         } else {
@@ -506,6 +664,14 @@ impl NetCrypto {
     /// - hash for the cookie inside the payload is correct
     fn validate_crypto_handshake(&self, packet: &CryptoHandshake)
         -> Result<(Cookie, CryptoHandshakePayload, PrecomputedKey), HandlePacketError> {
+        let cookie = self.get_crypto_handshake_cookie(packet)?;
+        self.get_crypto_handshake_payload(packet, cookie)
+    }
+
+    /// Get not timed out cookie of `CryptoHandshake`. It requires only
+    /// symmetric decryption so it's cheap to check before decryption of the
+    /// handshake payload.
+    fn get_crypto_handshake_cookie(&self, packet: &CryptoHandshake) -> Result<Cookie, HandlePacketError> {
         let cookie = match packet.cookie.get_payload(&self.symmetric_key) {
             Ok(cookie) => cookie,
             Err(e) => return Err(e.context(HandlePacketErrorKind::GetPayload).into()),
@@ -515,6 +681,12 @@ impl NetCrypto {
             return Err(HandlePacketErrorKind::CookieTimedOut.into());
         }
 
+        Ok(cookie)
+    }
+
+    /// Get payload of `CryptoHandshake` with already checked cookie.
+    fn get_crypto_handshake_payload(&self, packet: &CryptoHandshake, cookie: Cookie)
+        -> Result<(Cookie, CryptoHandshakePayload, PrecomputedKey), HandlePacketError> {
         let real_precomputed_key = precompute(&cookie.real_pk, &self.real_sk);
 
         let payload = match packet.get_payload(&real_precomputed_key) {
@@ -599,8 +771,10 @@ impl NetCrypto {
     /// with sender connection.
     fn handle_crypto_handshake_new_connection(&self, packet: &CryptoHandshake, addr: Option<SocketAddr>)
         -> impl Future<Output = Result<(), HandlePacketError>> + Send {
-        let (cookie, payload, _real_precomputed_key) = match self.validate_crypto_handshake(packet) {
-            Ok(result) => result,
+        // cookie is checked first since it's cheap so that junk handshakes
+        // are dropped before they are counted by the rate limiter
+        let cookie = match self.get_crypto_handshake_cookie(packet) {
+            Ok(cookie) => cookie,
             Err(e) => return Either::Left(future::err(e)),
         };
 
@@ -608,8 +782,26 @@ impl NetCrypto {
             return Either::Left(future::err(HandlePacketErrorKind::UnexpectedCryptoHandshake.into()));
         }
 
+        if addr.is_some() && self.transport_policy(&cookie.real_pk) == TransportPolicy::RelayOnly {
+            return Either::Left(future::err(HandlePacketErrorKind::UdpNotAllowed.into()));
+        }
+
+        let limits = *self.limits.read();
+        if !self.rate_limiter.lock().allow_handshake(cookie.real_pk, clock_now(), limits.max_handshakes_per_second) {
+            return Either::Left(future::err(HandlePacketErrorKind::HandshakeRateLimited.into()));
+        }
+
+        let (cookie, payload, _real_precomputed_key) = match self.get_crypto_handshake_payload(packet, cookie) {
+            Ok(result) => result,
+            Err(e) => return Either::Left(future::err(e)),
+        };
+
         let mut connections = self.connections.write();
 
+        if !connections.contains_key(&cookie.real_pk) && connections.len() >= limits.max_connections {
+            return Either::Left(future::err(HandlePacketErrorKind::TooManyConnections.into()));
+        }
+
         let kill_future = if let Some(connection) = connections.get(&cookie.real_pk) {
             let mut connection = connection.write();
             if connection.peer_dht_pk != cookie.dht_pk {
@@ -659,6 +851,8 @@ impl NetCrypto {
             payload.cookie,
             &self.symmetric_key,
         );
+        connection.congestion = self.new_congestion_controller(&cookie.real_pk);
+        connection.transport_policy = self.transport_policy(&cookie.real_pk);
         if let Some(addr) = addr {
             connection.set_udp_addr(addr);
             self.keys_by_addr.write().insert((addr.ip(), addr.port()), cookie.real_pk);
@@ -682,19 +876,24 @@ impl NetCrypto {
         let connection = self.key_by_addr(addr).and_then(|pk| self.connection_by_key(pk));
         if let Some(connection) = connection {
             let mut connection = connection.write();
+            if !connection.udp_allowed() {
+                return Either::Left(Either::Right(future::err(HandlePacketErrorKind::UdpNotAllowed.into())));
+            }
             connection.set_udp_addr(addr);
-            Either::Left(self.handle_crypto_handshake(&mut connection, packet))
+            Either::Left(Either::Left(self.handle_crypto_handshake(&mut connection, packet)))
         } else {
             Either::Right(self.handle_crypto_handshake_new_connection(packet, Some(addr)))
         }
     }
 
-    /// Handle `CryptoHandshake` packet received from TCP socket
-    pub fn handle_tcp_crypto_handshake(&self, packet: &CryptoHandshake, sender_pk: PublicKey)
+    /// Handle `CryptoHandshake` packet received from TCP relay with
+    /// `PublicKey` `relay_pk`
+    pub fn handle_tcp_crypto_handshake(&self, packet: &CryptoHandshake, sender_pk: PublicKey, relay_pk: PublicKey)
         -> impl Future<Output = Result<(), HandlePacketError>> + Send {
         let connection = self.connection_by_dht_key(sender_pk);
         if let Some(connection) = connection {
             let mut connection = connection.write();
+            connection.set_tcp_relay(relay_pk);
             Either::Left(self.handle_crypto_handshake(&mut connection, packet))
         } else {
             Either::Right(self.handle_crypto_handshake_new_connection(packet, None))
@@ -824,7 +1023,7 @@ impl NetCrypto {
     */
     fn handle_crypto_data(&self,
         connection: &mut CryptoConnection,
-        packet: &CryptoData,
+        packet: CryptoData,
         udp: bool
     ) -> impl Future<Output = Result<(), HandlePacketError>> + Send {
         let (sent_nonce, mut received_nonce, session_precomputed_key) =
@@ -855,13 +1054,29 @@ impl NetCrypto {
         let mut packet_nonce = received_nonce;
         increment_nonce_number(&mut packet_nonce, u64::from(diff));
 
-        let payload = match packet.get_payload(&session_precomputed_key, &packet_nonce) {
+        // payload is decrypted in place and its data is passed to the
+        // lossless or lossy sink without copying
+        let payload = match packet.into_payload(&session_precomputed_key, &packet_nonce) {
             Ok(payload) => payload,
             Err(e) => return future::err(
                 e.context(HandlePacketErrorKind::GetPayload).into()
             ).boxed()
         };
 
+        if let Some(ref capture) = *self.capture.read() {
+            let addr = if udp { connection.get_udp_addr() } else { None };
+            capture.record(Direction::Incoming, Layer::NetCrypto, addr, true, &payload.data);
+        }
+
+        // Connection that is not established yet can't buffer many packets so
+        // that peers that didn't confirm the handshake can't exhaust memory
+        if !connection.is_established() {
+            let max_packets = self.limits.read().max_unconfirmed_recv_packets;
+            if payload.packet_number.wrapping_sub(connection.recv_array.buffer_start) >= max_packets {
+                return future::err(HandlePacketError::too_many_unconfirmed_packets(payload.packet_number)).boxed()
+            }
+        }
+
         // Find the time when the last acknowledged packet was sent
         let mut last_sent_time = NetCrypto::last_sent_time(
             &connection.send_array,
@@ -874,6 +1089,7 @@ impl NetCrypto {
                 e.context(HandlePacketErrorKind::PacketsArrayError).into()
             ).boxed()
         }
+        self.wake_senders(&connection.peer_real_pk);
 
         // And get the ID of the packet
         let packet_id = match payload.data.first() {
@@ -890,6 +1106,7 @@ impl NetCrypto {
                 .boxed();
             self.connections.write().remove(&connection.peer_real_pk);
             self.clear_keys_by_addr(&connection);
+            self.wake_senders(&connection.peer_real_pk);
             return status_future;
         }
 
@@ -901,6 +1118,9 @@ impl NetCrypto {
         let status_future = self.send_connection_status(&connection, true)
             .map_err(|e| e.context(HandlePacketErrorKind::SendToConnectionStatus).into());
 
+        if !connection.is_established() {
+            connection.established_time = Some(clock_now());
+        }
         connection.status = ConnectionStatus::Established {
             sent_nonce,
             received_nonce,
@@ -933,7 +1153,7 @@ impl NetCrypto {
             connection.recv_array.set_buffer_end(payload.packet_number).ok();
             let mut tx = self.lossy_tx.clone();
             let peer_real_pk = connection.peer_real_pk;
-            let data = payload.data.clone();
+            let data = payload.data;
 
             async move {
                 tx.send((peer_real_pk, data)).await
@@ -951,16 +1171,20 @@ impl NetCrypto {
             if elapsed < connection.rtt {
                 connection.rtt = elapsed;
             }
+            connection.congestion.on_delay_sample(elapsed);
         }
 
         future::try_join(result, status_future).map_ok(drop).boxed()
     }
 
     /// Handle `CryptoData` packet received from UDP socket
-    pub fn handle_udp_crypto_data(&self, packet: &CryptoData, addr: SocketAddr) -> impl Future<Output = Result<(), HandlePacketError>> + Send {
+    pub fn handle_udp_crypto_data(&self, packet: CryptoData, addr: SocketAddr) -> impl Future<Output = Result<(), HandlePacketError>> + Send {
         let connection = self.key_by_addr(addr).and_then(|pk| self.connection_by_key(pk));
         if let Some(connection) = connection {
             let mut connection = connection.write();
+            if !connection.udp_allowed() {
+                return Either::Right(future::err(HandlePacketErrorKind::UdpNotAllowed.into()));
+            }
             connection.set_udp_addr(addr);
             Either::Left(self.handle_crypto_data(&mut connection, packet, /* udp */ true))
         } else {
@@ -968,11 +1192,13 @@ impl NetCrypto {
         }
     }
 
-    /// Handle `CryptoData` packet received from TCP socket
-    pub fn handle_tcp_crypto_data(&self, packet: &CryptoData, sender_pk: PublicKey) -> impl Future<Output = Result<(), HandlePacketError>> + Send {
+    /// Handle `CryptoData` packet received from TCP relay with `PublicKey`
+    /// `relay_pk`
+    pub fn handle_tcp_crypto_data(&self, packet: CryptoData, sender_pk: PublicKey, relay_pk: PublicKey) -> impl Future<Output = Result<(), HandlePacketError>> + Send {
         let connection = self.connection_by_dht_key(sender_pk);
         if let Some(connection) = connection {
             let mut connection = connection.write();
+            connection.set_tcp_relay(relay_pk);
             Either::Left(self.handle_crypto_data(&mut connection, packet, /* udp */ false))
         } else {
             Either::Right(future::err(HandlePacketError::no_tcp_connection(sender_pk)))
@@ -984,18 +1210,24 @@ impl NetCrypto {
         // TODO: can backpressure be used instead of congestion control? It
         // seems it's possible to implement wrapper for bounded sender with
         // priority queue and just send packets there
-        let udp_future = if let Some(addr) = connection.get_udp_addr() {
-            if connection.is_udp_alive() {
+        let udp_addr = if connection.udp_allowed() {
+            connection.get_udp_addr()
+        } else {
+            None
+        };
+        let udp_future = if let Some(addr) = udp_addr {
+            if connection.is_udp_alive() || !connection.tcp_allowed() {
                 return Either::Left(Box::new(self.send_to_udp(addr, packet.into()))
                     .map_err(|e| e.context(SendPacketErrorKind::Udp).into()))
             }
 
             let dht_packet: DhtPacket = packet.clone().into();
-            let udp_attempt_should_be_made = connection.udp_attempt_should_be_made() && {
-                // check if the packet is not too big
-                let mut buf = [0; DHT_ATTEMPT_MAX_PACKET_LENGTH];
-                dht_packet.to_bytes((&mut buf, 0)).is_ok()
-            };
+            let udp_attempt_should_be_made = connection.transport_policy == TransportPolicy::PreferDirect ||
+                connection.udp_attempt_should_be_made() && {
+                    // check if the packet is not too big
+                    let mut buf = [0; DHT_ATTEMPT_MAX_PACKET_LENGTH];
+                    dht_packet.to_bytes((&mut buf, 0)).is_ok()
+                };
 
             if udp_attempt_should_be_made {
                 connection.update_udp_send_attempt_time();
@@ -1010,7 +1242,11 @@ impl NetCrypto {
         let udp_future = udp_future
             .map_err(|e| e.context(SendPacketErrorKind::Udp).into());
 
-        let tcp_tx = self.tcp_tx.read().clone();
+        let tcp_tx = if connection.tcp_allowed() {
+            self.tcp_tx.read().clone()
+        } else {
+            None
+        };
         let tcp_future = maybe_send_bounded(tcp_tx, (packet.into(), connection.peer_dht_pk))
             .map_err(|e| e.context(SendPacketErrorKind::Tcp).into());
 
@@ -1032,6 +1268,9 @@ impl NetCrypto {
     /// Send `CryptoData` packet if the connection is established.
     fn send_data_packet(&self, connection: &mut CryptoConnection, data: Vec<u8>, packet_number: u32)
         -> impl Future<Output = Result<(), SendDataError>> + Send {
+        let data = Bytes::from(data);
+        let capture = self.capture.read().clone()
+            .map(|capture| (capture, connection.get_udp_addr(), data.clone()));
         let packet = match connection.status {
             ConnectionStatus::NotConfirmed { ref mut sent_nonce, ref session_precomputed_key, .. }
             | ConnectionStatus::Established { ref mut sent_nonce, ref session_precomputed_key, .. } => {
@@ -1047,7 +1286,10 @@ impl NetCrypto {
             _ => return Either::Left(future::err(SendDataError::from(SendDataErrorKind::NoConnection))),
         };
         Either::Right(self.send_packet(Packet::CryptoData(packet), connection)
-            .map_err(|e| e.context(SendDataErrorKind::SendTo).into()))
+            .map_err(|e| e.context(SendDataErrorKind::SendTo).into())
+            .map_ok(move |()| if let Some((capture, addr, data)) = capture {
+                capture.record(Direction::Outgoing, Layer::NetCrypto, addr, true, &data);
+            }))
     }
 
     /// Send request packet with indices of not received packets.
@@ -1069,6 +1311,7 @@ impl NetCrypto {
                 packet.sent_time = now;
                 (i, packet.data.clone())
             }).collect::<Vec<_>>();
+        connection.total_packets_resent += packets.len() as u64;
 
         let futures: Vec<_> = packets.into_iter().map(|(i, data)|
             self.send_data_packet(connection, data, i)
@@ -1084,12 +1327,17 @@ impl NetCrypto {
         let mut connections = self.connections.write();
         let mut keys_by_addr = self.keys_by_addr.write();
         let mut futures: Vec<Pin<Box<dyn Future<Output = Result<_, _>> + Send>>> = Vec::new();
+        // Connections for which tasks waiting to send lossless packets should
+        // be woken up
+        let mut wake_keys = Vec::new();
 
         // Only one cycle over all connections to prevent many lock acquirements
         connections.retain(|_pk, connection| {
             let mut connection = connection.write();
 
             if connection.is_timed_out() {
+                wake_keys.push(connection.peer_real_pk);
+
                 if let Some(addr) = connection.get_udp_addr_v4() {
                     keys_by_addr.remove(&(addr.ip(), addr.port()));
                 }
@@ -1134,15 +1382,34 @@ impl NetCrypto {
                     }
                 }
 
-                // TODO: either use send_rate or remove it
                 connection.update_congestion_stats();
 
+                let transport = connection.transport();
+                if connection.reported_transport != transport {
+                    connection.reported_transport = transport;
+                    let tx = self.transport_tx.read().clone();
+                    let transport_future = maybe_send_unbounded(tx, (connection.peer_real_pk, transport))
+                        .map_err(|e| e.context(SendDataErrorKind::SendToTransport).into());
+                    futures.push(Box::pin(transport_future));
+                }
+
                 futures.push(Box::pin(self.send_requested_packets(&mut connection)));
+
+                // send rate was updated so more packets might be allowed
+                if connection.can_send_lossless() {
+                    wake_keys.push(connection.peer_real_pk);
+                }
             }
 
             true
         });
 
+        self.stats.gauges.set_crypto_connections(connections.len() as u64);
+
+        for pk in &wake_keys {
+            self.wake_senders(pk);
+        }
+
         future::try_join_all(futures).map_ok(drop)
     }
 
@@ -1174,6 +1441,52 @@ impl NetCrypto {
         }
     }
 
+    /// Check if all lossless packets sent to established connections are
+    /// delivered.
+    fn is_send_drained(&self) -> bool {
+        self.connections.read().values().all(|connection| {
+            let connection = connection.read();
+            !connection.is_established() || connection.send_array.len() == 0
+        })
+    }
+
+    /// Run `net_crypto` periodical tasks until shutdown is triggered. Then
+    /// keep resending requested lossless packets until they are delivered or
+    /// `SHUTDOWN_DRAIN_TIMEOUT` elapses and kill all connections so that
+    /// friends don't have to wait for timeouts.
+    pub async fn run_until(self, signal: ShutdownSignal) -> Result<(), RunError> {
+        signal.run(self.clone().run()).await?;
+
+        let drain_future = async {
+            let mut wakeups = tokio::time::interval(PACKET_COUNTER_AVERAGE_INTERVAL);
+            while wakeups.next().await.is_some() {
+                self.main_loop().await?;
+                if self.is_send_drained() {
+                    break;
+                }
+            }
+            Ok(())
+        };
+        match tokio::time::timeout(SHUTDOWN_DRAIN_TIMEOUT, drain_future).await {
+            Ok(res) => res.map_err(|e: SendDataError| e.context(RunErrorKind::SendData))?,
+            Err(_) => warn!("Not all lossless packets were delivered before shutdown"),
+        }
+
+        let real_pks = self.connections.read().keys().cloned().collect::<Vec<_>>();
+        let kill_futures = real_pks.into_iter()
+            .map(|real_pk| self.kill_connection(real_pk));
+        for res in future::join_all(kill_futures).await {
+            match res {
+                // the status listener might be already stopped on shutdown
+                Err(ref e) if *e.kind() == KillConnectionErrorKind::SendToConnectionStatus =>
+                    debug!("Failed to send connection status on shutdown: {}", e),
+                res => res.map_err(|e| e.context(RunErrorKind::SendData))?,
+            }
+        }
+
+        Ok(())
+    }
+
     /// Set sink to send DHT `PublicKey` when it gets known.
     pub fn set_dht_pk_sink(&self, dht_pk_tx: DhtPkTx) {
         *self.dht_pk_tx.write() = Some(dht_pk_tx);
@@ -1185,16 +1498,74 @@ impl NetCrypto {
         *self.connection_status_tx.write() = Some(connection_status_tx);
     }
 
+    /// Set sink to send a transport of an established connection when it
+    /// changes.
+    pub fn set_transport_sink(&self, transport_tx: TransportTx) {
+        *self.transport_tx.write() = Some(transport_tx);
+    }
+
     /// Set sink for sending TCP packets via relays.
     pub fn set_tcp_sink(&self, tcp_tx: TcpTx) {
         *self.tcp_tx.write() = Some(tcp_tx);
     }
+
+    /// Set statistics object to report number of connections to.
+    pub fn set_stats(&mut self, stats: Stats) {
+        self.stats = stats;
+    }
+
+    /// Record decrypted data of all sent and received `CryptoData` packets to
+    /// `capture`.
+    pub fn set_capture(&self, capture: Capture) {
+        *self.capture.write() = Some(capture);
+    }
+
+    /// Set limits for incoming connections and packets.
+    pub fn set_limits(&self, limits: NetCryptoLimits) {
+        *self.limits.write() = limits;
+    }
+
+    /// Set congestion controller that is used for new connections to friends
+    /// without their own controller. `ToxCongestionController` is used by
+    /// default.
+    pub fn set_congestion_controller(&self, controller: Box<dyn CongestionController>) {
+        *self.congestion_controller.write() = controller;
+    }
+
+    /// Set congestion controller for the connection to a friend. If the
+    /// connection exists its controller is replaced immediately.
+    pub fn set_friend_congestion_controller(&self, real_pk: PublicKey, controller: Box<dyn CongestionController>) {
+        if let Some(connection) = self.connections.read().get(&real_pk) {
+            connection.write().congestion = controller.clone().into();
+        }
+        self.friend_congestion_controllers.write().insert(real_pk, controller);
+    }
+
+    /// Set transport policy for the connection to a friend. If the connection
+    /// exists its policy is replaced immediately. With
+    /// `TransportPolicy::RelayOnly` friend's UDP addresses are forgotten and
+    /// packets received from them via UDP are dropped.
+    pub fn set_friend_transport_policy(&self, real_pk: PublicKey, policy: TransportPolicy) {
+        if let Some(connection) = self.connections.read().get(&real_pk) {
+            let mut connection = connection.write();
+            connection.transport_policy = policy;
+            if !connection.udp_allowed() {
+                self.clear_keys_by_addr(&connection);
+                connection.udp_addr_v4 = None;
+                connection.udp_addr_v6 = None;
+            }
+        }
+        self.transport_policies.write().insert(real_pk, policy);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     // https://github.com/rust-lang/rust/issues/61520
     use super::{*, Packet};
+    use crate::toxcore::capture::SharedBuf;
+
+    use crate::toxcore::shutdown::Shutdown;
 
     impl NetCrypto {
         pub fn has_friend(&self, pk: &PublicKey) -> bool {
@@ -1438,6 +1809,51 @@ mod tests {
         assert_eq!(cookie.real_pk, peer_real_pk);
     }
 
+    #[tokio::test]
+    async fn handle_udp_cookie_request_rate_limited() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(4);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        net_crypto.set_limits(NetCryptoLimits {
+            max_cookie_requests_per_ip: 1,
+            .. NetCryptoLimits::default()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let cookie_request_payload = CookieRequestPayload {
+            pk: peer_real_pk,
+            id: 12345,
+        };
+        let cookie_request = CookieRequest::new(&precomputed_key, &peer_dht_pk, &cookie_request_payload);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.handle_udp_cookie_request(&cookie_request, addr).await.unwrap();
+
+        let res = net_crypto.handle_udp_cookie_request(&cookie_request, addr).await;
+        assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::CookieRequestRateLimited { ip: addr.ip() });
+
+        // requests from other addresses are still handled
+        let addr = "127.0.0.2:12345".parse().unwrap();
+        net_crypto.handle_udp_cookie_request(&cookie_request, addr).await.unwrap();
+    }
+
     #[tokio::test]
     async fn handle_udp_cookie_request_invalid() {
         crypto_init().unwrap();
@@ -2186,7 +2602,10 @@ mod tests {
     #[tokio::test]
     async fn handle_tcp_crypto_handshake() {
         async fn test_me(net_crypto: NetCrypto, packet: CryptoHandshake, _saddr: SocketAddr, pk: PublicKey) -> NetCrypto {
-            net_crypto.handle_tcp_crypto_handshake(&packet, pk).await.unwrap();
+            let (relay_pk, _relay_sk) = gen_keypair();
+            net_crypto.handle_tcp_crypto_handshake(&packet, pk, relay_pk).await.unwrap();
+            let tcp_relay = net_crypto.connections.read().values().next().unwrap().read().get_tcp_relay();
+            assert_eq!(tcp_relay, Some(relay_pk));
             net_crypto
         }
 
@@ -2256,8 +2675,9 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn handle_udp_crypto_handshake_new_address_new_dht_pk() {
-        let (udp_tx, udp_rx) = mpsc::channel(1);
+    async fn handle_udp_crypto_handshake_new_connection_relay_only() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
@@ -2278,32 +2698,12 @@ mod tests {
         let (peer_real_pk, peer_real_sk) = gen_keypair();
 
         net_crypto.add_friend(peer_real_pk);
+        net_crypto.set_friend_transport_policy(peer_real_pk, TransportPolicy::RelayOnly);
 
-        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
-        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
-
-        let received_nonce = gen_nonce();
-        let sent_nonce = gen_nonce();
-        let (peer_session_pk, _peer_session_sk) = gen_keypair();
-        let (_session_pk, session_sk) = gen_keypair();
-        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
-        connection.status = ConnectionStatus::Established {
-            sent_nonce,
-            received_nonce,
-            session_precomputed_key: session_precomputed_key.clone(),
-        };
-
-        let addr = "127.0.0.1:12345".parse().unwrap();
-        connection.set_udp_addr(addr);
-
-        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
-
-        let (new_peer_dht_pk, _new_peer_dht_sk) = gen_keypair();
         let real_precomputed_key = precompute(&real_pk, &peer_real_sk);
         let base_nonce = gen_nonce();
         let session_pk = gen_keypair().0;
-        let our_cookie = Cookie::new(peer_real_pk, new_peer_dht_pk);
+        let our_cookie = Cookie::new(peer_real_pk, peer_dht_pk);
         let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, &our_cookie);
         let cookie = EncryptedCookie {
             nonce: secretbox::gen_nonce(),
@@ -2317,47 +2717,76 @@ mod tests {
         };
         let crypto_handshake = CryptoHandshake::new(&real_precomputed_key, &crypto_handshake_payload, our_encrypted_cookie);
 
-        let new_addr = "127.0.0.2:12345".parse().unwrap();
-        net_crypto.handle_udp_crypto_handshake(&crypto_handshake, new_addr).await.unwrap();
-
-        // the old connection should be replaced with the new one
-
-        let connections = net_crypto.connections.read();
-        let connection = connections.get(&peer_real_pk).unwrap().read().clone();
+        let addr = "127.0.0.1:12345".parse().unwrap();
 
-        assert_eq!(connection.peer_dht_pk, new_peer_dht_pk);
-        assert_eq!(connection.get_udp_addr_v4(), Some(new_addr));
+        let error = net_crypto.handle_udp_crypto_handshake(&crypto_handshake, addr).await.err().unwrap();
+        assert_eq!(*error.kind(), HandlePacketErrorKind::UdpNotAllowed);
 
-        let received_nonce = unpack!(connection.status, ConnectionStatus::NotConfirmed, received_nonce);
-        assert_eq!(received_nonce, base_nonce);
+        assert!(net_crypto.connections.read().get(&peer_real_pk).is_none());
+        assert!(net_crypto.key_by_addr(addr).is_none());
+    }
 
-        let packet = unpack!(connection.status, ConnectionStatus::NotConfirmed, packet);
-        let packet = unpack!(packet.packet, StatusPacket::CryptoHandshake);
-        assert_eq!(packet.cookie, cookie);
+    #[tokio::test]
+    async fn handle_udp_crypto_handshake_new_connection_too_many_connections() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(4);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
 
-        let payload = packet.get_payload(&real_precomputed_key).unwrap();
-        assert_eq!(payload.cookie_hash, cookie.hash());
+        net_crypto.set_limits(NetCryptoLimits {
+            max_connections: 1,
+            .. NetCryptoLimits::default()
+        });
 
-        assert!(!net_crypto.keys_by_addr.read().contains_key(&(addr.ip(), addr.port())));
-        assert!(net_crypto.keys_by_addr.read().contains_key(&(new_addr.ip(), new_addr.port())));
+        let make_handshake = |net_crypto: &NetCrypto, peer_real_pk: PublicKey, peer_real_sk: &SecretKey| {
+            let real_precomputed_key = precompute(&real_pk, peer_real_sk);
+            let our_cookie = Cookie::new(peer_real_pk, gen_keypair().0);
+            let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, &our_cookie);
+            let crypto_handshake_payload = CryptoHandshakePayload {
+                base_nonce: gen_nonce(),
+                session_pk: gen_keypair().0,
+                cookie_hash: our_encrypted_cookie.hash(),
+                cookie: EncryptedCookie {
+                    nonce: secretbox::gen_nonce(),
+                    payload: vec![43; 88]
+                },
+            };
+            CryptoHandshake::new(&real_precomputed_key, &crypto_handshake_payload, our_encrypted_cookie)
+        };
 
-        // the old connection should be killed
+        let (peer_real_pk_1, peer_real_sk_1) = gen_keypair();
+        let (peer_real_pk_2, peer_real_sk_2) = gen_keypair();
+        net_crypto.add_friend(peer_real_pk_1);
+        net_crypto.add_friend(peer_real_pk_2);
 
-        let (received, _udp_rx) = udp_rx.into_future().await;
-        let (received, addr_to_send) = received.unwrap();
+        let handshake = make_handshake(&net_crypto, peer_real_pk_1, &peer_real_sk_1);
+        net_crypto.handle_udp_crypto_handshake(&handshake, "127.0.0.1:12345".parse().unwrap()).await.unwrap();
 
-        assert_eq!(addr_to_send, addr);
+        let handshake = make_handshake(&net_crypto, peer_real_pk_2, &peer_real_sk_2);
+        let res = net_crypto.handle_udp_crypto_handshake(&handshake, "127.0.0.2:12345".parse().unwrap()).await;
+        assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::TooManyConnections);
 
-        let packet = unpack!(received, DhtPacket::CryptoData);
-        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
-        assert_eq!(payload.buffer_start, 0);
-        assert_eq!(payload.packet_number, 0);
-        assert_eq!(payload.data, vec![PACKET_ID_KILL]);
+        assert!(net_crypto.connections.read().contains_key(&peer_real_pk_1));
+        assert!(!net_crypto.connections.read().contains_key(&peer_real_pk_2));
     }
 
     #[tokio::test]
-    async fn handle_udp_crypto_handshake_new_address_old_dht_pk() {
-        let (udp_tx, _udp_rx) = mpsc::channel(1);
+    async fn handle_udp_crypto_handshake_new_connection_rate_limited() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(8);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
@@ -2374,32 +2803,206 @@ mod tests {
             precomputed_keys,
         });
 
-        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
-        let (peer_real_pk, peer_real_sk) = gen_keypair();
+        net_crypto.set_limits(NetCryptoLimits {
+            max_handshakes_per_second: 1,
+            .. NetCryptoLimits::default()
+        });
 
-        net_crypto.add_friend(peer_real_pk);
+        let make_handshake = |net_crypto: &NetCrypto, peer_real_pk: PublicKey, peer_real_sk: &SecretKey| {
+            let real_precomputed_key = precompute(&real_pk, peer_real_sk);
+            let our_cookie = Cookie::new(peer_real_pk, gen_keypair().0);
+            let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, &our_cookie);
+            let crypto_handshake_payload = CryptoHandshakePayload {
+                base_nonce: gen_nonce(),
+                session_pk: gen_keypair().0,
+                cookie_hash: our_encrypted_cookie.hash(),
+                cookie: EncryptedCookie {
+                    nonce: secretbox::gen_nonce(),
+                    payload: vec![43; 88]
+                },
+            };
+            CryptoHandshake::new(&real_precomputed_key, &crypto_handshake_payload, our_encrypted_cookie)
+        };
 
-        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
-        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let (peer_real_pk_1, peer_real_sk_1) = gen_keypair();
+        let (peer_real_pk_2, peer_real_sk_2) = gen_keypair();
+        let (peer_real_pk_3, peer_real_sk_3) = gen_keypair();
+        net_crypto.add_friend(peer_real_pk_1);
+        net_crypto.add_friend(peer_real_pk_2);
 
-        let addr = "127.0.0.1:12345".parse().unwrap();
-        connection.set_udp_addr(addr);
+        tokio::time::pause();
 
-        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+        // handshakes from unknown peers are not counted
+        for _ in 0 .. 2 {
+            let handshake = make_handshake(&net_crypto, peer_real_pk_3, &peer_real_sk_3);
+            let res = net_crypto.handle_udp_crypto_handshake(&handshake, "127.0.0.3:12345".parse().unwrap()).await;
+            assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::UnexpectedCryptoHandshake);
+        }
 
-        let real_precomputed_key = precompute(&real_pk, &peer_real_sk);
-        let base_nonce = gen_nonce();
-        let session_pk = gen_keypair().0;
-        let our_cookie = Cookie::new(peer_real_pk, peer_dht_pk);
-        let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, &our_cookie);
-        let cookie = EncryptedCookie {
-            nonce: secretbox::gen_nonce(),
-            payload: vec![43; 88]
-        };
-        let crypto_handshake_payload = CryptoHandshakePayload {
-            base_nonce,
-            session_pk,
+        let handshake = make_handshake(&net_crypto, peer_real_pk_1, &peer_real_sk_1);
+        net_crypto.handle_udp_crypto_handshake(&handshake, "127.0.0.1:12345".parse().unwrap()).await.unwrap();
+
+        let handshake = make_handshake(&net_crypto, peer_real_pk_1, &peer_real_sk_1);
+        let addr = "127.0.0.2:12345".parse().unwrap();
+        let res = net_crypto.handle_udp_crypto_handshake(&handshake, addr).await;
+        assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::HandshakeRateLimited);
+
+        // other friends are not affected
+        let handshake_2 = make_handshake(&net_crypto, peer_real_pk_2, &peer_real_sk_2);
+        net_crypto.handle_udp_crypto_handshake(&handshake_2, "127.0.0.4:12345".parse().unwrap()).await.unwrap();
+        assert!(net_crypto.connections.read().contains_key(&peer_real_pk_2));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        net_crypto.handle_udp_crypto_handshake(&handshake, addr).await.unwrap();
+        assert_eq!(net_crypto.connection_saddr(&peer_real_pk_1), Some(addr));
+    }
+
+    #[tokio::test]
+    async fn handle_udp_crypto_handshake_new_address_new_dht_pk() {
+        let (udp_tx, udp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, peer_real_sk) = gen_keypair();
+
+        net_crypto.add_friend(peer_real_pk);
+
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        let (new_peer_dht_pk, _new_peer_dht_sk) = gen_keypair();
+        let real_precomputed_key = precompute(&real_pk, &peer_real_sk);
+        let base_nonce = gen_nonce();
+        let session_pk = gen_keypair().0;
+        let our_cookie = Cookie::new(peer_real_pk, new_peer_dht_pk);
+        let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, &our_cookie);
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce,
+            session_pk,
+            cookie_hash: our_encrypted_cookie.hash(),
+            cookie: cookie.clone()
+        };
+        let crypto_handshake = CryptoHandshake::new(&real_precomputed_key, &crypto_handshake_payload, our_encrypted_cookie);
+
+        let new_addr = "127.0.0.2:12345".parse().unwrap();
+        net_crypto.handle_udp_crypto_handshake(&crypto_handshake, new_addr).await.unwrap();
+
+        // the old connection should be replaced with the new one
+
+        let connections = net_crypto.connections.read();
+        let connection = connections.get(&peer_real_pk).unwrap().read().clone();
+
+        assert_eq!(connection.peer_dht_pk, new_peer_dht_pk);
+        assert_eq!(connection.get_udp_addr_v4(), Some(new_addr));
+
+        let received_nonce = unpack!(connection.status, ConnectionStatus::NotConfirmed, received_nonce);
+        assert_eq!(received_nonce, base_nonce);
+
+        let packet = unpack!(connection.status, ConnectionStatus::NotConfirmed, packet);
+        let packet = unpack!(packet.packet, StatusPacket::CryptoHandshake);
+        assert_eq!(packet.cookie, cookie);
+
+        let payload = packet.get_payload(&real_precomputed_key).unwrap();
+        assert_eq!(payload.cookie_hash, cookie.hash());
+
+        assert!(!net_crypto.keys_by_addr.read().contains_key(&(addr.ip(), addr.port())));
+        assert!(net_crypto.keys_by_addr.read().contains_key(&(new_addr.ip(), new_addr.port())));
+
+        // the old connection should be killed
+
+        let (received, _udp_rx) = udp_rx.into_future().await;
+        let (received, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let packet = unpack!(received, DhtPacket::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.buffer_start, 0);
+        assert_eq!(payload.packet_number, 0);
+        assert_eq!(payload.data, vec![PACKET_ID_KILL]);
+    }
+
+    #[tokio::test]
+    async fn handle_udp_crypto_handshake_new_address_old_dht_pk() {
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, peer_real_sk) = gen_keypair();
+
+        net_crypto.add_friend(peer_real_pk);
+
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        let real_precomputed_key = precompute(&real_pk, &peer_real_sk);
+        let base_nonce = gen_nonce();
+        let session_pk = gen_keypair().0;
+        let our_cookie = Cookie::new(peer_real_pk, peer_dht_pk);
+        let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, &our_cookie);
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce,
+            session_pk,
             cookie_hash: our_encrypted_cookie.hash(),
             cookie: cookie.clone()
         };
@@ -2580,11 +3183,11 @@ mod tests {
         let crypto_data_payload = CryptoDataPayload {
             buffer_start: 0,
             packet_number: 0,
-            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+            data: Bytes::from(vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3])
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await.unwrap();
+        net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).await.unwrap();
 
         // The diff between nonces is not bigger than the threshold so received
         // nonce shouldn't be changed
@@ -2643,11 +3246,11 @@ mod tests {
         let crypto_data_payload = CryptoDataPayload {
             buffer_start: 0,
             packet_number: 0,
-            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+            data: Bytes::from(vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3])
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, packet_nonce, &crypto_data_payload);
 
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await.unwrap();
+        net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).await.unwrap();
 
         // The diff between nonces is bigger than the threshold so received
         // nonce should be changed increased
@@ -2716,14 +3319,14 @@ mod tests {
         let crypto_data_payload = CryptoDataPayload {
             buffer_start: 1,
             packet_number: 0,
-            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+            data: Bytes::from(vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3])
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
 
         tokio::time::advance(Duration::from_millis(250)).await;
 
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await.unwrap();
+        net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).await.unwrap();
 
         // The diff between nonces is not bigger than the threshold so received
         // nonce shouldn't be changed
@@ -2784,11 +3387,11 @@ mod tests {
         let crypto_data_payload = CryptoDataPayload {
             buffer_start: 7, // bigger than end index of sent packets buffer
             packet_number: 0,
-            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+            data: Bytes::from(vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3])
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        let res = net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await;
+        let res = net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).await;
         assert!(res.is_err());
         assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::PacketsArrayError);
 
@@ -2800,6 +3403,77 @@ mod tests {
         assert_eq!(connection.send_array.buffer_end, 0);
     }
 
+    #[tokio::test]
+    async fn handle_crypto_data_too_many_unconfirmed_packets() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(4);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        net_crypto.set_limits(NetCryptoLimits {
+            max_unconfirmed_recv_packets: 2,
+            .. NetCryptoLimits::default()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::NotConfirmed {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            session_precomputed_key: session_precomputed_key.clone(),
+            packet: StatusPacketWithTime::new_crypto_handshake(CryptoHandshake {
+                cookie: EncryptedCookie {
+                    nonce: secretbox::gen_nonce(),
+                    payload: vec![43; 88],
+                },
+                nonce: gen_nonce(),
+                payload: vec![42; 248],
+            }),
+        };
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 2,
+            data: Bytes::from(vec![PACKET_ID_LOSSY_RANGE_START - 1, 1, 2, 3])
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
+
+        let res = net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).await;
+        assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::TooManyUnconfirmedPackets { packet_number: 2 });
+        assert!(connection.is_not_confirmed());
+        assert_eq!(connection.recv_array.len(), 0);
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 1,
+            data: Bytes::from(vec![PACKET_ID_LOSSY_RANGE_START - 1, 1, 2, 3])
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
+
+        net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).await.unwrap();
+        assert!(connection.is_established());
+    }
+
     #[tokio::test]
     async fn handle_crypto_data_lossless() {
         crypto_init().unwrap();
@@ -2838,28 +3512,28 @@ mod tests {
         let crypto_data_payload_1 = CryptoDataPayload {
             buffer_start: 0,
             packet_number: 0,
-            data: vec![PACKET_ID_LOSSY_RANGE_START - 1, 1, 2, 3]
+            data: Bytes::from(vec![PACKET_ID_LOSSY_RANGE_START - 1, 1, 2, 3])
         };
         let crypto_data_1 = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload_1);
 
         let crypto_data_payload_2 = CryptoDataPayload {
             buffer_start: 0,
             packet_number: 1,
-            data: vec![PACKET_ID_LOSSY_RANGE_START - 1, 4, 5, 6]
+            data: Bytes::from(vec![PACKET_ID_LOSSY_RANGE_START - 1, 4, 5, 6])
         };
         let crypto_data_2 = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload_2);
 
         let crypto_data_payload_3 = CryptoDataPayload {
             buffer_start: 0,
             packet_number: 2,
-            data: vec![PACKET_ID_LOSSY_RANGE_START - 1, 7, 8, 9]
+            data: Bytes::from(vec![PACKET_ID_LOSSY_RANGE_START - 1, 7, 8, 9])
         };
         let crypto_data_3 = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload_3);
 
         // Send packets in random order
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data_2, /* udp */ true).await.unwrap();
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data_3, /* udp */ true).await.unwrap();
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data_1, /* udp */ true).await.unwrap();
+        net_crypto.handle_crypto_data(&mut connection, crypto_data_2, /* udp */ true).await.unwrap();
+        net_crypto.handle_crypto_data(&mut connection, crypto_data_3, /* udp */ true).await.unwrap();
+        net_crypto.handle_crypto_data(&mut connection, crypto_data_1, /* udp */ true).await.unwrap();
 
         // The diff between nonces is not bigger than the threshold so received
         // nonce shouldn't be changed
@@ -2926,11 +3600,11 @@ mod tests {
         let crypto_data_payload = CryptoDataPayload {
             buffer_start: 0,
             packet_number: CRYPTO_PACKET_BUFFER_SIZE,
-            data: vec![PACKET_ID_LOSSY_RANGE_START - 1, 1, 2, 3]
+            data: Bytes::from(vec![PACKET_ID_LOSSY_RANGE_START - 1, 1, 2, 3])
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        let res = net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await;
+        let res = net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).await;
         assert!(res.is_err());
         assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::PacketsArrayError);
 
@@ -2987,11 +3661,11 @@ mod tests {
         let crypto_data_payload = CryptoDataPayload {
             buffer_start: 0,
             packet_number: 0,
-            data: vec![PACKET_ID_KILL]
+            data: Bytes::from(vec![PACKET_ID_KILL])
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        net_crypto.handle_crypto_data(&mut connection.write(), &crypto_data, /* udp */ true).await.unwrap();
+        net_crypto.handle_crypto_data(&mut connection.write(), crypto_data, /* udp */ true).await.unwrap();
 
         assert!(net_crypto.connections.read().is_empty());
         assert!(net_crypto.keys_by_addr.read().is_empty());
@@ -3059,13 +3733,13 @@ mod tests {
         let crypto_data_payload = CryptoDataPayload {
             buffer_start: 0,
             packet_number: 0,
-            data: vec![PACKET_ID_REQUEST, 1, 5, 0, 0, 0, 254] // request 0, 5 and 1024 packets
+            data: Bytes::from(vec![PACKET_ID_REQUEST, 1, 5, 0, 0, 0, 254]) // request 0, 5 and 1024 packets
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
         tokio::time::advance(Duration::from_secs(1)).await;
 
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await.unwrap();
+        net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).await.unwrap();
 
         assert!(connection.send_array.get(0).unwrap().requested);
         assert!(connection.send_array.get(1).is_none());
@@ -3124,11 +3798,11 @@ mod tests {
         let crypto_data_payload = CryptoDataPayload {
             buffer_start: 0,
             packet_number: 0,
-            data: vec![PACKET_ID_REQUEST]
+            data: Bytes::from(vec![PACKET_ID_REQUEST])
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await.unwrap();
+        net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).await.unwrap();
 
         assert!(!connection.send_array.get(0).unwrap().requested);
         assert!(!connection.send_array.get(1).unwrap().requested);
@@ -3175,11 +3849,11 @@ mod tests {
         let crypto_data_payload = CryptoDataPayload {
             buffer_start: 0,
             packet_number: 0,
-            data: vec![255, 1, 2, 3] // only 255 is invalid id
+            data: Bytes::from(vec![255, 1, 2, 3]) // only 255 is invalid id
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        let res = net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await;
+        let res = net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).await;
         assert!(res.is_err());
         assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::PacketId { id: 255 });
 
@@ -3229,11 +3903,11 @@ mod tests {
         let crypto_data_payload = CryptoDataPayload {
             buffer_start: 0,
             packet_number: 0,
-            data: Vec::new()
+            data: Bytes::from(Vec::new())
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        let res = net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await;
+        let res = net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).await;
         assert!(res.is_err());
         assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::DataEmpty);
 
@@ -3277,11 +3951,11 @@ mod tests {
         let crypto_data_payload = CryptoDataPayload {
             buffer_start: 0,
             packet_number: 0,
-            data: vec![0, 0, PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+            data: Bytes::from(vec![0, 0, PACKET_ID_LOSSY_RANGE_START, 1, 2, 3])
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        let res = net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await;
+        let res = net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).await;
         assert!(res.is_err());
         assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::CannotHandleCryptoData);
     }
@@ -3333,7 +4007,7 @@ mod tests {
         let crypto_data_payload = CryptoDataPayload {
             buffer_start: 0,
             packet_number: 0,
-            data: vec![0, 0, PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+            data: Bytes::from(vec![0, 0, PACKET_ID_LOSSY_RANGE_START, 1, 2, 3])
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
@@ -3360,7 +4034,7 @@ mod tests {
     #[tokio::test]
     async fn handle_udp_crypto_data_lossy() {
         async fn test_me(net_crypto: NetCrypto, packet: CryptoData, saddr: SocketAddr, _pk: PublicKey) -> NetCrypto {
-            net_crypto.handle_udp_crypto_data(&packet, saddr).await.unwrap();
+            net_crypto.handle_udp_crypto_data(packet, saddr).await.unwrap();
             net_crypto
         }
 
@@ -3370,7 +4044,10 @@ mod tests {
     #[tokio::test]
     async fn handle_tcp_crypto_data_lossy() {
         async fn test_me(net_crypto: NetCrypto, packet: CryptoData, _saddr: SocketAddr, pk: PublicKey) -> NetCrypto {
-            net_crypto.handle_tcp_crypto_data(&packet, pk).await.unwrap();
+            let (relay_pk, _relay_sk) = gen_keypair();
+            net_crypto.handle_tcp_crypto_data(packet, pk, relay_pk).await.unwrap();
+            let tcp_relay = net_crypto.connections.read().values().next().unwrap().read().get_tcp_relay();
+            assert_eq!(tcp_relay, Some(relay_pk));
             net_crypto
         }
 
@@ -3607,9 +4284,10 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn main_loop_sends_status_packets() {
+    async fn send_packet_relay_only() {
         crypto_init().unwrap();
         let (udp_tx, udp_rx) = mpsc::channel(1);
+        let (tcp_tx, tcp_rx) = mpsc::channel(1);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
@@ -3626,34 +4304,42 @@ mod tests {
             precomputed_keys,
         });
 
+        net_crypto.set_tcp_sink(tcp_tx);
+
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
         let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
         let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        connection.transport_policy = TransportPolicy::RelayOnly;
 
-        let packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
-
+        // UDP address is alive but it must not be used
         let addr = "127.0.0.1:12345".parse().unwrap();
         connection.set_udp_addr(addr);
 
-        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        let packet = CryptoData {
+            nonce_last_bytes: 123,
+            payload: vec![42; 10]
+        };
 
-        net_crypto.main_loop().await.unwrap();
+        net_crypto.send_packet(Packet::CryptoData(packet.clone()), &mut connection).await.unwrap();
 
-        let (received, _udp_rx) = udp_rx.into_future().await;
-        let (received, addr_to_send) = received.unwrap();
+        let (received, _tcp_rx) = tcp_rx.into_future().await;
+        let (received, key_to_send) = received.unwrap();
 
-        assert_eq!(addr_to_send, addr);
-        assert_eq!(
-            unpack!(received, DhtPacket::CookieRequest),
-            unpack!(packet.packet, StatusPacket::CookieRequest)
-        );
+        assert_eq!(key_to_send, peer_dht_pk);
+        assert_eq!(received, TcpDataPayload::CryptoData(packet));
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(net_crypto);
+
+        assert!(udp_rx.collect::<Vec<_>>().await.is_empty());
     }
 
     #[tokio::test]
-    async fn main_loop_removes_timed_out_connections() {
+    async fn send_packet_udp_only() {
         crypto_init().unwrap();
-        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (udp_tx, udp_rx) = mpsc::channel(1);
+        let (tcp_tx, tcp_rx) = mpsc::channel(1);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
@@ -3670,33 +4356,194 @@ mod tests {
             precomputed_keys,
         });
 
+        net_crypto.set_tcp_sink(tcp_tx);
+
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
         let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
         let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        connection.transport_policy = TransportPolicy::UdpOnly;
 
         let addr = "127.0.0.1:12345".parse().unwrap();
         connection.set_udp_addr(addr);
 
-        // make the connection timed out
-        let cookie_request_id = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, cookie_request_id);
-        let mut packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
-        packet.num_sent = MAX_NUM_SENDPACKET_TRIES;
-        packet.sent_time -= CRYPTO_SEND_PACKET_INTERVAL + Duration::from_secs(1);
-        connection.status = ConnectionStatus::CookieRequesting {
-            cookie_request_id,
-            packet
+        // too big packet for UDP attempt but UDP is the only allowed transport
+        let packet = CryptoData {
+            nonce_last_bytes: 123,
+            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH]
         };
 
-        assert!(connection.is_timed_out());
+        tokio::time::pause();
+        tokio::time::advance(UDP_DIRECT_TIMEOUT + Duration::from_secs(1)).await;
 
-        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+        net_crypto.send_packet(Packet::CryptoData(packet.clone()), &mut connection).await.unwrap();
 
-        net_crypto.main_loop().await.unwrap();
+        let (received, _udp_rx) = udp_rx.into_future().await;
+        let (received, addr_to_send) = received.unwrap();
 
-        assert!(net_crypto.connections.read().is_empty());
-        assert!(net_crypto.keys_by_addr.read().is_empty());
+        assert_eq!(addr_to_send, addr);
+        assert_eq!(received, DhtPacket::CryptoData(packet));
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(net_crypto);
+
+        assert!(tcp_rx.collect::<Vec<_>>().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn send_packet_prefer_direct() {
+        crypto_init().unwrap();
+        let (udp_tx, udp_rx) = mpsc::channel(1);
+        let (tcp_tx, tcp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        net_crypto.set_tcp_sink(tcp_tx);
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        connection.transport_policy = TransportPolicy::PreferDirect;
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+
+        let packet = CryptoData {
+            nonce_last_bytes: 123,
+            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH]
+        };
+
+        tokio::time::pause();
+        tokio::time::advance(UDP_DIRECT_TIMEOUT + Duration::from_secs(1)).await;
+        // UDP attempt was made recently
+        connection.update_udp_send_attempt_time();
+
+        net_crypto.send_packet(Packet::CryptoData(packet.clone()), &mut connection).await.unwrap();
+
+        // dead UDP address is used along with TCP relays
+        let (received, _udp_rx) = udp_rx.into_future().await;
+        let (received, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+        assert_eq!(received, DhtPacket::CryptoData(packet.clone()));
+
+        let (received, _tcp_rx) = tcp_rx.into_future().await;
+        let (received, key_to_send) = received.unwrap();
+
+        assert_eq!(key_to_send, peer_dht_pk);
+        assert_eq!(received, TcpDataPayload::CryptoData(packet));
+    }
+
+    #[tokio::test]
+    async fn main_loop_sends_status_packets() {
+        crypto_init().unwrap();
+        let (udp_tx, udp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        net_crypto.main_loop().await.unwrap();
+
+        let (received, _udp_rx) = udp_rx.into_future().await;
+        let (received, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+        assert_eq!(
+            unpack!(received, DhtPacket::CookieRequest),
+            unpack!(packet.packet, StatusPacket::CookieRequest)
+        );
+    }
+
+    #[tokio::test]
+    async fn main_loop_removes_timed_out_connections() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let mut net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+
+        // make the connection timed out
+        let cookie_request_id = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, cookie_request_id);
+        let mut packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
+        packet.num_sent = MAX_NUM_SENDPACKET_TRIES;
+        packet.sent_time -= CRYPTO_SEND_PACKET_INTERVAL + Duration::from_secs(1);
+        connection.status = ConnectionStatus::CookieRequesting {
+            cookie_request_id,
+            packet
+        };
+
+        assert!(connection.is_timed_out());
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        let stats = Stats::new();
+        stats.gauges.set_crypto_connections(1);
+        net_crypto.set_stats(stats.clone());
+
+        net_crypto.main_loop().await.unwrap();
+
+        assert!(net_crypto.connections.read().is_empty());
+        assert!(net_crypto.keys_by_addr.read().is_empty());
+        assert_eq!(stats.gauges.crypto_connections(), 0);
     }
 
     #[tokio::test]
@@ -3755,6 +4602,67 @@ mod tests {
         assert_eq!(payload.data, vec![PACKET_ID_REQUEST]);
     }
 
+    #[tokio::test]
+    async fn main_loop_reports_transport() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(4);
+        let (transport_tx, transport_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        net_crypto.set_transport_sink(transport_tx);
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        net_crypto.add_established_connection(peer_dht_pk, peer_real_pk, gen_nonce(), gen_nonce(), session_precomputed_key);
+
+        tokio::time::pause();
+
+        net_crypto.main_loop().await.unwrap();
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.set_friend_udp_addr(peer_real_pk, SocketAddr::V4(addr));
+
+        // transport is reported only when it changes
+        net_crypto.main_loop().await.unwrap();
+        net_crypto.main_loop().await.unwrap();
+
+        net_crypto.set_friend_transport_policy(peer_real_pk, TransportPolicy::RelayOnly);
+
+        net_crypto.main_loop().await.unwrap();
+
+        let (relay_pk, _relay_sk) = gen_keypair();
+        net_crypto.connection_by_key(peer_real_pk).unwrap().write().set_tcp_relay(relay_pk);
+
+        net_crypto.main_loop().await.unwrap();
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(net_crypto);
+
+        // no transport is reported until some transport works
+        assert_eq!(transport_rx.collect::<Vec<_>>().await, vec![
+            (peer_real_pk, Some(ConnectionTransport::UdpV4(addr))),
+            (peer_real_pk, None),
+            (peer_real_pk, Some(ConnectionTransport::TcpRelay(relay_pk))),
+        ]);
+    }
+
     #[tokio::test]
     async fn main_loop_sends_requested_packets() {
         crypto_init().unwrap();
@@ -3970,11 +4878,11 @@ mod tests {
 
         connection.recv_array.buffer_end = 270;
         assert!(connection.recv_array.insert(2, RecvPacket {
-            data: vec![42; 123],
+            data: Bytes::from(vec![42; 123]),
         }).is_ok());
         for i in 5 .. 269 {
             assert!(connection.recv_array.insert(i, RecvPacket {
-                data: vec![42; 123],
+                data: Bytes::from(vec![42; 123]),
             }).is_ok());
         }
 
@@ -4124,6 +5032,7 @@ mod tests {
         assert_eq!(connection.send_array.get(2).unwrap().sent_time, now + delay);
         assert_eq!(connection.send_array.get(4).unwrap().sent_time, now);
         assert_eq!(connection.send_array.get(5).unwrap().sent_time, now + delay);
+        assert_eq!(connection.total_packets_resent, 2);
 
         // Necessary to drop udp_tx so that udp_rx.collect() can be finished
         drop(net_crypto.udp_tx);
@@ -4200,6 +5109,284 @@ mod tests {
         assert_eq!(payload.data, data);
     }
 
+    #[tokio::test]
+    async fn send_lossless_capture() {
+        crypto_init().unwrap();
+        let (udp_tx, udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let capture_buf = SharedBuf::default();
+        let (capture, writer) = Capture::new(capture_buf.clone()).unwrap();
+        net_crypto.set_capture(capture);
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        net_crypto.add_established_connection(peer_dht_pk, peer_real_pk, gen_nonce(), gen_nonce(), session_precomputed_key);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.connections.read()[&peer_real_pk].write().set_udp_addr(addr);
+
+        net_crypto.send_lossless(peer_real_pk, vec![16, 42]).await.unwrap();
+
+        // packets that failed to be sent are not captured
+        drop(udp_rx);
+        assert!(net_crypto.send_lossless(peer_real_pk, vec![16, 43]).await.is_err());
+
+        drop(net_crypto);
+        writer.run().await;
+
+        let records = capture_buf.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].direction, Direction::Outgoing);
+        assert_eq!(records[0].layer, Layer::NetCrypto);
+        assert_eq!(records[0].addr, Some(addr));
+        assert_eq!(records[0].data, vec![16, 42]);
+    }
+
+    #[tokio::test]
+    async fn send_lossless_ready() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        net_crypto.add_established_connection(
+            peer_dht_pk,
+            peer_real_pk,
+            gen_nonce(),
+            gen_nonce(),
+            precompute(&peer_session_pk, &session_sk),
+        );
+        net_crypto.connections.read()[&peer_real_pk].write().set_udp_addr("127.0.0.1:12345".parse().unwrap());
+
+        // the send rate is exceeded
+        net_crypto.connections.read()[&peer_real_pk].write().packets_sent = 100;
+
+        let mut future = Box::pin(net_crypto.send_lossless_ready(peer_real_pk, vec![16, 42]));
+        assert!(futures::poll!(&mut future).is_pending());
+
+        // the send rate budget is renewed
+        net_crypto.connections.read()[&peer_real_pk].write().packets_sent = 0;
+        net_crypto.wake_senders(&peer_real_pk);
+
+        future.await.unwrap();
+
+        let connections = net_crypto.connections.read();
+        let connection = connections[&peer_real_pk].read();
+        assert_eq!(connection.packets_sent, 1);
+        assert_eq!(connection.send_array.buffer[0].clone().unwrap().data, vec![16, 42]);
+    }
+
+    #[tokio::test]
+    async fn main_loop_wakes_senders() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(8);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let mut peer_real_pks = Vec::new();
+        for port in 12345 .. 12347 {
+            let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+            let (peer_real_pk, _peer_real_sk) = gen_keypair();
+            net_crypto.add_established_connection(
+                peer_dht_pk,
+                peer_real_pk,
+                gen_nonce(),
+                gen_nonce(),
+                precompute(&peer_session_pk, &session_sk),
+            );
+            net_crypto.connections.read()[&peer_real_pk].write().set_udp_addr(format!("127.0.0.1:{}", port).parse().unwrap());
+            peer_real_pks.push(peer_real_pk);
+        }
+        let (rate_limited_pk, full_pk) = (peer_real_pks[0], peer_real_pks[1]);
+
+        // the send rate is exceeded
+        net_crypto.connections.read()[&rate_limited_pk].write().packets_sent = 100;
+        // the send array is full
+        net_crypto.connections.read()[&full_pk].write().send_array.buffer_end = CRYPTO_PACKET_BUFFER_SIZE;
+
+        let mut rate_limited_future = Box::pin(net_crypto.send_lossless_ready(rate_limited_pk, vec![16, 42]));
+        let mut full_future = Box::pin(net_crypto.send_lossless_ready(full_pk, vec![16, 42]));
+        assert!(futures::poll!(&mut rate_limited_future).is_pending());
+        assert!(futures::poll!(&mut full_future).is_pending());
+
+        // the send rate budget is renewed but the send array is still full
+        net_crypto.main_loop().await.unwrap();
+
+        assert!(!net_crypto.send_wakers.lock().contains_key(&rate_limited_pk));
+        assert!(net_crypto.send_wakers.lock().contains_key(&full_pk));
+
+        rate_limited_future.await.unwrap();
+        assert!(futures::poll!(&mut full_future).is_pending());
+    }
+
+    #[tokio::test]
+    async fn send_lossless_ready_no_connection() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+
+        let res = net_crypto.send_lossless_ready(peer_real_pk, vec![16, 42]).await;
+        assert_eq!(*res.err().unwrap().kind(), SendLosslessPacketErrorKind::NoConnection);
+    }
+
+    #[tokio::test]
+    async fn lossless_sink() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        net_crypto.add_established_connection(
+            peer_dht_pk,
+            peer_real_pk,
+            gen_nonce(),
+            gen_nonce(),
+            precompute(&peer_session_pk, &session_sk),
+        );
+        net_crypto.connections.read()[&peer_real_pk].write().set_udp_addr("127.0.0.1:12345".parse().unwrap());
+
+        let mut sink = net_crypto.lossless_sink(peer_real_pk);
+        sink.send(vec![16, 42]).await.unwrap();
+
+        // the send rate is exceeded so the sink is not ready
+        net_crypto.connections.read()[&peer_real_pk].write().packets_sent = 100;
+        assert!(futures::poll!(future::poll_fn(|cx| sink.poll_ready_unpin(cx))).is_pending());
+
+        net_crypto.connections.read()[&peer_real_pk].write().packets_sent = 0;
+        net_crypto.wake_senders(&peer_real_pk);
+        sink.send(vec![17, 43]).await.unwrap();
+
+        let connections = net_crypto.connections.read();
+        let connection = connections[&peer_real_pk].read();
+        assert_eq!(connection.send_array.len(), 2);
+        assert_eq!(connection.send_array.buffer[1].clone().unwrap().data, vec![17, 43]);
+    }
+
+    #[tokio::test]
+    async fn connection_info() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+
+        assert!(net_crypto.connection_info(peer_real_pk).is_none());
+
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        let info = net_crypto.connection_info(peer_real_pk).unwrap();
+        assert!(!info.is_established);
+        assert_eq!(info.transport, None);
+        assert_eq!(info.since_handshake, None);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.set_friend_udp_addr(peer_real_pk, SocketAddr::V4(addr));
+        net_crypto.send_lossless(peer_real_pk, vec![16, 42]).await.ok();
+
+        let info = net_crypto.connection_info(peer_real_pk).unwrap();
+        assert_eq!(info.transport, Some(ConnectionTransport::UdpV4(addr)));
+        assert_eq!(info.packets_sent, 1);
+        assert_eq!(info.send_queue, 1);
+    }
+
     #[tokio::test]
     async fn send_lossless_no_connection() {
         crypto_init().unwrap();
@@ -4289,6 +5476,101 @@ mod tests {
         assert_eq!(cookie_request_payload.pk, real_pk);
     }
 
+    #[test]
+    fn set_congestion_controller() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let delay_controller: Box<dyn CongestionController> = Box::new(DelayCongestionController::default());
+        let tox_controller: Box<dyn CongestionController> = Box::new(ToxCongestionController::default());
+
+        // existing connection gets the new controller immediately
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+        assert_eq!(net_crypto.connections.read()[&peer_real_pk].read().congestion.name(), "tox");
+        net_crypto.set_friend_congestion_controller(peer_real_pk, delay_controller.clone());
+        assert_eq!(net_crypto.connections.read()[&peer_real_pk].read().congestion.name(), "delay");
+
+        // new connections get the default controller
+        net_crypto.set_congestion_controller(delay_controller.clone());
+        let (peer_real_pk_2, _peer_real_sk_2) = gen_keypair();
+        let (peer_dht_pk_2, _peer_dht_sk_2) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk_2, peer_dht_pk_2);
+        assert_eq!(net_crypto.connections.read()[&peer_real_pk_2].read().congestion.name(), "delay");
+
+        // friend's controller overrides the default one
+        let (peer_real_pk_3, _peer_real_sk_3) = gen_keypair();
+        let (peer_dht_pk_3, _peer_dht_sk_3) = gen_keypair();
+        net_crypto.set_friend_congestion_controller(peer_real_pk_3, tox_controller.clone());
+        net_crypto.add_connection(peer_real_pk_3, peer_dht_pk_3);
+        assert_eq!(net_crypto.connections.read()[&peer_real_pk_3].read().congestion.name(), "tox");
+    }
+
+    #[test]
+    fn set_friend_transport_policy() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        // existing connection gets the new policy immediately
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr);
+        assert_eq!(net_crypto.connections.read()[&peer_real_pk].read().transport_policy, TransportPolicy::Auto);
+        net_crypto.set_friend_transport_policy(peer_real_pk, TransportPolicy::RelayOnly);
+        assert_eq!(net_crypto.connections.read()[&peer_real_pk].read().transport_policy, TransportPolicy::RelayOnly);
+
+        // UDP address is forgotten and can't be set again
+        assert!(net_crypto.connections.read()[&peer_real_pk].read().get_udp_addr_v4().is_none());
+        assert!(net_crypto.key_by_addr(addr).is_none());
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr);
+        assert!(net_crypto.connections.read()[&peer_real_pk].read().get_udp_addr_v4().is_none());
+        assert!(net_crypto.key_by_addr(addr).is_none());
+
+        // new connection gets the friend's policy
+        let (peer_real_pk_2, _peer_real_sk_2) = gen_keypair();
+        let (peer_dht_pk_2, _peer_dht_sk_2) = gen_keypair();
+        net_crypto.set_friend_transport_policy(peer_real_pk_2, TransportPolicy::UdpOnly);
+        net_crypto.add_connection(peer_real_pk_2, peer_dht_pk_2);
+        assert_eq!(net_crypto.connections.read()[&peer_real_pk_2].read().transport_policy, TransportPolicy::UdpOnly);
+
+        // policy is forgotten when the friend is removed
+        net_crypto.remove_friend(peer_real_pk_2);
+        assert_eq!(net_crypto.transport_policy(&peer_real_pk_2), TransportPolicy::Auto);
+    }
+
     #[tokio::test]
     async fn add_connection_already_exists() {
         crypto_init().unwrap();
@@ -4492,6 +5774,108 @@ mod tests {
         assert_eq!(payload.data, vec![PACKET_ID_KILL]);
     }
 
+    #[tokio::test]
+    async fn run_until_kills_connections() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+        let (udp_tx, udp_rx) = mpsc::channel(8);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        net_crypto.add_established_connection(peer_dht_pk, peer_real_pk, sent_nonce, gen_nonce(), session_precomputed_key.clone());
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr);
+
+        let shutdown = Shutdown::new();
+        let handle = tokio::spawn(net_crypto.clone().run_until(shutdown.signal()));
+        shutdown.shutdown();
+        handle.await.unwrap().unwrap();
+
+        assert!(net_crypto.connections.read().is_empty());
+
+        // the last packet should be the kill packet
+        drop(net_crypto);
+        let mut nonce = sent_nonce;
+        let mut last_data = None;
+        for (received, addr_to_send) in udp_rx.collect::<Vec<_>>().await {
+            assert_eq!(addr_to_send, addr);
+            let packet = unpack!(received, DhtPacket::CryptoData);
+            let payload = packet.get_payload(&session_precomputed_key, &nonce).unwrap();
+            increment_nonce(&mut nonce);
+            last_data = Some(payload.data);
+        }
+        assert_eq!(last_data, Some(Bytes::from(vec![PACKET_ID_KILL])));
+    }
+
+    #[tokio::test]
+    async fn run_until_waits_for_send_array() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+        let (udp_tx, _udp_rx) = mpsc::channel(64);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        net_crypto.add_established_connection(peer_dht_pk, peer_real_pk, gen_nonce(), gen_nonce(), session_precomputed_key);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr);
+
+        // the packet is never acknowledged so the send array won't drain
+        net_crypto.send_lossless(peer_real_pk, vec![16, 42]).await.unwrap();
+
+        let shutdown = Shutdown::new();
+        let handle = tokio::spawn(net_crypto.clone().run_until(shutdown.signal()));
+        shutdown.shutdown();
+
+        for _ in 0 .. 10 {
+            tokio::time::advance(SHUTDOWN_DRAIN_TIMEOUT / 20).await;
+        }
+        assert!(net_crypto.connections.read().contains_key(&peer_real_pk));
+
+        tokio::time::advance(SHUTDOWN_DRAIN_TIMEOUT).await;
+        handle.await.unwrap().unwrap();
+
+        assert!(net_crypto.connections.read().is_empty());
+    }
+
     #[tokio::test]
     async fn kill_connection_no_connection() {
         crypto_init().unwrap();
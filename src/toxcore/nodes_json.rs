@@ -0,0 +1,353 @@
+/*! Parser of the community `nodes.json` list of bootstrap nodes and TCP
+relays.
+
+The list is published at <https://nodes.tox.chat/json> and has the following
+form (only used fields are shown):
+
+```json
+{
+  "nodes": [
+    {
+      "ipv4": "198.98.51.198",
+      "ipv6": "2605:6400:1:fed5:22:45af:ec10:f329",
+      "port": 33445,
+      "tcp_ports": [443, 33445],
+      "public_key": "1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F",
+      "status_udp": true,
+      "status_tcp": true
+    }
+  ]
+}
+```
+
+Missing addresses are denoted with `-` or an empty string. Addresses that are
+not IP addresses (e.g. host names) are skipped since resolving them is out of
+scope of this module. Hand-written lists may omit `status_udp` and
+`status_tcp` fields, such entries are considered reachable.
+
+Nodes from [`NodesList::bootstrap_nodes`] are meant to be passed to
+`dht::server::Server::add_initial_bootstrap` or
+`dht::server::Server::set_initial_bootstrap` and relays from
+[`NodesList::tcp_relays`] to `tcp::client::Connections::add_relay_global`.
+[`watch_file`] can be used to reload the list periodically from a local file.
+
+This module is available only with `json` feature.
+
+[`NodesList::bootstrap_nodes`]: ./struct.NodesList.html#method.bootstrap_nodes
+[`NodesList::tcp_relays`]: ./struct.NodesList.html#method.tcp_relays
+[`watch_file`]: ./fn.watch_file.html
+*/
+
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use failure::{Fail, ResultExt};
+use futures::{SinkExt, StreamExt};
+use futures::channel::mpsc;
+use serde::Deserialize;
+
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::dht::packed_node::PackedNode;
+
+error_kind! {
+    #[doc = "Error that can happen when loading `nodes.json` list."]
+    #[derive(Debug)]
+    NodesJsonError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, Fail)]
+    NodesJsonErrorKind {
+        #[doc = "Error indicates that the file can't be read."]
+        #[fail(display = "Read nodes list file error")]
+        Io,
+        #[doc = "Error indicates that the list can't be parsed."]
+        #[fail(display = "Parse nodes list error")]
+        Parse,
+    }
+}
+
+/// Default interval of checking `nodes.json` file for changes.
+pub const NODES_JSON_RELOAD_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Entry of `nodes.json` list.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default)]
+pub struct NodeEntry {
+    /// IPv4 address or host name of the node, `-` if absent.
+    pub ipv4: String,
+    /// IPv6 address or host name of the node, `-` if absent.
+    pub ipv6: String,
+    /// UDP port of the node.
+    pub port: u16,
+    /// TCP ports of the node if it's a TCP relay.
+    pub tcp_ports: Vec<u16>,
+    /// DHT `PublicKey` of the node as hex string.
+    pub public_key: String,
+    /// Whether the node was reachable via UDP during the last check, `None`
+    /// if the list doesn't report it.
+    pub status_udp: Option<bool>,
+    /// Whether the node was reachable via TCP during the last check, `None`
+    /// if the list doesn't report it.
+    pub status_tcp: Option<bool>,
+    /// Maintainer of the node.
+    pub maintainer: String,
+    /// Location of the node.
+    pub location: String,
+}
+
+impl NodeEntry {
+    /// Parse `PublicKey` of the node.
+    pub fn pk(&self) -> Option<PublicKey> {
+        if self.public_key.len() != PUBLICKEYBYTES * 2 {
+            return None;
+        }
+
+        let bytes = (0 .. PUBLICKEYBYTES)
+            .map(|i| u8::from_str_radix(self.public_key.get(i * 2 .. i * 2 + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        PublicKey::from_slice(&bytes)
+    }
+
+    /// IP addresses of the node. IPv6 address is returned only when `ipv6` is
+    /// `true`.
+    pub fn ip_addrs(&self, ipv6: bool) -> Vec<IpAddr> {
+        let ipv4 = self.ipv4.parse::<IpAddr>().ok().filter(IpAddr::is_ipv4);
+        let ipv6 = if ipv6 {
+            self.ipv6.parse::<IpAddr>().ok().filter(IpAddr::is_ipv6)
+        } else {
+            None
+        };
+        ipv4.into_iter().chain(ipv6).collect()
+    }
+}
+
+/// List of nodes from `nodes.json`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default)]
+pub struct NodesList {
+    /// All entries of the list.
+    pub nodes: Vec<NodeEntry>,
+}
+
+impl NodesList {
+    /// Parse `nodes.json` list from a string.
+    pub fn from_json(json: &str) -> Result<NodesList, NodesJsonError> {
+        let list = serde_json::from_str(json)
+            .context(NodesJsonErrorKind::Parse)?;
+        Ok(list)
+    }
+
+    /// Read and parse `nodes.json` list from a file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<NodesList, NodesJsonError> {
+        let json = fs::read_to_string(path)
+            .context(NodesJsonErrorKind::Io)?;
+        NodesList::from_json(&json)
+    }
+
+    /// DHT nodes that were not reported as unreachable via UDP during the last
+    /// check. Entries with invalid `PublicKey` or without IP address are
+    /// skipped.
+    pub fn bootstrap_nodes(&self, ipv6: bool) -> Vec<PackedNode> {
+        self.nodes.iter()
+            .filter(|entry| entry.status_udp != Some(false) && entry.port != 0)
+            .filter_map(|entry| entry.pk().map(|pk| (entry, pk)))
+            .flat_map(|(entry, pk)| entry.ip_addrs(ipv6).into_iter()
+                .map(move |ip| PackedNode::new(SocketAddr::new(ip, entry.port), &pk))
+            )
+            .collect()
+    }
+
+    /// TCP relays that were not reported as unreachable via TCP during the
+    /// last check. Every address and TCP port of a relay produces a separate
+    /// entry. Entries with invalid `PublicKey` or without IP address are
+    /// skipped.
+    pub fn tcp_relays(&self, ipv6: bool) -> Vec<(SocketAddr, PublicKey)> {
+        self.nodes.iter()
+            .filter(|entry| entry.status_tcp != Some(false))
+            .filter_map(|entry| entry.pk().map(|pk| (entry, pk)))
+            .flat_map(|(entry, pk)| entry.ip_addrs(ipv6).into_iter()
+                .flat_map(move |ip| entry.tcp_ports.iter()
+                    .filter(|&&port| port != 0)
+                    .map(move |&port| (SocketAddr::new(ip, port), pk))
+                )
+            )
+            .collect()
+    }
+}
+
+/// Read and parse `nodes.json` list from a file without blocking the executor.
+async fn load_async(path: &Path) -> Result<NodesList, NodesJsonError> {
+    let json = tokio::fs::read_to_string(path).await
+        .context(NodesJsonErrorKind::Io)?;
+    NodesList::from_json(&json)
+}
+
+/// Reload `nodes.json` list from the file every `interval` and send it to
+/// `tx` when it's changed. The first successfully loaded list is always sent.
+/// Errors of reading and parsing are logged and don't stop reloading. The file
+/// is read in tokio's blocking thread pool. Result future is completed when the
+/// receiver is dropped.
+pub async fn watch_file(path: PathBuf, interval: Duration, mut tx: mpsc::Sender<NodesList>) {
+    let mut wakeups = tokio::time::interval(interval);
+    let mut last_list = None;
+
+    while wakeups.next().await.is_some() {
+        if tx.is_closed() {
+            break;
+        }
+
+        let list = match load_async(&path).await {
+            Ok(list) => list,
+            Err(e) => {
+                warn!("Failed to load nodes list from {}: {}", path.display(), e);
+                continue;
+            },
+        };
+
+        if last_list.as_ref() == Some(&list) {
+            continue;
+        }
+
+        last_list = Some(list.clone());
+        if tx.send(list).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PK_1: &str = "1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F";
+    const PK_2: &str = "F404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67";
+    const PK_3: &str = "8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832";
+
+    fn nodes_json() -> String {
+        format!(r#"{{
+            "last_scan": 1600000000,
+            "nodes": [
+                {{
+                    "ipv4": "198.98.51.198",
+                    "ipv6": "2605:6400:1:fed5:22:45af:ec10:f329",
+                    "port": 33445,
+                    "tcp_ports": [443, 33445],
+                    "public_key": "{}",
+                    "maintainer": "Impyy",
+                    "location": "US",
+                    "status_udp": true,
+                    "status_tcp": true,
+                    "version": "1000002018",
+                    "motd": "Welcome"
+                }},
+                {{
+                    "ipv4": "tox.example.com",
+                    "ipv6": "-",
+                    "port": 33445,
+                    "tcp_ports": [],
+                    "public_key": "{}",
+                    "status_udp": true,
+                    "status_tcp": false
+                }},
+                {{
+                    "ipv4": "67.215.253.85",
+                    "ipv6": "-",
+                    "port": 33445,
+                    "tcp_ports": [3389],
+                    "public_key": "{}",
+                    "status_udp": false,
+                    "status_tcp": true
+                }},
+                {{
+                    "ipv4": "1.2.3.4",
+                    "port": 33445,
+                    "public_key": "invalid",
+                    "status_udp": true,
+                    "status_tcp": true
+                }},
+                {{
+                    "ipv4": "5.6.7.8",
+                    "port": 33445,
+                    "tcp_ports": [443],
+                    "public_key": "{}"
+                }}
+            ]
+        }}"#, PK_1, PK_1, PK_2, PK_3)
+    }
+
+    fn pk(hex: &str) -> PublicKey {
+        NodeEntry { public_key: hex.to_owned(), ..NodeEntry::default() }.pk().unwrap()
+    }
+
+    #[test]
+    fn parse_pk() {
+        let entry = NodeEntry { public_key: PK_1.to_owned(), ..NodeEntry::default() };
+        assert_eq!(entry.pk().unwrap().as_ref()[.. 2], [0x1d, 0x5a]);
+
+        let entry = NodeEntry { public_key: PK_1[.. 62].to_owned() + "ZZ", ..NodeEntry::default() };
+        assert!(entry.pk().is_none());
+    }
+
+    #[test]
+    fn bootstrap_nodes() {
+        let list = NodesList::from_json(&nodes_json()).unwrap();
+        assert_eq!(list.nodes.len(), 5);
+        assert_eq!(list.nodes[4].status_udp, None);
+
+        assert_eq!(list.bootstrap_nodes(false), vec![
+            PackedNode::new("198.98.51.198:33445".parse().unwrap(), &pk(PK_1)),
+            PackedNode::new("5.6.7.8:33445".parse().unwrap(), &pk(PK_3)),
+        ]);
+        assert_eq!(list.bootstrap_nodes(true), vec![
+            PackedNode::new("198.98.51.198:33445".parse().unwrap(), &pk(PK_1)),
+            PackedNode::new("[2605:6400:1:fed5:22:45af:ec10:f329]:33445".parse().unwrap(), &pk(PK_1)),
+            PackedNode::new("5.6.7.8:33445".parse().unwrap(), &pk(PK_3)),
+        ]);
+    }
+
+    #[test]
+    fn tcp_relays() {
+        let list = NodesList::from_json(&nodes_json()).unwrap();
+
+        assert_eq!(list.tcp_relays(false), vec![
+            ("198.98.51.198:443".parse().unwrap(), pk(PK_1)),
+            ("198.98.51.198:33445".parse().unwrap(), pk(PK_1)),
+            ("67.215.253.85:3389".parse().unwrap(), pk(PK_2)),
+            ("5.6.7.8:443".parse().unwrap(), pk(PK_3)),
+        ]);
+    }
+
+    #[test]
+    fn parse_error() {
+        let error = NodesList::from_json("{\"nodes\": 1}").err().unwrap();
+        assert_eq!(*error.kind(), NodesJsonErrorKind::Parse);
+    }
+
+    #[test]
+    fn load_error() {
+        let error = NodesList::load("/nonexistent/nodes.json").err().unwrap();
+        assert_eq!(*error.kind(), NodesJsonErrorKind::Io);
+    }
+
+    #[tokio::test]
+    async fn watch_file_sends_changed_lists() {
+        let path = std::env::temp_dir().join(format!("tox-nodes-{}.json", std::process::id()));
+        fs::write(&path, nodes_json()).unwrap();
+
+        let (tx, mut rx) = mpsc::channel(1);
+        let interval = Duration::from_millis(10);
+        let watch = tokio::spawn(watch_file(path.clone(), interval, tx));
+
+        let list = rx.next().await.unwrap();
+        assert_eq!(list.nodes.len(), 5);
+
+        fs::write(&path, "{\"nodes\": []}").unwrap();
+        let list = rx.next().await.unwrap();
+        assert!(list.nodes.is_empty());
+
+        drop(rx);
+        watch.await.unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -3,8 +3,13 @@
 mod errors;
 mod nodes_pool;
 mod onion_path;
+mod path_policy;
 mod paths_pool;
 
+pub use self::onion_path::OnionPathType;
+pub use self::path_policy::*;
+pub use self::paths_pool::{OnionPathHopInfo, OnionPathInfo};
+
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -30,6 +35,8 @@ use crate::toxcore::onion::client::paths_pool::*;
 use crate::toxcore::onion::onion_announce::initial_ping_id;
 use crate::toxcore::onion::packet::*;
 use crate::toxcore::packed_node::*;
+use crate::toxcore::shutdown::ShutdownSignal;
+use crate::toxcore::stats::Stats;
 use crate::toxcore::tcp::client::{Connections as TcpConnections};
 use crate::toxcore::time::*;
 use crate::toxcore::io_tokio::*;
@@ -342,6 +349,8 @@ pub struct OnionClient {
     data_pk: PublicKey,
     /// Onion client state.
     state: Arc<Mutex<OnionClientState>>,
+    /// Statistics where announce requests and responses are counted.
+    stats: Stats,
 }
 
 impl OnionClient {
@@ -361,6 +370,7 @@ impl OnionClient {
             data_sk,
             data_pk,
             state: Arc::new(Mutex::new(OnionClientState::new())),
+            stats: Stats::new(),
         }
     }
 
@@ -374,6 +384,11 @@ impl OnionClient {
         self.state.lock().friend_request_tx = Some(friend_request_sink)
     }
 
+    /// Set statistics object to count announce requests and responses in.
+    pub fn set_stats(&mut self, stats: Stats) {
+        self.stats = stats;
+    }
+
     /// Check if a node was pinged recently.
     fn is_pinged_recently(&self, pk: PublicKey, search_pk: PublicKey, request_queue: &RequestQueue<AnnounceRequestData>) -> bool {
         let check_pks = |data: &AnnounceRequestData| -> bool {
@@ -392,6 +407,10 @@ impl OnionClient {
     /// Send onion request via TCP or UDP depending on path.
     fn send_onion_request(&self, path: OnionPath, inner_onion_request: InnerOnionRequest, saddr: SocketAddr)
         -> impl Future<Output = Result<(), mpsc::SendError>> + Send {
+        if let InnerOnionRequest::InnerOnionAnnounceRequest(_) = inner_onion_request {
+            self.stats.counters.increase_onion_announce_requests();
+        }
+
         match path.path_type {
             OnionPathType::TCP => {
                 let onion_request = path.create_tcp_onion_request(saddr, inner_onion_request);
@@ -464,6 +483,7 @@ impl OnionClient {
         }
 
         state.paths_pool.set_timeouts(announce_data.path_id, announce_data.friend_pk.is_some());
+        self.stats.counters.increase_onion_announce_responses();
 
         if payload.announce_status == AnnounceStatus::Found {
             if let Some(last_seen) = last_seen {
@@ -492,6 +512,16 @@ impl OnionClient {
         }, /* evict */ true);
 
         state.paths_pool.path_nodes.put(PackedNode::new(announce_data.saddr, &announce_data.pk));
+        // nodes are not taken from DHT in TCP only mode so we learn them from
+        // announce responses that come via TCP relays
+        if state.paths_pool.is_tcp_only() {
+            for node in &payload.nodes {
+                if !IsGlobal::is_global(&node.ip()) && is_global {
+                    continue;
+                }
+                state.paths_pool.path_nodes.put(*node);
+            }
+        }
 
         let mut futures = Vec::with_capacity(payload.nodes.len());
 
@@ -555,7 +585,11 @@ impl OnionClient {
             .map_err(|e| e.context(HandleDhtPkAnnounceErrorKind::SendTo).into());
 
         let friend_dht_pk = dht_pk_announce.dht_pk;
-        let futures = dht_pk_announce.nodes.into_iter().map(|node| match node.ip_port.protocol {
+        // DHT nodes can't be reached in TCP only mode
+        let tcp_only = state.paths_pool.is_tcp_only();
+        let futures = dht_pk_announce.nodes.into_iter().filter(|node|
+            !tcp_only || node.ip_port.protocol == ProtocolType::TCP
+        ).map(|node| match node.ip_port.protocol {
             ProtocolType::UDP => {
                 let packed_node = PackedNode::new(node.ip_port.to_saddr(), &node.pk);
                 Either::Left(self.dht.ping_node(&packed_node)
@@ -610,6 +644,61 @@ impl OnionClient {
         state.paths_pool.path_nodes.put(node);
     }
 
+    /// Set policy that decides which nodes can be combined into new onion
+    /// paths. `DiversePathPolicy` is used by default.
+    pub fn set_path_policy(&self, policy: Arc<dyn PathPolicy>) {
+        self.state.lock().paths_pool.set_policy(policy);
+    }
+
+    /// Pin a node so that it's used as the first hop of new onion paths when
+    /// we are connected to DHT.
+    pub fn pin_path_node(&self, node: PackedNode) {
+        self.state.lock().paths_pool.pin_node(node);
+    }
+
+    /// Unpin previously pinned node.
+    pub fn unpin_path_node(&self, pk: &PublicKey) {
+        self.state.lock().paths_pool.unpin_node(pk);
+    }
+
+    /// Exclude a node so that it's never used for onion paths.
+    pub fn exclude_path_node(&self, pk: PublicKey) {
+        self.state.lock().paths_pool.exclude_node(pk);
+    }
+
+    /// Allow previously excluded node to be used for onion paths.
+    pub fn include_path_node(&self, pk: &PublicKey) {
+        self.state.lock().paths_pool.include_node(pk);
+    }
+
+    /// Get health information about onion paths used to announce ourselves.
+    pub fn announce_paths(&self) -> Vec<OnionPathInfo> {
+        self.state.lock().paths_pool.paths_info(false)
+    }
+
+    /// Get health information about onion paths used to search friends.
+    pub fn friend_paths(&self) -> Vec<OnionPathInfo> {
+        self.state.lock().paths_pool.paths_info(true)
+    }
+
+    /// Enable or disable TCP only mode. In this mode all onion packets are
+    /// sent via TCP relays, nodes for onion paths are not taken from DHT and
+    /// our DHT `PublicKey` is announced only via onion with TCP relays as
+    /// our nodes. Path nodes are taken from DHT initial bootstrap list and
+    /// learned from announce responses received via TCP relays. At least
+    /// 2 nodes should be known to build the first path so if the bootstrap
+    /// list is empty they should be added with `add_path_node`.
+    pub fn enable_tcp_only(&self, enabled: bool) {
+        self.state.lock().paths_pool.set_tcp_only(enabled);
+    }
+
+    /// Drop all onion paths and statistics of their nodes so that new paths
+    /// will be built. It should be called when the network changes since old
+    /// paths most likely won't work anymore.
+    pub fn rotate_paths(&self) {
+        self.state.lock().paths_pool.rotate_paths();
+    }
+
     /// Add a friend to start looking for its DHT `PublicKey`.
     pub fn add_friend(&self, real_pk: PublicKey) {
         let mut state = self.state.lock();
@@ -774,10 +863,16 @@ impl OnionClient {
         ).0.map_err(|e| e.context(RunErrorKind::SendTo).into())
     }
 
-    /// Get nodes to include to DHT `PublicKey` announcement packet.
-    fn dht_pk_nodes(&self) -> Vec<TcpUdpPackedNode> {
-        let relays = self.tcp_connections.get_random_relays(2);
-        let close_nodes: Vec<PackedNode> = self.dht.get_closest(&self.dht.pk, 4 - relays.len() as u8, false).into();
+    /// Get nodes to include to DHT `PublicKey` announcement packet. In TCP
+    /// only mode we can't be reached via UDP so only TCP relays are included.
+    fn dht_pk_nodes(&self, tcp_only: bool) -> Vec<TcpUdpPackedNode> {
+        let (relays, close_nodes) = if tcp_only {
+            (self.tcp_connections.get_random_relays(4), Vec::new())
+        } else {
+            let relays = self.tcp_connections.get_random_relays(2);
+            let close_nodes: Vec<PackedNode> = self.dht.get_closest(&self.dht.pk, 4 - relays.len() as u8, false).into();
+            (relays, close_nodes)
+        };
         relays.into_iter().map(|node| TcpUdpPackedNode {
             pk: node.pk,
             ip_port: IpPort::from_tcp_saddr(node.saddr),
@@ -789,7 +884,7 @@ impl OnionClient {
 
     /// Announce our DHT `PublicKey` to a friend via onion.
     fn send_dht_pk_onion(&self, friend: &mut OnionFriend, paths_pool: &mut PathsPool) -> impl Future<Output = Result<(), mpsc::SendError>> + Send {
-        let dht_pk_announce = DhtPkAnnouncePayload::new(self.dht.pk, self.dht_pk_nodes());
+        let dht_pk_announce = DhtPkAnnouncePayload::new(self.dht.pk, self.dht_pk_nodes(paths_pool.is_tcp_only()));
         let inner_payload = OnionDataResponseInnerPayload::DhtPkAnnounce(dht_pk_announce);
         let nonce = gen_nonce();
         let payload = OnionDataResponsePayload::new(&precompute(&friend.real_pk, &self.real_sk), self.real_pk, &nonce, &inner_payload);
@@ -834,7 +929,7 @@ impl OnionClient {
             return Either::Left(future::ok(()))
         };
 
-        let dht_pk_announce_payload = DhtPkAnnouncePayload::new(self.dht.pk, self.dht_pk_nodes());
+        let dht_pk_announce_payload = DhtPkAnnouncePayload::new(self.dht.pk, self.dht_pk_nodes(false));
         let dht_pk_announce = DhtPkAnnounce::new(
             &precompute(&friend.real_pk, &self.real_sk),
             self.real_pk,
@@ -915,7 +1010,9 @@ impl OnionClient {
                 futures.push(Box::pin(self.send_dht_pk_onion(friend, &mut state.paths_pool)));
             }
 
-            if friend.last_dht_pk_dht_sent.map_or(true, |time| clock_elapsed(time) > DHT_DHTPK_SEND_INTERVAL) {
+            // DHT requests are sent via UDP
+            if !state.paths_pool.is_tcp_only() &&
+                friend.last_dht_pk_dht_sent.map_or(true, |time| clock_elapsed(time) > DHT_DHTPK_SEND_INTERVAL) {
                 futures.push(Box::pin(self.send_dht_pk_dht_request(friend)));
             }
         }
@@ -925,8 +1022,17 @@ impl OnionClient {
             .map_err(|e| e.context(RunErrorKind::SendTo).into())
     }
 
-    /// Populate nodes pool from DHT for building random paths.
+    /// Populate nodes pool from DHT for building random paths. Only initial
+    /// bootstrap nodes are taken from DHT in TCP only mode since we don't
+    /// talk to other DHT nodes directly.
     fn populate_path_nodes(&self, state: &mut OnionClientState) {
+        if state.paths_pool.is_tcp_only() {
+            for node in self.dht.initial_bootstrap() {
+                state.paths_pool.path_nodes.put(node);
+            }
+            return;
+        }
+
         for node in self.dht.random_friend_nodes(MAX_ONION_ANNOUNCE_NODES) {
             state.paths_pool.path_nodes.put(node);
         }
@@ -951,12 +1057,27 @@ impl OnionClient {
                 ).map_ok(drop)
             })
     }
+
+    /// Run periodical announcements and friends searching until shutdown is
+    /// triggered. Returns nodes used for building onion paths so that they
+    /// can be saved and added back with `add_path_node` after restart.
+    pub async fn run_until(self, signal: ShutdownSignal) -> Result<Vec<PackedNode>, RunError> {
+        signal.run(self.clone().run()).await?;
+
+        let state = self.state.lock();
+        Ok(state.paths_pool.path_nodes.iter().cloned().collect())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::net::IpAddr;
+
+    use crate::toxcore::shutdown::Shutdown;
+    use crate::toxcore::tcp::packet::Packet as TcpPacket;
+
     impl OnionClient {
         pub fn has_friend(&self, pk: &PublicKey) -> bool {
             self.state.lock().friends.contains_key(pk)
@@ -1125,6 +1246,59 @@ mod tests {
         assert_eq!(state.paths_pool.path_nodes.rand(), Some(node));
     }
 
+    #[test]
+    fn rotate_paths() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        // make DHT connected so that we will build UDP onion paths
+        dht.add_node(PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0));
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        let onion_client = OnionClient::new(dht, tcp_connections, real_sk, real_pk);
+
+        for i in 0 .. 3 {
+            let saddr = SocketAddr::new(IpAddr::from([1 + i, 2, 3, 4]), 33445);
+            onion_client.add_path_node(PackedNode::new(saddr, &gen_keypair().0));
+        }
+        {
+            let mut state = onion_client.state.lock();
+            state.paths_pool.random_path(&onion_client.dht, &onion_client.tcp_connections, false).unwrap();
+            state.paths_pool.random_path(&onion_client.dht, &onion_client.tcp_connections, true).unwrap();
+        }
+
+        assert_eq!(onion_client.announce_paths().len(), 1);
+        assert_eq!(onion_client.friend_paths().len(), 1);
+
+        onion_client.rotate_paths();
+
+        assert!(onion_client.announce_paths().is_empty());
+        assert!(onion_client.friend_paths().is_empty());
+    }
+
+    #[tokio::test]
+    async fn run_until_returns_path_nodes() {
+        tokio::time::pause();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        let onion_client = OnionClient::new(dht, tcp_connections, real_sk, real_pk);
+
+        let node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+        onion_client.add_path_node(node);
+
+        let shutdown = Shutdown::new();
+        let handle = tokio::spawn(onion_client.run_until(shutdown.signal()));
+        shutdown.shutdown();
+
+        let nodes = handle.await.unwrap().unwrap();
+        assert_eq!(nodes, vec![node]);
+    }
+
     #[test]
     fn add_remove_friend() {
         let (dht_pk, dht_sk) = gen_keypair();
@@ -1205,7 +1379,9 @@ mod tests {
         // make DHT connected so that we will build UDP onion paths
         dht.add_node(PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0));
         let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
-        let onion_client = OnionClient::new(dht, tcp_connections, real_sk, real_pk);
+        let mut onion_client = OnionClient::new(dht, tcp_connections, real_sk, real_pk);
+        let stats = Stats::new();
+        onion_client.set_stats(stats.clone());
 
         let mut state = onion_client.state.lock();
 
@@ -1249,6 +1425,12 @@ mod tests {
 
         onion_client.handle_announce_response(&packet, true).await.unwrap();
 
+        // The response and the request to the node from the packet should be
+        // counted
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.onion_announce_responses, 1);
+        assert_eq!(snapshot.onion_announce_requests, 1);
+
         let state = onion_client.state.lock();
 
         // The sender should be added to close nodes
@@ -1727,6 +1909,53 @@ mod tests {
         assert_eq!(payload.pk, dht_pk);
     }
 
+    #[tokio::test]
+    async fn handle_data_response_dht_pk_announce_udp_node_tcp_only() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (udp_tx, udp_rx) = mpsc::channel(1);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        let onion_client = OnionClient::new(dht, tcp_connections, real_sk.clone(), real_pk);
+        onion_client.enable_tcp_only(true);
+
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+        let (friend_real_pk, friend_real_sk) = gen_keypair();
+
+        onion_client.add_friend(friend_real_pk);
+
+        let saddr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let (node_pk, _node_sk) = gen_keypair();
+        let dht_pk_announce_payload = DhtPkAnnouncePayload::new(friend_dht_pk, vec![
+            TcpUdpPackedNode {
+                ip_port: IpPort {
+                    protocol: ProtocolType::UDP,
+                    ip_addr: saddr.ip(),
+                    port: saddr.port(),
+                },
+                pk: node_pk,
+            },
+        ]);
+        let onion_data_response_inner_payload = OnionDataResponseInnerPayload::DhtPkAnnounce(dht_pk_announce_payload);
+        let nonce = gen_nonce();
+        let onion_data_response_payload = OnionDataResponsePayload::new(&precompute(&real_pk, &friend_real_sk), friend_real_pk, &nonce, &onion_data_response_inner_payload);
+        let (temporary_pk, temporary_sk) = gen_keypair();
+        let onion_data_response = OnionDataResponse::new(&precompute(&onion_client.data_pk, &temporary_sk), temporary_pk, nonce, &onion_data_response_payload);
+
+        onion_client.handle_data_response(&onion_data_response).await.unwrap();
+
+        let state = onion_client.state.lock();
+        assert_eq!(state.friends[&friend_real_pk].dht_pk, Some(friend_dht_pk));
+        drop(state);
+
+        // the node from announce packet should not be pinged via UDP
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(onion_client);
+
+        assert!(udp_rx.collect::<Vec<_>>().await.is_empty());
+    }
+
     #[tokio::test]
     async fn handle_data_response_dht_pk_announce_tcp_node() {
         let (dht_pk, dht_sk) = gen_keypair();
@@ -2276,6 +2505,91 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn friends_loop_tcp_only() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (udp_tx, udp_rx) = mpsc::channel(MAX_ONION_FRIEND_NODES as usize);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        let (_relay_incoming_rx, relay_outgoing_rx, relay_pk) = tcp_connections.add_client();
+        let onion_client = OnionClient::new(dht, tcp_connections, real_sk.clone(), real_pk);
+        onion_client.enable_tcp_only(true);
+
+        // DHT is connected but it should not be used
+        let mut dht_close_nodes = onion_client.dht.close_nodes.write();
+        for i in 0 .. 4 {
+            let saddr = SocketAddr::new("127.0.0.1".parse().unwrap(), 23456 + i);
+            assert!(dht_close_nodes.try_add(PackedNode::new(saddr, &gen_keypair().0)));
+        }
+        drop(dht_close_nodes);
+
+        let mut state = onion_client.state.lock();
+
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let mut friend = OnionFriend::new(friend_pk);
+        friend.dht_pk = Some(gen_keypair().0);
+
+        let path_nodes = (0 .. 3).map(|i| {
+            let saddr = SocketAddr::new("127.0.0.1".parse().unwrap(), 12346 + i);
+            PackedNode::new(saddr, &gen_keypair().0)
+        }).collect::<Vec<_>>();
+        for &node in &path_nodes {
+            state.paths_pool.path_nodes.put(node);
+        }
+
+        let now = Instant::now();
+
+        let (data_pk, _data_sk) = gen_keypair();
+        for i in 0 .. MAX_ONION_FRIEND_NODES {
+            let saddr = SocketAddr::new("127.0.0.1".parse().unwrap(), 23456 + u16::from(i));
+            let path = OnionPath::new([path_nodes[0], path_nodes[1], path_nodes[2]], OnionPathType::TCP);
+            let node = OnionNode {
+                pk: gen_keypair().0,
+                saddr,
+                path_id: path.id(),
+                ping_id: None,
+                data_pk: Some(data_pk),
+                unsuccessful_pings: 0,
+                added_time: now,
+                ping_time: now,
+                response_time: now,
+                announce_status: AnnounceStatus::Failed,
+            };
+            assert!(friend.close_nodes.try_add(&real_pk, node, true));
+        }
+
+        state.friends.insert(friend_pk, friend);
+
+        onion_client.populate_path_nodes(&mut state);
+        // nodes are not taken from DHT
+        assert_eq!(state.paths_pool.path_nodes.len(), 3);
+
+        // relay channel can't hold all packets so they should be received
+        // concurrently
+        let (res, packets) = futures::join!(
+            onion_client.friends_loop(&mut state),
+            relay_outgoing_rx.take(MAX_ONION_FRIEND_NODES as usize).collect::<Vec<_>>()
+        );
+        res.unwrap();
+        assert_eq!(packets.len(), MAX_ONION_FRIEND_NODES as usize);
+        for packet in packets {
+            unpack!(packet, TcpPacket::OnionRequest);
+        }
+
+        let dht_pk_nodes = onion_client.dht_pk_nodes(true);
+        assert_eq!(dht_pk_nodes.len(), 1);
+        assert_eq!(dht_pk_nodes[0].pk, relay_pk);
+        assert_eq!(dht_pk_nodes[0].ip_port.protocol, ProtocolType::TCP);
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(state);
+        drop(onion_client);
+
+        assert!(udp_rx.collect::<Vec<_>>().await.is_empty());
+    }
+
     #[tokio::test]
     async fn send_dht_pk_dht_request() {
         let (dht_pk, dht_sk) = gen_keypair();
@@ -2361,6 +2675,31 @@ mod tests {
         assert!(state.paths_pool.path_nodes.rand().is_some());
     }
 
+    #[tokio::test]
+    async fn populate_path_nodes_tcp_only() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        let onion_client = OnionClient::new(dht, tcp_connections, real_sk, real_pk);
+        onion_client.enable_tcp_only(true);
+
+        let bootstrap_node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+        onion_client.dht.set_initial_bootstrap(vec![bootstrap_node]);
+
+        // DHT nodes should be ignored
+        let close_node = PackedNode::new("127.0.0.1:12346".parse().unwrap(), &gen_keypair().0);
+        assert!(onion_client.dht.close_nodes.write().try_add(close_node));
+
+        let mut state = onion_client.state.lock();
+
+        onion_client.populate_path_nodes(&mut state);
+
+        assert_eq!(state.paths_pool.path_nodes.iter().cloned().collect::<Vec<_>>(), vec![bootstrap_node]);
+    }
+
     #[tokio::test]
     async fn send_onion_request_udp() {
         let (dht_pk, dht_sk) = gen_keypair();
@@ -4,12 +4,14 @@ use std::collections::VecDeque;
 
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::dht::packed_node::PackedNode;
+#[cfg(test)]
 use crate::toxcore::onion::client::onion_path::{OnionPath, OnionPathType};
 
 /// Maximum number of nodes that onion can store for building random paths.
 const MAX_PATH_NODES: usize = 32;
 
 /// Minimum size of nodes pool to generate random path.
+#[cfg(test)]
 pub const MIN_NODES_POOL_SIZE: usize = 3;
 
 /// Nodes pool for building random onion paths.
@@ -42,6 +44,11 @@ impl NodesPool {
         self.nodes.push_back(node);
     }
 
+    /// Retain only nodes that satisfy the predicate.
+    pub fn retain<F: FnMut(&PackedNode) -> bool>(&mut self, f: F) {
+        self.nodes.retain(f);
+    }
+
     /// Get random node from the cache.
     pub fn rand(&self) -> Option<PackedNode> {
         let len = self.nodes.len();
@@ -52,6 +59,20 @@ impl NodesPool {
         }
     }
 
+    /// Iterate over all stored nodes.
+    pub fn iter(&self) -> impl Iterator<Item = &PackedNode> {
+        self.nodes.iter()
+    }
+}
+
+impl Default for NodesPool {
+    fn default() -> Self {
+        NodesPool::new()
+    }
+}
+
+#[cfg(test)]
+impl NodesPool {
     /// The number of stored nodes in the pool.
     pub fn len(&self) -> usize {
         self.nodes.len()
@@ -80,29 +101,6 @@ impl NodesPool {
         }
         Some(OnionPath::new([node_1, node_2, node_3], OnionPathType::UDP))
     }
-
-    /// Build new random onion path with first TCP node.
-    pub fn tcp_path(&self, node_1: PackedNode) -> Option<OnionPath> {
-        if self.len() < MIN_NODES_POOL_SIZE - 1 {
-            return None;
-        }
-        // non-empty nodes pool will always return some node
-        let node_2 = self.rand().unwrap();
-        let mut node_3;
-        loop {
-            node_3 = self.rand().unwrap();
-            if node_3 != node_2 {
-                break;
-            }
-        }
-        Some(OnionPath::new([node_1, node_2, node_3], OnionPathType::TCP))
-    }
-}
-
-impl Default for NodesPool {
-    fn default() -> Self {
-        NodesPool::new()
-    }
 }
 
 #[cfg(test)]
@@ -156,6 +154,28 @@ mod tests {
         assert_eq!(nodes_pool.nodes.len(), MAX_PATH_NODES);
     }
 
+    #[test]
+    fn retain() {
+        let mut nodes_pool = NodesPool::new();
+        let node_1 = PackedNode::new("127.0.0.1:33445".parse().unwrap(), &gen_keypair().0);
+        let node_2 = PackedNode::new("127.0.0.1:33446".parse().unwrap(), &gen_keypair().0);
+        nodes_pool.put(node_1);
+        nodes_pool.put(node_2);
+        nodes_pool.retain(|node| *node != node_1);
+        assert_eq!(nodes_pool.len(), 1);
+        assert_eq!(nodes_pool.rand(), Some(node_2));
+    }
+
+    #[test]
+    fn iter() {
+        let mut nodes_pool = NodesPool::new();
+        let node_1 = PackedNode::new("127.0.0.1:33445".parse().unwrap(), &gen_keypair().0);
+        let node_2 = PackedNode::new("127.0.0.1:33446".parse().unwrap(), &gen_keypair().0);
+        nodes_pool.put(node_1);
+        nodes_pool.put(node_2);
+        assert_eq!(nodes_pool.iter().cloned().collect::<Vec<_>>(), vec![node_1, node_2]);
+    }
+
     #[test]
     fn rand() {
         let mut nodes_pool = NodesPool::new();
@@ -0,0 +1,198 @@
+//! Policies of choosing nodes for random onion paths.
+
+use std::fmt::Debug;
+use std::net::IpAddr;
+
+use crate::toxcore::dht::ip_port::IsGlobal;
+use crate::toxcore::dht::packed_node::PackedNode;
+
+/// Node is considered to have poor success when it was a hop of at least this
+/// number of onion paths we didn't receive responses from.
+pub const POOR_NODE_MIN_FAILURES: u32 = 3;
+
+/// Statistics of onion paths that used a node as a hop. They are halved every
+/// `PATH_NODE_STATS_HALF_LIFE` so that nodes that failed during a network
+/// outage can be used again.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PathNodeStats {
+    /// Number of responses received via paths through this node.
+    pub successes: u32,
+    /// Number of paths through this node that timed out without responses.
+    pub failures: u32,
+}
+
+/** Policy of choosing nodes for new onion paths.
+
+Nodes pinned or excluded by the user are handled by `PathsPool` before the
+policy is asked, so pinned nodes are never checked with `is_node_allowed`.
+When no path can be built from nodes allowed by the policy, `PathsPool` asks
+it again with empty statistics so that a network outage that made all known
+nodes look poor doesn't prevent building paths forever.
+
+*/
+pub trait PathPolicy: Debug + Send + Sync {
+    /// Check if the node can be used as a hop of a new onion path.
+    fn is_node_allowed(&self, node: &PackedNode, stats: &PathNodeStats) -> bool;
+
+    /// Check if nodes can be combined into a new onion path.
+    fn is_path_allowed(&self, nodes: &[PackedNode; 3]) -> bool;
+}
+
+/// Policy that accepts any nodes. It can be used in local networks where all
+/// nodes share the same subnet.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RandomPathPolicy;
+
+impl PathPolicy for RandomPathPolicy {
+    fn is_node_allowed(&self, _node: &PackedNode, _stats: &PathNodeStats) -> bool {
+        true
+    }
+
+    fn is_path_allowed(&self, _nodes: &[PackedNode; 3]) -> bool {
+        true
+    }
+}
+
+/** Default policy that builds paths through different networks.
+
+It rejects paths where two hops share /16 IPv4 or /48 IPv6 prefix since such
+nodes are likely hosted by the same provider, and nodes that were hops of
+paths timed out at least `POOR_NODE_MIN_FAILURES` times and more often than
+they delivered responses. Prefixes of LAN addresses are not compared so that
+onion still works in local networks.
+
+*/
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DiversePathPolicy;
+
+/// Network prefix of an IP address that is compared to check whether nodes
+/// belong to the same network.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Subnet {
+    /// First 16 bits of IPv4 address.
+    V4([u8; 2]),
+    /// First 48 bits of IPv6 address.
+    V6([u16; 3]),
+}
+
+impl Subnet {
+    /// Get network prefix of a global IP address. LAN addresses don't have
+    /// it.
+    fn new(ip: IpAddr) -> Option<Subnet> {
+        if !IsGlobal::is_global(&ip) {
+            return None;
+        }
+
+        match ip {
+            IpAddr::V4(ip) => {
+                let octets = ip.octets();
+                Some(Subnet::V4([octets[0], octets[1]]))
+            },
+            IpAddr::V6(ip) => if let Some(ip) = ip.to_ipv4() {
+                // IPv4-mapped addresses belong to IPv4 networks
+                Subnet::new(IpAddr::V4(ip))
+            } else {
+                let segments = ip.segments();
+                Some(Subnet::V6([segments[0], segments[1], segments[2]]))
+            },
+        }
+    }
+}
+
+impl PathPolicy for DiversePathPolicy {
+    fn is_node_allowed(&self, _node: &PackedNode, stats: &PathNodeStats) -> bool {
+        stats.failures < POOR_NODE_MIN_FAILURES || stats.failures <= stats.successes
+    }
+
+    fn is_path_allowed(&self, nodes: &[PackedNode; 3]) -> bool {
+        let subnets = nodes.iter()
+            .flat_map(|node| Subnet::new(node.ip()))
+            .collect::<Vec<_>>();
+        subnets.iter()
+            .enumerate()
+            .all(|(i, subnet)| !subnets[i + 1 ..].contains(subnet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::toxcore::crypto_core::*;
+
+    fn node(saddr: &str) -> PackedNode {
+        PackedNode::new(saddr.parse().unwrap(), &gen_keypair().0)
+    }
+
+    #[test]
+    fn random_policy_allows_everything() {
+        let policy = RandomPathPolicy;
+        let stats = PathNodeStats { successes: 0, failures: 100 };
+        let nodes = [node("1.2.3.4:33445"), node("1.2.3.5:33445"), node("1.2.3.6:33445")];
+
+        assert!(policy.is_node_allowed(&nodes[0], &stats));
+        assert!(policy.is_path_allowed(&nodes));
+    }
+
+    #[test]
+    fn diverse_policy_rejects_poor_nodes() {
+        let policy = DiversePathPolicy;
+        let node = node("1.2.3.4:33445");
+
+        assert!(policy.is_node_allowed(&node, &PathNodeStats::default()));
+        assert!(policy.is_node_allowed(&node, &PathNodeStats { successes: 0, failures: POOR_NODE_MIN_FAILURES - 1 }));
+        assert!(policy.is_node_allowed(&node, &PathNodeStats { successes: 5, failures: 5 }));
+        assert!(!policy.is_node_allowed(&node, &PathNodeStats { successes: 0, failures: POOR_NODE_MIN_FAILURES }));
+        assert!(!policy.is_node_allowed(&node, &PathNodeStats { successes: 4, failures: 5 }));
+    }
+
+    #[test]
+    fn diverse_policy_ipv4() {
+        let policy = DiversePathPolicy;
+
+        assert!(policy.is_path_allowed(&[node("1.2.3.4:33445"), node("1.3.3.4:33445"), node("2.2.3.4:33445")]));
+        assert!(!policy.is_path_allowed(&[node("1.2.3.4:33445"), node("1.3.3.4:33445"), node("1.2.4.5:33445")]));
+        assert!(!policy.is_path_allowed(&[node("1.2.3.4:33445"), node("1.2.3.4:33446"), node("2.2.3.4:33445")]));
+    }
+
+    #[test]
+    fn diverse_policy_lan() {
+        let policy = DiversePathPolicy;
+
+        assert!(policy.is_path_allowed(&[node("127.0.0.1:33445"), node("127.0.0.1:33446"), node("127.0.0.1:33447")]));
+        assert!(policy.is_path_allowed(&[node("192.168.0.1:33445"), node("192.168.0.2:33445"), node("1.2.3.4:33445")]));
+        assert!(!policy.is_path_allowed(&[node("192.168.0.1:33445"), node("1.2.3.4:33445"), node("1.2.3.5:33445")]));
+    }
+
+    #[test]
+    fn diverse_policy_ipv6() {
+        let policy = DiversePathPolicy;
+
+        assert!(policy.is_path_allowed(&[
+            node("[2001:db8:1::1]:33445"),
+            node("[2001:db8:2::1]:33445"),
+            node("[2001:db9:1::1]:33445"),
+        ]));
+        assert!(!policy.is_path_allowed(&[
+            node("[2001:db8:1::1]:33445"),
+            node("[2001:db8:2::1]:33445"),
+            node("[2001:db8:1:ffff::1]:33445"),
+        ]));
+    }
+
+    #[test]
+    fn diverse_policy_ipv4_mapped() {
+        let policy = DiversePathPolicy;
+
+        assert!(!policy.is_path_allowed(&[
+            node("1.2.3.4:33445"),
+            node("[::ffff:1.2.4.5]:33445"),
+            node("2.2.3.4:33445"),
+        ]));
+        assert!(policy.is_path_allowed(&[
+            node("1.2.3.4:33445"),
+            node("[::ffff:1.3.4.5]:33445"),
+            node("2.2.3.4:33445"),
+        ]));
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use crate::toxcore::crypto_core::*;
@@ -5,6 +7,7 @@ use crate::toxcore::dht::packed_node::PackedNode;
 use crate::toxcore::dht::server::{Server as DhtServer};
 use crate::toxcore::onion::client::nodes_pool::*;
 use crate::toxcore::onion::client::onion_path::*;
+use crate::toxcore::onion::client::path_policy::*;
 use crate::toxcore::time::*;
 use crate::toxcore::onion::client::TIME_TO_STABLE;
 use crate::toxcore::tcp::client::{Connections as TcpConnections};
@@ -25,6 +28,14 @@ const ONION_PATH_TIMEOUT: Duration = Duration::from_secs(10);
 /// Maximum time for path being used.
 const ONION_PATH_MAX_LIFETIME: Duration = Duration::from_secs(1200);
 
+/// Maximum number of random nodes combinations that are checked by the path
+/// policy when a new path is built.
+const MAX_PATH_SELECTION_ATTEMPTS: usize = 16;
+
+/// Statistics of nodes are halved with this interval so that nodes that were
+/// hops of failed paths during a network outage can be used again.
+pub const PATH_NODE_STATS_HALF_LIFE: Duration = Duration::from_secs(600);
+
 /// Onion path that is stored for later usage.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct StoredOnionPath {
@@ -40,6 +51,10 @@ pub struct StoredOnionPath {
     /// How many times we attempted to use this path without receiving a
     /// response.
     pub attempts: u32,
+    /// How many times this path was used to send an onion packet.
+    pub uses: u32,
+    /// How many responses we received via this path.
+    pub successes: u32,
 }
 
 impl StoredOnionPath {
@@ -52,6 +67,9 @@ impl StoredOnionPath {
             last_used: now,
             last_success: None,
             attempts: ONION_PATH_MAX_NO_RESPONSE_USES / 2,
+            // path is created to send a packet via it
+            uses: 1,
+            successes: 0,
         }
     }
 
@@ -59,6 +77,7 @@ impl StoredOnionPath {
     pub fn update_success(&mut self) {
         self.last_success = Some(clock_now());
         self.attempts = 0;
+        self.successes = self.successes.saturating_add(1);
     }
 
     /// Check if we never received a response from this path.
@@ -66,16 +85,25 @@ impl StoredOnionPath {
         self.last_success.is_none()
     }
 
-    /// Check if this path is timed out.
-    pub fn is_timed_out(&self) -> bool {
+    /// Check if we stopped receiving responses from this path.
+    pub fn is_failed(&self) -> bool {
         let timeout = if self.is_new() {
             ONION_PATH_FIRST_TIMEOUT
         } else {
             ONION_PATH_TIMEOUT
         };
 
-        self.attempts >= ONION_PATH_MAX_NO_RESPONSE_USES && clock_elapsed(self.last_used) >= timeout ||
-            clock_elapsed(self.creation_time) >= ONION_PATH_MAX_LIFETIME
+        self.attempts >= ONION_PATH_MAX_NO_RESPONSE_USES && clock_elapsed(self.last_used) >= timeout
+    }
+
+    /// Check if this path is timed out.
+    pub fn is_timed_out(&self) -> bool {
+        self.is_failed() || clock_elapsed(self.creation_time) >= ONION_PATH_MAX_LIFETIME
+    }
+
+    /// Check if this path goes through the node with given `PublicKey`.
+    pub fn contains_node(&self, pk: &PublicKey) -> bool {
+        self.path.nodes.iter().any(|node| node.public_key == *pk)
     }
 
     /// Path is considered stable after `TIME_TO_STABLE` since it was
@@ -90,6 +118,46 @@ impl StoredOnionPath {
     pub fn use_path(&mut self) {
         self.last_used = clock_now();
         self.attempts += 1;
+        self.uses = self.uses.saturating_add(1);
+    }
+}
+
+/// Information about a node that is a hop of onion path.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OnionPathHopInfo {
+    /// The node packets are sent through.
+    pub node: PackedNode,
+    /// Statistics of all paths that used this node as a hop.
+    pub stats: PathNodeStats,
+}
+
+/// Health information about onion path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionPathInfo {
+    /// Whether the first node is a TCP relay or DHT node.
+    pub path_type: OnionPathType,
+    /// Nodes this path consists of.
+    pub hops: [OnionPathHopInfo; 3],
+    /// Time passed since this path was created.
+    pub age: Duration,
+    /// Time passed since we received a response via this path.
+    pub since_last_success: Option<Duration>,
+    /// How many times this path was used to send an onion packet.
+    pub uses: u32,
+    /// How many responses we received via this path.
+    pub successes: u32,
+    /// Whether this path is stable.
+    pub is_stable: bool,
+}
+
+impl OnionPathInfo {
+    /// Ratio of packets sent via this path that we received responses to.
+    pub fn success_rate(&self) -> f64 {
+        if self.uses == 0 {
+            0.0
+        } else {
+            f64::from(self.successes.min(self.uses)) / f64::from(self.uses)
+        }
     }
 }
 
@@ -102,6 +170,19 @@ pub struct PathsPool {
     self_paths: Vec<StoredOnionPath>,
     /// List of used random onion paths for friends searching.
     friend_paths: Vec<StoredOnionPath>,
+    /// Policy that decides which nodes can be combined into new paths.
+    policy: Arc<dyn PathPolicy>,
+    /// Statistics of paths that used nodes from the pool.
+    node_stats: HashMap<PublicKey, PathNodeStats>,
+    /// Time when nodes statistics were halved last time.
+    node_stats_decay_time: Instant,
+    /// Nodes that are used as the first hop of new UDP paths bypassing the
+    /// policy checks of single nodes.
+    pinned_nodes: Vec<PackedNode>,
+    /// Nodes that are never used for new paths.
+    excluded_nodes: HashSet<PublicKey>,
+    /// Whether only paths with the first TCP node can be used.
+    tcp_only: bool,
 }
 
 impl PathsPool {
@@ -111,19 +192,227 @@ impl PathsPool {
             path_nodes: NodesPool::new(),
             self_paths: Vec::new(),
             friend_paths: Vec::new(),
+            policy: Arc::new(DiversePathPolicy),
+            node_stats: HashMap::new(),
+            node_stats_decay_time: clock_now(),
+            pinned_nodes: Vec::new(),
+            excluded_nodes: HashSet::new(),
+            tcp_only: false,
         }
     }
 
+    /// Check if only paths with the first TCP node can be used.
+    pub fn is_tcp_only(&self) -> bool {
+        self.tcp_only
+    }
+
+    /// Set whether only paths with the first TCP node can be used. Stored UDP
+    /// paths are dropped when TCP only mode is enabled.
+    pub fn set_tcp_only(&mut self, tcp_only: bool) {
+        self.tcp_only = tcp_only;
+        if tcp_only {
+            self.self_paths.retain(|stored_path| stored_path.path.path_type == OnionPathType::TCP);
+            self.friend_paths.retain(|stored_path| stored_path.path.path_type == OnionPathType::TCP);
+        }
+    }
+
+    /// Set policy that decides which nodes can be combined into new paths.
+    /// Already built paths are not affected.
+    pub fn set_policy(&mut self, policy: Arc<dyn PathPolicy>) {
+        self.policy = policy;
+    }
+
+    /// Pin a node so that it's used as the first hop of new UDP paths. The
+    /// node is used even if the policy considers it poor but paths through
+    /// it still have to be allowed by the policy.
+    pub fn pin_node(&mut self, node: PackedNode) {
+        self.excluded_nodes.remove(&node.pk);
+        if !self.pinned_nodes.iter().any(|pinned| pinned.pk == node.pk) {
+            self.pinned_nodes.push(node);
+        }
+    }
+
+    /// Unpin previously pinned node.
+    pub fn unpin_node(&mut self, pk: &PublicKey) {
+        self.pinned_nodes.retain(|pinned| pinned.pk != *pk);
+    }
+
+    /// Exclude a node so that it's never used for new paths. Stored paths
+    /// through this node are dropped.
+    pub fn exclude_node(&mut self, pk: PublicKey) {
+        self.unpin_node(&pk);
+        self.self_paths.retain(|stored_path| !stored_path.contains_node(&pk));
+        self.friend_paths.retain(|stored_path| !stored_path.contains_node(&pk));
+        self.excluded_nodes.insert(pk);
+    }
+
+    /// Allow previously excluded node to be used for new paths.
+    pub fn include_node(&mut self, pk: &PublicKey) {
+        self.excluded_nodes.remove(pk);
+    }
+
+    /// Get statistics of paths that used the node with given `PublicKey`.
+    pub fn node_stats(&self, pk: &PublicKey) -> PathNodeStats {
+        self.node_stats.get(pk).cloned().unwrap_or_default()
+    }
+
+    /// Get health information about paths that are not timed out.
+    pub fn paths_info(&self, friend: bool) -> Vec<OnionPathInfo> {
+        let paths = if friend {
+            &self.friend_paths
+        } else {
+            &self.self_paths
+        };
+
+        let hop_info = |node: &OnionPathNode| OnionPathHopInfo {
+            node: PackedNode::new(node.saddr, &node.public_key),
+            stats: self.node_stats(&node.public_key),
+        };
+
+        paths.iter()
+            .filter(|stored_path| !stored_path.is_timed_out())
+            .map(|stored_path| OnionPathInfo {
+                path_type: stored_path.path.path_type,
+                hops: [
+                    hop_info(&stored_path.path.nodes[0]),
+                    hop_info(&stored_path.path.nodes[1]),
+                    hop_info(&stored_path.path.nodes[2]),
+                ],
+                age: clock_elapsed(stored_path.creation_time),
+                since_last_success: stored_path.last_success.map(clock_elapsed),
+                uses: stored_path.uses,
+                successes: stored_path.successes,
+                is_stable: stored_path.is_stable(),
+            })
+            .collect()
+    }
+
+    /// Drop all stored paths and nodes statistics so that new paths will be
+    /// built for next packets. It's useful after the network was changed
+    /// since statistics collected in the old network are not relevant.
+    pub fn rotate_paths(&mut self) {
+        self.self_paths.clear();
+        self.friend_paths.clear();
+        self.node_stats.clear();
+        self.node_stats_decay_time = clock_now();
+    }
+
+    /// Halve nodes statistics for every `PATH_NODE_STATS_HALF_LIFE` passed
+    /// since the last time they were halved.
+    fn decay_node_stats(&mut self) {
+        let periods = clock_elapsed(self.node_stats_decay_time).as_secs() / PATH_NODE_STATS_HALF_LIFE.as_secs();
+        if periods == 0 {
+            return;
+        }
+
+        self.node_stats_decay_time += PATH_NODE_STATS_HALF_LIFE * periods as u32;
+        let shift = periods.min(31) as u32;
+        for stats in self.node_stats.values_mut() {
+            stats.successes >>= shift;
+            stats.failures >>= shift;
+        }
+        self.node_stats.retain(|_, stats| *stats != PathNodeStats::default());
+    }
+
+    /// Get statistics of the node that are passed to the policy. Empty
+    /// statistics are returned when they should be ignored.
+    fn policy_stats(&self, pk: &PublicKey, use_stats: bool) -> PathNodeStats {
+        if use_stats {
+            self.node_stats(pk)
+        } else {
+            PathNodeStats::default()
+        }
+    }
+
+    /// Get nodes that can be used as hops of new paths. Banned nodes and
+    /// nodes with poor reputation are skipped but kept in the pool since
+    /// their reputation can recover.
+    fn candidate_nodes(&self, dht: &DhtServer, use_stats: bool) -> Vec<PackedNode> {
+        let pinned = self.pinned_nodes.iter()
+            .filter(|node| !self.excluded_nodes.contains(&node.pk));
+        let allowed = self.path_nodes.iter()
+            .filter(|node| !self.excluded_nodes.contains(&node.pk))
+            .filter(|node| dht.has_good_reputation(node))
+            .filter(|node| !self.pinned_nodes.iter().any(|pinned| pinned.pk == node.pk))
+            .filter(|node| self.policy.is_node_allowed(node, &self.policy_stats(&node.pk, use_stats)));
+        pinned.chain(allowed).cloned().collect()
+    }
+
+    /// Check if TCP relay can be used as the first hop of new paths.
+    fn is_relay_allowed(&self, relay: &PackedNode, use_stats: bool) -> bool {
+        !self.excluded_nodes.contains(&relay.pk) &&
+            self.policy.is_node_allowed(relay, &self.policy_stats(&relay.pk, use_stats))
+    }
+
+    /// Pick a random node from candidates that is not one of `used` nodes.
+    fn random_candidate(candidates: &[PackedNode], used: &[PackedNode]) -> Option<PackedNode> {
+        let unused = candidates.iter()
+            .filter(|node| !used.iter().any(|used| used.pk == node.pk))
+            .collect::<Vec<_>>();
+        if unused.is_empty() {
+            None
+        } else {
+            Some(*unused[random_limit_usize(unused.len())])
+        }
+    }
+
+    /// Build new onion path allowed by the policy. The first node is either
+    /// one of given TCP relays or a random pinned node if there are any.
+    /// Nodes statistics are ignored when `use_stats` is `false`.
+    fn build_path(&self, dht: &DhtServer, relays: Option<&[PackedNode]>, use_stats: bool) -> Option<OnionPath> {
+        let candidates = self.candidate_nodes(dht, use_stats);
+        let relays = relays.map(|relays| relays.iter()
+            .filter(|relay| self.is_relay_allowed(relay, use_stats))
+            .cloned()
+            .collect::<Vec<_>>()
+        );
+        let relays = relays.as_deref();
+        let pinned = candidates.iter()
+            .filter(|node| self.pinned_nodes.iter().any(|pinned| pinned.pk == node.pk))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for _ in 0 .. MAX_PATH_SELECTION_ATTEMPTS {
+            let node_1 = if let Some(relays) = relays {
+                PathsPool::random_candidate(relays, &[])?
+            } else if !pinned.is_empty() {
+                pinned[random_limit_usize(pinned.len())]
+            } else {
+                PathsPool::random_candidate(&candidates, &[])?
+            };
+            let node_2 = PathsPool::random_candidate(&candidates, &[node_1])?;
+            let node_3 = PathsPool::random_candidate(&candidates, &[node_1, node_2])?;
+            let nodes = [node_1, node_2, node_3];
+            if self.policy.is_path_allowed(&nodes) {
+                let path_type = if relays.is_some() {
+                    OnionPathType::TCP
+                } else {
+                    OnionPathType::UDP
+                };
+                return Some(OnionPath::new(nodes, path_type));
+            }
+        }
+
+        None
+    }
+
     /// Get a random onion path. Can be either one of existent paths or newly
-    /// generated. If we are not connected to DHT the first node from this path
-    /// will be a TCP node.
+    /// generated. If we are not connected to DHT or TCP only mode is enabled
+    /// the first node from this path will be a TCP node.
     pub fn random_path(&mut self, dht: &DhtServer, tcp_connections: &TcpConnections, friend: bool) -> Option<OnionPath> {
+        self.decay_node_stats();
         let paths = if friend {
             &mut self.friend_paths
         } else {
             &mut self.self_paths
         };
 
+        for stored_path in paths.iter().filter(|stored_path| stored_path.is_failed()) {
+            for node in &stored_path.path.nodes {
+                let stats = self.node_stats.entry(node.public_key).or_default();
+                stats.failures = stats.failures.saturating_add(1);
+            }
+        }
         paths.retain(|stored_path| !stored_path.is_timed_out());
 
         let path_number = random_limit_usize(NUMBER_ONION_PATHS);
@@ -132,12 +421,29 @@ impl PathsPool {
             return Some(stored_path.path.clone());
         }
 
-        let path = if dht.is_connected() {
-            self.path_nodes.udp_path()
-        } else if let Some(relay) = tcp_connections.get_random_relay() {
-            self.path_nodes.tcp_path(relay)
-        } else {
+        let relays = tcp_connections.get_random_relays(u8::MAX);
+        // keep statistics only for nodes we can still use
+        let path_nodes = &self.path_nodes;
+        let pinned_nodes = &self.pinned_nodes;
+        self.node_stats.retain(|pk, _|
+            path_nodes.iter().chain(pinned_nodes.iter()).chain(relays.iter()).any(|node| node.pk == *pk)
+        );
+
+        let relays = if dht.is_connected() && !self.tcp_only {
             None
+        } else {
+            Some(relays.as_slice())
+        };
+        // when there are not enough nodes with good statistics, e.g. after a
+        // network outage, they are ignored so that we can find out whether
+        // nodes work again
+        let path = self.build_path(dht, relays, true)
+            .or_else(|| self.build_path(dht, relays, false));
+
+        let paths = if friend {
+            &mut self.friend_paths
+        } else {
+            &mut self.self_paths
         };
 
         if let Some(path) = path {
@@ -203,6 +509,8 @@ impl PathsPool {
             // re-add path nodes to the cache as they are still valid
             for node in &path.path.nodes {
                 self.path_nodes.put(PackedNode::new(node.saddr, &node.public_key));
+                let stats = self.node_stats.entry(node.public_key).or_default();
+                stats.successes = stats.successes.saturating_add(1);
             }
         }
     }
@@ -218,6 +526,8 @@ impl Default for PathsPool {
 mod tests {
     use super::*;
 
+    use std::net::{IpAddr, SocketAddr};
+
     use futures::channel::mpsc;
 
     macro_rules! paths_pool_tests {
@@ -270,6 +580,33 @@ mod tests {
                     assert_eq!(path.path_type, OnionPathType::UDP);
                 }
 
+                #[test]
+                fn random_path_skips_banned_nodes() {
+                    let (dht_pk, dht_sk) = gen_keypair();
+                    let (udp_tx, _udp_rx) = mpsc::channel(1);
+                    let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+                    let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+                    // make DHT connected so that we will build UDP onion paths
+                    dht.add_node(PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0));
+                    let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+                    let mut paths_pool = PathsPool::new();
+                    for _ in 0 .. MIN_NODES_POOL_SIZE {
+                        let node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+                        paths_pool.path_nodes.put(node);
+                    }
+                    let banned_node = paths_pool.path_nodes.rand().unwrap();
+                    dht.ban_key(banned_node.pk);
+
+                    // not enough nodes left to build a path
+                    assert!(paths_pool.random_path(&dht, &tcp_connections, $friends).is_none());
+                    // banned node is kept in the pool
+                    assert_eq!(paths_pool.path_nodes.len(), MIN_NODES_POOL_SIZE);
+
+                    dht.unban_key(&banned_node.pk);
+
+                    assert!(paths_pool.random_path(&dht, &tcp_connections, $friends).is_some());
+                }
+
                 #[test]
                 fn random_path_new_tcp_random() {
                     let (dht_pk, dht_sk) = gen_keypair();
@@ -378,4 +715,277 @@ mod tests {
 
     paths_pool_tests!(self_tests, false, self_paths);
     paths_pool_tests!(friends_tests, true, friend_paths);
+
+    fn create_connected_dht() -> (DhtServer, TcpConnections) {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        // make DHT connected so that we will build UDP onion paths
+        dht.add_node(PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0));
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        (dht, tcp_connections)
+    }
+
+    /// Create `PathsPool` with enough nodes from different subnets to build
+    /// a path.
+    fn create_paths_pool() -> (DhtServer, TcpConnections, PathsPool) {
+        let (dht, tcp_connections) = create_connected_dht();
+        let mut paths_pool = PathsPool::new();
+        for i in 0 .. MIN_NODES_POOL_SIZE {
+            let saddr = SocketAddr::new(IpAddr::from([1 + i as u8, 2, 3, 4]), 33445);
+            paths_pool.path_nodes.put(PackedNode::new(saddr, &gen_keypair().0));
+        }
+        (dht, tcp_connections, paths_pool)
+    }
+
+    #[test]
+    fn random_path_rejects_same_subnet() {
+        let (dht, tcp_connections) = create_connected_dht();
+        let mut paths_pool = PathsPool::new();
+        for i in 0 .. MIN_NODES_POOL_SIZE {
+            let saddr = SocketAddr::new(IpAddr::from([1, 2, 3, i as u8]), 33445);
+            paths_pool.path_nodes.put(PackedNode::new(saddr, &gen_keypair().0));
+        }
+
+        assert!(paths_pool.random_path(&dht, &tcp_connections, false).is_none());
+
+        paths_pool.set_policy(Arc::new(RandomPathPolicy));
+
+        assert!(paths_pool.random_path(&dht, &tcp_connections, false).is_some());
+    }
+
+    #[test]
+    fn random_path_uses_pinned_node() {
+        let (dht, tcp_connections) = create_connected_dht();
+        let mut paths_pool = PathsPool::new();
+        for i in 0 .. 10 {
+            let saddr = SocketAddr::new(IpAddr::from([1, i, 3, 4]), 33445);
+            paths_pool.path_nodes.put(PackedNode::new(saddr, &gen_keypair().0));
+        }
+        let pinned = PackedNode::new("2.2.3.4:33445".parse().unwrap(), &gen_keypair().0);
+        paths_pool.pin_node(pinned);
+
+        for _ in 0 .. NUMBER_ONION_PATHS {
+            let path = paths_pool.random_path(&dht, &tcp_connections, false).unwrap();
+            assert_eq!(path.nodes[0].public_key, pinned.pk);
+        }
+
+        paths_pool.unpin_node(&pinned.pk);
+        assert!(paths_pool.pinned_nodes.is_empty());
+    }
+
+    #[test]
+    fn random_path_skips_excluded_nodes() {
+        let (dht, tcp_connections, mut paths_pool) = create_paths_pool();
+
+        let path = paths_pool.random_path(&dht, &tcp_connections, false).unwrap();
+        let excluded_pk = path.nodes[1].public_key;
+        paths_pool.exclude_node(excluded_pk);
+
+        // stored path through excluded node is dropped
+        assert!(paths_pool.self_paths.is_empty());
+        // not enough nodes left to build a path
+        assert!(paths_pool.random_path(&dht, &tcp_connections, false).is_none());
+
+        paths_pool.include_node(&excluded_pk);
+
+        assert!(paths_pool.random_path(&dht, &tcp_connections, false).is_some());
+    }
+
+    /// Make paths built from the pool fail `POOR_NODE_MIN_FAILURES` times.
+    async fn fail_paths(dht: &DhtServer, tcp_connections: &TcpConnections, paths_pool: &mut PathsPool) {
+        for _ in 0 .. POOR_NODE_MIN_FAILURES {
+            let path = paths_pool.random_path(dht, tcp_connections, false).unwrap();
+            let stored_path = paths_pool.self_paths.iter_mut().find(|stored_path| stored_path.path == path).unwrap();
+            stored_path.attempts = ONION_PATH_MAX_NO_RESPONSE_USES;
+            tokio::time::advance(ONION_PATH_FIRST_TIMEOUT).await;
+        }
+        // failed paths are accounted when a path is requested
+        paths_pool.random_path(dht, tcp_connections, false).unwrap();
+    }
+
+    #[tokio::test]
+    async fn random_path_counts_failures() {
+        tokio::time::pause();
+        let (dht, tcp_connections, mut paths_pool) = create_paths_pool();
+
+        fail_paths(&dht, &tcp_connections, &mut paths_pool).await;
+
+        // all nodes were hops of failed paths
+        for node in paths_pool.path_nodes.iter() {
+            assert_eq!(paths_pool.node_stats(&node.pk).failures, POOR_NODE_MIN_FAILURES);
+        }
+        assert!(paths_pool.build_path(&dht, None, true).is_none());
+        // statistics are ignored when there are not enough good nodes
+        assert!(paths_pool.build_path(&dht, None, false).is_some());
+    }
+
+    #[test]
+    fn random_path_avoids_poor_nodes() {
+        let (dht, tcp_connections) = create_connected_dht();
+        let mut paths_pool = PathsPool::new();
+        for i in 0 .. 6 {
+            let saddr = SocketAddr::new(IpAddr::from([1 + i, 2, 3, 4]), 33445);
+            paths_pool.path_nodes.put(PackedNode::new(saddr, &gen_keypair().0));
+        }
+        let poor_node = paths_pool.path_nodes.rand().unwrap();
+        paths_pool.node_stats.insert(poor_node.pk, PathNodeStats { successes: 0, failures: POOR_NODE_MIN_FAILURES });
+
+        for _ in 0 .. 20 {
+            paths_pool.rotate_paths();
+            paths_pool.node_stats.insert(poor_node.pk, PathNodeStats { successes: 0, failures: POOR_NODE_MIN_FAILURES });
+
+            let path = paths_pool.random_path(&dht, &tcp_connections, false).unwrap();
+            assert!(path.nodes.iter().all(|node| node.public_key != poor_node.pk));
+        }
+    }
+
+    #[tokio::test]
+    async fn poor_nodes_recover_after_outage() {
+        tokio::time::pause();
+        let (dht, tcp_connections, mut paths_pool) = create_paths_pool();
+
+        fail_paths(&dht, &tcp_connections, &mut paths_pool).await;
+        assert!(paths_pool.build_path(&dht, None, true).is_none());
+
+        tokio::time::advance(PATH_NODE_STATS_HALF_LIFE).await;
+        paths_pool.decay_node_stats();
+
+        // failures are halved so nodes are not considered poor anymore
+        for node in paths_pool.path_nodes.iter() {
+            assert!(paths_pool.node_stats(&node.pk).failures < POOR_NODE_MIN_FAILURES);
+        }
+        assert!(paths_pool.build_path(&dht, None, true).is_some());
+
+        tokio::time::advance(PATH_NODE_STATS_HALF_LIFE * 2).await;
+        paths_pool.decay_node_stats();
+
+        // statistics of nodes that recovered are forgotten completely
+        assert!(paths_pool.node_stats.is_empty());
+    }
+
+    #[test]
+    fn set_timeouts_counts_successes() {
+        let (dht, tcp_connections, mut paths_pool) = create_paths_pool();
+
+        let path = paths_pool.random_path(&dht, &tcp_connections, false).unwrap();
+        paths_pool.set_timeouts(path.id(), false);
+
+        for node in &path.nodes {
+            assert_eq!(paths_pool.node_stats(&node.public_key), PathNodeStats { successes: 1, failures: 0 });
+        }
+    }
+
+    #[test]
+    fn random_path_tcp_only() {
+        let (dht, tcp_connections, mut paths_pool) = create_paths_pool();
+        let (_relay_incoming_rx, _relay_outgoing_rx, relay_pk) = tcp_connections.add_client();
+
+        let path = paths_pool.random_path(&dht, &tcp_connections, false).unwrap();
+        assert_eq!(path.path_type, OnionPathType::UDP);
+
+        paths_pool.set_tcp_only(true);
+
+        // stored UDP paths are dropped
+        assert!(paths_pool.self_paths.is_empty());
+
+        let path = paths_pool.random_path(&dht, &tcp_connections, false).unwrap();
+        assert_eq!(path.path_type, OnionPathType::TCP);
+        assert_eq!(path.nodes[0].public_key, relay_pk);
+    }
+
+    #[test]
+    fn random_path_tcp_only_skips_excluded_relay() {
+        let (dht, tcp_connections, mut paths_pool) = create_paths_pool();
+        let (_relay_incoming_rx, _relay_outgoing_rx, relay_pk) = tcp_connections.add_client();
+        paths_pool.set_tcp_only(true);
+        paths_pool.exclude_node(relay_pk);
+
+        assert!(paths_pool.random_path(&dht, &tcp_connections, false).is_none());
+
+        paths_pool.include_node(&relay_pk);
+
+        let path = paths_pool.random_path(&dht, &tcp_connections, false).unwrap();
+        assert_eq!(path.nodes[0].public_key, relay_pk);
+    }
+
+    #[tokio::test]
+    async fn random_path_tcp_only_avoids_poor_relay() {
+        tokio::time::pause();
+        let (dht, tcp_connections, mut paths_pool) = create_paths_pool();
+        let (_relay_incoming_rx, _relay_outgoing_rx, relay_pk) = tcp_connections.add_client();
+        paths_pool.set_tcp_only(true);
+
+        fail_paths(&dht, &tcp_connections, &mut paths_pool).await;
+
+        // the relay was the first hop of all failed paths
+        assert_eq!(paths_pool.node_stats(&relay_pk).failures, POOR_NODE_MIN_FAILURES);
+        // forget failures of other nodes to check only the relay
+        paths_pool.node_stats.retain(|pk, _| *pk == relay_pk);
+        let relays = tcp_connections.get_random_relays(u8::MAX);
+        assert!(paths_pool.build_path(&dht, Some(&relays), true).is_none());
+
+        // good relay is preferred
+        let (_good_relay_incoming_rx, _good_relay_outgoing_rx, good_relay_pk) = tcp_connections.add_client();
+        let relays = tcp_connections.get_random_relays(u8::MAX);
+        for _ in 0 .. 20 {
+            let path = paths_pool.build_path(&dht, Some(&relays), true).unwrap();
+            assert_eq!(path.nodes[0].public_key, good_relay_pk);
+        }
+
+        // the poor relay is still used when it's the only one
+        paths_pool.exclude_node(good_relay_pk);
+        paths_pool.self_paths.clear();
+        let path = paths_pool.random_path(&dht, &tcp_connections, false).unwrap();
+        assert_eq!(path.nodes[0].public_key, relay_pk);
+    }
+
+    #[tokio::test]
+    async fn paths_info() {
+        tokio::time::pause();
+        let (dht, tcp_connections, mut paths_pool) = create_paths_pool();
+
+        let path = paths_pool.random_path(&dht, &tcp_connections, false).unwrap();
+        tokio::time::advance(Duration::from_secs(1)).await;
+        paths_pool.set_timeouts(path.id(), false);
+        paths_pool.get_or_random_path(&dht, &tcp_connections, path.id(), false).unwrap();
+        tokio::time::advance(Duration::from_secs(2)).await;
+
+        assert!(paths_pool.paths_info(true).is_empty());
+
+        let paths_info = paths_pool.paths_info(false);
+        assert_eq!(paths_info.len(), 1);
+        let path_info = &paths_info[0];
+        assert_eq!(path_info.path_type, OnionPathType::UDP);
+        for (hop, node) in path_info.hops.iter().zip(path.nodes.iter()) {
+            assert_eq!(hop.node, PackedNode::new(node.saddr, &node.public_key));
+            assert_eq!(hop.stats, PathNodeStats { successes: 1, failures: 0 });
+        }
+        assert_eq!(path_info.age, Duration::from_secs(3));
+        assert_eq!(path_info.since_last_success, Some(Duration::from_secs(2)));
+        assert_eq!(path_info.uses, 2);
+        assert_eq!(path_info.successes, 1);
+        assert_eq!(path_info.success_rate(), 0.5);
+        assert!(!path_info.is_stable);
+    }
+
+    #[test]
+    fn rotate_paths() {
+        let (dht, tcp_connections, mut paths_pool) = create_paths_pool();
+
+        let self_path = paths_pool.random_path(&dht, &tcp_connections, false).unwrap();
+        paths_pool.random_path(&dht, &tcp_connections, true).unwrap();
+        paths_pool.set_timeouts(self_path.id(), false);
+
+        paths_pool.rotate_paths();
+
+        assert!(paths_pool.paths_info(false).is_empty());
+        assert!(paths_pool.paths_info(true).is_empty());
+        // nodes statistics are dropped
+        assert_eq!(paths_pool.node_stats(&self_path.nodes[0].public_key), PathNodeStats::default());
+        // new paths are built
+        assert!(paths_pool.random_path(&dht, &tcp_connections, false).is_some());
+        assert_eq!(paths_pool.paths_info(false).len(), 1);
+    }
 }
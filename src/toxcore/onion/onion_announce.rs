@@ -5,17 +5,22 @@ use std::io::{ErrorKind, Error};
 use std::net::{IpAddr, SocketAddr};
 use std::time::{Duration, Instant, SystemTime};
 
+use nom::bytes::complete::take;
+use nom::number::complete::{be_u16, be_u32, be_u64};
+
 use crate::toxcore::binary_io::*;
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::time::*;
+use crate::toxcore::ip_port::*;
 use crate::toxcore::onion::packet::*;
 use crate::toxcore::dht::kbucket::Distance;
 
 /// Number of secret random bytes to make onion ping id unique for each node.
 pub const SECRET_BYTES_SIZE: usize = 32;
 
-/// Maximum number of entries in onion announce list. When number of entries
-/// exceeds this value farthest nodes are dropped using DHT distance function.
+/// Default maximum number of entries in onion announce list. When number of
+/// entries exceeds this value farthest nodes are dropped using DHT distance
+/// function.
 pub const ONION_ANNOUNCE_MAX_ENTRIES: usize = 160;
 
 /// Interval of time when onion ping id is valid after it was generated.
@@ -27,6 +32,9 @@ pub const PING_ID_TIMEOUT: Duration = Duration::from_secs(300);
 /// without re-announcing.
 pub const ONION_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(300);
 
+/// Interval of time for removing timed out entries from onion announce list.
+pub const ONION_ANNOUNCE_EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
+
 /// Create onion ping id filled with zeros.
 pub fn initial_ping_id() -> sha256::Digest {
     // can not fail since slice has enough length
@@ -40,7 +48,7 @@ list. It's considered expired after `ONION_ANNOUNCE_TIMEOUT`.
 
 */
 #[derive(Clone, Debug, Eq, PartialEq)]
-struct OnionAnnounceEntry {
+pub(crate) struct OnionAnnounceEntry {
     /// Long term PublicKey of announced node
     pub pk: PublicKey,
     /// IP address of announced node
@@ -148,6 +156,118 @@ impl OnionPingData {
     }
 }
 
+/** Serialize announce entry together with its age.
+
+Time of the entry can't be serialized since `Instant` is not bound to a
+specific moment, so the age in seconds is serialized instead.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`32`     | `PublicKey` of announced node
+`7`/`19` | `IpPort` of announced node without padding
+`32`     | `PublicKey` for data packets
+`8`      | Age of the entry in seconds
+`2`      | Length of onion return
+variable | Onion return
+
+*/
+fn entry_to_bytes<'a>(buf: (&'a mut [u8], usize), (age, entry): &(Duration, OnionAnnounceEntry)) -> Result<(&'a mut [u8], usize), GenError> {
+    let ip_port = IpPort::from_udp_saddr(SocketAddr::new(entry.ip_addr, entry.port));
+    do_gen!(buf,
+        gen_slice!(entry.pk.as_ref()) >>
+        gen_call!(|buf, ip_port| IpPort::to_bytes(ip_port, buf, IpPortPadding::NoPadding), &ip_port) >>
+        gen_slice!(entry.data_pk.as_ref()) >>
+        gen_be_u64!(age.as_secs()) >>
+        gen_be_u16!((secretbox::NONCEBYTES + entry.onion_return.payload.len()) as u16) >>
+        gen_call!(|buf, onion_return| OnionReturn::to_bytes(onion_return, buf), &entry.onion_return)
+    )
+}
+
+// Deserialize announce entry together with its age. Time of the entry is set
+// to the current time and should be corrected using the age.
+named!(entry_from_bytes<(Duration, OnionAnnounceEntry)>, do_parse!(
+    pk: call!(PublicKey::from_bytes) >>
+    ip_port: call!(IpPort::from_udp_bytes, IpPortPadding::NoPadding) >>
+    data_pk: call!(PublicKey::from_bytes) >>
+    age: be_u64 >>
+    onion_return: length_value!(be_u16, OnionReturn::from_bytes) >>
+    (Duration::from_secs(age), OnionAnnounceEntry::new(pk, ip_port.ip_addr, ip_port.port, onion_return, data_pk))
+));
+
+/** Snapshot of live entries of onion announce list together with secret
+bytes. It can be used to restore onion announce list after quick restart so
+that announced nodes don't have to announce themselves again. Note that secret
+bytes are included so the snapshot should be stored securely.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`32`     | Secret bytes of onion node
+`8`      | Unix time in seconds when the snapshot was taken
+`4`      | Number of entries
+variable | Entries with their age
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionAnnounceState {
+    /// Secret bytes of onion node
+    secret_bytes: [u8; SECRET_BYTES_SIZE],
+    /// Unix time in seconds when the snapshot was taken
+    time: u64,
+    /// Entries with their age
+    entries: Vec<(Duration, OnionAnnounceEntry)>,
+}
+
+impl OnionAnnounceState {
+    /// Number of entries in the snapshot.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if the snapshot doesn't have entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Size of serialized snapshot in bytes.
+    pub fn size(&self) -> usize {
+        SECRET_BYTES_SIZE + 8 + 4 + self.entries.iter()
+            .map(|(_, entry)|
+                PUBLICKEYBYTES + 1 + if entry.ip_addr.is_ipv4() { 4 } else { 16 } + 2 +
+                PUBLICKEYBYTES + 8 + 2 + secretbox::NONCEBYTES + entry.onion_return.payload.len()
+            )
+            .sum::<usize>()
+    }
+}
+
+impl ToBytes for OnionAnnounceState {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(&self.secret_bytes) >>
+            gen_be_u64!(self.time) >>
+            gen_be_u32!(self.entries.len() as u32) >>
+            gen_many_ref!(&self.entries, entry_to_bytes)
+        )
+    }
+}
+
+impl FromBytes for OnionAnnounceState {
+    named!(from_bytes<OnionAnnounceState>, do_parse!(
+        secret_bytes: map!(take(SECRET_BYTES_SIZE), |bytes| {
+            let mut secret_bytes = [0; SECRET_BYTES_SIZE];
+            secret_bytes.copy_from_slice(bytes);
+            secret_bytes
+        }) >>
+        time: be_u64 >>
+        entries: length_count!(be_u32, entry_from_bytes) >>
+        eof!() >>
+        (OnionAnnounceState { secret_bytes, time, entries })
+    ));
+}
+
 /** Holds list of announced onion nodes and process announce requests.
 */
 #[derive(Clone, Debug)]
@@ -156,6 +276,8 @@ pub struct OnionAnnounce {
     secret_bytes: [u8; SECRET_BYTES_SIZE],
     /// List of announced onion nodes
     entries: Vec<OnionAnnounceEntry>,
+    /// Maximum number of entries in the list
+    capacity: usize,
     /// Short term DHT `PublicKey`
     dht_pk: PublicKey
 }
@@ -168,10 +290,78 @@ impl OnionAnnounce {
         OnionAnnounce {
             secret_bytes,
             entries: Vec::with_capacity(ONION_ANNOUNCE_MAX_ENTRIES),
+            capacity: ONION_ANNOUNCE_MAX_ENTRIES,
             dht_pk
         }
     }
 
+    /// Number of announced onion nodes that are not timed out.
+    pub fn entries_count(&self) -> usize {
+        self.entries.iter().filter(|e| !e.is_timed_out()).count()
+    }
+
+    /// Maximum number of entries in onion announce list.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Set maximum number of entries in onion announce list. If the list has
+    /// more entries than the new capacity farthest entries are dropped.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.entries.truncate(capacity);
+    }
+
+    /// Remove timed out entries from onion announce list. Returns the number
+    /// of removed entries.
+    pub fn remove_timed_out(&mut self) -> usize {
+        let len = self.entries.len();
+        self.entries.retain(|e| !e.is_timed_out());
+        len - self.entries.len()
+    }
+
+    /// Take a snapshot of entries that are not timed out.
+    pub fn state(&self) -> OnionAnnounceState {
+        let entries = self.entries.iter()
+            .filter(|e| !e.is_timed_out())
+            .map(|e| (Duration::from_secs(clock_elapsed(e.time).as_secs()), e.clone()))
+            .collect();
+        OnionAnnounceState {
+            secret_bytes: self.secret_bytes,
+            time: unix_time(SystemTime::now()),
+            entries,
+        }
+    }
+
+    /** Restore secret bytes and entries from a snapshot.
+
+    Secret bytes are restored so that onion ping ids given before the
+    snapshot was taken remain valid. Entries that would be timed out taking
+    into account the time passed since the snapshot was taken are skipped.
+    Returns the number of restored entries.
+
+    */
+    pub fn restore(&mut self, state: OnionAnnounceState) -> usize {
+        self.secret_bytes = state.secret_bytes;
+        let downtime = Duration::from_secs(unix_time(SystemTime::now()).saturating_sub(state.time));
+
+        let mut count = 0;
+        for (age, mut entry) in state.entries {
+            let age = age + downtime;
+            if age >= ONION_ANNOUNCE_TIMEOUT {
+                continue;
+            }
+            // monotonic clock can't go back further than the system start so
+            // the age is clamped to zero in this case
+            let now = clock_now();
+            entry.time = now.checked_sub(age).unwrap_or(now);
+            if self.add_to_entries(entry).is_some() {
+                count += 1;
+            }
+        }
+        count
+    }
+
     /** Calculate onion ping id using sha256 hash of arguments together with
     secret bytes stored in this struct.
 
@@ -204,8 +394,8 @@ impl OnionAnnounce {
     Firstly we remove all timed out entries. Then if:
     - announce list already contains entry with such `PublicKey` then update
       entry and return it
-    - announce list with new entry does not exceed `ONION_ANNOUNCE_MAX_ENTRIES`
-      length add entry to the list and return it
+    - announce list with new entry does not exceed its capacity add entry to
+      the list and return it
     - the farthest entry from DHT `PublicKey` is farther than new entry then
       replace it with new entry
 
@@ -213,8 +403,9 @@ impl OnionAnnounce {
     we can easily find the farthest entry.
 
     */
-    fn add_to_entries(&mut self, entry: OnionAnnounceEntry) -> Option<&OnionAnnounceEntry> {
-        //TODO: remove timed out entries by timer?
+    pub(crate) fn add_to_entries(&mut self, entry: OnionAnnounceEntry) -> Option<&OnionAnnounceEntry> {
+        // timed out entries are also removed by timer but the list can be
+        // full of them between timer ticks
         self.entries.retain(|e| !e.is_timed_out());
         match self.entries.binary_search_by(|e| self.dht_pk.distance(&e.pk, &entry.pk)) {
             Ok(idx) => {
@@ -223,11 +414,11 @@ impl OnionAnnounce {
                 self.entries.get(idx)
             },
             Err(idx) => {
-                if self.entries.len() < ONION_ANNOUNCE_MAX_ENTRIES {
+                if self.entries.len() < self.capacity {
                     // adding new entry does not exceed the limit - just add it
                     self.entries.insert(idx, entry);
                     self.entries.get(idx)
-                } else if idx < ONION_ANNOUNCE_MAX_ENTRIES {
+                } else if idx < self.capacity {
                     // the farthest entry is farther than new entry - replace it
                     self.entries.pop();
                     self.entries.insert(idx, entry);
@@ -484,6 +675,7 @@ mod tests {
         }
 
         assert_eq!(onion_announce.entries.len(), ONION_ANNOUNCE_MAX_ENTRIES);
+        assert_eq!(onion_announce.entries_count(), ONION_ANNOUNCE_MAX_ENTRIES);
     }
 
     #[tokio::test]
@@ -869,4 +1061,115 @@ mod tests {
 
         assert!(onion_announce.handle_data_request(request).is_err());
     }
+
+    ////////////////////////////////////////////////////////////////////////////////////////
+    // Tests for capacity, expiry and snapshots
+
+    #[test]
+    fn set_capacity_drops_farthest_entries() {
+        crypto_init().unwrap();
+        let mut onion_announce = OnionAnnounce::new(PublicKey([0; PUBLICKEYBYTES]));
+
+        // entries with bigger keys are farther from zero DHT key
+        for i in 0 .. 10 {
+            let saddr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + i as u16);
+            let mut entry = create_random_entry(saddr);
+            entry.pk = PublicKey([10 - i as u8; PUBLICKEYBYTES]);
+            assert!(onion_announce.add_to_entries(entry).is_some());
+        }
+        let closest = onion_announce.entries[.. 4].to_vec();
+        assert_eq!(closest.iter().map(|entry| entry.pk.0[0]).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+
+        onion_announce.set_capacity(4);
+
+        assert_eq!(onion_announce.capacity(), 4);
+        assert_eq!(onion_announce.entries, closest);
+
+        // farther entry can't be added to the full list
+        let mut entry = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        entry.pk = PublicKey([5; PUBLICKEYBYTES]);
+        assert!(onion_announce.add_to_entries(entry).is_none());
+        assert_eq!(onion_announce.entries, closest);
+    }
+
+    #[tokio::test]
+    async fn remove_timed_out() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+        let mut onion_announce = OnionAnnounce::new(gen_keypair().0);
+
+        onion_announce.add_to_entries(create_random_entry("1.2.3.4:12345".parse().unwrap()));
+        tokio::time::advance(ONION_ANNOUNCE_TIMEOUT / 2).await;
+        let entry = create_random_entry("1.2.3.4:12346".parse().unwrap());
+        let entry_pk = entry.pk;
+        onion_announce.add_to_entries(entry);
+
+        assert_eq!(onion_announce.remove_timed_out(), 0);
+
+        tokio::time::advance(ONION_ANNOUNCE_TIMEOUT / 2).await;
+
+        assert_eq!(onion_announce.remove_timed_out(), 1);
+        assert_eq!(onion_announce.entries.len(), 1);
+        assert!(onion_announce.find_in_entries(entry_pk).is_some());
+    }
+
+    #[tokio::test]
+    async fn state_serialize_restore() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+
+        let expiring_entry = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let expiring_pk = expiring_entry.pk;
+        onion_announce.add_to_entries(expiring_entry);
+        tokio::time::advance(ONION_ANNOUNCE_TIMEOUT - Duration::from_secs(1)).await;
+        let entry = create_random_entry("[2001:db8::1]:12346".parse().unwrap());
+        let entry_pk = entry.pk;
+        onion_announce.add_to_entries(entry.clone());
+
+        let state = onion_announce.state();
+        assert_eq!(state.len(), 2);
+
+        let mut buf = vec![0; state.size()];
+        let (_, size) = state.to_bytes((&mut buf, 0)).unwrap();
+        assert_eq!(size, buf.len());
+        let (_, restored_state) = OnionAnnounceState::from_bytes(&buf).unwrap();
+        assert_eq!(restored_state.len(), 2);
+
+        let mut restored = OnionAnnounce::new(dht_pk);
+        assert_eq!(restored.restore(restored_state), 2);
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+
+        // ping ids remain the same
+        let time = SystemTime::now();
+        let ip_addr = "1.2.3.4".parse().unwrap();
+        assert_eq!(
+            restored.ping_id(time, entry_pk, ip_addr, 12345),
+            onion_announce.ping_id(time, entry_pk, ip_addr, 12345)
+        );
+
+        // ages are restored
+        assert!(restored.find_in_entries(expiring_pk).is_none());
+        let restored_entry = restored.find_in_entries(entry_pk).unwrap();
+        assert_eq!(restored_entry.ip_addr, entry.ip_addr);
+        assert_eq!(restored_entry.port, entry.port);
+        assert_eq!(restored_entry.onion_return, entry.onion_return);
+        assert_eq!(restored_entry.data_pk, entry.data_pk);
+        assert_eq!(restored_entry.time, entry.time);
+    }
+
+    #[test]
+    fn state_deserialize_invalid() {
+        crypto_init().unwrap();
+        let state = OnionAnnounce::new(gen_keypair().0).state();
+        let mut buf = vec![0; state.size()];
+        state.to_bytes((&mut buf, 0)).unwrap();
+        assert!(state.is_empty());
+
+        assert!(OnionAnnounceState::from_bytes(&buf[.. buf.len() - 1]).is_err());
+        buf.push(0);
+        assert!(OnionAnnounceState::from_bytes(&buf).is_err());
+    }
 }
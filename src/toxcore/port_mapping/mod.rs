@@ -0,0 +1,514 @@
+/*! NAT-PMP and PCP port mapping client.
+
+[`PortMapper`] asks the local gateway to forward a port to this host so that
+other nodes can reach us without hole punching. PCP is tried first and
+NAT-PMP is used as a fallback when the gateway doesn't support PCP. Mappings
+have limited lifetime so [`PortMapper::run`] renews them periodically and
+reports the external address. [`PortMapper::run_dht`] and
+[`PortMapper::run_tcp_relay`] pass it to the DHT server which advertises it
+in `BootstrapInfo` MOTD and stops hole punching while the UDP port is mapped.
+
+[`PortMapper`]: ./struct.PortMapper.html
+[`PortMapper::run`]: ./struct.PortMapper.html#method.run
+[`PortMapper::run_dht`]: ./struct.PortMapper.html#method.run_dht
+[`PortMapper::run_tcp_relay`]: ./struct.PortMapper.html#method.run_tcp_relay
+*/
+
+pub mod packet;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use failure::{Fail, ResultExt};
+use tokio::net::UdpSocket;
+
+use crate::toxcore::binary_io::*;
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::dht::server::Server;
+use self::packet::*;
+
+pub use self::packet::Protocol;
+
+error_kind! {
+    #[doc = "Error that can happen when creating a port mapping."]
+    #[derive(Debug)]
+    PortMappingError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, Fail)]
+    PortMappingErrorKind {
+        #[doc = "Error indicates that the socket for talking to the gateway can't be created."]
+        #[fail(display = "Create socket error")]
+        Socket,
+        #[doc = "Error indicates that the request can't be sent or the response can't be received."]
+        #[fail(display = "Gateway communication error")]
+        Io,
+        #[doc = "Error indicates that the gateway didn't respond."]
+        #[fail(display = "Gateway didn't respond")]
+        Timeout,
+        #[doc = "Error indicates that the request can't be serialized."]
+        #[fail(display = "Serialize request error")]
+        Serialize,
+        #[doc = "Error indicates that the response can't be parsed or doesn't match the request."]
+        #[fail(display = "Invalid response from gateway")]
+        InvalidResponse,
+        #[doc = "Error indicates that the gateway doesn't support the protocol version."]
+        #[fail(display = "Protocol version is not supported by gateway")]
+        UnsupportedVersion,
+        #[doc = "Error indicates that the gateway refused to create the mapping."]
+        #[fail(display = "Gateway refused the request with result code {}", code)]
+        Rejected {
+            /// Result code returned by the gateway.
+            code: u16,
+        },
+    }
+}
+
+/// Port on which gateways listen for NAT-PMP and PCP requests.
+pub const GATEWAY_PORT: u16 = 5351;
+
+/// Default lifetime of port mappings in seconds that is requested from the
+/// gateway.
+pub const DEFAULT_LIFETIME: u32 = 7200;
+
+/// Interval between attempts to create a port mapping after a failure.
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Minimum interval between mapping renewals.
+const MIN_RENEW_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many times a request is sent before giving up.
+const REQUEST_ATTEMPTS: u32 = 4;
+
+/// Time to wait for the response to the first request. It's doubled for every
+/// next attempt.
+const INITIAL_REQUEST_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Maximum size of NAT-PMP and PCP packets.
+const MAX_PACKET_SIZE: usize = 1100;
+
+/// Port mapping created by the gateway.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Mapping {
+    /// Protocol of the mapping.
+    pub protocol: Protocol,
+    /// Port on the local host.
+    pub internal_port: u16,
+    /// Address on the gateway that is forwarded to the local port.
+    pub external_addr: SocketAddr,
+    /// Time after which the mapping expires if not renewed.
+    pub lifetime: Duration,
+}
+
+/// NAT-PMP and PCP client.
+#[derive(Clone, Debug)]
+pub struct PortMapper {
+    /// Address of the gateway.
+    gateway: SocketAddr,
+    /// Lifetime of mappings in seconds that is requested from the gateway.
+    lifetime: u32,
+    /// PCP nonce that identifies our mappings. It must be the same for
+    /// renewals of a mapping.
+    nonce: [u8; PCP_NONCE_SIZE],
+}
+
+impl PortMapper {
+    /// Create new `PortMapper` for the gateway. Gateways normally listen on
+    /// `GATEWAY_PORT`.
+    pub fn new(gateway: SocketAddr) -> PortMapper {
+        let mut nonce = [0; PCP_NONCE_SIZE];
+        randombytes_into(&mut nonce);
+
+        PortMapper {
+            gateway,
+            lifetime: DEFAULT_LIFETIME,
+            nonce,
+        }
+    }
+
+    /// Set lifetime of mappings in seconds that is requested from the gateway.
+    pub fn set_lifetime(&mut self, lifetime: u32) {
+        self.lifetime = lifetime;
+    }
+
+    /// Ask the gateway to forward `internal_port` of this host. PCP is tried
+    /// first, NAT-PMP is used if the gateway doesn't support PCP.
+    pub async fn map(&self, protocol: Protocol, internal_port: u16) -> Result<Mapping, PortMappingError> {
+        let mut socket = self.connect().await?;
+
+        match self.map_pcp(&mut socket, protocol, internal_port).await {
+            Err(ref e) if *e.kind() == PortMappingErrorKind::UnsupportedVersion => {
+                debug!("Gateway {} doesn't support PCP, falling back to NAT-PMP", self.gateway);
+                self.map_pmp(&mut socket, protocol, internal_port).await
+            },
+            res => res,
+        }
+    }
+
+    /// Keep `internal_port` of this host forwarded by the gateway renewing the
+    /// mapping before it expires. `on_change` is called with the new mapping
+    /// every time it's created or renewed and with `None` when it's lost.
+    /// Result future is never completed.
+    pub async fn run<F>(self, protocol: Protocol, internal_port: u16, mut on_change: F)
+        where F: FnMut(Option<Mapping>) + Send
+    {
+        let mut is_mapped = false;
+
+        loop {
+            let delay = match self.map(protocol, internal_port).await {
+                Ok(mapping) => {
+                    debug!("Gateway {} mapped port {} to {}", self.gateway, internal_port, mapping.external_addr);
+                    is_mapped = true;
+                    on_change(Some(mapping));
+                    (mapping.lifetime / 2).max(MIN_RENEW_INTERVAL)
+                },
+                Err(e) => {
+                    warn!("Failed to map port {} on gateway {}: {}", internal_port, self.gateway, e);
+                    if is_mapped {
+                        is_mapped = false;
+                        on_change(None);
+                    }
+                    RETRY_INTERVAL
+                },
+            };
+
+            tokio::time::delay_for(delay).await;
+        }
+    }
+
+    /// Keep UDP port of DHT server forwarded by the gateway and report the
+    /// external address to the server. Result future is never completed.
+    pub async fn run_dht(self, server: Server, internal_port: u16) {
+        self.run(Protocol::Udp, internal_port, move |mapping|
+            server.set_external_addr(mapping.map(|mapping| mapping.external_addr))
+        ).await
+    }
+
+    /// Keep TCP port of the relay listener forwarded by the gateway and
+    /// report the external address to the DHT server. Result future is never
+    /// completed.
+    pub async fn run_tcp_relay(self, server: Server, internal_port: u16) {
+        self.run(Protocol::Tcp, internal_port, move |mapping|
+            server.set_external_tcp_addr(mapping.map(|mapping| mapping.external_addr))
+        ).await
+    }
+
+    /// Create socket connected to the gateway.
+    async fn connect(&self) -> Result<UdpSocket, PortMappingError> {
+        let local_ip = if self.gateway.is_ipv4() {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        };
+        let socket = UdpSocket::bind(&SocketAddr::new(local_ip, 0)).await
+            .context(PortMappingErrorKind::Socket)?;
+        socket.connect(&self.gateway).await
+            .context(PortMappingErrorKind::Socket)?;
+        Ok(socket)
+    }
+
+    /// Send the request to the gateway and wait for a response. The request
+    /// is retransmitted with exponentially increasing timeout.
+    async fn request<P: ToBytes>(&self, socket: &mut UdpSocket, packet: &P) -> Result<Vec<u8>, PortMappingError> {
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let (_, size) = packet.to_bytes((&mut buf, 0))
+            .map_err(|_| PortMappingError::from(PortMappingErrorKind::Serialize))?;
+        let request = buf[.. size].to_vec();
+
+        let mut timeout = INITIAL_REQUEST_TIMEOUT;
+        for _ in 0 .. REQUEST_ATTEMPTS {
+            socket.send(&request).await
+                .context(PortMappingErrorKind::Io)?;
+
+            match tokio::time::timeout(timeout, socket.recv(&mut buf)).await {
+                Ok(Ok(size)) => return Ok(buf[.. size].to_vec()),
+                Ok(Err(e)) => return Err(e.context(PortMappingErrorKind::Io).into()),
+                Err(_) => timeout *= 2,
+            }
+        }
+
+        Err(PortMappingErrorKind::Timeout.into())
+    }
+
+    /// Create port mapping using PCP.
+    async fn map_pcp(&self, socket: &mut UdpSocket, protocol: Protocol, internal_port: u16) -> Result<Mapping, PortMappingError> {
+        let client_ip = socket.local_addr()
+            .context(PortMappingErrorKind::Socket)?
+            .ip();
+        let external_ip = if client_ip.is_ipv4() {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        };
+        let request = PcpMapRequest {
+            lifetime: self.lifetime,
+            client_ip,
+            nonce: self.nonce,
+            protocol,
+            internal_port,
+            external_port: internal_port,
+            external_ip,
+        };
+
+        let response = self.request(socket, &request).await?;
+
+        // NAT-PMP gateways respond to PCP requests with NAT-PMP header
+        if response.first() == Some(&PMP_VERSION) {
+            return Err(PortMappingErrorKind::UnsupportedVersion.into());
+        }
+        match response.get(3) {
+            Some(&RESULT_SUCCESS) => {},
+            Some(&RESULT_UNSUPPORTED_VERSION) =>
+                return Err(PortMappingErrorKind::UnsupportedVersion.into()),
+            Some(&code) =>
+                return Err(PortMappingErrorKind::Rejected { code: u16::from(code) }.into()),
+            None =>
+                return Err(PortMappingErrorKind::InvalidResponse.into()),
+        }
+
+        let response = match PcpMapResponse::from_bytes(&response) {
+            Ok((_, response)) => response,
+            Err(_) => return Err(PortMappingErrorKind::InvalidResponse.into()),
+        };
+        if response.nonce != self.nonce || response.protocol != protocol || response.internal_port != internal_port {
+            return Err(PortMappingErrorKind::InvalidResponse.into());
+        }
+
+        Ok(Mapping {
+            protocol,
+            internal_port,
+            external_addr: SocketAddr::new(response.external_ip, response.external_port),
+            lifetime: Duration::from_secs(response.lifetime.into()),
+        })
+    }
+
+    /// Create port mapping using NAT-PMP.
+    async fn map_pmp(&self, socket: &mut UdpSocket, protocol: Protocol, internal_port: u16) -> Result<Mapping, PortMappingError> {
+        let response = self.request(socket, &PmpExternalAddressRequest).await?;
+        check_pmp_result_code(&response)?;
+        let external_ip = match PmpExternalAddressResponse::from_bytes(&response) {
+            Ok((_, response)) => response.ip,
+            Err(_) => return Err(PortMappingErrorKind::InvalidResponse.into()),
+        };
+
+        let request = PmpMapRequest {
+            protocol,
+            internal_port,
+            external_port: internal_port,
+            lifetime: self.lifetime,
+        };
+        let response = self.request(socket, &request).await?;
+        check_pmp_result_code(&response)?;
+        let response = match PmpMapResponse::from_bytes(&response) {
+            Ok((_, response)) => response,
+            Err(_) => return Err(PortMappingErrorKind::InvalidResponse.into()),
+        };
+        if response.protocol != protocol || response.internal_port != internal_port {
+            return Err(PortMappingErrorKind::InvalidResponse.into());
+        }
+
+        Ok(Mapping {
+            protocol,
+            internal_port,
+            external_addr: SocketAddr::new(IpAddr::V4(external_ip), response.external_port),
+            lifetime: Duration::from_secs(response.lifetime.into()),
+        })
+    }
+}
+
+/// Check result code of NAT-PMP response. Error responses can be shorter than
+/// successful ones so the code is checked before parsing.
+fn check_pmp_result_code(response: &[u8]) -> Result<(), PortMappingError> {
+    if response.len() < 4 || response[0] != PMP_VERSION {
+        return Err(PortMappingErrorKind::InvalidResponse.into());
+    }
+
+    match u16::from_be_bytes([response[2], response[3]]) {
+        0 => Ok(()),
+        code if code == u16::from(RESULT_UNSUPPORTED_VERSION) =>
+            Err(PortMappingErrorKind::UnsupportedVersion.into()),
+        code => Err(PortMappingErrorKind::Rejected { code }.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::channel::mpsc;
+    use futures::{FutureExt, StreamExt};
+
+    /// Stand-in gateway that answers requests on loopback.
+    struct Gateway {
+        socket: UdpSocket,
+        pcp: bool,
+        lifetime: u32,
+    }
+
+    impl Gateway {
+        async fn new(pcp: bool, lifetime: u32) -> (Gateway, SocketAddr) {
+            let socket = UdpSocket::bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap()).await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            (Gateway { socket, pcp, lifetime }, addr)
+        }
+
+        /// Answer requests and count map requests.
+        async fn run(mut self, requests_tx: mpsc::UnboundedSender<Protocol>) {
+            let mut buf = [0; MAX_PACKET_SIZE];
+            loop {
+                let (size, addr) = self.socket.recv_from(&mut buf).await.unwrap();
+                let request = buf[.. size].to_vec();
+                let mut response = [0; MAX_PACKET_SIZE];
+
+                let size = if let Ok((_, request)) = PcpMapRequest::from_bytes(&request) {
+                    if !self.pcp {
+                        // NAT-PMP gateway reports unsupported version
+                        response[.. 4].copy_from_slice(&[PMP_VERSION, 0x81, 0, RESULT_UNSUPPORTED_VERSION]);
+                        8
+                    } else {
+                        requests_tx.unbounded_send(request.protocol).unwrap();
+                        PcpMapResponse {
+                            result_code: RESULT_SUCCESS,
+                            lifetime: self.lifetime,
+                            epoch: 1,
+                            nonce: request.nonce,
+                            protocol: request.protocol,
+                            internal_port: request.internal_port,
+                            external_port: request.external_port + 1,
+                            external_ip: "1.2.3.4".parse().unwrap(),
+                        }.to_bytes((&mut response, 0)).unwrap().1
+                    }
+                } else if PmpExternalAddressRequest::from_bytes(&request).is_ok() {
+                    PmpExternalAddressResponse {
+                        result_code: 0,
+                        epoch: 1,
+                        ip: "5.6.7.8".parse().unwrap(),
+                    }.to_bytes((&mut response, 0)).unwrap().1
+                } else if let Ok((_, request)) = PmpMapRequest::from_bytes(&request) {
+                    requests_tx.unbounded_send(request.protocol).unwrap();
+                    PmpMapResponse {
+                        protocol: request.protocol,
+                        result_code: 0,
+                        epoch: 1,
+                        internal_port: request.internal_port,
+                        external_port: request.external_port + 2,
+                        lifetime: self.lifetime,
+                    }.to_bytes((&mut response, 0)).unwrap().1
+                } else {
+                    continue;
+                };
+
+                self.socket.send_to(&response[.. size], &addr).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn map_pcp() {
+        let (gateway, gateway_addr) = Gateway::new(true, 7200).await;
+        let (requests_tx, _requests_rx) = mpsc::unbounded();
+        tokio::spawn(gateway.run(requests_tx));
+
+        let mapper = PortMapper::new(gateway_addr);
+        let mapping = mapper.map(Protocol::Udp, 33445).await.unwrap();
+
+        assert_eq!(mapping, Mapping {
+            protocol: Protocol::Udp,
+            internal_port: 33445,
+            external_addr: "1.2.3.4:33446".parse().unwrap(),
+            lifetime: Duration::from_secs(7200),
+        });
+    }
+
+    #[tokio::test]
+    async fn map_pmp_fallback() {
+        let (gateway, gateway_addr) = Gateway::new(false, 3600).await;
+        let (requests_tx, _requests_rx) = mpsc::unbounded();
+        tokio::spawn(gateway.run(requests_tx));
+
+        let mapper = PortMapper::new(gateway_addr);
+        let mapping = mapper.map(Protocol::Tcp, 33445).await.unwrap();
+
+        assert_eq!(mapping, Mapping {
+            protocol: Protocol::Tcp,
+            internal_port: 33445,
+            external_addr: "5.6.7.8:33447".parse().unwrap(),
+            lifetime: Duration::from_secs(3600),
+        });
+    }
+
+    #[tokio::test]
+    async fn map_timeout() {
+        // socket that never responds
+        let socket = UdpSocket::bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap()).await.unwrap();
+        let gateway_addr = socket.local_addr().unwrap();
+
+        let mapper = PortMapper::new(gateway_addr);
+        tokio::time::pause();
+        let map_future = tokio::spawn(async move {
+            mapper.map(Protocol::Udp, 33445).await
+        });
+        for _ in 0 .. REQUEST_ATTEMPTS {
+            tokio::time::advance(INITIAL_REQUEST_TIMEOUT * 2u32.pow(REQUEST_ATTEMPTS)).await;
+        }
+
+        let error = map_future.await.unwrap().err().unwrap();
+        assert_eq!(*error.kind(), PortMappingErrorKind::Timeout);
+        drop(socket);
+    }
+
+    #[test]
+    fn pmp_result_code() {
+        assert!(check_pmp_result_code(&[0, 0x80, 0, 0]).is_ok());
+        assert_eq!(*check_pmp_result_code(&[0, 0x80, 0, 1]).err().unwrap().kind(), PortMappingErrorKind::UnsupportedVersion);
+        assert_eq!(*check_pmp_result_code(&[0, 0x80, 0, 3]).err().unwrap().kind(), PortMappingErrorKind::Rejected { code: 3 });
+        assert_eq!(*check_pmp_result_code(&[0, 0x80]).err().unwrap().kind(), PortMappingErrorKind::InvalidResponse);
+    }
+
+    #[tokio::test]
+    async fn run_dht_renews_mapping() {
+        let (gateway, gateway_addr) = Gateway::new(true, 2).await;
+        let (requests_tx, requests_rx) = mpsc::unbounded();
+        tokio::spawn(gateway.run(requests_tx));
+
+        let (tx, _rx) = mpsc::channel(1);
+        let (pk, sk) = gen_keypair();
+        let server = Server::new(tx, pk, sk);
+
+        let mapper = PortMapper::new(gateway_addr);
+        let run_future = mapper.run_dht(server.clone(), 33445).boxed();
+
+        // gateway gives mappings for 2 seconds so the mapping is renewed
+        // after 1 second
+        let requests = requests_rx.take(2).collect::<Vec<_>>();
+        futures::select! {
+            requests = requests.fuse() => assert_eq!(requests, vec![Protocol::Udp, Protocol::Udp]),
+            _ = run_future.fuse() => unreachable!(),
+        }
+
+        assert_eq!(server.external_addr(), Some("1.2.3.4:33446".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn run_tcp_relay_reports_addr() {
+        let (gateway, gateway_addr) = Gateway::new(false, 7200).await;
+        let (requests_tx, _requests_rx) = mpsc::unbounded();
+        tokio::spawn(gateway.run(requests_tx));
+
+        let (tx, _rx) = mpsc::channel(1);
+        let (pk, sk) = gen_keypair();
+        let server = Server::new(tx, pk, sk);
+
+        let mapper = PortMapper::new(gateway_addr);
+        tokio::spawn(mapper.run_tcp_relay(server.clone(), 443));
+
+        let mapped = async {
+            while server.external_tcp_addr().is_none() {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), mapped).await.unwrap();
+
+        assert_eq!(server.external_tcp_addr(), Some("5.6.7.8:445".parse().unwrap()));
+        assert_eq!(server.external_addr(), None);
+    }
+}
@@ -0,0 +1,536 @@
+/*! NAT-PMP and PCP packets.
+
+NAT-PMP is described in [RFC 6886](https://tools.ietf.org/html/rfc6886) and
+PCP in [RFC 6887](https://tools.ietf.org/html/rfc6887). Only packets necessary
+to create port mappings are implemented. Unlike Tox packets all numbers are
+encoded in network byte order.
+*/
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use nom::number::complete::{be_u8, be_u16, be_u32};
+use nom::bytes::complete::take;
+
+use crate::toxcore::binary_io::*;
+
+/// Version of NAT-PMP protocol.
+pub const PMP_VERSION: u8 = 0;
+
+/// Version of PCP protocol.
+pub const PCP_VERSION: u8 = 2;
+
+/// Result code that indicates success for both NAT-PMP and PCP.
+pub const RESULT_SUCCESS: u8 = 0;
+
+/// Result code that indicates that the version of protocol is not supported
+/// by the gateway for both NAT-PMP and PCP.
+pub const RESULT_UNSUPPORTED_VERSION: u8 = 1;
+
+/// Length of PCP mapping nonce.
+pub const PCP_NONCE_SIZE: usize = 12;
+
+/// Bit that is set in opcode of response packets.
+const RESPONSE_BIT: u8 = 0x80;
+
+/// Opcode of NAT-PMP external address request.
+const PMP_OPCODE_EXTERNAL_ADDRESS: u8 = 0;
+
+/// Opcode of PCP map request.
+const PCP_OPCODE_MAP: u8 = 1;
+
+/// Transport protocol of a port mapping.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Protocol {
+    /// UDP port mapping.
+    Udp,
+    /// TCP port mapping.
+    Tcp,
+}
+
+impl Protocol {
+    /// Opcode of NAT-PMP map request for this protocol.
+    fn pmp_opcode(self) -> u8 {
+        match self {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+        }
+    }
+
+    /// Get protocol from opcode of NAT-PMP map request.
+    fn from_pmp_opcode(opcode: u8) -> Option<Protocol> {
+        match opcode {
+            1 => Some(Protocol::Udp),
+            2 => Some(Protocol::Tcp),
+            _ => None,
+        }
+    }
+
+    /// IANA protocol number used by PCP.
+    fn iana_number(self) -> u8 {
+        match self {
+            Protocol::Udp => 17,
+            Protocol::Tcp => 6,
+        }
+    }
+
+    /// Get protocol from IANA protocol number.
+    fn from_iana_number(number: u8) -> Option<Protocol> {
+        match number {
+            17 => Some(Protocol::Udp),
+            6 => Some(Protocol::Tcp),
+            _ => None,
+        }
+    }
+}
+
+/// PCP encodes all addresses as IPv6 addresses, IPv4 addresses are
+/// IPv4-mapped.
+fn pcp_ip_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// Parse IP address encoded as IPv6 address. IPv4-mapped addresses are
+/// converted to IPv4 addresses.
+fn pcp_ip_from_octets(octets: &[u8]) -> IpAddr {
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(octets);
+    let ip = Ipv6Addr::from(bytes);
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, _, _] => IpAddr::V4(Ipv4Addr::new(bytes[12], bytes[13], bytes[14], bytes[15])),
+        _ => IpAddr::V6(ip),
+    }
+}
+
+/** NAT-PMP request of gateway's external IPv4 address.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`1`    | Version (0)
+`1`    | Opcode (0)
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PmpExternalAddressRequest;
+
+impl ToBytes for PmpExternalAddressRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(PMP_VERSION) >>
+            gen_be_u8!(PMP_OPCODE_EXTERNAL_ADDRESS)
+        )
+    }
+}
+
+impl FromBytes for PmpExternalAddressRequest {
+    named!(from_bytes<PmpExternalAddressRequest>, do_parse!(
+        tag!(&[PMP_VERSION, PMP_OPCODE_EXTERNAL_ADDRESS][..]) >>
+        (PmpExternalAddressRequest)
+    ));
+}
+
+/** NAT-PMP response with gateway's external IPv4 address.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`1`    | Version (0)
+`1`    | Opcode (128)
+`2`    | Result code
+`4`    | Seconds since start of epoch
+`4`    | External IPv4 address
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PmpExternalAddressResponse {
+    /// Result code of the request.
+    pub result_code: u16,
+    /// Seconds since the gateway's port mapping table was initialized.
+    pub epoch: u32,
+    /// External IPv4 address of the gateway.
+    pub ip: Ipv4Addr,
+}
+
+impl ToBytes for PmpExternalAddressResponse {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(PMP_VERSION) >>
+            gen_be_u8!(PMP_OPCODE_EXTERNAL_ADDRESS | RESPONSE_BIT) >>
+            gen_be_u16!(self.result_code) >>
+            gen_be_u32!(self.epoch) >>
+            gen_slice!(self.ip.octets())
+        )
+    }
+}
+
+impl FromBytes for PmpExternalAddressResponse {
+    named!(from_bytes<PmpExternalAddressResponse>, do_parse!(
+        tag!(&[PMP_VERSION, PMP_OPCODE_EXTERNAL_ADDRESS | RESPONSE_BIT][..]) >>
+        result_code: be_u16 >>
+        epoch: be_u32 >>
+        ip: call!(Ipv4Addr::from_bytes) >>
+        (PmpExternalAddressResponse { result_code, epoch, ip })
+    ));
+}
+
+/** NAT-PMP request to create a port mapping. Lifetime `0` means removing of
+the mapping.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`1`    | Version (0)
+`1`    | Opcode (1 for UDP, 2 for TCP)
+`2`    | Reserved (0)
+`2`    | Internal port
+`2`    | Suggested external port
+`4`    | Requested lifetime in seconds
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PmpMapRequest {
+    /// Protocol of the mapping.
+    pub protocol: Protocol,
+    /// Port on the local host.
+    pub internal_port: u16,
+    /// Suggested port on the gateway.
+    pub external_port: u16,
+    /// Requested lifetime of the mapping in seconds.
+    pub lifetime: u32,
+}
+
+impl ToBytes for PmpMapRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(PMP_VERSION) >>
+            gen_be_u8!(self.protocol.pmp_opcode()) >>
+            gen_be_u16!(0) >>
+            gen_be_u16!(self.internal_port) >>
+            gen_be_u16!(self.external_port) >>
+            gen_be_u32!(self.lifetime)
+        )
+    }
+}
+
+impl FromBytes for PmpMapRequest {
+    named!(from_bytes<PmpMapRequest>, do_parse!(
+        tag!(&[PMP_VERSION][..]) >>
+        protocol: map_opt!(be_u8, Protocol::from_pmp_opcode) >>
+        tag!(&[0, 0][..]) >>
+        internal_port: be_u16 >>
+        external_port: be_u16 >>
+        lifetime: be_u32 >>
+        (PmpMapRequest { protocol, internal_port, external_port, lifetime })
+    ));
+}
+
+/** NAT-PMP response to port mapping request.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`1`    | Version (0)
+`1`    | Opcode (129 for UDP, 130 for TCP)
+`2`    | Result code
+`4`    | Seconds since start of epoch
+`2`    | Internal port
+`2`    | Mapped external port
+`4`    | Lifetime of the mapping in seconds
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PmpMapResponse {
+    /// Protocol of the mapping.
+    pub protocol: Protocol,
+    /// Result code of the request.
+    pub result_code: u16,
+    /// Seconds since the gateway's port mapping table was initialized.
+    pub epoch: u32,
+    /// Port on the local host.
+    pub internal_port: u16,
+    /// Port on the gateway.
+    pub external_port: u16,
+    /// Lifetime of the mapping in seconds.
+    pub lifetime: u32,
+}
+
+impl ToBytes for PmpMapResponse {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(PMP_VERSION) >>
+            gen_be_u8!(self.protocol.pmp_opcode() | RESPONSE_BIT) >>
+            gen_be_u16!(self.result_code) >>
+            gen_be_u32!(self.epoch) >>
+            gen_be_u16!(self.internal_port) >>
+            gen_be_u16!(self.external_port) >>
+            gen_be_u32!(self.lifetime)
+        )
+    }
+}
+
+impl FromBytes for PmpMapResponse {
+    named!(from_bytes<PmpMapResponse>, do_parse!(
+        tag!(&[PMP_VERSION][..]) >>
+        protocol: map_opt!(be_u8, |opcode| Protocol::from_pmp_opcode(opcode & !RESPONSE_BIT)) >>
+        result_code: be_u16 >>
+        epoch: be_u32 >>
+        internal_port: be_u16 >>
+        external_port: be_u16 >>
+        lifetime: be_u32 >>
+        (PmpMapResponse { protocol, result_code, epoch, internal_port, external_port, lifetime })
+    ));
+}
+
+/** PCP request to create a port mapping. Lifetime `0` means removing of the
+mapping.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`1`    | Version (2)
+`1`    | Opcode (1)
+`2`    | Reserved (0)
+`4`    | Requested lifetime in seconds
+`16`   | Client's IP address
+`12`   | Mapping nonce
+`1`    | IANA protocol number
+`3`    | Reserved (0)
+`2`    | Internal port
+`2`    | Suggested external port
+`16`   | Suggested external IP address
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PcpMapRequest {
+    /// Requested lifetime of the mapping in seconds.
+    pub lifetime: u32,
+    /// IP address of the client as seen by the gateway.
+    pub client_ip: IpAddr,
+    /// Random nonce that identifies the mapping.
+    pub nonce: [u8; PCP_NONCE_SIZE],
+    /// Protocol of the mapping.
+    pub protocol: Protocol,
+    /// Port on the local host.
+    pub internal_port: u16,
+    /// Suggested port on the gateway.
+    pub external_port: u16,
+    /// Suggested IP address on the gateway.
+    pub external_ip: IpAddr,
+}
+
+impl ToBytes for PcpMapRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(PCP_VERSION) >>
+            gen_be_u8!(PCP_OPCODE_MAP) >>
+            gen_be_u16!(0) >>
+            gen_be_u32!(self.lifetime) >>
+            gen_slice!(pcp_ip_octets(self.client_ip)) >>
+            gen_slice!(self.nonce) >>
+            gen_be_u8!(self.protocol.iana_number()) >>
+            gen_slice!([0; 3]) >>
+            gen_be_u16!(self.internal_port) >>
+            gen_be_u16!(self.external_port) >>
+            gen_slice!(pcp_ip_octets(self.external_ip))
+        )
+    }
+}
+
+impl FromBytes for PcpMapRequest {
+    named!(from_bytes<PcpMapRequest>, do_parse!(
+        tag!(&[PCP_VERSION, PCP_OPCODE_MAP, 0, 0][..]) >>
+        lifetime: be_u32 >>
+        client_ip: map!(take(16usize), pcp_ip_from_octets) >>
+        nonce: map!(take(PCP_NONCE_SIZE), |bytes| {
+            let mut nonce = [0; PCP_NONCE_SIZE];
+            nonce.copy_from_slice(bytes);
+            nonce
+        }) >>
+        protocol: map_opt!(be_u8, Protocol::from_iana_number) >>
+        tag!(&[0, 0, 0][..]) >>
+        internal_port: be_u16 >>
+        external_port: be_u16 >>
+        external_ip: map!(take(16usize), pcp_ip_from_octets) >>
+        (PcpMapRequest { lifetime, client_ip, nonce, protocol, internal_port, external_port, external_ip })
+    ));
+}
+
+/** PCP response to port mapping request.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`1`    | Version (2)
+`1`    | Opcode (129)
+`1`    | Reserved (0)
+`1`    | Result code
+`4`    | Lifetime of the mapping in seconds
+`4`    | Seconds since start of epoch
+`12`   | Reserved (0)
+`12`   | Mapping nonce
+`1`    | IANA protocol number
+`3`    | Reserved (0)
+`2`    | Internal port
+`2`    | Assigned external port
+`16`   | Assigned external IP address
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PcpMapResponse {
+    /// Result code of the request.
+    pub result_code: u8,
+    /// Lifetime of the mapping in seconds.
+    pub lifetime: u32,
+    /// Seconds since the gateway's port mapping table was initialized.
+    pub epoch: u32,
+    /// Nonce of the mapping from the request.
+    pub nonce: [u8; PCP_NONCE_SIZE],
+    /// Protocol of the mapping.
+    pub protocol: Protocol,
+    /// Port on the local host.
+    pub internal_port: u16,
+    /// Port on the gateway.
+    pub external_port: u16,
+    /// IP address on the gateway.
+    pub external_ip: IpAddr,
+}
+
+impl ToBytes for PcpMapResponse {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(PCP_VERSION) >>
+            gen_be_u8!(PCP_OPCODE_MAP | RESPONSE_BIT) >>
+            gen_be_u8!(0) >>
+            gen_be_u8!(self.result_code) >>
+            gen_be_u32!(self.lifetime) >>
+            gen_be_u32!(self.epoch) >>
+            gen_slice!([0; 12]) >>
+            gen_slice!(self.nonce) >>
+            gen_be_u8!(self.protocol.iana_number()) >>
+            gen_slice!([0; 3]) >>
+            gen_be_u16!(self.internal_port) >>
+            gen_be_u16!(self.external_port) >>
+            gen_slice!(pcp_ip_octets(self.external_ip))
+        )
+    }
+}
+
+impl FromBytes for PcpMapResponse {
+    named!(from_bytes<PcpMapResponse>, do_parse!(
+        tag!(&[PCP_VERSION, PCP_OPCODE_MAP | RESPONSE_BIT][..]) >>
+        take!(1) >>
+        result_code: be_u8 >>
+        lifetime: be_u32 >>
+        epoch: be_u32 >>
+        take!(12) >>
+        nonce: map!(take(PCP_NONCE_SIZE), |bytes| {
+            let mut nonce = [0; PCP_NONCE_SIZE];
+            nonce.copy_from_slice(bytes);
+            nonce
+        }) >>
+        protocol: map_opt!(be_u8, Protocol::from_iana_number) >>
+        take!(3) >>
+        internal_port: be_u16 >>
+        external_port: be_u16 >>
+        external_ip: map!(take(16usize), pcp_ip_from_octets) >>
+        (PcpMapResponse { result_code, lifetime, epoch, nonce, protocol, internal_port, external_port, external_ip })
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        pmp_external_address_request_encode_decode,
+        PmpExternalAddressRequest
+    );
+
+    encode_decode_test!(
+        pmp_external_address_response_encode_decode,
+        PmpExternalAddressResponse {
+            result_code: 0,
+            epoch: 42,
+            ip: "1.2.3.4".parse().unwrap(),
+        }
+    );
+
+    encode_decode_test!(
+        pmp_map_request_encode_decode,
+        PmpMapRequest {
+            protocol: Protocol::Tcp,
+            internal_port: 33445,
+            external_port: 33446,
+            lifetime: 7200,
+        }
+    );
+
+    encode_decode_test!(
+        pmp_map_response_encode_decode,
+        PmpMapResponse {
+            protocol: Protocol::Udp,
+            result_code: 0,
+            epoch: 42,
+            internal_port: 33445,
+            external_port: 33446,
+            lifetime: 7200,
+        }
+    );
+
+    encode_decode_test!(
+        pcp_map_request_encode_decode,
+        PcpMapRequest {
+            lifetime: 7200,
+            client_ip: "192.168.1.2".parse().unwrap(),
+            nonce: [42; PCP_NONCE_SIZE],
+            protocol: Protocol::Udp,
+            internal_port: 33445,
+            external_port: 0,
+            external_ip: "::".parse().unwrap(),
+        }
+    );
+
+    encode_decode_test!(
+        pcp_map_response_encode_decode,
+        PcpMapResponse {
+            result_code: 0,
+            lifetime: 7200,
+            epoch: 42,
+            nonce: [42; PCP_NONCE_SIZE],
+            protocol: Protocol::Tcp,
+            internal_port: 33445,
+            external_port: 33446,
+            external_ip: "1.2.3.4".parse().unwrap(),
+        }
+    );
+
+    #[test]
+    fn pmp_map_request_bytes() {
+        let request = PmpMapRequest {
+            protocol: Protocol::Udp,
+            internal_port: 0x0102,
+            external_port: 0x0304,
+            lifetime: 0x0506_0708,
+        };
+        let mut buf = [0; 12];
+        let (_, size) = request.to_bytes((&mut buf, 0)).unwrap();
+        assert_eq!(&buf[.. size], &[0, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8][..]);
+    }
+
+    #[test]
+    fn pcp_ip_ipv4_mapped() {
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let octets = pcp_ip_octets(ip);
+        assert_eq!(&octets[10 ..], &[0xff, 0xff, 1, 2, 3, 4][..]);
+        assert_eq!(pcp_ip_from_octets(&octets), ip);
+    }
+}
@@ -0,0 +1,317 @@
+/*!
+Exporter of [`Stats`] and state of DHT and TCP servers in
+[Prometheus](https://prometheus.io/) text exposition format.
+
+Exporter runs a tiny HTTP server that responds to `GET /metrics` requests.
+It's available only when `prometheus` feature is enabled.
+
+[`Stats`]: ../stats/struct.Stats.html
+*/
+
+use std::fmt::Write;
+use std::io::Error;
+use std::time::Duration;
+
+use futures::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::toxcore::dht::server::{Server as DhtServer};
+use crate::toxcore::stats::*;
+use crate::toxcore::tcp::server::{Server as TcpServer};
+
+/// Path metrics are served on.
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Maximum size of HTTP request head we are able to handle.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// How long we wait for HTTP request from a client.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Content type of Prometheus text exposition format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Prometheus exporter that serves metrics over HTTP.
+#[derive(Clone)]
+pub struct Exporter {
+    /// Statistics shared with codecs and other modules.
+    stats: Stats,
+    /// DHT server to get close nodes and onion announce entries from.
+    dht_server: Option<DhtServer>,
+    /// TCP server to get connected clients from.
+    tcp_server: Option<TcpServer>,
+}
+
+impl Exporter {
+    /// Create new `Exporter` that serves the given `Stats`.
+    pub fn new(stats: Stats) -> Self {
+        Exporter {
+            stats,
+            dht_server: None,
+            tcp_server: None,
+        }
+    }
+
+    /// Set DHT server to export its close nodes and onion announce entries.
+    pub fn set_dht_server(&mut self, dht_server: DhtServer) {
+        self.dht_server = Some(dht_server);
+    }
+
+    /// Set TCP server to export number of its connected clients.
+    pub fn set_tcp_server(&mut self, tcp_server: TcpServer) {
+        self.tcp_server = Some(tcp_server);
+    }
+
+    /// Render all metrics in Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let snapshot = self.stats.snapshot();
+        let mut out = String::new();
+
+        let packets = snapshot.packets.iter().flat_map(|stats| {
+            let labels = |direction| format!(
+                "transport=\"{}\",subsystem=\"{}\",kind=\"0x{:02x}\",direction=\"{}\"",
+                transport_label(stats.transport),
+                subsystem_label(stats.subsystem),
+                stats.kind,
+                direction
+            );
+            vec![(labels("in"), stats.incoming), (labels("out"), stats.outgoing)]
+        }).collect::<Vec<_>>();
+        write_metric(&mut out, "tox_packets_total", "counter",
+            "Number of packets by transport, subsystem and packet kind.", &packets);
+        write_metric(&mut out, "tox_bytes_total", "counter",
+            "Size of packets in bytes.", &[
+                ("direction=\"in\"".to_owned(), snapshot.bytes_in),
+                ("direction=\"out\"".to_owned(), snapshot.bytes_out),
+            ]);
+        write_metric(&mut out, "tox_parse_failures_total", "counter",
+            "Number of packets that can't be parsed.", &[(String::new(), snapshot.parse_failures)]);
+        write_metric(&mut out, "tox_decrypt_failures_total", "counter",
+            "Number of packets that can't be decrypted.", &[(String::new(), snapshot.decrypt_failures)]);
+        write_metric(&mut out, "tox_onion_announce_requests_total", "counter",
+            "Number of announce requests sent via onion paths.", &[(String::new(), snapshot.onion_announce_requests)]);
+        write_metric(&mut out, "tox_onion_announce_responses_total", "counter",
+            "Number of valid announce responses received via onion paths.", &[(String::new(), snapshot.onion_announce_responses)]);
+
+        let ktree_occupancy = snapshot.ktree_occupancy.iter()
+            .enumerate()
+            .map(|(index, &count)| (format!("bucket=\"{}\"", index), u64::from(count)))
+            .collect::<Vec<_>>();
+        write_metric(&mut out, "tox_ktree_bucket_nodes", "gauge",
+            "Number of nodes in every k-bucket of DHT close nodes list.", &ktree_occupancy);
+        write_metric(&mut out, "tox_crypto_connections", "gauge",
+            "Number of net crypto connections.", &[(String::new(), snapshot.crypto_connections)]);
+        write_metric(&mut out, "tox_tcp_relays", "gauge",
+            "Number of TCP relays we are connected to.", &[(String::new(), snapshot.tcp_relays)]);
+
+        if let Some(ref dht_server) = self.dht_server {
+            write_metric(&mut out, "tox_dht_close_nodes", "gauge",
+                "Number of nodes in DHT close nodes list.", &[(String::new(), dht_server.close_nodes_count() as u64)]);
+            write_metric(&mut out, "tox_onion_announce_entries", "gauge",
+                "Number of nodes announced to us via onion.", &[(String::new(), dht_server.onion_announce_entries_count() as u64)]);
+            write_metric(&mut out, "tox_onion_announce_capacity", "gauge",
+                "Maximum number of nodes that can be announced to us via onion.", &[(String::new(), dht_server.onion_announce_capacity() as u64)]);
+        }
+
+        if let Some(ref tcp_server) = self.tcp_server {
+            write_metric(&mut out, "tox_tcp_server_clients", "gauge",
+                "Number of clients connected to TCP relay.", &[(String::new(), tcp_server.clients_count() as u64)]);
+        }
+
+        out
+    }
+
+    /// Run HTTP server on `TcpListener` that serves metrics. Errors of
+    /// accepting connections are logged and don't stop the server.
+    pub async fn run(self, mut listener: TcpListener) -> Result<(), Error> {
+        let mut incoming = listener.incoming();
+
+        while let Some(stream) = incoming.next().await {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept metrics connection: {}", e);
+                    continue;
+                },
+            };
+            let exporter = self.clone();
+
+            tokio::spawn(async move {
+                let request = tokio::time::timeout(REQUEST_TIMEOUT, exporter.handle_connection(stream));
+                match request.await {
+                    Ok(Err(e)) => debug!("Failed to handle metrics request: {}", e),
+                    Err(_) => debug!("Metrics request timed out"),
+                    Ok(Ok(())) => { },
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Read HTTP request from the stream and write response to it.
+    async fn handle_connection(&self, mut stream: TcpStream) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 1024];
+
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            if buf.len() >= MAX_REQUEST_SIZE {
+                return write_response(&mut stream, "431 Request Header Fields Too Large", "").await;
+            }
+
+            let size = stream.read(&mut chunk).await?;
+            if size == 0 {
+                // connection was closed before we got the whole request
+                return Ok(());
+            }
+            buf.extend_from_slice(&chunk[..size]);
+        }
+
+        let request_line = buf.split(|&b| b == b'\r').next().unwrap_or_default();
+        let mut parts = request_line.split(|&b| b == b' ');
+        let method = parts.next().unwrap_or_default();
+        let path = parts.next().unwrap_or_default();
+
+        if method != b"GET" {
+            write_response(&mut stream, "405 Method Not Allowed", "").await
+        } else if path != METRICS_PATH.as_bytes() {
+            write_response(&mut stream, "404 Not Found", "").await
+        } else {
+            let body = self.render();
+            write_response(&mut stream, "200 OK", &body).await
+        }
+    }
+}
+
+/// Write HTTP response with the given status and body and close the
+/// connection.
+async fn write_response(stream: &mut TcpStream, status: &str, body: &str) -> Result<(), Error> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        CONTENT_TYPE,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    TcpStream::shutdown(stream, std::net::Shutdown::Write)
+}
+
+/// Write metric with its `HELP` and `TYPE` lines. Every sample is a pair of
+/// labels without braces and value.
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, u64)]) {
+    // writing to String can't fail
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+/// Label value of a transport.
+fn transport_label(transport: Transport) -> &'static str {
+    match transport {
+        Transport::Udp => "udp",
+        Transport::Tcp => "tcp",
+    }
+}
+
+/// Label value of a subsystem.
+fn subsystem_label(subsystem: Subsystem) -> &'static str {
+    match subsystem {
+        Subsystem::Dht => "dht",
+        Subsystem::Onion => "onion",
+        Subsystem::NetCrypto => "net_crypto",
+        Subsystem::Tcp => "tcp",
+        Subsystem::Unknown => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::channel::mpsc;
+
+    use crate::toxcore::crypto_core::*;
+    use crate::toxcore::dht::packed_node::PackedNode;
+    use crate::toxcore::onion::onion_announce::ONION_ANNOUNCE_MAX_ENTRIES;
+
+    #[test]
+    fn render_stats() {
+        let stats = Stats::new();
+        stats.counters.add_incoming_packet(Transport::Udp, 0x02, 100);
+        stats.counters.add_outgoing_packet(Transport::Tcp, 0x10, 30);
+        stats.counters.increase_decrypt_failures();
+        stats.gauges.set_ktree_occupancy(vec![0, 3]);
+        stats.gauges.set_tcp_relays(2);
+
+        let metrics = Exporter::new(stats).render();
+
+        assert!(metrics.contains("# TYPE tox_packets_total counter\n"));
+        assert!(metrics.contains("tox_packets_total{transport=\"udp\",subsystem=\"dht\",kind=\"0x02\",direction=\"in\"} 1\n"));
+        assert!(metrics.contains("tox_packets_total{transport=\"tcp\",subsystem=\"tcp\",kind=\"0x10\",direction=\"out\"} 1\n"));
+        assert!(metrics.contains("tox_bytes_total{direction=\"in\"} 100\n"));
+        assert!(metrics.contains("tox_bytes_total{direction=\"out\"} 30\n"));
+        assert!(metrics.contains("tox_decrypt_failures_total 1\n"));
+        assert!(metrics.contains("tox_ktree_bucket_nodes{bucket=\"1\"} 3\n"));
+        assert!(metrics.contains("tox_tcp_relays 2\n"));
+        // servers are not set
+        assert!(!metrics.contains("tox_dht_close_nodes"));
+        assert!(!metrics.contains("tox_tcp_server_clients"));
+    }
+
+    #[test]
+    fn render_servers() {
+        crypto_init().unwrap();
+        let (tx, _rx) = mpsc::channel(1);
+        let (pk, sk) = gen_keypair();
+        let dht_server = DhtServer::new(tx, pk, sk);
+        dht_server.add_node(PackedNode::new("127.0.0.1:33445".parse().unwrap(), &gen_keypair().0));
+
+        let mut exporter = Exporter::new(Stats::new());
+        exporter.set_dht_server(dht_server);
+        exporter.set_tcp_server(TcpServer::new());
+
+        let metrics = exporter.render();
+
+        assert!(metrics.contains("tox_dht_close_nodes 1\n"));
+        assert!(metrics.contains("tox_onion_announce_entries 0\n"));
+        assert!(metrics.contains(&format!("tox_onion_announce_capacity {}\n", ONION_ANNOUNCE_MAX_ENTRIES)));
+        assert!(metrics.contains("tox_tcp_server_clients 0\n"));
+    }
+
+    async fn scrape(addr: std::net::SocketAddr, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn run() {
+        let stats = Stats::new();
+        stats.counters.add_incoming_packet(Transport::Udp, 0x00, 61);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Exporter::new(stats).run(listener));
+
+        let response = scrape(addr, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("tox_packets_total{transport=\"udp\",subsystem=\"dht\",kind=\"0x00\",direction=\"in\"} 1\n"));
+
+        let response = scrape(addr, b"GET /other HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let response = scrape(addr, b"POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
@@ -0,0 +1,151 @@
+/*! Graceful shutdown of long-running modules.
+
+All `run` futures of tox modules are endless loops. Dropping them stops the
+module immediately so peers find out about it only after timeouts and the
+state collected during the run is lost. Every module also has a `run_until`
+method that takes a [`ShutdownSignal`]. When the signal is triggered via
+[`Shutdown`] handle the module stops its timers, notifies peers when it's
+possible and returns the state that should be saved to restore it after
+restart:
+
+- `dht::server::Server` returns close nodes that can be used for
+  bootstrapping.
+- `net_crypto::NetCrypto` waits until queued lossless packets are delivered
+  (up to `SHUTDOWN_DRAIN_TIMEOUT`) and sends kill packets to all connections.
+- `onion::client::OnionClient` returns nodes used to build onion paths.
+- `tcp::client::Connections` sends `DisconnectNotification` packets and
+  returns relays it was connected to.
+- `tcp::server::ServerExt` stops accepting connections and disconnects
+  clients notifying their linked clients.
+- `friend_connection::FriendConnections` reports all connected friends as
+  disconnected.
+
+[`Shutdown`]: ./struct.Shutdown.html
+[`ShutdownSignal`]: ./struct.ShutdownSignal.html
+*/
+
+use std::sync::Arc;
+
+use futures::{future, Future, FutureExt};
+use tokio::sync::watch;
+
+/// Handle to trigger graceful shutdown of all modules that were given its
+/// signals.
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+    rx: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+impl Shutdown {
+    /// Create new `Shutdown` handle.
+    pub fn new() -> Shutdown {
+        let (tx, rx) = watch::channel(false);
+        Shutdown {
+            tx: Arc::new(tx),
+            rx,
+        }
+    }
+
+    /// Trigger shutdown. All signals obtained from this handle are resolved.
+    pub fn shutdown(&self) {
+        // it can fail only when there are no receivers but we hold one
+        self.tx.broadcast(true).ok();
+    }
+
+    /// Check if shutdown was triggered.
+    pub fn is_shutdown(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Get a signal that is resolved when shutdown is triggered.
+    pub fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            rx: self.rx.clone(),
+        }
+    }
+}
+
+/// Signal that is resolved when shutdown is triggered by `Shutdown` handle.
+/// If all handles are dropped without triggering shutdown it's never resolved.
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    rx: watch::Receiver<bool>,
+}
+
+impl ShutdownSignal {
+    /// Wait until shutdown is triggered.
+    pub async fn wait(mut self) {
+        while !*self.rx.borrow() {
+            if self.rx.recv().await.is_none() {
+                future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Run `future` until it's completed or shutdown is triggered. Returns
+    /// `Ok(())` in the latter case.
+    pub async fn run<F, E>(self, future: F) -> Result<(), E>
+        where F: Future<Output = Result<(), E>>
+    {
+        futures::select! {
+            res = future.fuse() => res,
+            () = self.wait().fuse() => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn signal_is_resolved() {
+        let shutdown = Shutdown::new();
+        let signal = shutdown.signal();
+        assert!(!shutdown.is_shutdown());
+
+        shutdown.shutdown();
+
+        assert!(shutdown.is_shutdown());
+        signal.wait().await;
+        // signals obtained after shutdown are resolved too
+        shutdown.signal().wait().await;
+    }
+
+    #[tokio::test]
+    async fn signal_stops_future() {
+        let shutdown = Shutdown::new();
+        let signal = shutdown.signal();
+
+        let endless = future::pending::<Result<(), ()>>();
+        let handle = tokio::spawn(signal.run(endless));
+        shutdown.shutdown();
+
+        assert_eq!(handle.await.unwrap(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn signal_returns_error() {
+        let shutdown = Shutdown::new();
+        let res = shutdown.signal().run(future::err::<(), _>(42)).await;
+        assert_eq!(res, Err(42));
+    }
+
+    #[tokio::test]
+    async fn signal_without_handles_is_pending() {
+        tokio::time::pause();
+        let signal = Shutdown::new().signal();
+
+        let res = tokio::time::timeout(Duration::from_secs(1), signal.wait()).await;
+        assert!(res.is_err());
+    }
+}
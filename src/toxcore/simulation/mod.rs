@@ -0,0 +1,939 @@
+/*! In-memory simulated network for multi-node testing.
+
+[`SimNetwork`] replaces UDP sockets of DHT servers and TCP connections to
+relays with in-memory channels so that dozens of complete nodes can talk to
+each other in a single test. Every link between two hosts can have latency and
+packet loss, hosts can be put behind NATs of different kinds and the network
+can be partitioned. Packet loss is driven by a seeded PRNG and latency by
+tokio timers under tokio's paused clock.
+
+Only the network itself is deterministic, runs of the nodes are not. Keys,
+nonces and ping ids are generated by sodium's RNG, which can't be seeded, and
+nodes iterate over hash maps with random order. So the exact sequence of
+packets differs from run to run and scenarios should check eventual outcomes
+only.
+
+Stream connections used by TCP relays are established only if the listener is
+reachable. Establishing a connection takes the latency of the link and data
+sent over an established connection is delayed by the latency of the link
+too. Data is never lost since it's a reliable stream: every time the PRNG
+decides that a chunk of data is lost the chunk is delayed by
+`STREAM_RETRANSMISSION_TIMEOUT` instead and the connection is reset if the
+chunk is lost more than `STREAM_MAX_RETRANSMISSIONS` times in a row. Writing
+to a connection between partitioned hosts fails as if it was reset.
+
+[`SimNetwork`]: ./struct.SimNetwork.html
+*/
+
+pub mod node;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use failure::Fail;
+use futures::{ready, Future, FutureExt, StreamExt};
+use futures::channel::mpsc;
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::Instant;
+
+use crate::toxcore::dht::packet::Packet;
+use crate::toxcore::dht::server::Server;
+use crate::toxcore::transport::{ConnectFuture, Connector, Listener, StreamTransport};
+
+/// First port that NATs use for mappings.
+const NAT_FIRST_PORT: u16 = 40000;
+
+/// Delay of a chunk of stream data that was lost and has to be retransmitted.
+pub const STREAM_RETRANSMISSION_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Maximum number of times a chunk of stream data can be retransmitted before
+/// the connection is reset.
+pub const STREAM_MAX_RETRANSMISSIONS: usize = 5;
+
+/// Properties of a link between two hosts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkConfig {
+    /// Time it takes for a packet to reach the destination.
+    pub latency: Duration,
+    /// Probability of a packet to be lost from `0.0` to `1.0`.
+    pub loss: f64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        LinkConfig {
+            latency: Duration::from_millis(10),
+            loss: 0.0,
+        }
+    }
+}
+
+/// Behaviour of a simulated NAT.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NatKind {
+    /// The same external port is used for all destinations and anyone can
+    /// send packets to it.
+    FullCone,
+    /// The same external port is used for all destinations and only hosts
+    /// that we sent packets to can send packets back from any port.
+    RestrictedCone,
+    /// The same external port is used for all destinations and only
+    /// addresses that we sent packets to can send packets back.
+    PortRestrictedCone,
+    /// A new external port is allocated sequentially for every destination and
+    /// only this destination can send packets back.
+    Symmetric,
+}
+
+/// External port of a NAT.
+#[derive(Clone, Debug)]
+struct NatPort {
+    /// Address of the host behind NAT this port is forwarded to.
+    private_addr: SocketAddr,
+    /// Addresses the host sent packets to through this port.
+    remotes: HashSet<SocketAddr>,
+}
+
+/// Simulated NAT.
+#[derive(Clone, Debug)]
+struct Nat {
+    /// Kind of NAT.
+    kind: NatKind,
+    /// External IP address of NAT.
+    public_ip: IpAddr,
+    /// Next external port to allocate.
+    next_port: u16,
+    /// Mappings from private address and destination (for symmetric NAT) to
+    /// external port.
+    mappings: HashMap<(SocketAddr, Option<SocketAddr>), u16>,
+    /// External ports.
+    ports: HashMap<u16, NatPort>,
+}
+
+impl Nat {
+    fn new(kind: NatKind, public_ip: IpAddr) -> Nat {
+        Nat {
+            kind,
+            public_ip,
+            next_port: NAT_FIRST_PORT,
+            mappings: HashMap::new(),
+            ports: HashMap::new(),
+        }
+    }
+
+    /// Find the next external port that is not used by any mapping. Returns
+    /// `None` if all ports are used.
+    fn allocate_port(&mut self) -> Option<u16> {
+        for _ in NAT_FIRST_PORT ..= u16::MAX {
+            let port = self.next_port;
+            self.next_port = if port == u16::MAX { NAT_FIRST_PORT } else { port + 1 };
+            if !self.ports.contains_key(&port) {
+                return Some(port);
+            }
+        }
+
+        None
+    }
+
+    /// Translate source address of an outgoing packet. Returns `None` if there
+    /// are no free external ports left.
+    fn outbound(&mut self, private_addr: SocketAddr, remote: SocketAddr) -> Option<SocketAddr> {
+        let key = if self.kind == NatKind::Symmetric {
+            (private_addr, Some(remote))
+        } else {
+            (private_addr, None)
+        };
+
+        let port = match self.mappings.get(&key) {
+            Some(&port) => port,
+            None => {
+                let port = self.allocate_port()?;
+                self.mappings.insert(key, port);
+                self.ports.insert(port, NatPort {
+                    private_addr,
+                    remotes: HashSet::new(),
+                });
+                port
+            },
+        };
+
+        if let Some(nat_port) = self.ports.get_mut(&port) {
+            nat_port.remotes.insert(remote);
+        }
+
+        Some(SocketAddr::new(self.public_ip, port))
+    }
+
+    /// Translate destination address of an incoming packet. Returns `None` if
+    /// the packet is filtered out.
+    fn inbound(&self, port: u16, remote: SocketAddr) -> Option<SocketAddr> {
+        let nat_port = self.ports.get(&port)?;
+        let allowed = match self.kind {
+            NatKind::FullCone => true,
+            NatKind::RestrictedCone => nat_port.remotes.iter().any(|addr| addr.ip() == remote.ip()),
+            NatKind::PortRestrictedCone | NatKind::Symmetric => nat_port.remotes.contains(&remote),
+        };
+
+        if allowed {
+            Some(nat_port.private_addr)
+        } else {
+            None
+        }
+    }
+}
+
+/// Small deterministic PRNG (xorshift64*) for packet loss.
+#[derive(Clone, Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // zero state is a fixed point of xorshift
+        Rng(seed | 1)
+    }
+
+    /// Random number from `[0, 1)` interval.
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let n = self.0.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (n >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Counters of packets passed through the network.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SimStats {
+    /// Number of packets delivered to nodes.
+    pub delivered: u64,
+    /// Number of packets dropped because of loss, NAT filtering, partitions
+    /// or unknown destination.
+    pub dropped: u64,
+}
+
+/// State of the network.
+#[derive(Debug)]
+struct SimNetworkState {
+    /// Channels to deliver packets to nodes.
+    nodes: HashMap<SocketAddr, mpsc::UnboundedSender<(Packet, SocketAddr)>>,
+    /// Channels to deliver incoming stream connections to listeners.
+    listeners: HashMap<SocketAddr, mpsc::UnboundedSender<SimStream>>,
+    /// External IP of NAT for hosts behind NAT.
+    hosts_behind_nat: HashMap<IpAddr, IpAddr>,
+    /// NATs by their external IP.
+    nats: HashMap<IpAddr, Nat>,
+    /// Properties of links that are not configured explicitly.
+    default_link: LinkConfig,
+    /// Properties of links from one host to another.
+    links: HashMap<(IpAddr, IpAddr), LinkConfig>,
+    /// Pairs of hosts that can't reach each other.
+    partitions: HashSet<(IpAddr, IpAddr)>,
+    /// PRNG for packet loss.
+    rng: Rng,
+    /// Counters of packets.
+    stats: SimStats,
+}
+
+impl SimNetworkState {
+    /// Find out where a packet sent from `from` to `to` should be delivered
+    /// and how it should look there. Returns destination node address, source
+    /// address as seen by the destination and latency.
+    fn route(&mut self, from: SocketAddr, to: SocketAddr) -> Option<(SocketAddr, SocketAddr, Duration)> {
+        let (dst, src, link) = self.translate(from, to)?;
+
+        if link.loss > 0.0 && self.rng.next_f64() < link.loss {
+            return None;
+        }
+
+        Some((dst, src, link.latency))
+    }
+
+    /// Translate addresses of a packet or a stream connection from `from` to
+    /// `to` without simulating loss. Returns destination address, source
+    /// address as seen by the destination and properties of the link.
+    fn translate(&mut self, from: SocketAddr, to: SocketAddr) -> Option<(SocketAddr, SocketAddr, LinkConfig)> {
+        let from_nat = self.hosts_behind_nat.get(&from.ip()).cloned();
+
+        let (src, dst) = if let Some(to_nat) = self.hosts_behind_nat.get(&to.ip()).cloned() {
+            // private addresses are reachable only from behind the same NAT
+            if from_nat != Some(to_nat) {
+                return None;
+            }
+            (from, to)
+        } else {
+            let src = match from_nat {
+                Some(nat_ip) => self.nats.get_mut(&nat_ip)?.outbound(from, to)?,
+                None => from,
+            };
+            let dst = match self.nats.get(&to.ip()) {
+                Some(nat) => nat.inbound(to.port(), src)?,
+                None => to,
+            };
+            (src, dst)
+        };
+
+        if self.partitions.contains(&(from.ip(), dst.ip())) {
+            return None;
+        }
+
+        let link = self.link(from.ip(), dst.ip());
+
+        Some((dst, src, link))
+    }
+
+    /// Get properties of the link from one host to another.
+    fn link(&self, from: IpAddr, to: IpAddr) -> LinkConfig {
+        self.links.get(&(from, to)).cloned().unwrap_or(self.default_link)
+    }
+
+    /// Calculate how long a chunk of stream data sent from one host to another
+    /// takes to reach the destination including retransmissions. Returns
+    /// `None` if the chunk is lost more than `STREAM_MAX_RETRANSMISSIONS`
+    /// times.
+    fn stream_delay(&mut self, from: IpAddr, to: IpAddr) -> Option<Duration> {
+        let link = self.link(from, to);
+        let mut delay = link.latency;
+        for _ in 0 ..= STREAM_MAX_RETRANSMISSIONS {
+            if link.loss <= 0.0 || self.rng.next_f64() >= link.loss {
+                return Some(delay);
+            }
+            delay += STREAM_RETRANSMISSION_TIMEOUT;
+        }
+
+        None
+    }
+}
+
+/// One end of an in-memory stream connection. It's an analogue of
+/// `TcpStream` for the simulated network.
+#[derive(Debug)]
+pub struct SimStream {
+    /// Network the connection belongs to.
+    network: SimNetwork,
+    /// IP address of the host this end belongs to.
+    local_ip: IpAddr,
+    /// IP address of the host the other end belongs to.
+    remote_ip: IpAddr,
+    /// Address of the other end as seen by this end.
+    peer_addr: SocketAddr,
+    /// Sender of data chunks to the other end with time they should be
+    /// delivered at.
+    tx: mpsc::UnboundedSender<(Instant, Vec<u8>)>,
+    /// Receiver of data chunks from the other end.
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// Received chunk that was not read completely.
+    chunk: Vec<u8>,
+    /// Position of unread data in `chunk`.
+    chunk_pos: usize,
+}
+
+impl SimStream {
+    /// Create connected pair of streams. `a` and `b` contain IP address of the
+    /// host each end belongs to and address of the other end as seen by it.
+    fn pair(network: &SimNetwork, a: (IpAddr, SocketAddr), b: (IpAddr, SocketAddr)) -> (SimStream, SimStream) {
+        let (a_tx, b_rx) = mpsc::unbounded();
+        let (b_tx, a_rx) = mpsc::unbounded();
+        let a_rx = SimStream::deliver(a_rx);
+        let b_rx = SimStream::deliver(b_rx);
+        let stream = |local_ip, remote_ip, peer_addr, tx, rx| SimStream {
+            network: network.clone(),
+            local_ip,
+            remote_ip,
+            peer_addr,
+            tx,
+            rx,
+            chunk: Vec::new(),
+            chunk_pos: 0,
+        };
+        (
+            stream(a.0, b.0, a.1, a_tx, a_rx),
+            stream(b.0, a.0, b.1, b_tx, b_rx),
+        )
+    }
+
+    /// Spawn a task that delivers data chunks in order they were sent, each
+    /// not earlier than its delivery time.
+    fn deliver(mut rx: mpsc::UnboundedReceiver<(Instant, Vec<u8>)>) -> mpsc::UnboundedReceiver<Vec<u8>> {
+        let (tx, delivered_rx) = mpsc::unbounded();
+        tokio::spawn(async move {
+            while let Some((deliver_at, chunk)) = rx.next().await {
+                tokio::time::delay_until(deliver_at).await;
+                if tx.unbounded_send(chunk).is_err() {
+                    break;
+                }
+            }
+        });
+        delivered_rx
+    }
+}
+
+impl AsyncRead for SimStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        while this.chunk_pos == this.chunk.len() {
+            match ready!(this.rx.poll_next_unpin(cx)) {
+                Some(chunk) => {
+                    this.chunk = chunk;
+                    this.chunk_pos = 0;
+                },
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+
+        let len = buf.len().min(this.chunk.len() - this.chunk_pos);
+        buf[.. len].copy_from_slice(&this.chunk[this.chunk_pos .. this.chunk_pos + len]);
+        this.chunk_pos += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for SimStream {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        let delay = {
+            let mut state = this.network.state.lock();
+            if state.partitions.contains(&(this.local_ip, this.remote_ip)) {
+                this.tx.close_channel();
+                return Poll::Ready(Err(Error::new(ErrorKind::ConnectionReset, "Hosts are partitioned")));
+            }
+            state.stream_delay(this.local_ip, this.remote_ip)
+        };
+
+        let delay = match delay {
+            Some(delay) => delay,
+            None => {
+                this.tx.close_channel();
+                return Poll::Ready(Err(Error::new(ErrorKind::ConnectionReset, "Too many retransmissions")));
+            },
+        };
+
+        match this.tx.unbounded_send((Instant::now() + delay, buf.to_vec())) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(Error::new(ErrorKind::BrokenPipe, "Connection is closed"))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), Error>> {
+        self.get_mut().tx.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl StreamTransport for SimStream {
+    fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.peer_addr)
+    }
+}
+
+/// Source of incoming stream connections on the simulated network. It's an
+/// analogue of `TcpListener`.
+#[derive(Debug)]
+pub struct SimListener {
+    /// Receiver of incoming connections.
+    rx: mpsc::UnboundedReceiver<SimStream>,
+}
+
+impl Listener for SimListener {
+    type Stream = SimStream;
+
+    fn poll_accept(&mut self, cx: &mut Context) -> Poll<Result<SimStream, Error>> {
+        match ready!(self.rx.poll_next_unpin(cx)) {
+            Some(stream) => Poll::Ready(Ok(stream)),
+            None => Poll::Ready(Err(Error::new(ErrorKind::NotConnected, "Listener is closed"))),
+        }
+    }
+}
+
+/// `Connector` that establishes stream connections from a host of the
+/// simulated network.
+#[derive(Clone, Debug)]
+pub struct SimConnector {
+    /// Network to establish connections in.
+    network: SimNetwork,
+    /// Address connections are established from.
+    addr: SocketAddr,
+}
+
+impl Connector for SimConnector {
+    fn connect(&self, addr: SocketAddr) -> ConnectFuture {
+        let network = self.network.clone();
+        let from = self.addr;
+        async move {
+            let stream = network.connect(from, addr).await?;
+            Ok(Box::new(stream) as Box<dyn StreamTransport>)
+        }.boxed()
+    }
+}
+
+/// In-memory network that connects simulated nodes.
+#[derive(Clone, Debug)]
+pub struct SimNetwork {
+    /// State of the network.
+    state: Arc<Mutex<SimNetworkState>>,
+}
+
+impl SimNetwork {
+    /// Create new `SimNetwork`. `seed` is used to simulate packet loss.
+    pub fn new(seed: u64) -> SimNetwork {
+        SimNetwork {
+            state: Arc::new(Mutex::new(SimNetworkState {
+                nodes: HashMap::new(),
+                listeners: HashMap::new(),
+                hosts_behind_nat: HashMap::new(),
+                nats: HashMap::new(),
+                default_link: LinkConfig::default(),
+                links: HashMap::new(),
+                partitions: HashSet::new(),
+                rng: Rng::new(seed),
+                stats: SimStats::default(),
+            })),
+        }
+    }
+
+    /// Set properties of links that are not configured explicitly.
+    pub fn set_default_link(&self, link: LinkConfig) {
+        self.state.lock().default_link = link;
+    }
+
+    /// Set properties of the link between two hosts in both directions.
+    pub fn set_link(&self, a: IpAddr, b: IpAddr, link: LinkConfig) {
+        let mut state = self.state.lock();
+        state.links.insert((a, b), link);
+        state.links.insert((b, a), link);
+    }
+
+    /// Add NAT with external IP address `public_ip`.
+    pub fn add_nat(&self, public_ip: IpAddr, kind: NatKind) {
+        self.state.lock().nats.insert(public_ip, Nat::new(kind, public_ip));
+    }
+
+    /// Put host with IP address `private_ip` behind NAT with external IP
+    /// address `public_ip`.
+    pub fn put_behind_nat(&self, private_ip: IpAddr, public_ip: IpAddr) {
+        self.state.lock().hosts_behind_nat.insert(private_ip, public_ip);
+    }
+
+    /// Split network so that hosts from `a` can't reach hosts from `b` and vice
+    /// versa.
+    pub fn partition(&self, a: &[IpAddr], b: &[IpAddr]) {
+        let mut state = self.state.lock();
+        for &a in a {
+            for &b in b {
+                state.partitions.insert((a, b));
+                state.partitions.insert((b, a));
+            }
+        }
+    }
+
+    /// Remove all partitions.
+    pub fn heal(&self) {
+        self.state.lock().partitions.clear();
+    }
+
+    /// Get counters of packets passed through the network.
+    pub fn stats(&self) -> SimStats {
+        self.state.lock().stats
+    }
+
+    /// Send packet from node with address `from` to `to`.
+    fn send(&self, packet: Packet, from: SocketAddr, to: SocketAddr) {
+        let mut state = self.state.lock();
+        let route = state.route(from, to)
+            .and_then(|(dst, src, latency)| state.nodes.get(&dst).map(|tx| (tx.clone(), src, latency)));
+
+        let (tx, src, latency) = match route {
+            Some(route) => route,
+            None => {
+                trace!("Dropping packet from {} to {}", from, to);
+                state.stats.dropped += 1;
+                return;
+            },
+        };
+        state.stats.delivered += 1;
+        drop(state);
+
+        if latency == Duration::from_secs(0) {
+            tx.unbounded_send((packet, src)).ok();
+        } else {
+            tokio::spawn(async move {
+                tokio::time::delay_for(latency).await;
+                tx.unbounded_send((packet, src)).ok();
+            });
+        }
+    }
+
+    /// Start accepting stream connections on address `addr`. It's an analogue
+    /// of `TcpListener::bind`.
+    pub fn listen(&self, addr: SocketAddr) -> SimListener {
+        let (tx, rx) = mpsc::unbounded();
+        self.state.lock().listeners.insert(addr, tx);
+        SimListener { rx }
+    }
+
+    /// Get `Connector` that establishes stream connections from address
+    /// `addr`. It can be passed to `tcp::client::Connections::set_connector`.
+    pub fn connector(&self, addr: SocketAddr) -> SimConnector {
+        SimConnector {
+            network: self.clone(),
+            addr,
+        }
+    }
+
+    /// Establish stream connection from address `from` to listener with
+    /// address `to`.
+    async fn connect(&self, from: SocketAddr, to: SocketAddr) -> Result<SimStream, Error> {
+        let route = {
+            let mut state = self.state.lock();
+            state.translate(from, to)
+                .and_then(|(dst, src, link)| state.listeners.get(&dst).map(|tx| (tx.clone(), dst, src, link.latency)))
+        };
+
+        let (listener_tx, dst, src, latency) = match route {
+            Some(route) => route,
+            None => {
+                trace!("Refusing stream connection from {} to {}", from, to);
+                return Err(Error::new(ErrorKind::ConnectionRefused, "Listener is unreachable"));
+            },
+        };
+
+        if latency > Duration::from_secs(0) {
+            tokio::time::delay_for(latency).await;
+        }
+
+        let (client, server) = SimStream::pair(self, (from.ip(), to), (dst.ip(), src));
+        listener_tx.unbounded_send(server)
+            .map_err(|_| Error::new(ErrorKind::ConnectionRefused, "Listener is closed"))?;
+
+        Ok(client)
+    }
+
+    /// Run DHT server on the simulated network with address `addr`. `rx` is
+    /// the receiving end of the channel passed to `Server::new`. It's an
+    /// analogue of `ServerExt::run_socket`.
+    pub fn run_node(&self, server: Server, mut rx: mpsc::Receiver<(Packet, SocketAddr)>, addr: SocketAddr)
+        -> impl Future<Output = Result<(), Error>> + Send {
+        let (incoming_tx, mut incoming_rx) = mpsc::unbounded();
+        self.state.lock().nodes.insert(addr, incoming_tx);
+
+        let network = self.clone();
+        let network_writer = async move {
+            while let Some((packet, to)) = rx.next().await {
+                network.send(packet, addr, to);
+            }
+
+            Ok(())
+        };
+
+        let server_c = server.clone();
+        let network_reader = async move {
+            while let Some((packet, from)) = incoming_rx.next().await {
+                if let Err(e) = server_c.handle_packet(packet, from).await {
+                    debug!("Node {} failed to handle packet from {}: {}", addr, from, e);
+                }
+            }
+
+            Ok(())
+        };
+
+        async move {
+            futures::select! {
+                read = network_reader.fuse() => read,
+                write = network_writer.fuse() => write,
+                run = server.run().fuse() => {
+                    let res: Result<_, _> = run;
+                    res.map_err(|e| Error::new(ErrorKind::Other, e.compat()))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn network_with_nat(kind: NatKind) -> SimNetwork {
+        let network = SimNetwork::new(1);
+        network.add_nat("2.0.0.1".parse().unwrap(), kind);
+        network.put_behind_nat("10.0.0.1".parse().unwrap(), "2.0.0.1".parse().unwrap());
+        network.put_behind_nat("10.0.0.2".parse().unwrap(), "2.0.0.1".parse().unwrap());
+        network
+    }
+
+    #[test]
+    fn route_public() {
+        let network = SimNetwork::new(1);
+        let mut state = network.state.lock();
+        assert_eq!(
+            state.route(addr("1.0.0.1:33445"), addr("1.0.0.2:33445")),
+            Some((addr("1.0.0.2:33445"), addr("1.0.0.1:33445"), LinkConfig::default().latency))
+        );
+    }
+
+    #[test]
+    fn route_port_restricted_cone_nat() {
+        let network = network_with_nat(NatKind::PortRestrictedCone);
+        let mut state = network.state.lock();
+
+        // not mapped yet
+        assert!(state.route(addr("1.0.0.1:33445"), addr("2.0.0.1:40000")).is_none());
+
+        let (_, src, _) = state.route(addr("10.0.0.1:33445"), addr("1.0.0.1:33445")).unwrap();
+        assert_eq!(src, addr("2.0.0.1:40000"));
+        // the same mapping is used for other destinations
+        let (_, src, _) = state.route(addr("10.0.0.1:33445"), addr("1.0.0.2:33445")).unwrap();
+        assert_eq!(src, addr("2.0.0.1:40000"));
+
+        let (dst, _, _) = state.route(addr("1.0.0.1:33445"), addr("2.0.0.1:40000")).unwrap();
+        assert_eq!(dst, addr("10.0.0.1:33445"));
+        // other port of the same host is filtered
+        assert!(state.route(addr("1.0.0.1:33446"), addr("2.0.0.1:40000")).is_none());
+        // unknown host is filtered
+        assert!(state.route(addr("1.0.0.3:33445"), addr("2.0.0.1:40000")).is_none());
+    }
+
+    #[test]
+    fn route_restricted_cone_nat() {
+        let network = network_with_nat(NatKind::RestrictedCone);
+        let mut state = network.state.lock();
+
+        state.route(addr("10.0.0.1:33445"), addr("1.0.0.1:33445")).unwrap();
+        let (dst, _, _) = state.route(addr("1.0.0.1:33446"), addr("2.0.0.1:40000")).unwrap();
+        assert_eq!(dst, addr("10.0.0.1:33445"));
+        assert!(state.route(addr("1.0.0.3:33445"), addr("2.0.0.1:40000")).is_none());
+    }
+
+    #[test]
+    fn route_full_cone_nat() {
+        let network = network_with_nat(NatKind::FullCone);
+        let mut state = network.state.lock();
+
+        state.route(addr("10.0.0.1:33445"), addr("1.0.0.1:33445")).unwrap();
+        let (dst, _, _) = state.route(addr("1.0.0.3:33445"), addr("2.0.0.1:40000")).unwrap();
+        assert_eq!(dst, addr("10.0.0.1:33445"));
+    }
+
+    #[test]
+    fn route_symmetric_nat() {
+        let network = network_with_nat(NatKind::Symmetric);
+        let mut state = network.state.lock();
+
+        let (_, src_1, _) = state.route(addr("10.0.0.1:33445"), addr("1.0.0.1:33445")).unwrap();
+        let (_, src_2, _) = state.route(addr("10.0.0.1:33445"), addr("1.0.0.2:33445")).unwrap();
+        assert_eq!(src_1, addr("2.0.0.1:40000"));
+        assert_eq!(src_2, addr("2.0.0.1:40001"));
+
+        assert!(state.route(addr("1.0.0.2:33445"), addr("2.0.0.1:40000")).is_none());
+        let (dst, _, _) = state.route(addr("1.0.0.2:33445"), addr("2.0.0.1:40001")).unwrap();
+        assert_eq!(dst, addr("10.0.0.1:33445"));
+    }
+
+    #[test]
+    fn nat_skips_used_ports() {
+        let mut nat = Nat::new(NatKind::Symmetric, "2.0.0.1".parse().unwrap());
+
+        assert_eq!(nat.outbound(addr("10.0.0.1:33445"), addr("1.0.0.1:33445")), Some(addr("2.0.0.1:40000")));
+        nat.next_port = u16::MAX;
+        assert_eq!(nat.outbound(addr("10.0.0.1:33445"), addr("1.0.0.2:33445")), Some(addr("2.0.0.1:65535")));
+        // port 40000 is still used by the first mapping
+        assert_eq!(nat.outbound(addr("10.0.0.1:33445"), addr("1.0.0.3:33445")), Some(addr("2.0.0.1:40001")));
+        // existing mapping is kept
+        assert_eq!(nat.outbound(addr("10.0.0.1:33445"), addr("1.0.0.1:33445")), Some(addr("2.0.0.1:40000")));
+    }
+
+    #[test]
+    fn nat_ports_exhausted() {
+        let mut nat = Nat::new(NatKind::Symmetric, "2.0.0.1".parse().unwrap());
+        for port in NAT_FIRST_PORT ..= u16::MAX {
+            let remote = SocketAddr::new("1.0.0.1".parse().unwrap(), port);
+            assert_eq!(nat.outbound(addr("10.0.0.1:33445"), remote), Some(SocketAddr::new(nat.public_ip, port)));
+        }
+
+        assert!(nat.outbound(addr("10.0.0.1:33445"), addr("1.0.0.2:33445")).is_none());
+    }
+
+    #[test]
+    fn route_private() {
+        let network = network_with_nat(NatKind::PortRestrictedCone);
+        let mut state = network.state.lock();
+
+        // hosts behind the same NAT can reach each other directly
+        let (dst, src, _) = state.route(addr("10.0.0.1:33445"), addr("10.0.0.2:33445")).unwrap();
+        assert_eq!(dst, addr("10.0.0.2:33445"));
+        assert_eq!(src, addr("10.0.0.1:33445"));
+        // but private addresses are unreachable from outside
+        assert!(state.route(addr("1.0.0.1:33445"), addr("10.0.0.2:33445")).is_none());
+    }
+
+    #[test]
+    fn route_partition_and_loss() {
+        let network = SimNetwork::new(1);
+        let a = "1.0.0.1".parse().unwrap();
+        let b = "1.0.0.2".parse().unwrap();
+
+        network.partition(&[a], &[b]);
+        assert!(network.state.lock().route(addr("1.0.0.1:33445"), addr("1.0.0.2:33445")).is_none());
+        assert!(network.state.lock().route(addr("1.0.0.2:33445"), addr("1.0.0.1:33445")).is_none());
+
+        network.heal();
+        network.set_link(a, b, LinkConfig { latency: Duration::from_millis(50), loss: 0.5 });
+        let delivered = (0 .. 1000)
+            .filter(|_| network.state.lock().route(addr("1.0.0.1:33445"), addr("1.0.0.2:33445")).is_some())
+            .count();
+        assert!(delivered > 400 && delivered < 600);
+    }
+
+    #[tokio::test]
+    async fn connect_stream() {
+        use futures::SinkExt;
+        use tokio_util::codec::{BytesCodec, Framed};
+
+        let network = network_with_nat(NatKind::Symmetric);
+        let mut listener = network.listen(addr("1.0.0.1:33445"));
+
+        let client = network.connector(addr("10.0.0.1:33445"))
+            .connect(addr("1.0.0.1:33445")).await.unwrap();
+        let server = futures::future::poll_fn(|cx| listener.poll_accept(cx)).await.unwrap();
+        assert_eq!(client.peer_addr().unwrap(), addr("1.0.0.1:33445"));
+        assert_eq!(server.peer_addr().unwrap(), addr("2.0.0.1:40000"));
+
+        let mut client = Framed::new(client, BytesCodec::new());
+        let mut server = Framed::new(server, BytesCodec::new());
+
+        client.send(bytes::Bytes::from_static(b"hello")).await.unwrap();
+        let data = server.next().await.unwrap().unwrap();
+        assert_eq!(&data[..], b"hello");
+
+        server.close().await.unwrap();
+        assert!(client.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn connect_stream_unreachable() {
+        let network = network_with_nat(NatKind::FullCone);
+        let _listener = network.listen(addr("10.0.0.2:33445"));
+
+        // private listener is unreachable from outside
+        let error = network.connect(addr("1.0.0.1:33445"), addr("10.0.0.2:33445")).await.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+        // and there is no listener at all
+        let error = network.connect(addr("10.0.0.1:33445"), addr("1.0.0.2:33445")).await.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn stream_partitioned() {
+        use futures::SinkExt;
+        use tokio_util::codec::{BytesCodec, Framed};
+
+        let network = SimNetwork::new(1);
+        let _listener = network.listen(addr("1.0.0.2:33445"));
+        let client = network.connect(addr("1.0.0.1:33445"), addr("1.0.0.2:33445")).await.unwrap();
+        let mut client = Framed::new(client, BytesCodec::new());
+
+        network.partition(&["1.0.0.1".parse().unwrap()], &["1.0.0.2".parse().unwrap()]);
+        let error = client.send(bytes::Bytes::from_static(b"hello")).await.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+
+        let error = network.connect(addr("1.0.0.1:33445"), addr("1.0.0.2:33445")).await.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn stream_delay() {
+        let network = SimNetwork::new(1);
+        let a = "1.0.0.1".parse().unwrap();
+        let b = "1.0.0.2".parse().unwrap();
+        let latency = Duration::from_millis(50);
+        let mut state = network.state.lock();
+
+        state.links.insert((a, b), LinkConfig { latency, loss: 0.0 });
+        assert_eq!(state.stream_delay(a, b), Some(latency));
+
+        state.links.insert((a, b), LinkConfig { latency, loss: 0.5 });
+        let delays = (0 .. 1000).map(|_| state.stream_delay(a, b)).collect::<Vec<_>>();
+        assert!(delays.iter().flatten().all(|&delay|
+            (0 ..= STREAM_MAX_RETRANSMISSIONS as u32).any(|n| delay == latency + STREAM_RETRANSMISSION_TIMEOUT * n)
+        ));
+        assert!(delays.contains(&Some(latency)));
+        assert!(delays.iter().any(|&delay| delay > Some(latency)));
+
+        state.links.insert((a, b), LinkConfig { latency, loss: 1.0 });
+        assert!(state.stream_delay(a, b).is_none());
+    }
+
+    #[tokio::test]
+    async fn stream_latency_and_loss() {
+        use futures::SinkExt;
+        use tokio_util::codec::{BytesCodec, Framed};
+
+        tokio::time::pause();
+
+        let network = SimNetwork::new(1);
+        let latency = Duration::from_millis(50);
+        network.set_link("1.0.0.1".parse().unwrap(), "1.0.0.2".parse().unwrap(), LinkConfig { latency, loss: 0.3 });
+        let mut listener = network.listen(addr("1.0.0.2:33445"));
+
+        let network_c = network.clone();
+        let client = tokio::spawn(async move {
+            network_c.connect(addr("1.0.0.1:33445"), addr("1.0.0.2:33445")).await
+        });
+        tokio::time::advance(latency).await;
+        let client = client.await.unwrap().unwrap();
+        let server = futures::future::poll_fn(|cx| listener.poll_accept(cx)).await.unwrap();
+
+        let start = Instant::now();
+        let reader = tokio::spawn(async move {
+            let mut server = Framed::new(server, BytesCodec::new());
+            let mut data = Vec::new();
+            let mut first_chunk_time = None;
+            while data.len() < 100 {
+                let chunk = server.next().await.unwrap().unwrap();
+                first_chunk_time.get_or_insert_with(Instant::now);
+                data.extend_from_slice(&chunk);
+            }
+            (data, first_chunk_time.unwrap(), Instant::now())
+        });
+
+        let mut client = Framed::new(client, BytesCodec::new());
+        for i in 0 .. 100 {
+            client.send(bytes::Bytes::from(vec![i])).await.unwrap();
+        }
+
+        for _ in 0 .. 100 {
+            tokio::time::advance(STREAM_RETRANSMISSION_TIMEOUT / 10).await;
+        }
+
+        let (data, first_chunk_time, last_chunk_time) = reader.await.unwrap();
+        // data is delivered in order despite retransmissions
+        assert_eq!(data, (0 .. 100).collect::<Vec<u8>>());
+        assert!(first_chunk_time >= start + latency);
+        assert!(last_chunk_time >= start + latency + STREAM_RETRANSMISSION_TIMEOUT);
+    }
+
+    #[test]
+    fn rng_is_deterministic() {
+        let mut rng_1 = Rng::new(42);
+        let mut rng_2 = Rng::new(42);
+        for _ in 0 .. 100 {
+            let n = rng_1.next_f64();
+            assert!((0.0 .. 1.0).contains(&n));
+            assert_eq!(n.to_bits(), rng_2.next_f64().to_bits());
+        }
+    }
+}
@@ -0,0 +1,448 @@
+/*! Complete Tox node and TCP relay running on the simulated network.
+*/
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use failure::{Error, Fail};
+use futures::{FutureExt, StreamExt, TryFutureExt};
+use futures::channel::mpsc;
+
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::dht::packed_node::PackedNode;
+use crate::toxcore::dht::server::Server;
+use crate::toxcore::friend_connection::FriendConnections;
+use crate::toxcore::friend_connection::packet::PACKET_ID_ALIVE;
+use crate::toxcore::net_crypto::{NetCrypto, NetCryptoNewArgs};
+use crate::toxcore::onion::client::OnionClient;
+use crate::toxcore::onion::packet::InnerOnionResponse;
+use crate::toxcore::simulation::SimNetwork;
+use crate::toxcore::stats::Stats;
+use crate::toxcore::tcp::client::{Connections, IncomingPacket};
+use crate::toxcore::tcp::packet::DataPayload;
+use crate::toxcore::tcp::server::{Server as TcpServer, ServerExt};
+
+/// Maximum number of clients a simulated TCP relay accepts.
+const RELAY_CONNECTIONS_LIMIT: usize = 512;
+
+/// Complete node with DHT, onion, net_crypto, friend connections and TCP
+/// connections modules running on the simulated network. TCP relays should be
+/// added via `tcp_connections` explicitly.
+#[derive(Clone)]
+pub struct SimNode {
+    /// Address of the node in the simulated network.
+    pub addr: SocketAddr,
+    /// DHT `PublicKey` of the node.
+    pub dht_pk: PublicKey,
+    /// Long term `PublicKey` of the node.
+    pub real_pk: PublicKey,
+    /// DHT server.
+    pub dht_server: Server,
+    /// TCP connections. Connections to relays are established through the
+    /// simulated network.
+    pub tcp_connections: Connections,
+    /// Onion client.
+    pub onion_client: OnionClient,
+    /// Net crypto.
+    pub net_crypto: NetCrypto,
+    /// Friend connections.
+    pub friend_connections: FriendConnections,
+}
+
+impl SimNode {
+    /// Create new node with random keys, attach it to the network with address
+    /// `addr` and spawn all its modules. Nodes from `bootstrap` are used for
+    /// DHT bootstrapping and building onion paths.
+    pub fn spawn(network: &SimNetwork, addr: SocketAddr, bootstrap: &[PackedNode]) -> SimNode {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+
+        let (tx, rx) = mpsc::channel(32);
+        let (tcp_incoming_tx, mut tcp_incoming_rx) = mpsc::unbounded();
+        let (lossless_tx, mut lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, mut lossy_rx) = mpsc::unbounded::<(PublicKey, Bytes)>();
+
+        let mut dht_server = Server::new(tx.clone(), dht_pk, dht_sk.clone());
+        dht_server.enable_lan_discovery(false);
+
+        let mut tcp_connections = Connections::new(dht_pk, dht_sk.clone(), tcp_incoming_tx);
+        tcp_connections.set_connector(Arc::new(network.connector(addr)));
+        let onion_client = OnionClient::new(dht_server.clone(), tcp_connections.clone(), real_sk.clone(), real_pk);
+
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx: tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk: real_sk.clone(),
+            precomputed_keys: dht_server.get_precomputed_keys(),
+        });
+
+        let (net_crypto_tcp_tx, mut net_crypto_tcp_rx) = mpsc::channel(32);
+        net_crypto.set_tcp_sink(net_crypto_tcp_tx);
+
+        dht_server.set_net_crypto(net_crypto.clone());
+        dht_server.set_onion_client(onion_client.clone());
+
+        for &node in bootstrap {
+            dht_server.add_initial_bootstrap(node);
+            onion_client.add_path_node(node);
+        }
+
+        let friend_connections = FriendConnections::new(
+            real_sk,
+            real_pk,
+            dht_server.clone(),
+            tcp_connections.clone(),
+            onion_client.clone(),
+            net_crypto.clone(),
+        );
+
+        let friend_connections_c = friend_connections.clone();
+        let lossless_future = async move {
+            while let Some((pk, packet)) = lossless_rx.next().await {
+                if packet.first() == Some(&PACKET_ID_ALIVE) {
+                    friend_connections_c.handle_ping(pk);
+                }
+            }
+            Result::<(), Error>::Ok(())
+        };
+
+        let tcp_connections_c = tcp_connections.clone();
+        let net_crypto_tcp_future = async move {
+            while let Some((packet, pk)) = net_crypto_tcp_rx.next().await {
+                if let Err(e) = tcp_connections_c.send_data(pk, packet).await {
+                    debug!("Node {} failed to send packet via TCP relay: {}", addr, e);
+                }
+            }
+            Result::<(), Error>::Ok(())
+        };
+
+        let onion_client_c = onion_client.clone();
+        let net_crypto_c = net_crypto.clone();
+        let tcp_incoming_future = async move {
+            while let Some((relay_pk, packet)) = tcp_incoming_rx.next().await {
+                let res = match packet {
+                    IncomingPacket::Data(sender_pk, packet) => match packet {
+                        DataPayload::CookieRequest(packet) => net_crypto_c.handle_tcp_cookie_request(&packet, sender_pk).await,
+                        DataPayload::CookieResponse(packet) => net_crypto_c.handle_tcp_cookie_response(&packet, sender_pk).await,
+                        DataPayload::CryptoHandshake(packet) => net_crypto_c.handle_tcp_crypto_handshake(&packet, sender_pk, relay_pk).await,
+                        DataPayload::CryptoData(packet) => net_crypto_c.handle_tcp_crypto_data(packet, sender_pk, relay_pk).await,
+                    }.map_err(Error::from),
+                    IncomingPacket::Oob(_sender_pk, _packet) => Ok(()),
+                    IncomingPacket::Onion(packet) => match packet {
+                        InnerOnionResponse::OnionAnnounceResponse(packet) =>
+                            onion_client_c.handle_announce_response(&packet, true).await.map_err(Error::from),
+                        InnerOnionResponse::OnionDataResponse(packet) =>
+                            onion_client_c.handle_data_response(&packet).await.map_err(Error::from),
+                    },
+                };
+                if let Err(e) = res {
+                    debug!("Node {} failed to handle packet from TCP relay {:?}: {}", addr, relay_pk, e);
+                }
+            }
+            Result::<(), Error>::Ok(())
+        };
+
+        let lossy_future = async move {
+            while lossy_rx.next().await.is_some() { }
+            Result::<(), Error>::Ok(())
+        };
+
+        let futures = vec![
+            network.run_node(dht_server.clone(), rx, addr).map_err(Error::from).boxed(),
+            tcp_connections.clone().run().map_err(|e| e.compat().into()).boxed(),
+            onion_client.clone().run().map_err(|e| e.compat().into()).boxed(),
+            net_crypto.clone().run().map_err(|e| e.compat().into()).boxed(),
+            friend_connections.clone().run().map_err(|e| e.compat().into()).boxed(),
+            net_crypto_tcp_future.boxed(),
+            tcp_incoming_future.boxed(),
+            lossless_future.boxed(),
+            lossy_future.boxed(),
+        ];
+
+        tokio::spawn(async move {
+            if let Err(e) = futures::future::try_join_all(futures).await {
+                error!("Simulated node {} stopped with error: {:?}", addr, e);
+            }
+        });
+
+        SimNode {
+            addr,
+            dht_pk,
+            real_pk,
+            dht_server,
+            tcp_connections,
+            onion_client,
+            net_crypto,
+            friend_connections,
+        }
+    }
+
+    /// `PackedNode` that can be used to bootstrap from this node.
+    pub fn packed_node(&self) -> PackedNode {
+        PackedNode::new(self.addr, &self.dht_pk)
+    }
+
+    /// Check if the node is connected to a friend.
+    pub fn is_friend_connected(&self, friend_pk: PublicKey) -> bool {
+        self.friend_connections.get_connection_status(friend_pk).unwrap_or(false)
+    }
+}
+
+/// TCP relay running on the simulated network.
+#[derive(Clone)]
+pub struct SimRelay {
+    /// Address of the relay in the simulated network.
+    pub addr: SocketAddr,
+    /// `PublicKey` of the relay.
+    pub pk: PublicKey,
+    /// TCP server.
+    pub server: TcpServer,
+}
+
+impl SimRelay {
+    /// Create new TCP relay with random keys and start accepting connections
+    /// on address `addr`.
+    pub fn spawn(network: &SimNetwork, addr: SocketAddr) -> SimRelay {
+        let (pk, sk) = gen_keypair();
+        let server = TcpServer::new();
+        let listener = network.listen(addr);
+
+        let future = server.clone().run(listener, sk, Stats::new(), RELAY_CONNECTIONS_LIMIT);
+        tokio::spawn(async move {
+            if let Err(e) = future.await {
+                error!("Simulated TCP relay {} stopped with error: {:?}", addr, e);
+            }
+        });
+
+        SimRelay {
+            addr,
+            pk,
+            server,
+        }
+    }
+
+    /// `PackedNode` that can be used to connect to this relay.
+    pub fn packed_node(&self) -> PackedNode {
+        PackedNode::new(self.addr, &self.pk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    use crate::toxcore::net_crypto::*;
+    use crate::toxcore::net_crypto::errors::SendLosslessPacketError;
+    use crate::toxcore::simulation::*;
+
+    /// Advance paused clock by `duration` in small steps letting all tasks
+    /// process packets since every advance yields to them. Stops early when
+    /// `condition` becomes true.
+    async fn run_until<F: FnMut() -> bool>(duration: Duration, mut condition: F) -> bool {
+        let step = Duration::from_millis(10);
+        let mut elapsed = Duration::from_secs(0);
+        while elapsed < duration {
+            if condition() {
+                return true;
+            }
+            tokio::time::advance(step).await;
+            elapsed += step;
+        }
+        condition()
+    }
+
+    /// Spawn `count` public nodes that bootstrap from the first one. Nodes
+    /// are placed in different /16 networks so that onion paths can be built
+    /// through them.
+    fn spawn_public_nodes(network: &SimNetwork, count: u8) -> Vec<SimNode> {
+        let first = SimNode::spawn(network, "1.1.0.1:33445".parse().unwrap(), &[]);
+        let bootstrap = [first.packed_node()];
+        let mut nodes = vec![first];
+        for i in 2 ..= count {
+            let addr = SocketAddr::new(IpAddr::from([1, i, 0, 1]), 33445);
+            nodes.push(SimNode::spawn(network, addr, &bootstrap));
+        }
+        nodes
+    }
+
+    #[tokio::test]
+    async fn dht_nodes_find_each_other() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+
+        let network = SimNetwork::new(1);
+        network.set_default_link(LinkConfig {
+            latency: Duration::from_millis(30),
+            loss: 0.1,
+        });
+        let nodes = spawn_public_nodes(&network, 16);
+
+        let connected = run_until(Duration::from_secs(60), ||
+            nodes.iter().all(|node| node.dht_server.close_nodes_count() >= 8)
+        ).await;
+
+        assert!(connected);
+        assert!(network.stats().dropped > 0);
+    }
+
+    #[tokio::test]
+    async fn partitioned_node_loses_connection() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+
+        let network = SimNetwork::new(2);
+        let nodes = spawn_public_nodes(&network, 8);
+
+        assert!(run_until(Duration::from_secs(30), ||
+            nodes.iter().all(|node| node.dht_server.is_connected())
+        ).await);
+
+        let isolated = [nodes[7].addr.ip()];
+        let others = nodes[.. 7].iter().map(|node| node.addr.ip()).collect::<Vec<_>>();
+        network.partition(&isolated, &others);
+
+        assert!(run_until(Duration::from_secs(300), ||
+            !nodes[7].dht_server.is_connected()
+        ).await);
+
+        network.heal();
+        // the node still knows about the others and reconnects via bootstrap
+        assert!(run_until(Duration::from_secs(60), ||
+            nodes[7].dht_server.is_connected()
+        ).await);
+    }
+
+    #[tokio::test]
+    async fn friends_behind_nat_find_each_other_through_onion() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+
+        let network = SimNetwork::new(3);
+        let nodes = spawn_public_nodes(&network, 12);
+        let bootstrap = nodes.iter().map(SimNode::packed_node).collect::<Vec<_>>();
+
+        let alice_nat: IpAddr = "2.0.0.1".parse().unwrap();
+        let bob_nat: IpAddr = "3.0.0.1".parse().unwrap();
+        network.add_nat(alice_nat, NatKind::PortRestrictedCone);
+        network.add_nat(bob_nat, NatKind::RestrictedCone);
+        network.put_behind_nat("10.0.0.1".parse().unwrap(), alice_nat);
+        network.put_behind_nat("10.0.1.1".parse().unwrap(), bob_nat);
+
+        let alice = SimNode::spawn(&network, "10.0.0.1:33445".parse().unwrap(), &bootstrap);
+        let bob = SimNode::spawn(&network, "10.0.1.1:33445".parse().unwrap(), &bootstrap);
+
+        alice.friend_connections.add_friend(bob.real_pk);
+        bob.friend_connections.add_friend(alice.real_pk);
+
+        let connected = run_until(Duration::from_secs(300), ||
+            alice.is_friend_connected(bob.real_pk) && bob.is_friend_connected(alice.real_pk)
+        ).await;
+
+        assert!(connected);
+    }
+
+    #[tokio::test]
+    async fn friends_connect_through_tcp_relay_when_udp_is_blocked() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+
+        let network = SimNetwork::new(4);
+        let nodes = spawn_public_nodes(&network, 12);
+        let bootstrap = nodes.iter().map(SimNode::packed_node).collect::<Vec<_>>();
+        let relay = SimRelay::spawn(&network, "1.100.0.1:33445".parse().unwrap());
+
+        let alice = SimNode::spawn(&network, "1.101.0.1:33445".parse().unwrap(), &bootstrap);
+        let bob = SimNode::spawn(&network, "1.102.0.1:33445".parse().unwrap(), &bootstrap);
+        // friends can't exchange UDP packets directly
+        network.partition(&[alice.addr.ip()], &[bob.addr.ip()]);
+
+        for node in &[&alice, &bob] {
+            node.tcp_connections.add_relay_global(relay.addr, relay.pk).await.unwrap();
+        }
+
+        alice.friend_connections.add_friend(bob.real_pk);
+        bob.friend_connections.add_friend(alice.real_pk);
+
+        let connected = run_until(Duration::from_secs(300), ||
+            alice.is_friend_connected(bob.real_pk) && bob.is_friend_connected(alice.real_pk)
+        ).await;
+
+        assert!(connected);
+        assert_eq!(relay.server.clients_count(), 2);
+    }
+
+    /// Connect two friends over a lossy link, send `count` lossless packets
+    /// from alice to bob using `controller` and return alice's connection info
+    /// once all packets are confirmed by bob.
+    async fn transfer_over_lossy_link(seed: u64, controller: Box<dyn CongestionController>, count: usize) -> Option<ConnectionInfo> {
+        let network = SimNetwork::new(seed);
+        let nodes = spawn_public_nodes(&network, 4);
+        let bootstrap = nodes.iter().map(SimNode::packed_node).collect::<Vec<_>>();
+
+        let alice = SimNode::spawn(&network, "1.101.0.1:33445".parse().unwrap(), &bootstrap);
+        let bob = SimNode::spawn(&network, "1.102.0.1:33445".parse().unwrap(), &bootstrap);
+        network.set_link(alice.addr.ip(), bob.addr.ip(), LinkConfig {
+            latency: Duration::from_millis(50),
+            loss: 0.05,
+        });
+        alice.net_crypto.set_friend_congestion_controller(bob.real_pk, controller);
+
+        alice.friend_connections.add_friend(bob.real_pk);
+        bob.friend_connections.add_friend(alice.real_pk);
+
+        let connected = run_until(Duration::from_secs(300), ||
+            alice.is_friend_connected(bob.real_pk) && bob.is_friend_connected(alice.real_pk)
+        ).await;
+        if !connected {
+            return None;
+        }
+
+        let net_crypto = alice.net_crypto.clone();
+        let bob_pk = bob.real_pk;
+        let sent = tokio::spawn(async move {
+            for i in 0 .. count {
+                net_crypto.send_lossless_ready(bob_pk, vec![160, i as u8]).await?;
+            }
+            Result::<(), SendLosslessPacketError>::Ok(())
+        });
+
+        let delivered = run_until(Duration::from_secs(300), || {
+            let info = alice.net_crypto.connection_info(bob.real_pk);
+            info.map_or(false, |info| info.packets_sent >= count as u64 && info.send_queue == 0)
+        }).await;
+        if !delivered {
+            return None;
+        }
+        sent.await.unwrap().ok()?;
+
+        alice.net_crypto.connection_info(bob.real_pk)
+    }
+
+    #[tokio::test]
+    async fn lossless_packets_are_delivered_over_lossy_link() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+
+        let controllers: Vec<Box<dyn CongestionController>> = vec![
+            Box::new(ToxCongestionController::default()),
+            Box::new(DelayCongestionController::default()),
+        ];
+        for (seed, controller) in controllers.into_iter().enumerate() {
+            let name = controller.name();
+            let info = transfer_over_lossy_link(5 + seed as u64, controller, 2000).await
+                .unwrap_or_else(|| panic!("{} controller failed to deliver packets", name));
+
+            // lost packets were resent and the controller didn't stall
+            assert!(info.packets_resent > 0, "{} controller resent no packets", name);
+            assert!(info.send_rate > 0.0, "{} controller stalled", name);
+        }
+    }
+}
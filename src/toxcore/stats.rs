@@ -1,16 +1,25 @@
 /*!
-Statistics of incoming/outgoing packets
-This is used by both Udp codec and Tcp codec.
+Statistics of incoming/outgoing packets and state of subsystems.
+Counters are updated by both Udp codec and Tcp codec. Gauges are updated
+periodically by DHT server, onion client, net crypto and TCP modules that were
+given the same `Stats` object.
 */
 
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 
+use parking_lot::RwLock;
+
+/// Number of different packet kinds that can be encoded with one byte.
+const PACKET_KINDS: usize = 256;
+
 /// Struct for various counters
 #[derive(Clone, Default)]
 pub struct Stats {
     /// incoming/outgoing counters
-    pub counters: Arc<Counters>
+    pub counters: Arc<Counters>,
+    /// Current state of subsystems
+    pub gauges: Arc<Gauges>,
 }
 
 impl Stats {
@@ -18,28 +27,209 @@ impl Stats {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Read all counters and gauges at once. Updates are blocked while the
+    /// snapshot is taken so every packet is either accounted in all counters
+    /// of the snapshot or in none of them.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let counters = &self.counters;
+        let gauges = &self.gauges;
+        let _counters_guard = counters.update_lock.write();
+        let _gauges_guard = gauges.update_lock.write();
+
+        let udp_packets = counters.udp_packets_in.iter()
+            .zip(counters.udp_packets_out.iter())
+            .enumerate()
+            .map(|(kind, (incoming, outgoing))| (Transport::Udp, kind as u8, incoming, outgoing));
+        let tcp_packets = counters.tcp_packets_in.iter()
+            .zip(counters.tcp_packets_out.iter())
+            .enumerate()
+            .map(|(kind, (incoming, outgoing))| (Transport::Tcp, kind as u8, incoming, outgoing));
+        let packets = udp_packets.chain(tcp_packets)
+            .map(|(transport, kind, incoming, outgoing)| PacketStats {
+                transport,
+                subsystem: Subsystem::from_packet_kind(transport, kind),
+                kind,
+                incoming: incoming.load(Ordering::Relaxed),
+                outgoing: outgoing.load(Ordering::Relaxed),
+            })
+            .filter(|stats| stats.incoming != 0 || stats.outgoing != 0)
+            .collect();
+
+        StatsSnapshot {
+            incoming: counters.incoming(),
+            outgoing: counters.outgoing(),
+            bytes_in: counters.bytes_in(),
+            bytes_out: counters.bytes_out(),
+            parse_failures: counters.parse_failures(),
+            decrypt_failures: counters.decrypt_failures(),
+            packets,
+            onion_announce_requests: counters.onion_announce_requests(),
+            onion_announce_responses: counters.onion_announce_responses(),
+            ktree_occupancy: gauges.ktree_occupancy(),
+            crypto_connections: gauges.crypto_connections(),
+            tcp_relays: gauges.tcp_relays(),
+            tcp_clients: gauges.tcp_clients(),
+        }
+    }
+}
+
+/// Transport protocol packet was sent or received with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Transport {
+    /// UDP packets handled by `DhtCodec`.
+    Udp,
+    /// TCP packets handled by TCP `Codec`.
+    Tcp,
+}
+
+/// Subsystem packet belongs to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Subsystem {
+    /// DHT packets: pings, nodes requests, DHT requests, LAN discovery and
+    /// bootstrap info.
+    Dht,
+    /// Onion packets.
+    Onion,
+    /// Net crypto packets: cookies, handshakes and crypto data.
+    NetCrypto,
+    /// TCP relay packets.
+    Tcp,
+    /// Packets of unknown kind.
+    Unknown,
+}
+
+impl Subsystem {
+    /// Get subsystem of a packet by its transport and the first byte of
+    /// serialized packet.
+    pub fn from_packet_kind(transport: Transport, kind: u8) -> Subsystem {
+        match transport {
+            Transport::Tcp => Subsystem::Tcp,
+            Transport::Udp => match kind {
+                0x00 | 0x01 | 0x02 | 0x04 | 0x20 | 0x21 | 0xf0 => Subsystem::Dht,
+                0x18 ..= 0x1b => Subsystem::NetCrypto,
+                0x80 ..= 0x8f => Subsystem::Onion,
+                _ => Subsystem::Unknown,
+            },
+        }
+    }
 }
 
-#[derive(Default)]
 /// A struct for counting incoming and outgoing packets.
 pub struct Counters {
     /// Incoming packets count for Udp/Tcp
     incoming: AtomicU64,
     /// Outgoing packets count for Udp/Tcp
     outgoing: AtomicU64,
+    /// Size of incoming packets for Udp/Tcp
+    bytes_in: AtomicU64,
+    /// Size of outgoing packets for Udp/Tcp
+    bytes_out: AtomicU64,
+    /// Number of packets that can't be parsed
+    parse_failures: AtomicU64,
+    /// Number of packets that can't be decrypted
+    decrypt_failures: AtomicU64,
+    /// Incoming Udp packets count by packet kind
+    udp_packets_in: Vec<AtomicU64>,
+    /// Outgoing Udp packets count by packet kind
+    udp_packets_out: Vec<AtomicU64>,
+    /// Incoming Tcp packets count by packet kind
+    tcp_packets_in: Vec<AtomicU64>,
+    /// Outgoing Tcp packets count by packet kind
+    tcp_packets_out: Vec<AtomicU64>,
+    /// Number of announce requests sent via onion paths
+    onion_announce_requests: AtomicU64,
+    /// Number of valid announce responses received via onion paths
+    onion_announce_responses: AtomicU64,
+    /// Lock that is held for reading by updates and for writing by snapshots
+    update_lock: RwLock<()>,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        fn packet_counters() -> Vec<AtomicU64> {
+            (0 .. PACKET_KINDS).map(|_| AtomicU64::new(0)).collect()
+        }
+
+        Counters {
+            incoming: AtomicU64::new(0),
+            outgoing: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            parse_failures: AtomicU64::new(0),
+            decrypt_failures: AtomicU64::new(0),
+            udp_packets_in: packet_counters(),
+            udp_packets_out: packet_counters(),
+            tcp_packets_in: packet_counters(),
+            tcp_packets_out: packet_counters(),
+            onion_announce_requests: AtomicU64::new(0),
+            onion_announce_responses: AtomicU64::new(0),
+            update_lock: RwLock::new(()),
+        }
+    }
 }
 
 impl Counters {
     /// Add 1 to incoming counter
     pub fn increase_incoming(&self) {
+        let _guard = self.update_lock.read();
         self.incoming.fetch_add(1, Ordering::Relaxed);
     }
 
     /// Add 1 to outgoing counter
     pub fn increase_outgoing(&self) {
+        let _guard = self.update_lock.read();
         self.outgoing.fetch_add(1, Ordering::Relaxed);
     }
 
+    /// Account incoming packet of specified kind and size
+    pub fn add_incoming_packet(&self, transport: Transport, kind: u8, size: usize) {
+        let _guard = self.update_lock.read();
+        self.incoming.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(size as u64, Ordering::Relaxed);
+        let counters = match transport {
+            Transport::Udp => &self.udp_packets_in,
+            Transport::Tcp => &self.tcp_packets_in,
+        };
+        counters[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account outgoing packet of specified kind and size
+    pub fn add_outgoing_packet(&self, transport: Transport, kind: u8, size: usize) {
+        let _guard = self.update_lock.read();
+        self.outgoing.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(size as u64, Ordering::Relaxed);
+        let counters = match transport {
+            Transport::Udp => &self.udp_packets_out,
+            Transport::Tcp => &self.tcp_packets_out,
+        };
+        counters[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add 1 to parse failures counter
+    pub fn increase_parse_failures(&self) {
+        let _guard = self.update_lock.read();
+        self.parse_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add 1 to decrypt failures counter
+    pub fn increase_decrypt_failures(&self) {
+        let _guard = self.update_lock.read();
+        self.decrypt_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add 1 to onion announce requests counter
+    pub fn increase_onion_announce_requests(&self) {
+        let _guard = self.update_lock.read();
+        self.onion_announce_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add 1 to onion announce responses counter
+    pub fn increase_onion_announce_responses(&self) {
+        let _guard = self.update_lock.read();
+        self.onion_announce_responses.fetch_add(1, Ordering::Relaxed);
+    }
+
     /// Get incoming counter
     pub fn incoming(&self) -> u64 {
         self.incoming.load(Ordering::Relaxed)
@@ -49,6 +239,168 @@ impl Counters {
     pub fn outgoing(&self) -> u64 {
         self.outgoing.load(Ordering::Relaxed)
     }
+
+    /// Get incoming bytes counter
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// Get outgoing bytes counter
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// Get parse failures counter
+    pub fn parse_failures(&self) -> u64 {
+        self.parse_failures.load(Ordering::Relaxed)
+    }
+
+    /// Get decrypt failures counter
+    pub fn decrypt_failures(&self) -> u64 {
+        self.decrypt_failures.load(Ordering::Relaxed)
+    }
+
+    /// Get onion announce requests counter
+    pub fn onion_announce_requests(&self) -> u64 {
+        self.onion_announce_requests.load(Ordering::Relaxed)
+    }
+
+    /// Get onion announce responses counter
+    pub fn onion_announce_responses(&self) -> u64 {
+        self.onion_announce_responses.load(Ordering::Relaxed)
+    }
+}
+
+/// A struct for current state of subsystems.
+#[derive(Default)]
+pub struct Gauges {
+    /// Number of nodes in every k-bucket of DHT close nodes list
+    ktree_occupancy: RwLock<Vec<u8>>,
+    /// Number of net crypto connections
+    crypto_connections: AtomicU64,
+    /// Number of TCP relays we are connected to as a client
+    tcp_relays: AtomicU64,
+    /// Number of clients connected to our TCP relay
+    tcp_clients: AtomicU64,
+    /// Lock that is held for reading by updates and for writing by snapshots
+    update_lock: RwLock<()>,
+}
+
+impl Gauges {
+    /// Set number of nodes in every k-bucket
+    pub fn set_ktree_occupancy(&self, occupancy: Vec<u8>) {
+        let _guard = self.update_lock.read();
+        *self.ktree_occupancy.write() = occupancy;
+    }
+
+    /// Set number of net crypto connections
+    pub fn set_crypto_connections(&self, count: u64) {
+        let _guard = self.update_lock.read();
+        self.crypto_connections.store(count, Ordering::Relaxed);
+    }
+
+    /// Set number of TCP relays we are connected to
+    pub fn set_tcp_relays(&self, count: u64) {
+        let _guard = self.update_lock.read();
+        self.tcp_relays.store(count, Ordering::Relaxed);
+    }
+
+    /// Set number of clients connected to our TCP relay
+    pub fn set_tcp_clients(&self, count: u64) {
+        let _guard = self.update_lock.read();
+        self.tcp_clients.store(count, Ordering::Relaxed);
+    }
+
+    /// Get number of nodes in every k-bucket
+    pub fn ktree_occupancy(&self) -> Vec<u8> {
+        self.ktree_occupancy.read().clone()
+    }
+
+    /// Get number of net crypto connections
+    pub fn crypto_connections(&self) -> u64 {
+        self.crypto_connections.load(Ordering::Relaxed)
+    }
+
+    /// Get number of TCP relays we are connected to
+    pub fn tcp_relays(&self) -> u64 {
+        self.tcp_relays.load(Ordering::Relaxed)
+    }
+
+    /// Get number of clients connected to our TCP relay
+    pub fn tcp_clients(&self) -> u64 {
+        self.tcp_clients.load(Ordering::Relaxed)
+    }
+}
+
+/// Number of packets of a single kind.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PacketStats {
+    /// Transport the packets were sent or received with
+    pub transport: Transport,
+    /// Subsystem the packets belong to
+    pub subsystem: Subsystem,
+    /// The first byte of serialized packet
+    pub kind: u8,
+    /// Number of incoming packets
+    pub incoming: u64,
+    /// Number of outgoing packets
+    pub outgoing: u64,
+}
+
+/// Values of all counters and gauges at some moment.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StatsSnapshot {
+    /// Incoming packets count for Udp/Tcp
+    pub incoming: u64,
+    /// Outgoing packets count for Udp/Tcp
+    pub outgoing: u64,
+    /// Size of incoming packets for Udp/Tcp
+    pub bytes_in: u64,
+    /// Size of outgoing packets for Udp/Tcp
+    pub bytes_out: u64,
+    /// Number of packets that can't be parsed
+    pub parse_failures: u64,
+    /// Number of packets that can't be decrypted
+    pub decrypt_failures: u64,
+    /// Packets count by packet kind. Kinds without packets are omitted.
+    pub packets: Vec<PacketStats>,
+    /// Number of announce requests sent via onion paths
+    pub onion_announce_requests: u64,
+    /// Number of valid announce responses received via onion paths
+    pub onion_announce_responses: u64,
+    /// Number of nodes in every k-bucket of DHT close nodes list
+    pub ktree_occupancy: Vec<u8>,
+    /// Number of net crypto connections
+    pub crypto_connections: u64,
+    /// Number of TCP relays we are connected to as a client
+    pub tcp_relays: u64,
+    /// Number of clients connected to our TCP relay
+    pub tcp_clients: u64,
+}
+
+impl StatsSnapshot {
+    /// Total number of nodes in DHT close nodes list.
+    pub fn ktree_size(&self) -> usize {
+        self.ktree_occupancy.iter().map(|&n| n as usize).sum()
+    }
+
+    /// Share of onion announce requests we received a response for. Returns
+    /// `None` if no requests were sent.
+    pub fn onion_path_success_rate(&self) -> Option<f64> {
+        if self.onion_announce_requests == 0 {
+            None
+        } else {
+            Some((self.onion_announce_responses as f64 / self.onion_announce_requests as f64).min(1.0))
+        }
+    }
+
+    /// Packets count of the subsystem as pair of incoming and outgoing
+    /// packets.
+    pub fn subsystem_packets(&self, subsystem: Subsystem) -> (u64, u64) {
+        self.packets.iter()
+            .filter(|stats| stats.subsystem == subsystem)
+            .fold((0, 0), |(incoming, outgoing), stats| (incoming + stats.incoming, outgoing + stats.outgoing))
+    }
 }
 
 #[cfg(test)]
@@ -71,4 +423,91 @@ mod tests {
         stats.counters.increase_outgoing();
         assert_eq!(2, stats.counters.outgoing());
     }
+
+    #[test]
+    fn packets() {
+        let stats = Stats::new();
+        stats.counters.add_incoming_packet(Transport::Udp, 0x02, 100);
+        stats.counters.add_incoming_packet(Transport::Udp, 0x83, 200);
+        stats.counters.add_outgoing_packet(Transport::Udp, 0x02, 50);
+        stats.counters.add_outgoing_packet(Transport::Tcp, 0x10, 30);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.incoming, 2);
+        assert_eq!(snapshot.outgoing, 2);
+        assert_eq!(snapshot.bytes_in, 300);
+        assert_eq!(snapshot.bytes_out, 80);
+        assert_eq!(snapshot.packets, vec![
+            PacketStats { transport: Transport::Udp, subsystem: Subsystem::Dht, kind: 0x02, incoming: 1, outgoing: 1 },
+            PacketStats { transport: Transport::Udp, subsystem: Subsystem::Onion, kind: 0x83, incoming: 1, outgoing: 0 },
+            PacketStats { transport: Transport::Tcp, subsystem: Subsystem::Tcp, kind: 0x10, incoming: 0, outgoing: 1 },
+        ]);
+        assert_eq!(snapshot.subsystem_packets(Subsystem::Dht), (1, 1));
+        assert_eq!(snapshot.subsystem_packets(Subsystem::NetCrypto), (0, 0));
+    }
+
+    #[test]
+    fn failures() {
+        let stats = Stats::new();
+        stats.counters.increase_parse_failures();
+        stats.counters.increase_decrypt_failures();
+        stats.counters.increase_decrypt_failures();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.parse_failures, 1);
+        assert_eq!(snapshot.decrypt_failures, 2);
+    }
+
+    #[test]
+    fn gauges() {
+        let stats = Stats::new();
+        stats.gauges.set_ktree_occupancy(vec![0, 2, 8]);
+        stats.gauges.set_crypto_connections(3);
+        stats.gauges.set_tcp_relays(4);
+        stats.gauges.set_tcp_clients(5);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.ktree_occupancy, vec![0, 2, 8]);
+        assert_eq!(snapshot.ktree_size(), 10);
+        assert_eq!(snapshot.crypto_connections, 3);
+        assert_eq!(snapshot.tcp_relays, 4);
+        assert_eq!(snapshot.tcp_clients, 5);
+    }
+
+    #[test]
+    fn snapshot_is_consistent() {
+        let stats = Stats::new();
+
+        let threads = (0 .. 4).map(|_| {
+            let stats = stats.clone();
+            std::thread::spawn(move || {
+                for _ in 0 .. 10_000 {
+                    stats.counters.add_incoming_packet(Transport::Udp, 0x02, 10);
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        for _ in 0 .. 100 {
+            let snapshot = stats.snapshot();
+            assert_eq!(snapshot.bytes_in, snapshot.incoming * 10);
+            assert_eq!(snapshot.subsystem_packets(Subsystem::Dht).0, snapshot.incoming);
+        }
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(stats.snapshot().incoming, 40_000);
+    }
+
+    #[test]
+    fn onion_path_success_rate() {
+        let stats = Stats::new();
+        assert_eq!(stats.snapshot().onion_path_success_rate(), None);
+
+        for _ in 0 .. 4 {
+            stats.counters.increase_onion_announce_requests();
+        }
+        stats.counters.increase_onion_announce_responses();
+        assert_eq!(stats.snapshot().onion_path_success_rate(), Some(0.25));
+    }
 }
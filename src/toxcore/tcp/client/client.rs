@@ -9,8 +9,8 @@ use futures::future::Either;
 use futures::channel::mpsc;
 use parking_lot::RwLock;
 use tokio_util::codec::Framed;
-use tokio::net::TcpStream;
 
+use crate::toxcore::capture::Capture;
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::onion::packet::InnerOnionResponse;
 use crate::toxcore::stats::Stats;
@@ -21,6 +21,7 @@ use crate::toxcore::tcp::links::*;
 use crate::toxcore::tcp::packet::*;
 use crate::toxcore::time::*;
 use crate::toxcore::tcp::client::errors::*;
+use crate::toxcore::transport::{Connector, TcpConnector};
 
 /// Buffer size (in packets) for outgoing packets. This number shouldn't be high
 /// to minimize latency. If some relay can't take more packets we can use
@@ -82,6 +83,10 @@ pub struct Client {
     /// List of nodes we want to be connected to. When the connection to the
     /// relay establishes we send `RouteRequest` packets with these `PublicKey`s.
     connections: Arc<RwLock<HashSet<PublicKey>>>,
+    /// Connector used to establish connection to the relay.
+    connector: Arc<dyn Connector>,
+    /// Capture of decrypted packets exchanged with the relay.
+    capture: Option<Capture>,
 }
 
 impl Client {
@@ -96,9 +101,22 @@ impl Client {
             connection_attempts: Arc::new(RwLock::new(0)),
             links: Arc::new(RwLock::new(Links::new())),
             connections: Arc::new(RwLock::new(HashSet::new())),
+            connector: Arc::new(TcpConnector),
+            capture: None,
         }
     }
 
+    /// Set connector used to establish connection to the relay. By default
+    /// `TcpConnector` is used.
+    pub fn set_connector(&mut self, connector: Arc<dyn Connector>) {
+        self.connector = connector;
+    }
+
+    /// Record decrypted packets exchanged with the relay to `capture`.
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.capture = Some(capture);
+    }
+
     /// Handle packet received from TCP relay.
     pub async fn handle_packet(&self, packet: Packet) -> Result<(), HandlePacketError> {
         match packet {
@@ -294,7 +312,7 @@ impl Client {
             _ => return Ok(()),
         }
 
-        let socket = TcpStream::connect(&self.addr).await
+        let socket = self.connector.connect(self.addr).await
             .map_err(|e| SpawnError::from(e.context(SpawnErrorKind::Io)))?;
 
         let (socket, channel) =
@@ -302,8 +320,12 @@ impl Client {
                 .map_err(|e| SpawnError::from(e.context(SpawnErrorKind::Io)))?;
 
         let stats = Stats::new();
+        let mut codec = Codec::new(channel, stats);
+        if let Some(ref capture) = self.capture {
+            codec.set_capture(capture.clone(), self.addr);
+        }
         let secure_socket =
-            Framed::new(socket, Codec::new(channel, stats));
+            Framed::new(socket, codec);
         let (mut to_server, mut from_server) =
             secure_socket.split();
         let (to_server_tx, to_server_rx) =
@@ -565,6 +587,7 @@ pub mod tests {
 
     use tokio::net::TcpListener;
 
+    use crate::toxcore::capture::{Direction, SharedBuf};
     use crate::toxcore::dht::packet::CryptoData;
     use crate::toxcore::ip_port::*;
     use crate::toxcore::onion::packet::*;
@@ -1226,7 +1249,12 @@ pub mod tests {
         // run first client
         let (client_pk_1, client_sk_1) = gen_keypair();
         let (incoming_tx_1, mut incoming_rx_1) = mpsc::unbounded();
-        let client_1 = Client::new(server_pk, addr, incoming_tx_1);
+        let mut client_1 = Client::new(server_pk, addr, incoming_tx_1);
+        // decrypted packets of the first client are captured
+        let capture_buf = SharedBuf::default();
+        let (capture, capture_writer) = Capture::new(capture_buf.clone()).unwrap();
+        client_1.set_capture(capture);
+        tokio::spawn(capture_writer.run());
         // connection attempts should be set to 0 after successful connection
         set_connection_attempts(&client_1, 3);
         client_1.clone().spawn(client_sk_1, client_pk_1).await.unwrap();
@@ -1286,6 +1314,21 @@ pub mod tests {
             assert_eq!(received_pk, client_pk_1);
             assert_eq!(received_data, data_1);
         }
+
+        // sent and received data packets of the first client are captured
+        let mut interval = tokio::time::interval(Duration::from_millis(10));
+        loop {
+            interval.next().await;
+            let records = capture_buf.records();
+            let data_records = records.iter()
+                // data packets start with connection id that is at least 0x10
+                .filter(|record| record.addr == Some(addr) && record.data.first().map_or(false, |&id| id >= 0x10))
+                .map(|record| record.direction)
+                .collect::<Vec<_>>();
+            if data_records.contains(&Direction::Outgoing) && data_records.contains(&Direction::Incoming) {
+                break;
+            }
+        }
     }
 
     #[tokio::test]
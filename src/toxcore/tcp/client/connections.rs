@@ -22,12 +22,16 @@ use futures::{future, Future, FutureExt, TryFutureExt, StreamExt};
 use futures::future::Either;
 use futures::channel::mpsc;
 
+use crate::toxcore::capture::Capture;
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::dht::packed_node::PackedNode;
 use crate::toxcore::tcp::client::client::*;
 use crate::toxcore::tcp::packet::*;
 use crate::toxcore::time::*;
+use crate::toxcore::stats::Stats;
+use crate::toxcore::shutdown::ShutdownSignal;
 use crate::toxcore::tcp::client::errors::*;
+use crate::toxcore::transport::{Connector, TcpConnector};
 use failure::Fail;
 
 /// The amount of maximum connections for each friend.
@@ -100,6 +104,12 @@ pub struct Connections {
     /// List of DHT nodes we are connected to via TCP relays. Key is a
     /// `PublicKey` of DHT node.
     connections: Arc<RwLock<HashMap<PublicKey, NodeConnection>>>,
+    /// Statistics where number of connected relays is reported.
+    stats: Stats,
+    /// Connector used to establish connections to relays.
+    connector: Arc<dyn Connector>,
+    /// Capture of decrypted packets exchanged with relays.
+    capture: Option<Capture>,
 }
 
 impl Connections {
@@ -111,16 +121,47 @@ impl Connections {
             incoming_tx,
             clients: Arc::new(RwLock::new(HashMap::new())),
             connections: Arc::new(RwLock::new(HashMap::new())),
+            stats: Stats::new(),
+            connector: Arc::new(TcpConnector),
+            capture: None,
         }
     }
 
+    /// Set connector used to establish connections to relays. By default
+    /// `TcpConnector` is used. It should be set before adding any relays.
+    pub fn set_connector(&mut self, connector: Arc<dyn Connector>) {
+        self.connector = connector;
+    }
+
+    /// Record decrypted packets exchanged with relays to `capture`. It should
+    /// be set before adding any relays.
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.capture = Some(capture);
+    }
+
+    /// Create new client for the relay with connector and capture of these
+    /// connections.
+    fn new_client(&self, relay_addr: SocketAddr, relay_pk: PublicKey) -> Client {
+        let mut client = Client::new(relay_pk, relay_addr, self.incoming_tx.clone());
+        client.set_connector(self.connector.clone());
+        if let Some(ref capture) = self.capture {
+            client.set_capture(capture.clone());
+        }
+        client
+    }
+
+    /// Set statistics object to report number of connected relays to.
+    pub fn set_stats(&mut self, stats: Stats) {
+        self.stats = stats;
+    }
+
     /// Add relay we are supposed to be connected to. These relays are necessary
     /// for initial connection so that we are able to find friends and to send
     /// them our relays. Later when more relays are received from our friends
     /// they should be added via `add_relay_connection` method.
     pub fn add_relay_global(&self, relay_addr: SocketAddr, relay_pk: PublicKey) -> impl Future<Output = Result<(), ConnectionError>> + Send {
         if let hash_map::Entry::Vacant(vacant) = self.clients.write().entry(relay_pk) {
-            let client = Client::new(relay_pk, relay_addr, self.incoming_tx.clone());
+            let client = self.new_client(relay_addr, relay_pk);
             vacant.insert(client.clone());
             Either::Left(client.spawn(self.dht_sk.clone(), self.dht_pk)
                 .map_err(|e| e.context(ConnectionErrorKind::Spawn).into()))
@@ -148,7 +189,7 @@ impl Connections {
             ).count();
 
             if online_connections_count < RECOMMENDED_FRIEND_TCP_CONNECTIONS && connections_count < MAX_FRIEND_TCP_CONNECTIONS {
-                let client = Client::new(relay_pk, relay_addr, self.incoming_tx.clone());
+                let client = self.new_client(relay_addr, relay_pk);
                 clients.insert(relay_pk, client.clone());
                 connection.connections.insert(relay_pk);
                 let future =
@@ -391,6 +432,9 @@ impl Connections {
             // TODO: remove connections if there are too many?
         }
 
+        let connected_relays = clients.values().filter(|client| client.is_connected()).count();
+        self.stats.gauges.set_tcp_relays(connected_relays as u64);
+
         future::try_join_all(futures).map_ok(drop)
     }
 
@@ -405,6 +449,28 @@ impl Connections {
 
         Ok(())
     }
+
+    /// Run TCP periodical tasks until shutdown is triggered. Then remove all
+    /// connections to friends sending `DisconnectNotification` packets and
+    /// disconnect from relays. Returns relays that were used so that they can
+    /// be saved and added back after restart.
+    pub async fn run_until(self, signal: ShutdownSignal) -> Result<Vec<PackedNode>, ConnectionError> {
+        signal.run(self.clone().run()).await?;
+
+        let node_pks = self.connections.read().keys().cloned().collect::<Vec<_>>();
+        let futures = node_pks.into_iter()
+            .map(|node_pk| self.remove_connection(node_pk));
+        future::try_join_all(futures).await?;
+
+        let clients = self.clients.read();
+        let relays = clients.values()
+            .map(|client| {
+                client.disconnect();
+                PackedNode::new(client.addr, &client.pk)
+            })
+            .collect();
+        Ok(relays)
+    }
 }
 
 #[cfg(test)]
@@ -413,6 +479,7 @@ mod tests {
 
     use crate::toxcore::dht::packet::CryptoData;
     use crate::toxcore::ip_port::*;
+    use crate::toxcore::shutdown::Shutdown;
     use crate::toxcore::tcp::client::client::tests::*;
     use crate::toxcore::tcp::connection_id::ConnectionId;
 
@@ -594,6 +661,43 @@ mod tests {
         assert!(!connections.contains_key(&node_pk));
     }
 
+    #[tokio::test]
+    async fn run_until_disconnects() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let connections = Connections::new(dht_pk, dht_sk, incoming_tx);
+
+        let (node_pk, _node_sk) = gen_keypair();
+
+        let (_incoming_rx, outgoing_rx, client) = create_client();
+        let relay_pk = client.pk;
+        let relay_addr = client.addr;
+
+        connections.clients.write().insert(relay_pk, client.clone());
+        connections.add_connection(relay_pk, node_pk).await.unwrap();
+
+        let index = 42;
+        client.handle_packet(Packet::RouteResponse(RouteResponse {
+            connection_id: ConnectionId::from_index(index),
+            pk: node_pk,
+        })).await.unwrap();
+
+        let shutdown = Shutdown::new();
+        let handle = tokio::spawn(connections.clone().run_until(shutdown.signal()));
+        shutdown.shutdown();
+
+        let relays = handle.await.unwrap().unwrap();
+        assert_eq!(relays, vec![PackedNode::new(relay_addr, &relay_pk)]);
+        assert!(!connections.has_connection(&node_pk));
+        assert!(client.is_disconnected());
+
+        let packets = outgoing_rx.collect::<Vec<_>>().await;
+        let packet = unpack!(packets.last().unwrap().clone(), Packet::DisconnectNotification);
+        assert_eq!(packet.connection_id, ConnectionId::from_index(index));
+    }
+
     #[tokio::test]
     async fn remove_connection_no_connection() {
         crypto_init().unwrap();
@@ -877,7 +981,7 @@ mod tests {
         crypto_init().unwrap();
         let (dht_pk, dht_sk) = gen_keypair();
         let (incoming_tx, _incoming_rx) = mpsc::unbounded();
-        let connections = Connections::new(dht_pk, dht_sk, incoming_tx);
+        let mut connections = Connections::new(dht_pk, dht_sk, incoming_tx);
 
         let (_incoming_rx_1, _outgoing_rx_1, relay_1) = create_client();
         let (_incoming_rx_2, _outgoing_rx_2, relay_2) = create_client();
@@ -899,6 +1003,9 @@ mod tests {
         connections.clients.write().insert(relay_pk_1, relay_1);
         connections.clients.write().insert(relay_pk_2, relay_2);
 
+        let stats = Stats::new();
+        connections.set_stats(stats.clone());
+
         tokio::time::pause();
         // time when we don't wait for connections to appear
         tokio::time::advance(TCP_CONNECTION_ANNOUNCE_TIMEOUT + Duration::from_secs(1)).await;
@@ -909,6 +1016,8 @@ mod tests {
 
         assert!(clients.get(&relay_pk_1).unwrap().is_connected());
         assert!(clients.get(&relay_pk_2).unwrap().is_sleeping());
+        // sleeping relay is not counted
+        assert_eq!(stats.gauges.tcp_relays(), 1);
     }
 
     #[test]
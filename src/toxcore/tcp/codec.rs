@@ -2,17 +2,23 @@
 */
 
 use std::io::Error as IoError;
+use std::net::SocketAddr;
 
 use crate::toxcore::binary_io::*;
+use crate::toxcore::crypto_core::MACBYTES;
 use crate::toxcore::tcp::packet::*;
 use crate::toxcore::tcp::secure::*;
 use crate::toxcore::stats::*;
+use crate::toxcore::capture::{Capture, Direction, Layer};
 
 use failure::Fail;
-use nom::{Needed, Offset, Err, error::ErrorKind};
+use nom::{Needed, Err, error::ErrorKind};
 use bytes::{BytesMut, Buf};
 use tokio_util::codec::{Decoder, Encoder};
 
+/// Size of the length prefix of serialized `EncryptedPacket`.
+const ENCRYPTED_PACKET_LEN_SIZE: usize = 2;
+
 /// Error that can happen when decoding `Packet` from bytes
 #[derive(Debug, Fail)]
 pub enum DecodeError {
@@ -89,7 +95,9 @@ impl From<IoError> for EncodeError {
 /// implements tokio-io's Decoder and Encoder to deal with Packet
 pub struct Codec {
     channel: Channel,
-    stats: Stats
+    stats: Stats,
+    /// Capture of decrypted packets and address of the peer.
+    capture: Option<(Capture, SocketAddr)>,
 }
 
 impl Codec {
@@ -97,7 +105,21 @@ impl Codec {
     pub fn new(channel: Channel, stats: Stats) -> Codec {
         Codec {
             channel,
-            stats
+            stats,
+            capture: None,
+        }
+    }
+
+    /// Record all decrypted packets of the connection with peer `addr` to
+    /// `capture`.
+    pub fn set_capture(&mut self, capture: Capture, addr: SocketAddr) {
+        self.capture = Some((capture, addr));
+    }
+
+    /// Record decrypted packet if capture is enabled.
+    fn capture(&self, direction: Direction, parsed: bool, data: &[u8]) {
+        if let Some((ref capture, addr)) = self.capture {
+            capture.record(direction, Layer::Tcp, Some(addr), parsed, data);
         }
     }
 }
@@ -107,44 +129,65 @@ impl Decoder for Codec {
     type Error = DecodeError;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // deserialize EncryptedPacket
-        let (consumed, encrypted_packet) = match EncryptedPacket::from_bytes(buf) {
+        // deserialize length of EncryptedPacket
+        let payload_len = match EncryptedPacket::payload_len(buf) {
             Err(Err::Incomplete(_)) => {
                 return Ok(None)
             },
             Err(Err::Error(error)) => {
+                self.stats.counters.increase_parse_failures();
                 let (_, kind) = error;
                 return Err(DecodeError::DeserializeEncryptedError { error: kind, buf: buf.to_vec() })
             },
             Err(Err::Failure(error)) => {
+                self.stats.counters.increase_parse_failures();
                 let (_, kind) = error;
                 return Err(DecodeError::DeserializeEncryptedError { error: kind, buf: buf.to_vec() })
             },
-            Ok((i, encrypted_packet)) => {
-                (buf.offset(i), encrypted_packet)
-            }
+            Ok((_, payload_len)) => payload_len,
         };
 
+        let consumed = ENCRYPTED_PACKET_LEN_SIZE + payload_len;
+        if buf.len() < consumed {
+            buf.reserve(consumed - buf.len());
+            return Ok(None)
+        }
+
+        // split EncryptedPacket from the buffer without copying to decrypt its
+        // payload in place
+        let mut encrypted_packet = buf.split_to(consumed);
+        encrypted_packet.advance(ENCRYPTED_PACKET_LEN_SIZE);
+
         // decrypt payload
-        let decrypted_data = self.channel.decrypt(&encrypted_packet.payload)
-            .map_err(|()| DecodeError::DecryptError)?;
+        let stats = &self.stats;
+        let decrypted_data = self.channel.decrypt_in_place(&mut encrypted_packet)
+            .map_err(|()| {
+                stats.counters.increase_decrypt_failures();
+                DecodeError::DecryptError
+            })?;
 
         // deserialize Packet
-        match Packet::from_bytes(&decrypted_data) {
-            Err(Err::Incomplete(needed)) => Err(DecodeError::IncompleteDecryptedPacket { needed, packet: decrypted_data }),
+        let packet = Packet::from_bytes(decrypted_data);
+        self.capture(Direction::Incoming, packet.is_ok(), decrypted_data);
+        match packet {
+            Err(Err::Incomplete(needed)) => {
+                self.stats.counters.increase_parse_failures();
+                Err(DecodeError::IncompleteDecryptedPacket { needed, packet: decrypted_data.to_vec() })
+            },
             Err(Err::Error(error)) => {
+                self.stats.counters.increase_parse_failures();
                 let (_, kind) = error;
-                Err(DecodeError::DeserializeDecryptedError { error: kind, packet: decrypted_data })
+                Err(DecodeError::DeserializeDecryptedError { error: kind, packet: decrypted_data.to_vec() })
             },
             Err(Err::Failure(error)) => {
+                self.stats.counters.increase_parse_failures();
                 let (_, kind) = error;
-                Err(DecodeError::DeserializeDecryptedError { error: kind, packet: decrypted_data })
+                Err(DecodeError::DeserializeDecryptedError { error: kind, packet: decrypted_data.to_vec() })
             },
             Ok((_i, packet)) => {
-                // Add 1 to incoming counter
-                self.stats.counters.increase_incoming();
+                // Account incoming packet by its kind
+                self.stats.counters.add_incoming_packet(Transport::Tcp, decrypted_data[0], consumed);
 
-                buf.advance(consumed);
                 Ok(Some(packet))
             }
         }
@@ -156,27 +199,34 @@ impl Encoder for Codec {
     type Error = EncodeError;
 
     fn encode(&mut self, packet: Self::Item, buf: &mut BytesMut) -> Result<(), Self::Error> {
-        // Add 1 to outgoing counter
-        self.stats.counters.increase_outgoing();
+        // reserve space for the length and MAC of EncryptedPacket so that
+        // Packet can be serialized and encrypted right in the buffer
+        let start = buf.len();
+        let header_size = ENCRYPTED_PACKET_LEN_SIZE + MACBYTES;
+        buf.resize(start + header_size, 0);
 
         // serialize Packet
-        let mut packet_buf = [0; MAX_TCP_PACKET_SIZE];
-        let (_, packet_size) = packet.to_bytes((&mut packet_buf, 0))
-            .map_err(|error| EncodeError::SerializeError { error })?;
-
-        // encrypt it
-        let encrypted = self.channel.encrypt(&packet_buf[..packet_size]);
-
-        // create EncryptedPacket
-        let encrypted_packet = EncryptedPacket { payload: encrypted };
-
-        // serialize EncryptedPacket to binary form
-        let mut encrypted_packet_buf = [0; MAX_TCP_ENC_PACKET_SIZE];
-        let (_, encrypted_packet_size) = encrypted_packet.to_bytes((&mut encrypted_packet_buf, 0))
-            .expect("EncryptedPacket serialize failed");  // there is nothing to fail since
-                                                                // serialized Packet is not longer than 2032 bytes
-                                                                // and we provided 2050 bytes for EncryptedPacket
-        buf.extend_from_slice(&encrypted_packet_buf[..encrypted_packet_size]);
+        let packet_size = match to_bytes_mut(&packet, buf, MAX_TCP_PACKET_SIZE) {
+            Ok(packet_size) => packet_size,
+            Err(error) => {
+                buf.truncate(start);
+                return Err(EncodeError::SerializeError { error })
+            },
+        };
+        let packet_kind = buf[start + header_size];
+
+        self.capture(Direction::Outgoing, true, &buf[start + header_size..]);
+
+        // encrypt it and write the length of encrypted payload which can't
+        // exceed 2048 bytes since serialized Packet is not longer than 2032
+        // bytes
+        self.channel.encrypt_in_place(&mut buf[start + ENCRYPTED_PACKET_LEN_SIZE..]);
+        let payload_len = (MACBYTES + packet_size) as u16;
+        buf[start..start + ENCRYPTED_PACKET_LEN_SIZE].copy_from_slice(&payload_len.to_be_bytes());
+
+        // Account outgoing packet by its kind
+        self.stats.counters.add_outgoing_packet(Transport::Tcp, packet_kind, header_size + packet_size);
+
         Ok(())
     }
 }
@@ -191,6 +241,8 @@ mod tests {
     use crate::toxcore::tcp::connection_id::ConnectionId;
 
     use std::io::{ErrorKind as IoErrorKind};
+
+    use crate::toxcore::capture::SharedBuf;
     use std::net::{
       IpAddr,
       Ipv4Addr,
@@ -314,6 +366,30 @@ mod tests {
         assert_eq!(alice_codec.decode(&mut buf).unwrap(), None);
     }
     #[test]
+    fn decode_encrypted_packet_partial() {
+        crypto_init().unwrap();
+        let (alice_channel, bob_channel) = create_channels();
+        let stats = Stats::new();
+        let mut alice_codec = Codec::new(alice_channel, stats.clone());
+        let mut bob_codec = Codec::new(bob_channel, stats);
+
+        let packet_1 = Packet::PingRequest( PingRequest { ping_id: 4242 } );
+        let packet_2 = Packet::PongResponse( PongResponse { ping_id: 4242 } );
+        let mut encoded = BytesMut::new();
+        alice_codec.encode(packet_1.clone(), &mut encoded).expect("Alice should encode");
+        alice_codec.encode(packet_2.clone(), &mut encoded).expect("Alice should encode");
+
+        // only the part of the first packet is received
+        let mut buf = encoded.split_to(10);
+        assert_eq!(bob_codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(buf.len(), 10);
+
+        buf.extend_from_slice(&encoded);
+        assert_eq!(bob_codec.decode(&mut buf).unwrap(), Some(packet_1));
+        assert_eq!(bob_codec.decode(&mut buf).unwrap(), Some(packet_2));
+        assert!(buf.is_empty());
+    }
+    #[test]
     fn decode_encrypted_packet_zero_length() {
         crypto_init().unwrap();
         let (alice_channel, _) = create_channels();
@@ -404,4 +480,39 @@ mod tests {
         // Alice cannot serialize Packet because it is too long
         assert!(alice_codec.encode(packet, &mut buf).is_err());
     }
+
+    #[tokio::test]
+    async fn encode_decode_capture() {
+        crypto_init().unwrap();
+        let (alice_channel, bob_channel) = create_channels();
+        let mut buf = BytesMut::new();
+        let stats = Stats::new();
+        let mut alice_codec = Codec::new(alice_channel, stats.clone());
+        let mut bob_codec = Codec::new(bob_channel, stats);
+
+        let capture_buf = SharedBuf::default();
+        let addr = "1.2.3.4:33445".parse().unwrap();
+        let (capture, writer) = Capture::new(capture_buf.clone()).unwrap();
+        alice_codec.set_capture(capture.clone(), addr);
+        bob_codec.set_capture(capture.clone(), addr);
+
+        let packet = Packet::PingRequest(PingRequest { ping_id: 4242 });
+        alice_codec.encode(packet.clone(), &mut buf).expect("Alice should encode");
+        let res = bob_codec.decode(&mut buf).unwrap().expect("Bob should decode");
+        assert_eq!(packet, res);
+
+        drop((capture, alice_codec, bob_codec));
+        writer.run().await;
+
+        let records = capture_buf.records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Outgoing);
+        assert_eq!(records[1].direction, Direction::Incoming);
+        for record in records {
+            assert_eq!(record.layer, Layer::Tcp);
+            assert_eq!(record.addr, Some(addr));
+            assert!(record.parsed);
+            assert_eq!(record.data[0], 0x04);
+        }
+    }
 }
@@ -16,7 +16,7 @@ use crate::toxcore::tcp::secure;
 use futures::{self, StreamExt, SinkExt, TryFutureExt};
 use std::io::{Error, ErrorKind};
 use tokio_util::codec::Framed;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
 
 /// Create a handshake from client to server
 pub fn create_client_handshake(client_pk: &PublicKey,
@@ -95,12 +95,12 @@ pub fn handle_server_handshake(common_key: &PrecomputedKey,
 
 /// Sends handshake to the server, receives handshake from the server
 /// and processes it
-pub async fn make_client_handshake(
-    socket: TcpStream,
+pub async fn make_client_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
     client_pk: &PublicKey,
     client_sk: &SecretKey,
     server_pk: &PublicKey
-) -> Result<(TcpStream, secure::Channel), Error> {
+) -> Result<(S, secure::Channel), Error> {
     let (session, common_key, handshake) =
         create_client_handshake(client_pk, client_sk, server_pk)?;
 
@@ -134,10 +134,10 @@ pub async fn make_client_handshake(
 
 /// Receives handshake from the client, processes it and
 /// sends handshake to the client
-pub async fn make_server_handshake(
-    socket: TcpStream,
+pub async fn make_server_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
     server_sk: SecretKey
-) -> Result<(TcpStream, secure::Channel, PublicKey), Error> {
+) -> Result<(S, secure::Channel, PublicKey), Error> {
     let client = Framed::new(socket, ClientHandshakeCodec);
 
     let (handshake, client) = client.into_future().await;
@@ -119,10 +119,21 @@ pub const MAX_TCP_ENC_PACKET_SIZE: usize = 2050;
 /// A serialized EncryptedPacket payload should be not longer than 2048 bytes
 pub const MAX_TCP_ENC_PACKET_PAYLOAD_SIZE: usize = 2048;
 
+impl EncryptedPacket {
+    /// Parse the length of `EncryptedPacket` payload. It allows to decrypt the
+    /// payload in place without copying it.
+    pub fn payload_len(input: &[u8]) -> IResult<&[u8], usize> {
+        do_parse!(input,
+            length: be_u16 >>
+            verify!(value!(length), |len| *len > 0 && *len as usize <= MAX_TCP_ENC_PACKET_PAYLOAD_SIZE) >>
+            (length as usize)
+        )
+    }
+}
+
 impl FromBytes for EncryptedPacket {
     named!(from_bytes<EncryptedPacket>, do_parse!(
-        length: be_u16 >>
-        verify!(value!(length), |len| *len > 0 && *len as usize <= MAX_TCP_ENC_PACKET_PAYLOAD_SIZE) >>
+        length: call!(EncryptedPacket::payload_len) >>
         payload: take!(length) >>
         (EncryptedPacket { payload: payload.to_vec() })
     ));
@@ -133,6 +133,25 @@ impl Channel {
         increment_nonce( &mut nonce );
         decrypted
     }
+    /** Encrypt data in place, increment sent_nonce
+
+    `buf` should contain `MACBYTES` of free space followed by plain data.
+    */
+    pub fn encrypt_in_place(&self, buf: &mut [u8]) {
+        let mut nonce = self.sent_nonce.borrow_mut();
+        encrypt_data_symmetric_in_place(&self.precomputed_key, &nonce, buf);
+        increment_nonce( &mut nonce );
+    }
+    /** Decrypt data in place, increment recv_nonce
+
+    Returns the slice of `buf` with plain data.
+    */
+    pub fn decrypt_in_place<'a>(&self, buf: &'a mut [u8]) -> Result<&'a mut [u8], ()> {
+        let mut nonce = self.recv_nonce.borrow_mut();
+        let decrypted = decrypt_data_symmetric_in_place(&self.precomputed_key, &nonce, buf);
+        increment_nonce( &mut nonce );
+        decrypted
+    }
 }
 
 #[cfg(test)]
@@ -182,4 +201,21 @@ mod tests {
 
         assert_eq!( bob_msg.as_bytes().to_vec(), alice_channel.decrypt(bob_msg_encrypted.as_ref()).unwrap() );
     }
+    #[test]
+    fn test_secure_communication_in_place() {
+        crypto_init().unwrap();
+        let (alice_channel, bob_channel) = create_channels();
+
+        let alice_msg = b"Hello Bob!";
+        let mut buf = vec![0; MACBYTES];
+        buf.extend_from_slice(alice_msg);
+        alice_channel.encrypt_in_place(&mut buf);
+        // in place encryption produces the same result and increments nonce
+        assert_eq!(bob_channel.decrypt_in_place(&mut buf.clone()).unwrap(), alice_msg);
+        assert!(bob_channel.decrypt_in_place(&mut buf).is_err());
+
+        let bob_msg = "Oh hello Alice!";
+        let mut bob_msg_encrypted = bob_channel.encrypt(bob_msg.as_bytes());
+        assert_eq!( alice_channel.decrypt_in_place(&mut bob_msg_encrypted).unwrap(), bob_msg.as_bytes() );
+    }
 }
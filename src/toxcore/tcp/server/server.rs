@@ -2,6 +2,7 @@
 */
 
 use crate::toxcore::crypto_core::*;
+use crate::toxcore::capture::Capture;
 use crate::toxcore::onion::packet::InnerOnionResponse;
 use crate::toxcore::tcp::server::client::Client;
 use crate::toxcore::tcp::connection_id::ConnectionId;
@@ -35,6 +36,8 @@ pub struct Server {
     state: Arc<RwLock<ServerState>>,
     // None if the server is not responsible to handle OnionRequests
     onion_sink: Option<mpsc::Sender<(OnionRequest, SocketAddr)>>,
+    // Capture of decrypted packets of all connections
+    capture: Option<Capture>,
 }
 
 #[derive(Default)]
@@ -55,6 +58,21 @@ impl Server {
     pub fn set_udp_onion_sink(&mut self, onion_sink: mpsc::Sender<(OnionRequest, SocketAddr)>) {
         self.onion_sink = Some(onion_sink)
     }
+    /** Record decrypted packets of all connections to `capture`
+    */
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.capture = Some(capture)
+    }
+    /** Get capture of decrypted packets if it's set
+    */
+    pub fn capture(&self) -> Option<&Capture> {
+        self.capture.as_ref()
+    }
+    /** Get number of clients connected to the server
+    */
+    pub fn clients_count(&self) -> usize {
+        self.state.read().connected_clients.len()
+    }
     /** Insert the client into `connected_clients`. If `connected_clients`
     contains a client with the same pk it will be terminated.
     */
@@ -136,6 +154,22 @@ impl Server {
         Either::Right(self.shutdown_client_inner(pk, &mut state))
     }
 
+    /** Shutdown all connected clients notifying their linked clients with
+    `DisconnectNotification`. Connections of removed clients are closed after
+    all queued packets are sent.
+    */
+    pub fn shutdown_all(&self) -> impl Future<Output = Result<(), Error>> + Send {
+        let mut state = self.state.write();
+
+        let pks = state.connected_clients.keys().cloned().collect::<Vec<_>>();
+        let mut futures = Vec::with_capacity(pks.len());
+        for pk in pks {
+            futures.push(self.shutdown_client_inner(&pk, &mut state));
+        }
+
+        future::try_join_all(futures).map_ok(drop)
+    }
+
     /** Actual shutdown is done here.
     */
     fn shutdown_client_inner(&self, pk: &PublicKey, state: &mut ServerState) -> impl Future<Output = Result<(), Error>> + Send {
@@ -558,6 +592,20 @@ mod tests {
         // that's all.
     }
 
+    #[tokio::test]
+    async fn clients_count() {
+        crypto_init().unwrap();
+        let server = Server::new();
+        assert_eq!(server.clients_count(), 0);
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let (client_2, _rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        server.insert(client_1).await.unwrap();
+        server.insert(client_2).await.unwrap();
+
+        assert_eq!(server.clients_count(), 2);
+    }
+
     /// A function that generates random keypair, random `std::net::IpAddr`,
     /// random port, creates mpsc channel and returns created with them Client
     fn create_random_client(saddr: SocketAddr) -> (Client, mpsc::Receiver<Packet>) {
@@ -1542,6 +1590,24 @@ mod tests {
         assert!(state.connected_clients.contains_key(&client_pk));
     }
     #[tokio::test]
+    async fn shutdown_all() {
+        crypto_init().unwrap();
+        let server = Server::new();
+
+        let (client_1, rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        server.insert(client_1).await.unwrap();
+        let (client_2, rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        server.insert(client_2).await.unwrap();
+
+        server.shutdown_all().await.unwrap();
+
+        assert_eq!(server.clients_count(), 0);
+        assert!(server.state.read().keys_by_addr.is_empty());
+        // channels to clients are closed
+        assert!(rx_1.collect::<Vec<_>>().await.is_empty());
+        assert!(rx_2.collect::<Vec<_>>().await.is_empty());
+    }
+    #[tokio::test]
     async fn shutdown_not_connected() {
         crypto_init().unwrap();
         let server = Server::new();
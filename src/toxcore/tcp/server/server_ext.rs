@@ -1,4 +1,5 @@
-/*! Extension trait for run TCP server on `TcpStream` and ping sender
+/*! Extension trait for run TCP server on `TcpStream` or any other
+`StreamTransport` and ping sender
 */
 
 use std::io::{Error as IoError};
@@ -10,7 +11,6 @@ use std::pin::Pin;
 use failure::Fail;
 use futures::{future, Future, FutureExt, TryFutureExt, SinkExt, StreamExt, TryStreamExt};
 use futures::channel::mpsc;
-use tokio::net::{TcpStream, TcpListener};
 use tokio_util::codec::Framed;
 use tokio::time::{Error as TimerError};
 // use tokio_timer::timeout::{Error as TimeoutError};
@@ -19,7 +19,9 @@ use crate::toxcore::crypto_core::*;
 use crate::toxcore::tcp::codec::{DecodeError, EncodeError, Codec};
 use crate::toxcore::tcp::handshake::make_server_handshake;
 use crate::toxcore::tcp::server::{Client, Server};
+use crate::toxcore::shutdown::ShutdownSignal;
 use crate::toxcore::stats::*;
+use crate::toxcore::transport::{Listener, StreamTransport};
 
 /// Interval of time for Tcp Ping sender
 const TCP_PING_INTERVAL: Duration = Duration::from_secs(1);
@@ -52,6 +54,13 @@ pub enum ServerRunError {
         #[fail(cause)]
         error: IoError
     },
+    /// Shutdown clients error
+    #[fail(display = "Shutdown clients error: {:?}", error)]
+    ShutdownError {
+        /// Shutdown clients error
+        #[fail(cause)]
+        error: IoError
+    },
 }
 
 /// Error that can happen during TCP connection execution
@@ -117,24 +126,31 @@ pub enum ConnectionError {
     },
 }
 
-/// Extension trait for running TCP server on incoming `TcpStream` and ping sender
+/// Extension trait for running TCP server on incoming `TcpStream` or any other
+/// `StreamTransport` and ping sender
 pub trait ServerExt {
-    /// Running TCP ping sender and incoming `TcpStream`. This function uses
-    /// `tokio::spawn` inside so it should be executed via tokio to be able to
-    /// get tokio default executor.
-    fn run(self: Self, listener: TcpListener, dht_sk: SecretKey, stats: Stats, connections_limit: usize) -> Pin<Box<dyn Future<Output = Result<(), ServerRunError>> + Send>>;
-    /// Running TCP server on incoming `TcpStream`
-    fn run_connection(self: Self, stream: TcpStream, dht_sk: SecretKey, stats: Stats) -> Box<dyn Future<Output = Result<(), ConnectionError>> + Send + Unpin>;
+    /// Running TCP ping sender and incoming connections from `TcpListener` or
+    /// any other `Listener`. This function uses `tokio::spawn` inside so it
+    /// should be executed via tokio to be able to get tokio default executor.
+    fn run<L: Listener>(self: Self, listener: L, dht_sk: SecretKey, stats: Stats, connections_limit: usize) -> Pin<Box<dyn Future<Output = Result<(), ServerRunError>> + Send>>;
+    /// Running TCP server the same way as `run` does until shutdown is
+    /// triggered. Then stop accepting connections and disconnect all clients
+    /// notifying their linked clients.
+    fn run_until<L: Listener>(self: Self, listener: L, dht_sk: SecretKey, stats: Stats, connections_limit: usize, signal: ShutdownSignal) -> Pin<Box<dyn Future<Output = Result<(), ServerRunError>> + Send>>;
+    /// Running TCP server on incoming `TcpStream` or any other
+    /// `StreamTransport`
+    fn run_connection<S: StreamTransport>(self: Self, stream: S, dht_sk: SecretKey, stats: Stats) -> Box<dyn Future<Output = Result<(), ConnectionError>> + Send + Unpin>;
 }
 
 impl ServerExt for Server {
-    fn run(self: Self, mut listener: TcpListener, dht_sk: SecretKey, stats: Stats, connections_limit: usize) -> Pin<Box<dyn Future<Output = Result<(), ServerRunError>> + Send>> {
+    fn run<L: Listener>(self: Self, mut listener: L, dht_sk: SecretKey, stats: Stats, connections_limit: usize) -> Pin<Box<dyn Future<Output = Result<(), ServerRunError>> + Send>> {
         let connections_count = Arc::new(AtomicUsize::new(0));
 
         let self_c = self.clone();
+        let stats_c = stats.clone();
 
         let connections_future = async move {
-            listener.incoming()
+            futures::stream::poll_fn(|cx| listener.poll_accept(cx).map(Some))
                 .map_err(|error| ServerRunError::IncomingError { error })
                 .try_for_each(move |stream| {
                     if connections_count.load(Ordering::SeqCst) < connections_limit {
@@ -172,6 +188,7 @@ impl ServerExt for Server {
                 trace!("Tcp server ping sender wake up");
                 self.send_pings().await
                     .map_err(|error| ServerRunError::SendPingsError { error })?;
+                stats_c.gauges.set_tcp_clients(self.clients_count() as u64);
             }
 
             Ok(())
@@ -187,7 +204,20 @@ impl ServerExt for Server {
         })
     }
 
-    fn run_connection(self: Self, stream: TcpStream, dht_sk: SecretKey, stats: Stats) -> Box<dyn Future<Output = Result<(), ConnectionError>> + Send + Unpin> {
+    fn run_until<L: Listener>(self: Self, listener: L, dht_sk: SecretKey, stats: Stats, connections_limit: usize, signal: ShutdownSignal) -> Pin<Box<dyn Future<Output = Result<(), ServerRunError>> + Send>> {
+        let run_future = self.clone().run(listener, dht_sk, stats, connections_limit);
+
+        Box::pin(async move {
+            signal.run(run_future).await?;
+
+            debug!("Shutdown TCP server with {} clients", self.clients_count());
+
+            self.shutdown_all().await
+                .map_err(|error| ServerRunError::ShutdownError { error })
+        })
+    }
+
+    fn run_connection<S: StreamTransport>(self: Self, stream: S, dht_sk: SecretKey, stats: Stats) -> Box<dyn Future<Output = Result<(), ConnectionError>> + Send + Unpin> {
         let addr = match stream.peer_addr() {
             Ok(addr) => addr,
             Err(error) => return Box::new(future::err(ConnectionError::PeerAddrError {
@@ -214,7 +244,11 @@ impl ServerExt for Server {
 
             debug!("Handshake for TCP client {:?} is completed", client_pk);
 
-            let secure_socket = Framed::new(stream, Codec::new(channel, stats));
+            let mut codec = Codec::new(channel, stats);
+            if let Some(capture) = self.capture() {
+                codec.set_capture(capture.clone(), addr);
+            }
+            let secure_socket = Framed::new(stream, codec);
             let (mut to_client, from_client) = secure_socket.split();
             let (to_client_tx, mut to_client_rx) = mpsc::channel(SERVER_CHANNEL_SIZE);
 
@@ -271,13 +305,19 @@ impl ServerExt for Server {
 mod tests {
     use super::*;
 
+    use std::net::SocketAddr;
+    use std::task::{Context, Poll};
+
     use failure::Error;
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio::net::{TcpStream, TcpListener};
 
     use crate::toxcore::tcp::codec::Codec;
     use crate::toxcore::tcp::handshake::make_client_handshake;
     use crate::toxcore::tcp::packet::{Packet, PingRequest, PongResponse};
 
     use crate::toxcore::tcp::server::client::*;
+    use crate::toxcore::shutdown::Shutdown;
 
     #[tokio::test]
     async fn run_connection() {
@@ -367,4 +407,120 @@ mod tests {
         let r = both.await.into_inner().0;
         assert!(r.is_ok());
     }
+
+    #[tokio::test]
+    async fn run_until() {
+        crypto_init().unwrap();
+
+        let (client_pk, client_sk) = gen_keypair();
+        let (server_pk, server_sk) = gen_keypair();
+
+        let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(&addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stats = Stats::new();
+        let server = Server::new();
+        let shutdown = Shutdown::new();
+        let server_handle = tokio::spawn(
+            server.clone().run_until(listener, server_sk, stats.clone(), 1, shutdown.signal())
+        );
+
+        let socket = TcpStream::connect(&addr).await.unwrap();
+        let (stream, channel) = make_client_handshake(socket, &client_pk, &client_sk, &server_pk).await.unwrap();
+
+        let secure_socket = Framed::new(stream, Codec::new(channel, stats));
+        let (mut to_server, mut from_server) = secure_socket.split();
+        to_server.send(Packet::PingRequest(PingRequest {
+            ping_id: 42
+        })).await.unwrap();
+        let packet = from_server.next().await.unwrap();
+        assert_eq!(packet.unwrap(), Packet::PongResponse(PongResponse {
+            ping_id: 42
+        }));
+        assert_eq!(server.clients_count(), 1);
+
+        shutdown.shutdown();
+        server_handle.await.unwrap().unwrap();
+
+        assert_eq!(server.clients_count(), 0);
+        // connection is closed by the server
+        assert!(from_server.next().await.is_none());
+    }
+
+    /// Obfuscating wrapper that XORs all bytes of the stream.
+    struct XorStream(TcpStream);
+
+    const XOR_KEY: u8 = 0x5a;
+
+    impl AsyncRead for XorStream {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<Result<usize, IoError>> {
+            let n = futures::ready!(Pin::new(&mut self.0).poll_read(cx, buf))?;
+            buf[.. n].iter_mut().for_each(|b| *b ^= XOR_KEY);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl AsyncWrite for XorStream {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<Result<usize, IoError>> {
+            let buf = buf.iter().map(|b| b ^ XOR_KEY).collect::<Vec<_>>();
+            Pin::new(&mut self.0).poll_write(cx, &buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), IoError>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), IoError>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    impl StreamTransport for XorStream {
+        fn peer_addr(&self) -> Result<SocketAddr, IoError> {
+            self.0.peer_addr()
+        }
+    }
+
+    #[tokio::test]
+    async fn run_connection_custom_transport() {
+        crypto_init().unwrap();
+        let (client_pk, client_sk) = gen_keypair();
+        let (server_pk, server_sk) = gen_keypair();
+
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut listener = TcpListener::bind(&addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stats = Stats::new();
+        let stats_c = stats.clone();
+        let server = async {
+            let connection = listener.incoming().next().await.unwrap().unwrap();
+            Server::new().run_connection(XorStream(connection), server_sk, stats)
+                .map_err(Error::from).await
+        };
+
+        let client = async {
+            let socket = TcpStream::connect(&addr).map_err(Error::from).await?;
+            let (stream, channel) = make_client_handshake(XorStream(socket), &client_pk, &client_sk, &server_pk)
+                .map_err(Error::from).await?;
+            let secure_socket = Framed::new(stream, Codec::new(channel, stats_c));
+            let (mut to_server, mut from_server) = secure_socket.split();
+            let packet = Packet::PingRequest(PingRequest {
+                ping_id: 42
+            });
+
+            to_server.send(packet).map_err(Error::from).await.unwrap();
+            let packet = from_server.next().await.unwrap();
+
+            assert_eq!(packet.unwrap(), Packet::PongResponse(PongResponse {
+                ping_id: 42
+            }));
+            Ok(())
+        };
+
+        let both = futures::future::select(server.boxed(), client.boxed());
+        let r = both.await.into_inner().0;
+        assert!(r.is_ok());
+    }
 }
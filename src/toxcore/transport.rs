@@ -0,0 +1,256 @@
+/*! Transport abstractions for running DHT and TCP relay servers.
+
+DHT server needs only a datagram carrier and TCP relay server and client need
+only reliable byte streams, so both are described by small traits implemented
+for tokio sockets. Implementing them for other carriers like Unix sockets, QUIC
+datagrams, in-process pipes or obfuscating wrappers allows to reuse
+`dht::codec`, `tcp::handshake` and `tcp::codec` without changes.
+*/
+
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::BytesMut;
+use futures::{ready, FutureExt, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Size of the buffer for incoming datagrams. It's enough for any UDP packet.
+const RECV_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Unreliable carrier of addressed datagrams like `UdpSocket`.
+pub trait DatagramTransport: Send + Unpin + 'static {
+    /// Address this transport is bound to. Its address family is used to
+    /// route outgoing packets when DHT server runs on several transports.
+    fn local_addr(&self) -> Result<SocketAddr, Error>;
+    /// Attempt to receive a single datagram into `buf` returning its length
+    /// and the sender address.
+    fn poll_recv_from(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<Result<(usize, SocketAddr), Error>>;
+    /// Attempt to send a single datagram to `target` returning the number of
+    /// bytes sent.
+    fn poll_send_to(&mut self, cx: &mut Context, buf: &[u8], target: &SocketAddr) -> Poll<Result<usize, Error>>;
+}
+
+impl DatagramTransport for UdpSocket {
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn poll_recv_from(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<Result<(usize, SocketAddr), Error>> {
+        UdpSocket::poll_recv_from(self, cx, buf)
+    }
+
+    fn poll_send_to(&mut self, cx: &mut Context, buf: &[u8], target: &SocketAddr) -> Poll<Result<usize, Error>> {
+        UdpSocket::poll_send_to(self, cx, buf, target)
+    }
+}
+
+/// Reliable ordered byte stream like `TcpStream`.
+pub trait StreamTransport: AsyncRead + AsyncWrite + Send + Unpin + 'static {
+    /// Address of the remote peer. TCP relay server uses it to identify
+    /// clients along with their `PublicKey`.
+    fn peer_addr(&self) -> Result<SocketAddr, Error>;
+}
+
+impl StreamTransport for TcpStream {
+    fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        TcpStream::peer_addr(self)
+    }
+}
+
+/// Source of incoming stream connections like `TcpListener`.
+pub trait Listener: Send + Unpin + 'static {
+    /// Type of accepted connections.
+    type Stream: StreamTransport;
+    /// Attempt to accept a new incoming connection.
+    fn poll_accept(&mut self, cx: &mut Context) -> Poll<Result<Self::Stream, Error>>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn poll_accept(&mut self, cx: &mut Context) -> Poll<Result<TcpStream, Error>> {
+        TcpListener::poll_accept(self, cx).map_ok(|(stream, _addr)| stream)
+    }
+}
+
+/// Future returned by `Connector::connect`.
+pub type ConnectFuture = Pin<Box<dyn Future<Output = Result<Box<dyn StreamTransport>, Error>> + Send>>;
+
+/// Source of outgoing stream connections like `TcpStream::connect`. It's used
+/// by TCP relay client to establish connections to relays.
+pub trait Connector: Send + Sync + 'static {
+    /// Establish a new connection to `addr`.
+    fn connect(&self, addr: SocketAddr) -> ConnectFuture;
+}
+
+/// `Connector` that establishes TCP connections.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpConnector;
+
+impl Connector for TcpConnector {
+    fn connect(&self, addr: SocketAddr) -> ConnectFuture {
+        TcpStream::connect(addr)
+            .map(|res| res.map(|stream| Box::new(stream) as Box<dyn StreamTransport>))
+            .boxed()
+    }
+}
+
+/// Unified `Stream` and `Sink` interface to a `DatagramTransport` using a
+/// codec to encode and decode datagrams. It's an analogue of `UdpFramed` that
+/// works with any `DatagramTransport`.
+pub struct DatagramFramed<T, C> {
+    transport: T,
+    codec: C,
+    recv_buf: Box<[u8]>,
+    rd: BytesMut,
+    wr: BytesMut,
+    out_addr: Option<SocketAddr>,
+}
+
+impl<T: DatagramTransport, C> DatagramFramed<T, C> {
+    /// Create new `DatagramFramed` backed by the given transport and codec.
+    pub fn new(transport: T, codec: C) -> DatagramFramed<T, C> {
+        DatagramFramed {
+            transport,
+            codec,
+            recv_buf: vec![0; RECV_BUFFER_SIZE].into_boxed_slice(),
+            rd: BytesMut::new(),
+            wr: BytesMut::new(),
+            out_addr: None,
+        }
+    }
+
+    /// Get a reference to the underlying transport.
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    /// Consume `DatagramFramed` returning the underlying transport.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+impl<T: DatagramTransport, C: Decoder + Unpin> Stream for DatagramFramed<T, C> {
+    type Item = Result<(C::Item, SocketAddr), C::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            let (n, addr) = ready!(this.transport.poll_recv_from(cx, &mut this.recv_buf))?;
+
+            this.rd.clear();
+            this.rd.extend_from_slice(&this.recv_buf[.. n]);
+
+            // datagrams that don't contain a frame are skipped
+            if let Some(frame) = this.codec.decode(&mut this.rd)? {
+                return Poll::Ready(Some(Ok((frame, addr))));
+            }
+        }
+    }
+}
+
+impl<T: DatagramTransport, C: Encoder + Unpin> Sink<(C::Item, SocketAddr)> for DatagramFramed<T, C> {
+    type Error = C::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, (frame, addr): (C::Item, SocketAddr)) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        this.codec.encode(frame, &mut this.wr)?;
+        this.out_addr = Some(addr);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        let addr = match this.out_addr {
+            Some(addr) => addr,
+            None => return Poll::Ready(Ok(())),
+        };
+
+        let n = ready!(this.transport.poll_send_to(cx, &this.wr, &addr))?;
+        let wrote_all = n == this.wr.len();
+        this.wr.clear();
+        this.out_addr = None;
+
+        if wrote_all {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Ready(Err(Error::new(ErrorKind::Other, "Failed to write entire datagram").into()))
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::BytesCodec;
+
+    #[tokio::test]
+    async fn udp_socket_framed() {
+        let socket_1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket_2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr_1 = DatagramTransport::local_addr(&socket_1).unwrap();
+        let addr_2 = DatagramTransport::local_addr(&socket_2).unwrap();
+
+        let mut framed_1 = DatagramFramed::new(socket_1, BytesCodec::new());
+        let mut framed_2 = DatagramFramed::new(socket_2, BytesCodec::new());
+
+        framed_1.send((bytes::Bytes::from_static(b"hello"), addr_2)).await.unwrap();
+        let (data, addr) = framed_2.next().await.unwrap().unwrap();
+        assert_eq!(&data[..], b"hello");
+        assert_eq!(addr, addr_1);
+    }
+
+    #[tokio::test]
+    async fn udp_socket_framed_skips_empty_frames() {
+        let socket_1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket_2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr_2 = DatagramTransport::local_addr(&socket_2).unwrap();
+
+        let mut framed_1 = DatagramFramed::new(socket_1, BytesCodec::new());
+        let mut framed_2 = DatagramFramed::new(socket_2, BytesCodec::new());
+
+        // BytesCodec doesn't produce a frame from an empty datagram
+        framed_1.send((bytes::Bytes::new(), addr_2)).await.unwrap();
+        framed_1.send((bytes::Bytes::from_static(b"hello"), addr_2)).await.unwrap();
+        let (data, _addr) = framed_2.next().await.unwrap().unwrap();
+        assert_eq!(&data[..], b"hello");
+    }
+
+    #[tokio::test]
+    async fn tcp_listener_accept() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = TcpStream::connect(addr).await.unwrap();
+        let accepted = futures::future::poll_fn(|cx| Listener::poll_accept(&mut listener, cx)).await.unwrap();
+        assert_eq!(StreamTransport::peer_addr(&accepted).unwrap(), client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn tcp_connector_connect() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stream = TcpConnector.connect(addr).await.unwrap();
+        let accepted = futures::future::poll_fn(|cx| Listener::poll_accept(&mut listener, cx)).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        assert_eq!(accepted.local_addr().unwrap(), addr);
+    }
+}
@@ -0,0 +1,182 @@
+/*! Binding of UDP sockets with port range fallback.
+
+Like c-toxcore, [`UdpBinder`] tries ports from a range one by one when the
+default port is already taken so several clients can run on the same host.
+The bound address is returned together with the socket, its port is the one
+other nodes see as the source of `LanDiscovery` and `BootstrapInfo` packets.
+
+[`UdpBinder`]: ./struct.UdpBinder.html
+*/
+
+use std::io::ErrorKind as IoErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+
+use failure::{Fail, ResultExt};
+use tokio::net::UdpSocket;
+
+error_kind! {
+    #[doc = "Error that can happen when binding UDP socket."]
+    #[derive(Debug)]
+    BindError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, Fail)]
+    BindErrorKind {
+        #[doc = "Error indicates that the port range is empty."]
+        #[fail(display = "Port range is empty")]
+        EmptyPortRange,
+        #[doc = "Error indicates that all ports from the range are in use."]
+        #[fail(display = "All ports from the range are in use")]
+        AddrInUse,
+        #[doc = "Error indicates that the socket can't be bound."]
+        #[fail(display = "Bind socket error")]
+        Bind,
+        #[doc = "Error indicates that socket options can't be set."]
+        #[fail(display = "Set socket option error")]
+        SetOption,
+    }
+}
+
+/// First port of the default range for Tox UDP sockets.
+pub const PORT_RANGE_START: u16 = 33445;
+
+/// Last port of the default range for Tox UDP sockets.
+pub const PORT_RANGE_END: u16 = 33545;
+
+/// Factory of UDP sockets that tries ports from a range until binding
+/// succeeds.
+#[derive(Clone, Debug)]
+pub struct UdpBinder {
+    /// IP address to bind sockets to.
+    ip: IpAddr,
+    /// Ports to try in order. Range `0 ..= 0` means random port chosen by OS.
+    ports: RangeInclusive<u16>,
+    /// Whether `SO_BROADCAST` option should be set.
+    broadcast: bool,
+    /// Whether `IPV6_MULTICAST_LOOP` option should be set for IPv6 sockets.
+    multicast_loop_v6: bool,
+}
+
+impl UdpBinder {
+    /// Create new `UdpBinder` with default port range and with broadcast and
+    /// IPv6 multicast loop enabled as required by `LanDiscovery`.
+    pub fn new(ip: IpAddr) -> UdpBinder {
+        UdpBinder {
+            ip,
+            ports: PORT_RANGE_START ..= PORT_RANGE_END,
+            broadcast: true,
+            multicast_loop_v6: true,
+        }
+    }
+
+    /// Set ports to try. Range `0 ..= 0` means random port chosen by OS.
+    pub fn set_port_range(&mut self, ports: RangeInclusive<u16>) {
+        self.ports = ports;
+    }
+
+    /// Set whether `SO_BROADCAST` option should be set.
+    pub fn set_broadcast(&mut self, broadcast: bool) {
+        self.broadcast = broadcast;
+    }
+
+    /// Set whether `IPV6_MULTICAST_LOOP` option should be set for IPv6
+    /// sockets.
+    pub fn set_multicast_loop_v6(&mut self, multicast_loop_v6: bool) {
+        self.multicast_loop_v6 = multicast_loop_v6;
+    }
+
+    /// Bind UDP socket to the first free port from the range. Returns the
+    /// socket and the address it's bound to.
+    pub async fn bind(&self) -> Result<(UdpSocket, SocketAddr), BindError> {
+        if self.ports.start() > self.ports.end() {
+            return Err(BindErrorKind::EmptyPortRange.into());
+        }
+
+        for port in self.ports.clone() {
+            let addr = SocketAddr::new(self.ip, port);
+            match UdpSocket::bind(&addr).await {
+                Ok(socket) => return self.configure(socket),
+                Err(ref e) if e.kind() == IoErrorKind::AddrInUse => {
+                    debug!("UDP port {} is in use, trying the next one", port);
+                },
+                Err(e) => return Err(e.context(BindErrorKind::Bind).into()),
+            }
+        }
+
+        Err(BindErrorKind::AddrInUse.into())
+    }
+
+    /// Set socket options and get the bound address.
+    fn configure(&self, socket: UdpSocket) -> Result<(UdpSocket, SocketAddr), BindError> {
+        if self.broadcast {
+            socket.set_broadcast(true)
+                .context(BindErrorKind::SetOption)?;
+        }
+        if self.multicast_loop_v6 && self.ip.is_ipv6() {
+            socket.set_multicast_loop_v6(true)
+                .context(BindErrorKind::SetOption)?;
+        }
+
+        let addr = socket.local_addr()
+            .context(BindErrorKind::Bind)?;
+        info!("Bound UDP socket to {}", addr);
+
+        Ok((socket, addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn localhost() -> IpAddr {
+        "127.0.0.1".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn bind_random_port() {
+        let mut binder = UdpBinder::new(localhost());
+        binder.set_port_range(0 ..= 0);
+
+        let (socket, addr) = binder.bind().await.unwrap();
+        assert_eq!(socket.local_addr().unwrap(), addr);
+        assert_ne!(addr.port(), 0);
+        assert!(socket.broadcast().unwrap());
+    }
+
+    #[tokio::test]
+    async fn bind_next_port() {
+        let mut binder = UdpBinder::new(localhost());
+        binder.set_port_range(0 ..= 0);
+        let (_socket, taken_addr) = binder.bind().await.unwrap();
+        let port = taken_addr.port();
+
+        binder.set_port_range(port ..= port.saturating_add(10));
+        binder.set_broadcast(false);
+        let (socket, addr) = binder.bind().await.unwrap();
+        assert!(addr.port() > port && addr.port() <= port.saturating_add(10));
+        assert!(!socket.broadcast().unwrap());
+    }
+
+    #[tokio::test]
+    async fn bind_all_ports_in_use() {
+        let mut binder = UdpBinder::new(localhost());
+        binder.set_port_range(0 ..= 0);
+        let (_socket, taken_addr) = binder.bind().await.unwrap();
+        let port = taken_addr.port();
+
+        binder.set_port_range(port ..= port);
+        let error = binder.bind().await.err().unwrap();
+        assert_eq!(*error.kind(), BindErrorKind::AddrInUse);
+    }
+
+    #[allow(clippy::reversed_empty_ranges)]
+    #[tokio::test]
+    async fn bind_empty_range() {
+        let mut binder = UdpBinder::new(localhost());
+        binder.set_port_range(2 ..= 1);
+
+        let error = binder.bind().await.err().unwrap();
+        assert_eq!(*error.kind(), BindErrorKind::EmptyPortRange);
+    }
+}